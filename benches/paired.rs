@@ -0,0 +1,555 @@
+//! Paired-thread benchmarks of the queues and packets against the library.
+//!
+//! Unlike the `#[bench]`s in the bench binary, which time a producer against a
+//! free-running consumer, every sample here runs a fresh queue from the first
+//! send to the last receive with both ends timed, as the bench binary's table
+//! does. Each row is repeated over payload sizes and reports the spread of
+//! its samples, along with the change from the last run's row of the same
+//! name when the two don't overlap.
+//!
+//! `cargo bench --features queue_experiments --bench paired [-- <filter>]`
+//!
+//! Only the rows whose names contain one of the filters are run.
+//! `PAIRED_SAMPLES` and `PAIRED_MSGS` set the number of samples per row and
+//! the number of messages per sample.
+//!
+//! `-- --plot-data <dir>` also writes the rows which sweep a parameter (the
+//! cache bound, the capacity, the producers or the payload size) to `<dir>`,
+//! one data file per queue with the parameter, mean and standard deviation in
+//! columns, along with a gnuplot script per sweep, e.g. `<dir>/cache_bound.gp`,
+//! which draws them to an svg next to it.
+#![feature(test)]
+#![allow(dead_code)]
+
+extern crate crossbeam;
+extern crate std_spsc_is_slow;
+extern crate test;
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::time::{Duration, Instant};
+
+use crossbeam::scope;
+use test::black_box;
+
+use std_spsc_is_slow::{spsc, spsc2, spsc_seg, spsc_epoch, mpmc, mpmc2, bounded_mpmc, stream, stream2};
+use std_spsc_is_slow::cache_padded::Padding;
+
+const SAMPLES: usize = 20;
+const MSGS: u64 = 100_000;
+
+// Two-sided 95% for a normal distribution, which the sample mean is close
+// enough to at these sample counts.
+const Z_95: f64 = 1.96;
+
+/// A parameter some rows vary, for `--plot-data`.
+struct Sweep {
+    name: &'static str,
+    label: &'static str,
+    log_x: bool,
+}
+
+const CACHE_BOUND: Sweep = Sweep { name: "cache_bound", label: "node cache bound", log_x: true };
+const CAPACITY: Sweep = Sweep { name: "capacity", label: "capacity (slots)", log_x: true };
+const PRODUCERS: Sweep = Sweep { name: "producers", label: "producers", log_x: false };
+const PAYLOAD: Sweep = Sweep { name: "payload", label: "payload (bytes)", log_x: true };
+
+/// A message of some size, built from its index.
+trait Payload: Send + Sync + Debug + 'static {
+    const NAME: &'static str;
+    fn make(i: u64) -> Self;
+}
+
+impl Payload for u64 {
+    const NAME: &'static str = "8B";
+    fn make(i: u64) -> Self { i }
+}
+
+impl Payload for [u64; 8] {
+    const NAME: &'static str = "64B";
+    fn make(i: u64) -> Self { [i; 8] }
+}
+
+impl Payload for [u64; 128] {
+    const NAME: &'static str = "1KiB";
+    fn make(i: u64) -> Self { [i; 128] }
+}
+
+fn main() {
+    let mut runner = Runner::new();
+    matrix::<u64>(&mut runner);
+    matrix::<[u64; 8]>(&mut runner);
+    matrix::<[u64; 128]>(&mut runner);
+    runner.save();
+}
+
+// The bench binary's table, less the multi-consumer mpmc2 rows and the
+// batched pushes, which don't fit one sender function shared by producers.
+// The criterion bench, `queues`, has both.
+fn matrix<T: Payload>(r: &mut Runner) {
+    let row = r.paired("std channel", 1, || {
+        let (tx, rx) = channel::<T>();
+        (move |t| tx.send(t).unwrap(), move || rx.recv().unwrap())
+    });
+    r.plot_payload::<T>("std channel", row);
+
+    r.paired("mpmc", 1, || mpmc_pair::<T, _, _>(mpmc::Queue::new()));
+    let row = r.paired("mpmc aligned", 1, || mpmc_pair::<T, _, _>(mpmc::Queue::aligned()));
+    r.plot_payload::<T>("mpmc aligned", row);
+    r.plot::<T>(&PRODUCERS, "mpmc aligned", 1, row);
+    r.paired("mpmc node cache 128", 1, || mpmc_pair::<T, _, _>(mpmc::Queue::with_node_cache(128)));
+    r.paired("mpmc aligned node cache 128", 1, || mpmc_pair::<T, _, _>(mpmc::Queue::aligned_with_node_cache(128)));
+    for &producers in &[2, 4] {
+        let row = r.paired(&format!("mpmc aligned {}p", producers), producers,
+            || mpmc_pair::<T, _, _>(mpmc::Queue::aligned()));
+        r.plot::<T>(&PRODUCERS, "mpmc aligned", producers, row);
+    }
+    r.paired("mpmc aligned node cache 128 4p", 4,
+        || mpmc_pair::<T, _, _>(mpmc::Queue::aligned_with_node_cache(128)));
+    r.paired("mpmc AcqRel swap 4p", 4,
+        || mpmc_pair::<T, _, _>(mpmc::Queue::<_, _, mpmc::AcqRelSwap>::aligned_with_ordering()));
+    r.paired("mpmc fenced swap 4p", 4,
+        || mpmc_pair::<T, _, _>(mpmc::Queue::<_, _, mpmc::FencedSwap>::aligned_with_ordering()));
+
+    for &producers in &[1, 2, 4] {
+        let row = r.paired(&format!("mpmc2 {}p", producers), producers,
+            || mpmc2_pair::<T, _>(mpmc2::Queue::new()));
+        r.plot::<T>(&PRODUCERS, "mpmc2", producers, row);
+        let row = r.paired(&format!("mpmc2 aligned {}p", producers), producers,
+            || mpmc2_pair::<T, _>(mpmc2::Queue::aligned()));
+        r.plot::<T>(&PRODUCERS, "mpmc2 aligned", producers, row);
+    }
+
+    for &producers in &[1, 4] {
+        for &capacity in &[128, 1024, 8192] {
+            let row = r.paired(&format!("bounded {} {}p", capacity, producers), producers,
+                || bounded_pair::<T, _>(bounded_mpmc::Queue::new(capacity)));
+            r.plot::<T>(&CAPACITY, &format!("bounded {}p", producers), capacity as u64, row);
+            let row = r.paired(&format!("bounded aligned {} {}p", capacity, producers), producers,
+                || bounded_pair::<T, _>(bounded_mpmc::Queue::aligned(capacity)));
+            r.plot::<T>(&CAPACITY, &format!("bounded aligned {}p", producers), capacity as u64, row);
+            if capacity == 8192 && producers == 1 {
+                r.plot_payload::<T>("bounded aligned 8192 1p", row);
+            }
+        }
+    }
+
+    // The spsc queues are only safe with one producer, which `paired` is
+    // always given for these.
+    unsafe {
+        for &bound in &[0, 1, 16, 128, 1024] {
+            let plain = r.paired(&format!("spsc cache {}", bound), 1,
+                || spsc_pair::<T, _, _>(spsc::Queue::new(bound)));
+            let aligned = r.paired(&format!("spsc aligned cache {}", bound), 1,
+                || spsc_pair::<T, _, _>(spsc::Queue::aligned(bound)));
+            // 0 is unbounded, which has no place on the axis
+            if bound > 0 {
+                r.plot::<T>(&CACHE_BOUND, "spsc", bound as u64, plain);
+                r.plot::<T>(&CACHE_BOUND, "spsc aligned", bound as u64, aligned);
+            }
+            if bound == 128 {
+                r.plot_payload::<T>("spsc aligned cache 128", aligned);
+            }
+        }
+        r.paired("spsc no cache", 1, || spsc_pair::<T, _, _>(spsc::Queue::no_cache()));
+        r.paired("spsc aligned no cache", 1, || spsc_pair::<T, _, _>(spsc::Queue::aligned_no_cache()));
+
+        r.paired("spsc2 128", 1, || spsc2_pair::<T, _>(spsc2::Queue::new(128)));
+        for &bound in &[1, 8, 16, 32, 64, 128, 256, 512, 1024] {
+            let row = r.paired(&format!("spsc2 aligned {}", bound), 1,
+                || spsc2_pair::<T, _>(spsc2::Queue::aligned(bound)));
+            r.plot::<T>(&CACHE_BOUND, "spsc2 aligned", bound as u64, row);
+            if bound == 128 {
+                r.plot_payload::<T>("spsc2 aligned 128", row);
+            }
+        }
+
+        r.paired("spsc_seg", 1, || spsc_seg_pair::<T, _>(spsc_seg::Queue::new()));
+        let row = r.paired("spsc_seg aligned", 1, || spsc_seg_pair::<T, _>(spsc_seg::Queue::aligned()));
+        r.plot_payload::<T>("spsc_seg aligned", row);
+        r.paired("spsc_epoch", 1, || spsc_epoch_pair::<T>(spsc_epoch::Queue::new()));
+    }
+
+    r.paired("stream", 1, || stream_pair::<spsc::_NQueue<_>, T>());
+    r.paired("stream aligned", 1, || stream_pair::<spsc::CNQueue<_>, T>());
+    r.paired("stream no cache", 1, || stream_pair::<spsc::__Queue<_>, T>());
+    r.paired("stream aligned no cache", 1, || stream_pair::<spsc::C_Queue<_>, T>());
+    r.paired("stream less contend", 1, || stream_pair::<spsc2::_Queue<_>, T>());
+    r.paired("stream less contend aligned", 1, || stream_pair::<spsc2::AQueue<_>, T>());
+
+    for &spin in &[0, 100] {
+        let spun = if spin == 0 { String::new() } else { format!(" spin {}", spin) };
+        r.paired(&format!("packet{}", spun), 1, || packet_pair::<spsc::_NQueue<_>, T>(spin));
+        let row = r.paired(&format!("packet aligned{}", spun), 1, || packet_pair::<spsc::CNQueue<_>, T>(spin));
+        r.plot_payload::<T>(&format!("packet aligned{}", spun), row);
+        r.paired(&format!("packet no cache{}", spun), 1, || packet_pair::<spsc::__Queue<_>, T>(spin));
+        r.paired(&format!("packet aligned no cache{}", spun), 1, || packet_pair::<spsc::C_Queue<_>, T>(spin));
+        r.paired(&format!("packet less contend{}", spun), 1, || packet_pair::<spsc2::_Queue<_>, T>(spin));
+        r.paired(&format!("packet less contend aligned{}", spun), 1, || packet_pair::<spsc2::AQueue<_>, T>(spin));
+        r.paired(&format!("packet segmented{}", spun), 1, || packet_pair::<spsc_seg::_Queue<_>, T>(spin));
+        r.paired(&format!("packet segmented aligned{}", spun), 1, || packet_pair::<spsc_seg::AQueue<_>, T>(spin));
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// the two ends of each flavor
+////////////////////////////////////////////////////////////////////////////////
+
+fn mpmc_pair<T, A, O>(queue: mpmc::Queue<T, A, O>) -> (impl Fn(T) + Sync, impl FnMut() -> T)
+where T: Payload, A: Padding + Send + Sync + 'static, O: mpmc::PushOrdering + Send + Sync + 'static {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |t| tx.push(t), move || loop {
+        if let mpmc::Data(t) = rx.pop() { return t }
+    })
+}
+
+fn mpmc2_pair<T, A>(queue: mpmc2::Queue<T, A>) -> (impl Fn(T) + Sync, impl FnMut() -> T)
+where T: Payload, A: Padding + Send + Sync + 'static {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |t| tx.push(t), move || loop {
+        if let mpmc2::Data(t) = rx.pop() { return t }
+    })
+}
+
+fn bounded_pair<T, A>(queue: bounded_mpmc::Queue<T, A>) -> (impl Fn(T) + Sync, impl FnMut() -> T)
+where T: Payload, A: Padding + Send + Sync + 'static {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |t| {
+        let mut t = t;
+        while let Err(back) = tx.push(t) { t = back }
+    }, move || loop {
+        if let Some(t) = rx.pop() { return t }
+    })
+}
+
+fn spsc_pair<T, A, C>(queue: spsc::Queue<T, A, C>) -> (impl Fn(T) + Sync, impl FnMut() -> T)
+where T: Payload, A: Padding + Send + Sync + 'static, C: spsc::UseCache + Send + Sync + 'static {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |t| tx.push(t), move || loop {
+        if let Some(t) = rx.pop() { return t }
+    })
+}
+
+fn spsc2_pair<T, A>(queue: spsc2::Queue<T, A>) -> (impl Fn(T) + Sync, impl FnMut() -> T)
+where T: Payload, A: Padding + Send + Sync + 'static {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |t| { let _ = tx.push(t); }, move || loop {
+        if let Some(t) = rx.pop() { return t }
+    })
+}
+
+fn spsc_seg_pair<T, A>(queue: spsc_seg::Queue<T, A>) -> (impl Fn(T) + Sync, impl FnMut() -> T)
+where T: Payload, A: Padding + Send + Sync + 'static {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |t| tx.push(t), move || loop {
+        if let Some(t) = rx.pop() { return t }
+    })
+}
+
+fn spsc_epoch_pair<T: Payload>(queue: spsc_epoch::Queue<T>) -> (impl Fn(T) + Sync, impl FnMut() -> T) {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |t| tx.push(t), move || loop {
+        if let Some(t) = rx.pop() { return t }
+    })
+}
+
+fn stream_pair<Q, T>() -> (impl Fn(T) + Sync, impl FnMut() -> T)
+where T: Payload, Q: stream::Queue<stream::Message<T>> + Send + Sync + 'static {
+    let tx = Arc::new(stream::Packet::<Q, T>::new());
+    let rx = tx.clone();
+    (move |t| tx.send(t).unwrap(), move || rx.recv(None).ok().unwrap())
+}
+
+// Runs the full channel protocol, disconnecting both ends once the sample
+// has received everything.
+fn packet_pair<Q, T>(spin: usize) -> (impl Fn(T) + Sync, impl FnMut() -> T)
+where T: Payload, Q: stream2::Queue<stream2::Message<T>> + Send + Sync + 'static {
+    let packet = Arc::new(stream2::Packet::<Q, T>::new());
+    packet.set_spin(spin);
+    let tx = Chan(packet.clone());
+    let rx = Port(packet);
+    (move |t| tx.0.send(t).unwrap(), move || rx.0.recv(None).ok().unwrap())
+}
+
+struct Chan<Q, T>(Arc<stream2::Packet<Q, T>>)
+where Q: stream2::Queue<stream2::Message<T>>;
+
+impl<Q, T> Drop for Chan<Q, T>
+where Q: stream2::Queue<stream2::Message<T>> {
+    fn drop(&mut self) {
+        self.0.drop_chan();
+    }
+}
+
+struct Port<Q, T>(Arc<stream2::Packet<Q, T>>)
+where Q: stream2::Queue<stream2::Message<T>>;
+
+impl<Q, T> Drop for Port<Q, T>
+where Q: stream2::Queue<stream2::Message<T>> {
+    fn drop(&mut self) {
+        self.0.drop_port();
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// sampling
+////////////////////////////////////////////////////////////////////////////////
+
+struct Runner {
+    filters: Vec<String>,
+    samples: usize,
+    msgs: u64,
+    last: HashMap<String, (f64, f64)>,
+    results: Vec<(String, f64, f64)>,
+    // `--plot-data`'s directory, and the points for it so far
+    plot_dir: Option<PathBuf>,
+    points: Vec<Point>,
+}
+
+/// A row's mean and standard deviation, in ns/msg.
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    mean: f64,
+    sd: f64,
+}
+
+struct Point {
+    sweep: &'static Sweep,
+    series: String,
+    x: u64,
+    row: Row,
+}
+
+impl Runner {
+    fn new() -> Self {
+        // cargo bench passes --bench along to harnessless benches
+        let args: Vec<String> = env::args().skip(1).collect();
+        let plot_at = args.iter().position(|a| a == "--plot-data");
+        let plot_dir = plot_at.map(|i| match args.get(i + 1) {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                eprintln!("--plot-data needs a directory");
+                process::exit(2)
+            }
+        });
+        let filters = args.iter().enumerate()
+            .filter(|&(i, a)| !a.starts_with("--") && plot_at.map_or(true, |p| i != p + 1))
+            .map(|(_, a)| a.clone())
+            .collect();
+        let samples = env_or("PAIRED_SAMPLES", SAMPLES as u64).max(2) as usize;
+        let msgs = env_or("PAIRED_MSGS", MSGS).max(1);
+        let last = fs::read_to_string(results_path()).map(|s| {
+            s.lines().filter_map(|line| {
+                let mut fields = line.split('\t');
+                let name = fields.next()?;
+                let mean = fields.next()?.parse().ok()?;
+                let ci = fields.next()?.parse().ok()?;
+                Some((name.to_string(), (mean, ci)))
+            }).collect()
+        }).unwrap_or_default();
+        Runner {
+            filters: filters,
+            samples: samples,
+            msgs: msgs,
+            last: last,
+            results: vec![],
+            plot_dir: plot_dir,
+            points: vec![],
+        }
+    }
+
+    /// Times `producers` threads sending through one end of whatever `make`
+    /// builds while this thread receives from the other, rebuilding it for
+    /// each sample. Returns `None` if the row was filtered out.
+    fn paired<T, F, S, R>(&mut self, name: &str, producers: u64, mut make: F) -> Option<Row>
+    where T: Payload, F: FnMut() -> (S, R), S: Fn(T) + Sync, R: FnMut() -> T {
+        let name = format!("{} / {}", name, T::NAME);
+        if !self.filters.is_empty() && !self.filters.iter().any(|f| name.contains(&**f)) {
+            return None
+        }
+        let msgs = self.msgs;
+        let mut sample = || {
+            let (send, mut recv) = make();
+            let send = &send;
+            let start = Instant::now();
+            scope(|scope| {
+                for p in 0..producers {
+                    scope.spawn(move || {
+                        // the first producer picks up any remainder
+                        let n = msgs / producers + if p == 0 { msgs % producers } else { 0 };
+                        for i in 0..n {
+                            send(black_box(T::make(i)));
+                        }
+                    });
+                }
+                for _ in 0..msgs {
+                    black_box(recv());
+                }
+            });
+            nanos(start.elapsed()) / (msgs as f64)
+        };
+
+        // warm up the allocator and the caches
+        sample();
+        let mut samples: Vec<f64> = (0..self.samples).map(|_| sample()).collect();
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let var = samples.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() / (n - 1.0);
+        let ci = Z_95 * (var / n).sqrt();
+        let median = samples[samples.len() / 2];
+
+        print!("{:<40} {:>8.1} ± {:>6.1} ns/msg  median {:>8.1}  [{:>8.1} {:>8.1}]",
+            name, mean, ci, median, samples[0], samples[samples.len() - 1]);
+        match self.last.get(&name) {
+            Some(&(last, last_ci)) if (mean - last).abs() > ci + last_ci =>
+                println!("  {:+.1}%", (mean - last) / last * 100.0),
+            Some(..) => println!("  no change"),
+            None => println!(),
+        }
+        self.results.push((name, mean, ci));
+        Some(Row { mean: mean, sd: var.sqrt() })
+    }
+
+    /// Records a row as the point at `x` of `series` in `sweep`, the series
+    /// being per payload.
+    fn plot<T: Payload>(&mut self, sweep: &'static Sweep, series: &str, x: u64, row: Option<Row>) {
+        if let (Some(_), Some(row)) = (self.plot_dir.as_ref(), row) {
+            let series = format!("{} / {}", series, T::NAME);
+            self.points.push(Point { sweep: sweep, series: series, x: x, row: row });
+        }
+    }
+
+    /// Records a row as a point of the payload size sweep, which runs across
+    /// the `matrix`es rather than within one.
+    fn plot_payload<T: Payload>(&mut self, series: &str, row: Option<Row>) {
+        if let (Some(_), Some(row)) = (self.plot_dir.as_ref(), row) {
+            let x = mem::size_of::<T>() as u64;
+            self.points.push(Point { sweep: &PAYLOAD, series: series.to_string(), x: x, row: row });
+        }
+    }
+
+    // Rows which weren't run this time keep their last results.
+    fn save(mut self) {
+        for (name, mean, ci) in self.results.drain(..) {
+            self.last.insert(name, (mean, ci));
+        }
+        let path = results_path();
+        let mut rows: Vec<_> = self.last.into_iter().collect();
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        let saved = fs::create_dir_all(path.parent().unwrap()).and_then(|_| {
+            let mut file = fs::File::create(&path)?;
+            for (name, (mean, ci)) in rows {
+                writeln!(file, "{}\t{}\t{}", name, mean, ci)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("couldn't save results to {}: {}", path.display(), e);
+        }
+
+        if let Some(dir) = self.plot_dir {
+            if let Err(e) = write_plots(&dir, &self.points) {
+                eprintln!("couldn't write plot data to {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+// For each sweep with any points, `<sweep>.<series>.dat` per series and
+// `<sweep>.gp` to plot them all. The script names the files by absolute path,
+// so that it runs from anywhere.
+fn write_plots(dir: &Path, points: &[Point]) -> ::std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let dir = dir.canonicalize()?;
+    for sweep in &[&CACHE_BOUND, &CAPACITY, &PRODUCERS, &PAYLOAD] {
+        // series in the order they were first run
+        let mut series: Vec<&str> = vec![];
+        for p in points.iter().filter(|p| p.sweep.name == sweep.name) {
+            if !series.contains(&&*p.series) {
+                series.push(&p.series);
+            }
+        }
+        if series.is_empty() {
+            continue
+        }
+
+        let mut plots = vec![];
+        for name in series {
+            let data = dir.join(format!("{}.{}.dat", sweep.name, file_name(name)));
+            let mut file = fs::File::create(&data)?;
+            writeln!(file, "# {}", name)?;
+            writeln!(file, "# {}\tns/msg\tstddev", sweep.label)?;
+            let mut rows: Vec<_> = points.iter()
+                .filter(|p| p.sweep.name == sweep.name && p.series == name)
+                .collect();
+            rows.sort_by_key(|p| p.x);
+            for p in rows {
+                writeln!(file, "{}\t{:.2}\t{:.2}", p.x, p.row.mean, p.row.sd)?;
+            }
+            plots.push(format!("'{}' using 1:2:3 with yerrorlines title '{}'",
+                data.display(), name));
+        }
+
+        let mut script = fs::File::create(dir.join(format!("{}.gp", sweep.name)))?;
+        writeln!(script, "set terminal svg size 1000,600 dynamic")?;
+        // the series names have underscores, which enhanced text subscripts
+        writeln!(script, "set termoption noenhanced")?;
+        writeln!(script, "set output '{}'", dir.join(format!("{}.svg", sweep.name)).display())?;
+        writeln!(script, "set title 'paired, by {}'", sweep.label)?;
+        writeln!(script, "set xlabel '{}'", sweep.label)?;
+        writeln!(script, "set ylabel 'ns/msg'")?;
+        if sweep.log_x {
+            writeln!(script, "set logscale x 2")?;
+        }
+        writeln!(script, "set yrange [0:*]")?;
+        writeln!(script, "set key outside right")?;
+        writeln!(script, "set grid")?;
+        writeln!(script, "plot {}", plots.join(", \\\n     "))?;
+    }
+    Ok(())
+}
+
+// A series name as a file name: lower case, with runs of anything but
+// letters and digits as one `_`.
+fn file_name(series: &str) -> String {
+    let mut name = String::new();
+    for c in series.chars() {
+        if c.is_ascii_alphanumeric() {
+            name.push(c.to_ascii_lowercase());
+        } else if !name.ends_with('_') {
+            name.push('_');
+        }
+    }
+    name.trim_matches('_').to_string()
+}
+
+fn results_path() -> PathBuf {
+    let target = env::var_os("CARGO_TARGET_DIR").map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target"));
+    target.join("paired").join("last.tsv")
+}
+
+fn env_or(var: &str, default: u64) -> u64 {
+    env::var(var).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn nanos(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000000000f64 + (d.subsec_nanos() as f64)
+}
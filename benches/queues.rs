@@ -0,0 +1,372 @@
+//! Criterion benchmarks of the queues, for its statistics and HTML reports.
+//!
+//! Like the `paired` bench and the bench binary's table, every iteration
+//! times a fresh queue from the first send to the last receive with both ends
+//! running, here `producers` threads sending `MSGS` messages between them to
+//! `consumers` threads, the first of which is the bench thread. Building and
+//! dropping the queue is left out of the time. The rows are grouped by queue
+//! and payload size, each group with std's channel as its baseline.
+//!
+//! The queues build on stable, so
+//!
+//! `cargo bench --features queues --bench queues [-- <filter>]`
+//!
+//! runs everything but the stream and packet groups, which need the channel
+//! flavors, and with them nightly and `--features queue_experiments`. With
+//! gnuplot installed, the reports are written to
+//! `target/criterion/report/index.html`.
+
+#[macro_use]
+extern crate criterion;
+extern crate crossbeam;
+extern crate std_spsc_is_slow;
+
+use std::cmp;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+
+use criterion::{black_box, BatchSize, Bencher, Benchmark, Criterion, ParameterizedBenchmark, Throughput};
+use crossbeam::scope;
+
+use std_spsc_is_slow::{spsc, spsc2, spsc_seg, spsc_epoch, mpmc, mpmc2, bounded_mpmc};
+use std_spsc_is_slow::cache_padded::Padding;
+#[cfg(feature = "queue_experiments")]
+use std_spsc_is_slow::{stream, stream2};
+
+const MSGS: u64 = 10_000;
+
+/// A message of some size, built from its index.
+trait Payload: Send + Sync + Debug + 'static {
+    const NAME: &'static str;
+    fn make(i: u64) -> Self;
+}
+
+impl Payload for u64 {
+    const NAME: &'static str = "8B";
+    fn make(i: u64) -> Self { i }
+}
+
+impl Payload for [u64; 8] {
+    const NAME: &'static str = "64B";
+    fn make(i: u64) -> Self { [i; 8] }
+}
+
+impl Payload for [u64; 128] {
+    const NAME: &'static str = "1KiB";
+    fn make(i: u64) -> Self { [i; 128] }
+}
+
+fn payloads(c: &mut Criterion) {
+    matrix::<u64>(c);
+    matrix::<[u64; 8]>(c);
+    matrix::<[u64; 128]>(c);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = payloads
+}
+criterion_main!(benches);
+
+fn matrix<T: Payload>(c: &mut Criterion) {
+    let throughput = Throughput::Elements(MSGS as u32);
+
+    let mut mpmc = Benchmark::new("std channel", |b| paired(b, 1, 1, std_pair::<T>))
+        .with_function("plain", |b| paired(b, 1, 1, || mpmc_pair::<T, _, _>(mpmc::Queue::new(), 0)))
+        .with_function("aligned", |b| paired(b, 1, 1, || mpmc_pair::<T, _, _>(mpmc::Queue::aligned(), 0)))
+        .with_function("node cache 128",
+            |b| paired(b, 1, 1, || mpmc_pair::<T, _, _>(mpmc::Queue::with_node_cache(128), 0)))
+        .with_function("aligned node cache 128",
+            |b| paired(b, 1, 1, || mpmc_pair::<T, _, _>(mpmc::Queue::aligned_with_node_cache(128), 0)));
+    for &producers in &[2, 4] {
+        mpmc = mpmc.with_function(format!("aligned {}p", producers),
+            move |b| paired(b, producers, 1, || mpmc_pair::<T, _, _>(mpmc::Queue::aligned(), 0)));
+    }
+    for &batch in &[8, 64] {
+        mpmc = mpmc.with_function(format!("aligned 4p batch {}", batch),
+            move |b| paired(b, 4, 1, || mpmc_pair::<T, _, _>(mpmc::Queue::aligned(), batch)));
+    }
+    let mpmc = mpmc
+        .with_function("aligned node cache 128 4p",
+            |b| paired(b, 4, 1, || mpmc_pair::<T, _, _>(mpmc::Queue::aligned_with_node_cache(128), 0)))
+        .with_function("AcqRel swap 4p", |b| paired(b, 4, 1,
+            || mpmc_pair::<T, _, _>(mpmc::Queue::<_, _, mpmc::AcqRelSwap>::aligned_with_ordering(), 0)))
+        .with_function("fenced swap 4p", |b| paired(b, 4, 1,
+            || mpmc_pair::<T, _, _>(mpmc::Queue::<_, _, mpmc::FencedSwap>::aligned_with_ordering(), 0)));
+    c.bench(&format!("mpmc/{}", T::NAME), mpmc.throughput(throughput.clone()));
+
+    let mut mpmc2 = Benchmark::new("std channel", |b| paired(b, 1, 1, std_pair::<T>));
+    for &(producers, consumers) in &[(1, 1), (2, 1), (4, 1), (1, 2), (2, 2), (1, 4), (4, 4)] {
+        mpmc2 = mpmc2
+            .with_function(format!("{}p {}c", producers, consumers),
+                move |b| paired(b, producers, consumers, || mpmc2_pair::<T, _>(mpmc2::Queue::new())))
+            .with_function(format!("aligned {}p {}c", producers, consumers),
+                move |b| paired(b, producers, consumers, || mpmc2_pair::<T, _>(mpmc2::Queue::aligned())));
+    }
+    c.bench(&format!("mpmc2/{}", T::NAME), mpmc2.throughput(throughput.clone()));
+
+    let bounded = ParameterizedBenchmark::new("1p",
+            |b, &capacity| paired(b, 1, 1, || bounded_pair::<T, _>(bounded_mpmc::Queue::new(capacity))),
+            vec![128, 1024, 8192])
+        .with_function("4p",
+            |b, &capacity| paired(b, 4, 1, || bounded_pair::<T, _>(bounded_mpmc::Queue::new(capacity))))
+        .with_function("aligned 1p",
+            |b, &capacity| paired(b, 1, 1, || bounded_pair::<T, _>(bounded_mpmc::Queue::aligned(capacity))))
+        .with_function("aligned 4p",
+            |b, &capacity| paired(b, 4, 1, || bounded_pair::<T, _>(bounded_mpmc::Queue::aligned(capacity))))
+        .throughput(|_| Throughput::Elements(MSGS as u32));
+    c.bench(&format!("bounded capacity/{}", T::NAME), bounded);
+
+    // The spsc queues are only safe with one producer and one consumer,
+    // which `paired` is always given for these.
+    let cache_bound = ParameterizedBenchmark::new("spsc",
+            |b, &bound| paired(b, 1, 1, || unsafe { spsc_pair::<T, _, _>(spsc::Queue::new(bound)) }),
+            vec![1, 16, 128, 1024])
+        .with_function("spsc aligned",
+            |b, &bound| paired(b, 1, 1, || unsafe { spsc_pair::<T, _, _>(spsc::Queue::aligned(bound)) }))
+        .with_function("spsc2",
+            |b, &bound| paired(b, 1, 1, || unsafe { spsc2_pair::<T, _>(spsc2::Queue::new(bound)) }))
+        .with_function("spsc2 aligned",
+            |b, &bound| paired(b, 1, 1, || unsafe { spsc2_pair::<T, _>(spsc2::Queue::aligned(bound)) }))
+        .throughput(|_| Throughput::Elements(MSGS as u32));
+    c.bench(&format!("spsc cache bound/{}", T::NAME), cache_bound);
+
+    let spsc = Benchmark::new("std channel", |b| paired(b, 1, 1, std_pair::<T>))
+        .with_function("spsc unbounded",
+            |b| paired(b, 1, 1, || unsafe { spsc_pair::<T, _, _>(spsc::Queue::new(0)) }))
+        .with_function("spsc aligned unbounded",
+            |b| paired(b, 1, 1, || unsafe { spsc_pair::<T, _, _>(spsc::Queue::aligned(0)) }))
+        .with_function("spsc no cache",
+            |b| paired(b, 1, 1, || unsafe { spsc_pair::<T, _, _>(spsc::Queue::no_cache()) }))
+        .with_function("spsc aligned no cache",
+            |b| paired(b, 1, 1, || unsafe { spsc_pair::<T, _, _>(spsc::Queue::aligned_no_cache()) }))
+        .with_function("spsc_seg",
+            |b| paired(b, 1, 1, || unsafe { spsc_seg_pair::<T, _>(spsc_seg::Queue::new()) }))
+        .with_function("spsc_seg aligned",
+            |b| paired(b, 1, 1, || unsafe { spsc_seg_pair::<T, _>(spsc_seg::Queue::aligned()) }))
+        .with_function("spsc_epoch",
+            |b| paired(b, 1, 1, || unsafe { spsc_epoch_pair::<T>(spsc_epoch::Queue::new()) }));
+    c.bench(&format!("spsc/{}", T::NAME), spsc.throughput(throughput.clone()));
+
+    channels::<T>(c, throughput);
+}
+
+#[cfg(feature = "queue_experiments")]
+fn channels<T: Payload>(c: &mut Criterion, throughput: Throughput) {
+    let stream = Benchmark::new("std channel", |b| paired(b, 1, 1, std_pair::<T>))
+        .with_function("stream", |b| paired(b, 1, 1, stream_pair::<spsc::_NQueue<_>, T>))
+        .with_function("stream aligned", |b| paired(b, 1, 1, stream_pair::<spsc::CNQueue<_>, T>))
+        .with_function("stream no cache", |b| paired(b, 1, 1, stream_pair::<spsc::__Queue<_>, T>))
+        .with_function("stream aligned no cache", |b| paired(b, 1, 1, stream_pair::<spsc::C_Queue<_>, T>))
+        .with_function("stream less contend", |b| paired(b, 1, 1, stream_pair::<spsc2::_Queue<_>, T>))
+        .with_function("stream less contend aligned",
+            |b| paired(b, 1, 1, stream_pair::<spsc2::AQueue<_>, T>));
+    c.bench(&format!("stream/{}", T::NAME), stream.throughput(throughput.clone()));
+
+    let mut packet = Benchmark::new("std channel", |b| paired(b, 1, 1, std_pair::<T>));
+    for &spin in &[0, 100] {
+        let spun = if spin == 0 { String::new() } else { format!(" spin {}", spin) };
+        packet = packet
+            .with_function(format!("packet{}", spun),
+                move |b| paired(b, 1, 1, || packet_pair::<spsc::_NQueue<_>, T>(spin)))
+            .with_function(format!("packet aligned{}", spun),
+                move |b| paired(b, 1, 1, || packet_pair::<spsc::CNQueue<_>, T>(spin)))
+            .with_function(format!("packet no cache{}", spun),
+                move |b| paired(b, 1, 1, || packet_pair::<spsc::__Queue<_>, T>(spin)))
+            .with_function(format!("packet aligned no cache{}", spun),
+                move |b| paired(b, 1, 1, || packet_pair::<spsc::C_Queue<_>, T>(spin)))
+            .with_function(format!("packet less contend{}", spun),
+                move |b| paired(b, 1, 1, || packet_pair::<spsc2::_Queue<_>, T>(spin)))
+            .with_function(format!("packet less contend aligned{}", spun),
+                move |b| paired(b, 1, 1, || packet_pair::<spsc2::AQueue<_>, T>(spin)))
+            .with_function(format!("packet segmented{}", spun),
+                move |b| paired(b, 1, 1, || packet_pair::<spsc_seg::_Queue<_>, T>(spin)))
+            .with_function(format!("packet segmented aligned{}", spun),
+                move |b| paired(b, 1, 1, || packet_pair::<spsc_seg::AQueue<_>, T>(spin)));
+    }
+    c.bench(&format!("packet/{}", T::NAME), packet.throughput(throughput));
+}
+
+#[cfg(not(feature = "queue_experiments"))]
+fn channels<T: Payload>(_: &mut Criterion, _: Throughput) {}
+
+////////////////////////////////////////////////////////////////////////////////
+// sampling
+////////////////////////////////////////////////////////////////////////////////
+
+/// Times `producers` threads sending `MSGS` messages through one end of
+/// whatever `make` builds while `consumers` threads, this one among them,
+/// receive from the other.
+fn paired<F, S, R>(b: &mut Bencher, producers: u64, consumers: u64, make: F)
+where F: FnMut() -> (S, R), S: Fn(u64) + Sync, R: Fn(u64) + Sync {
+    b.iter_batched(make, |(send, recv)| {
+        scope(|scope| {
+            for p in 0..producers {
+                let send = &send;
+                scope.spawn(move || send(share(producers, p)));
+            }
+            for c in 1..consumers {
+                let recv = &recv;
+                scope.spawn(move || recv(share(consumers, c)));
+            }
+            recv(share(consumers, 0));
+        });
+        // returned so that the queue is dropped outside of the timing
+        (send, recv)
+    }, BatchSize::PerIteration)
+}
+
+// The first of `threads` picks up any remainder.
+fn share(threads: u64, i: u64) -> u64 {
+    MSGS / threads + if i == 0 { MSGS % threads } else { 0 }
+}
+
+fn send_n<T: Payload, P: FnMut(T)>(n: u64, mut push: P) {
+    for i in 0..n {
+        push(black_box(T::make(i)));
+    }
+}
+
+// Spins on `pop` until it has had `n` messages.
+fn recv_n<T, P: FnMut() -> Option<T>>(n: u64, mut pop: P) {
+    for _ in 0..n {
+        loop {
+            if let Some(t) = pop() {
+                black_box(t);
+                break
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// the two ends of each flavor
+////////////////////////////////////////////////////////////////////////////////
+
+// Neither end of a channel is Sync, but they're only used from one thread at a
+// time, so each thread takes the lock once for the iteration.
+fn std_pair<T: Payload>() -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync) {
+    let (tx, rx) = channel::<T>();
+    let (tx, rx) = (Mutex::new(tx), Mutex::new(rx));
+    (move |n| {
+        let tx = tx.lock().unwrap();
+        send_n(n, |t| tx.send(t).unwrap())
+    }, move |n| {
+        let rx = rx.lock().unwrap();
+        recv_n(n, || rx.recv().ok())
+    })
+}
+
+// `batch` messages to a `push_batch`, or one at a time if it's 0.
+fn mpmc_pair<T, A, O>(queue: mpmc::Queue<T, A, O>, batch: u64) -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync)
+where T: Payload, A: Padding + Send + Sync, O: mpmc::PushOrdering + Send + Sync {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |n| {
+        if batch == 0 {
+            return send_n(n, |t| tx.push(t))
+        }
+        let mut i = 0;
+        while i < n {
+            let end = cmp::min(i + batch, n);
+            tx.push_batch((i..end).map(|i| black_box(T::make(i))));
+            i = end;
+        }
+    }, move |n| recv_n(n, || match rx.pop() {
+        mpmc::Data(t) => Some(t),
+        _ => None,
+    }))
+}
+
+fn mpmc2_pair<T, A>(queue: mpmc2::Queue<T, A>) -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync)
+where T: Payload, A: Padding + Send + Sync {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |n| send_n(n, |t| tx.push(t)), move |n| recv_n(n, || match rx.pop() {
+        mpmc2::Data(t) => Some(t),
+        _ => None,
+    }))
+}
+
+fn bounded_pair<T, A>(queue: bounded_mpmc::Queue<T, A>) -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync)
+where T: Payload, A: Padding + Send + Sync {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |n| send_n(n, |t| {
+        let mut t = t;
+        while let Err(back) = tx.push(t) { t = back }
+    }), move |n| recv_n(n, || rx.pop()))
+}
+
+fn spsc_pair<T, A, C>(queue: spsc::Queue<T, A, C>) -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync)
+where T: Payload, A: Padding + Send + Sync, C: spsc::UseCache + Send + Sync {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |n| send_n(n, |t| tx.push(t)), move |n| recv_n(n, || rx.pop()))
+}
+
+fn spsc2_pair<T, A>(queue: spsc2::Queue<T, A>) -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync)
+where T: Payload, A: Padding + Send + Sync {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |n| send_n(n, |t| tx.push(t)), move |n| recv_n(n, || rx.pop()))
+}
+
+fn spsc_seg_pair<T, A>(queue: spsc_seg::Queue<T, A>) -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync)
+where T: Payload, A: Padding + Send + Sync {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |n| send_n(n, |t| tx.push(t)), move |n| recv_n(n, || rx.pop()))
+}
+
+fn spsc_epoch_pair<T: Payload>(queue: spsc_epoch::Queue<T>) -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync) {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    (move |n| send_n(n, |t| tx.push(t)), move |n| recv_n(n, || rx.pop()))
+}
+
+#[cfg(feature = "queue_experiments")]
+fn stream_pair<Q, T>() -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync)
+where T: Payload, Q: stream::Queue<stream::Message<T>> + Send + Sync + 'static {
+    let tx = Arc::new(stream::Packet::<Q, T>::new());
+    let rx = tx.clone();
+    (move |n| send_n(n, |t| tx.send(t).unwrap()), move |n| recv_n(n, || rx.recv(None).ok()))
+}
+
+// Runs the full channel protocol, disconnecting both ends as the iteration's
+// queue is dropped.
+#[cfg(feature = "queue_experiments")]
+fn packet_pair<Q, T>(spin: usize) -> (impl Fn(u64) + Sync, impl Fn(u64) + Sync)
+where T: Payload, Q: stream2::Queue<stream2::Message<T>> + Send + Sync + 'static {
+    let packet = Arc::new(stream2::Packet::<Q, T>::new());
+    packet.set_spin(spin);
+    let tx = Chan(packet.clone());
+    let rx = Port(packet);
+    (move |n| send_n(n, |t| tx.0.send(t).unwrap()), move |n| recv_n(n, || rx.0.recv(None).ok()))
+}
+
+#[cfg(feature = "queue_experiments")]
+struct Chan<Q, T>(Arc<stream2::Packet<Q, T>>)
+where Q: stream2::Queue<stream2::Message<T>>;
+
+#[cfg(feature = "queue_experiments")]
+impl<Q, T> Drop for Chan<Q, T>
+where Q: stream2::Queue<stream2::Message<T>> {
+    fn drop(&mut self) {
+        self.0.drop_chan();
+    }
+}
+
+#[cfg(feature = "queue_experiments")]
+struct Port<Q, T>(Arc<stream2::Packet<Q, T>>)
+where Q: stream2::Queue<stream2::Message<T>>;
+
+#[cfg(feature = "queue_experiments")]
+impl<Q, T> Drop for Port<Q, T>
+where Q: stream2::Queue<stream2::Message<T>> {
+    fn drop(&mut self) {
+        self.0.drop_port();
+    }
+}
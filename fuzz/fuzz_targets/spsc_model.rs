@@ -0,0 +1,13 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::fuzzing;
+
+fuzz_target!(|data: &[u8]| {
+    let ops = fuzzing::decode(data);
+    for target in fuzzing::targets() {
+        fuzzing::check(target, &ops);
+    }
+});
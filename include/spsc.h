@@ -0,0 +1,57 @@
+/*
+ * C interface to std_spsc_is_slow's spsc queue, see src/ffi.rs.
+ *
+ * Build the library with
+ *     cargo +nightly build --release --features queue_experiments
+ * and link against target/release/libstd_spsc_is_slow.so.
+ *
+ * Only one thread may push and one thread pop a queue at a time, and a queue
+ * may only be freed once neither is using it. Every function checks its
+ * pointers for null.
+ */
+#ifndef STD_SPSC_IS_SLOW_SPSC_H
+#define STD_SPSC_IS_SLOW_SPSC_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct SpscU64 SpscU64;
+typedef struct SpscBytes SpscBytes;
+
+/* spsc_bytes_pop results */
+#define SPSC_POPPED     1
+#define SPSC_EMPTY      0
+#define SPSC_TOO_SMALL  (-1)
+#define SPSC_ERROR      (-2)
+
+/* A queue of u64s. `bound` limits the node cache, 0 for unbounded, and
+ * `aligned` puts the producer and consumer on separate cache lines. Returns
+ * NULL on failure. */
+SpscU64 *spsc_u64_new(size_t bound, bool aligned);
+/* Returns false only if `q` is NULL. */
+bool spsc_u64_push(SpscU64 *q, uint64_t v);
+/* Returns false if the queue is empty. */
+bool spsc_u64_pop(SpscU64 *q, uint64_t *out);
+/* Frees the queue and anything left in it. NULL is ignored. */
+void spsc_u64_free(SpscU64 *q);
+
+/* A queue of byte strings, copied in on push and out on pop. */
+SpscBytes *spsc_bytes_new(size_t bound, bool aligned);
+/* `data` may be NULL if `len` is 0. */
+bool spsc_bytes_push(SpscBytes *q, const uint8_t *data, size_t len);
+/* Pops into the `cap` bytes at `buf`, setting `*len`. If the next string is
+ * longer than `cap` it stays queued, `*len` is set to its length and
+ * SPSC_TOO_SMALL returned. */
+int spsc_bytes_pop(SpscBytes *q, uint8_t *buf, size_t cap, size_t *len);
+void spsc_bytes_free(SpscBytes *q);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,390 @@
+//! A node allocator for spsc, for Linux, which carves fixed size slots out of
+//! one pre-faulted mapping, to see how much of the no-cache queue's cost is
+//! page faults and TLB misses rather than malloc itself.
+//!
+//! The mapping is tried first with `MAP_HUGETLB`, which only works with huge
+//! pages reserved (`vm.nr_hugepages`), then as ordinary pages advised with
+//! `MADV_HUGEPAGE` for transparent huge pages. It is then `mlock`ed, which
+//! needs `RLIMIT_MEMLOCK` to cover it, and every page is touched. Whatever of
+//! that fails is recorded in `Arena::fallbacks` rather than failing the
+//! arena, so a benchmark can run anywhere and say what it actually measured.
+//!
+//! Slots are handed out from the start of the mapping until it runs out, and
+//! freed slots go on a Treiber stack to be handed out again, taken from under
+//! a flag as `spsc::NodePool` does. Once every slot is in use `alloc` returns
+//! null, and the queue mallocs the node instead.
+
+use std::alloc;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use cache_padded::CachePadded;
+use spsc::{self, NodeAlloc};
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_PRIVATE: c_int = 0x02;
+const MAP_ANONYMOUS: c_int = 0x20;
+const MAP_HUGETLB: c_int = 0x4_0000;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+const MADV_HUGEPAGE: c_int = 14;
+
+const PAGE: usize = 4096;
+const HUGE_PAGE: usize = 2 << 20;
+
+// std links libc anyway, so there's no need for the crate to reach these.
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64)
+        -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    fn madvise(addr: *mut c_void, len: usize, advice: c_int) -> c_int;
+    fn mlock(addr: *const c_void, len: usize) -> c_int;
+}
+
+/// What to ask of the mapping. Each is only tried, see `Fallback`.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Map explicit huge pages with `MAP_HUGETLB`.
+    pub huge_tlb: bool,
+    /// Without `MAP_HUGETLB`, advise transparent huge pages.
+    pub advise_huge: bool,
+    /// `mlock` the mapping.
+    pub lock: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { huge_tlb: true, advise_huge: true, lock: true }
+    }
+}
+
+/// Something asked for in the `Options` which the arena went without.
+#[derive(Debug)]
+pub enum Fallback {
+    /// `MAP_HUGETLB` failed, usually for want of reserved huge pages, and the
+    /// arena is in ordinary pages.
+    NoHugeTlb(io::Error),
+    /// `MADV_HUGEPAGE` failed, transparent huge pages being off or missing.
+    NoHugeAdvice(io::Error),
+    /// `mlock` failed, usually for a low `RLIMIT_MEMLOCK`.
+    NotLocked(io::Error),
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fallback::NoHugeTlb(ref e) => write!(f, "no MAP_HUGETLB ({})", e),
+            Fallback::NoHugeAdvice(ref e) => write!(f, "no MADV_HUGEPAGE ({})", e),
+            Fallback::NotLocked(ref e) => write!(f, "not mlocked ({})", e),
+        }
+    }
+}
+
+// A free slot, linked through its first word.
+struct FreeSlot {
+    next: AtomicPtr<FreeSlot>,
+}
+
+pub struct Arena {
+    map: *mut c_void,
+    map_len: usize,
+    slot: usize,
+    slots: usize,
+    fallbacks: Vec<Fallback>,
+
+    // the next slot never handed out, producer side
+    bump: CachePadded<AtomicUsize>,
+    // freed slots, pushed by the consumer
+    free: CachePadded<AtomicPtr<FreeSlot>>,
+    taking: AtomicBool,
+
+    allocs: CachePadded<AtomicUsize>,
+    frees: CachePadded<AtomicUsize>,
+    exhausted: AtomicUsize,
+}
+
+unsafe impl Send for Arena { }
+unsafe impl Sync for Arena { }
+
+impl Arena {
+    /// Maps an arena of `slots` slots, each big enough for `layout`. Only
+    /// fails if an ordinary anonymous mapping does, anything short of that
+    /// is a `Fallback`.
+    ///
+    /// # Panics
+    ///
+    /// If `layout` is aligned to more than a page.
+    pub fn new(layout: alloc::Layout, slots: usize, options: Options) -> io::Result<Arena> {
+        assert!(layout.align() <= PAGE, "slots can't be aligned past a page");
+        // every slot must be able to hold the free list's link
+        let align = layout.align().max(mem::align_of::<FreeSlot>());
+        let size = layout.size().max(mem::size_of::<FreeSlot>());
+        let slot = (size + align - 1) & !(align - 1);
+        let len = slot.checked_mul(slots.max(1))
+            .and_then(|len| len.checked_add(HUGE_PAGE - 1))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "arena too large"))?
+            & !(HUGE_PAGE - 1);
+
+        let mut fallbacks = vec![];
+        let mut map = MAP_FAILED;
+        unsafe {
+            if options.huge_tlb {
+                map = mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS | MAP_HUGETLB, -1, 0);
+                if map == MAP_FAILED {
+                    fallbacks.push(Fallback::NoHugeTlb(io::Error::last_os_error()));
+                }
+            }
+            if map == MAP_FAILED {
+                map = mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+                if map == MAP_FAILED {
+                    return Err(io::Error::last_os_error())
+                }
+                if options.advise_huge && madvise(map, len, MADV_HUGEPAGE) != 0 {
+                    fallbacks.push(Fallback::NoHugeAdvice(io::Error::last_os_error()));
+                }
+            }
+            if options.lock && mlock(map, len) != 0 {
+                fallbacks.push(Fallback::NotLocked(io::Error::last_os_error()));
+            }
+            // mlock faults the pages in, but it may have failed
+            let mut page = 0;
+            while page < len {
+                ptr::write_volatile((map as *mut u8).add(page), 0);
+                page += PAGE;
+            }
+        }
+        Ok(Arena {
+            map: map,
+            map_len: len,
+            slot: slot,
+            slots: slots,
+            fallbacks: fallbacks,
+            bump: CachePadded::new(AtomicUsize::new(0)),
+            free: CachePadded::new(AtomicPtr::new(ptr::null_mut())),
+            taking: AtomicBool::new(false),
+            allocs: CachePadded::new(AtomicUsize::new(0)),
+            frees: CachePadded::new(AtomicUsize::new(0)),
+            exhausted: AtomicUsize::new(0),
+        })
+    }
+
+    /// An arena of `slots` nodes for `spsc::Queue<T, ..>`s.
+    pub fn for_nodes<T>(slots: usize, options: Options) -> io::Result<Arena> {
+        Arena::new(spsc::node_layout::<T>(), slots, options)
+    }
+
+    /// What the arena went without of its `Options`, empty if nothing.
+    pub fn fallbacks(&self) -> &[Fallback] {
+        &self.fallbacks
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// Slots handed out and not yet given back, racing any queues using it.
+    pub fn in_use(&self) -> usize {
+        self.allocs.load(Ordering::Relaxed)
+            .wrapping_sub(self.frees.load(Ordering::Relaxed))
+    }
+
+    /// How many times `alloc` found every slot in use.
+    pub fn exhausted(&self) -> usize {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    /// A slot, or null if every slot is in use or the layout doesn't fit one.
+    pub fn alloc_slot(&self, layout: alloc::Layout) -> *mut u8 {
+        if layout.size() > self.slot || self.slot % layout.align() != 0 {
+            return ptr::null_mut()
+        }
+        let slot = match self.take() {
+            Some(slot) => slot,
+            None => {
+                // don't let the count run on once it's past the end
+                if self.bump.load(Ordering::Relaxed) >= self.slots {
+                    self.exhausted.fetch_add(1, Ordering::Relaxed);
+                    return ptr::null_mut()
+                }
+                let i = self.bump.fetch_add(1, Ordering::Relaxed);
+                if i >= self.slots {
+                    self.exhausted.fetch_add(1, Ordering::Relaxed);
+                    return ptr::null_mut()
+                }
+                unsafe { (self.map as *mut u8).add(i * self.slot) }
+            }
+        };
+        self.allocs.fetch_add(1, Ordering::Relaxed);
+        slot
+    }
+
+    /// Takes back a slot, returning false if `slot` isn't in the arena.
+    ///
+    /// This is unsafe as the slot must have come from `alloc_slot`, and not
+    /// been given back since.
+    pub unsafe fn dealloc_slot(&self, slot: *mut u8) -> bool {
+        let offset = (slot as usize).wrapping_sub(self.map as usize);
+        if offset >= self.slots * self.slot {
+            return false
+        }
+        debug_assert_eq!(offset % self.slot, 0);
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        let slot = slot as *mut FreeSlot;
+        let mut top = self.free.load(Ordering::Relaxed);
+        loop {
+            // the slot's old contents are dead, so this write claims it
+            ptr::write(slot, FreeSlot { next: AtomicPtr::new(top) });
+            match self.free.compare_exchange_weak(top, slot, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(cur) => top = cur,
+            }
+        }
+    }
+
+    // As NodePool::take: with one taker at a time the top slot can't be
+    // taken and given back between reading its next and the CAS.
+    fn take(&self) -> Option<*mut u8> {
+        if self.taking.swap(true, Ordering::Acquire) {
+            return None
+        }
+        let mut top = self.free.load(Ordering::Acquire);
+        let slot = loop {
+            if top.is_null() {
+                break None
+            }
+            let next = unsafe { (*top).next.load(Ordering::Relaxed) };
+            match self.free.compare_exchange_weak(top, next, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => break Some(top as *mut u8),
+                Err(cur) => top = cur,
+            }
+        };
+        self.taking.store(false, Ordering::Release);
+        slot
+    }
+}
+
+unsafe impl NodeAlloc for Arena {
+    #[inline]
+    unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 {
+        self.alloc_slot(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, node: *mut u8, _: alloc::Layout) -> bool {
+        self.dealloc_slot(node)
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.map, self.map_len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arena, Options};
+    use spsc::{self, CA_Queue, CAQueue};
+    use std::alloc::Layout;
+    use std::sync::Arc;
+
+    // Nothing that needs privileges, so these run anywhere.
+    fn plain() -> Options {
+        Options { huge_tlb: false, advise_huge: false, lock: false }
+    }
+
+    #[test]
+    fn accounting() {
+        let arena = Arena::new(Layout::new::<[u64; 3]>(), 4, plain()).unwrap();
+        assert!(arena.fallbacks().is_empty());
+        let layout = Layout::new::<[u64; 3]>();
+        let a = arena.alloc_slot(layout);
+        let b = arena.alloc_slot(layout);
+        assert!(!a.is_null() && !b.is_null() && a != b);
+        assert_eq!(b as usize - a as usize, 24);
+        assert_eq!(arena.in_use(), 2);
+        unsafe {
+            assert!(arena.dealloc_slot(a));
+            assert_eq!(arena.in_use(), 1);
+            // a freed slot is handed out again before a new one
+            assert_eq!(arena.alloc_slot(layout), a);
+            assert!(arena.dealloc_slot(a));
+            assert!(arena.dealloc_slot(b));
+        }
+        assert_eq!(arena.in_use(), 0);
+        assert_eq!(arena.exhausted(), 0);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let layout = Layout::new::<u64>();
+        let arena = Arena::new(layout, 3, plain()).unwrap();
+        let slots: Vec<_> = (0..3).map(|_| arena.alloc_slot(layout)).collect();
+        assert!(slots.iter().all(|s| !s.is_null()));
+        assert!(arena.alloc_slot(layout).is_null());
+        assert!(arena.alloc_slot(layout).is_null());
+        assert_eq!(arena.exhausted(), 2);
+        assert_eq!(arena.in_use(), 3);
+        unsafe {
+            // memory from elsewhere is refused, for the caller to free
+            let mut other = 0u64;
+            assert!(!arena.dealloc_slot(&mut other as *mut u64 as *mut u8));
+            assert!(arena.dealloc_slot(slots[1]));
+        }
+        assert_eq!(arena.alloc_slot(layout), slots[1]);
+        // too big for a slot
+        assert!(arena.alloc_slot(Layout::new::<[u64; 2]>()).is_null());
+    }
+
+    #[test]
+    fn falls_back() {
+        // whether this gets huge pages and the lock depends on the machine,
+        // but it always gets an arena
+        let arena = Arena::for_nodes::<u64>(1024, Options::default()).unwrap();
+        for f in arena.fallbacks() {
+            assert!(!f.to_string().is_empty());
+        }
+        assert!(!arena.alloc_slot(spsc::node_layout::<u64>()).is_null());
+    }
+
+    // Queues past the end of their arena malloc, and free those nodes rather
+    // than giving them to the arena, which ends up with every slot back.
+    #[test]
+    fn queues_past_the_end() {
+        let arena = Arc::new(Arena::for_nodes::<usize>(4, plain()).unwrap());
+        unsafe {
+            let q = CAQueue::with_alloc(2, arena.clone());
+            for i in 0..10 {
+                q.push(i);
+            }
+            assert_eq!(arena.in_use(), 4);
+            for i in 0..10 {
+                assert_eq!(q.pop(), Some(i));
+            }
+            assert!(arena.exhausted() > 0);
+            drop(q);
+            assert_eq!(arena.in_use(), 0);
+
+            let q = CA_Queue::no_cache_with_alloc(arena.clone());
+            for round in 0..3 {
+                for i in 0..6 {
+                    q.push(round * 6 + i);
+                }
+                for i in 0..6 {
+                    assert_eq!(q.pop(), Some(round * 6 + i));
+                }
+                assert_eq!(q.pop(), None);
+            }
+            drop(q);
+            assert_eq!(arena.in_use(), 0);
+        }
+    }
+}
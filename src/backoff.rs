@@ -0,0 +1,230 @@
+//! Exponential backoff for loops which poll until something turns up: an
+//! empty queue, an `Inconsistent` one, or a signal.
+//!
+//! Each `snooze` first spins, doubling the spin loop hints each time until
+//! the spin budget is used up, then yields, so that on a busy machine
+//! whoever we're waiting on gets to run, and then, if a park timeout was
+//! given, parks for that long. `is_yielding` says when the spinning is over,
+//! for callers which would rather block properly from then on.
+
+use std::cmp;
+use std::hint;
+use std::time::Duration;
+
+use sched;
+
+// Spins double up to this many hints per snooze.
+const MAX_SPIN_SHIFT: u32 = 6;
+
+// The defaults for `Backoff::new`: 1 + 2 + ... + 64 hints, then 10 yields.
+const DEFAULT_SPINS: u32 = (1 << (MAX_SPIN_SHIFT + 1)) - 1;
+const DEFAULT_YIELDS: u32 = 10;
+
+/// How a `Backoff` waits, so that tests can watch it without waiting.
+pub trait Sleeper {
+    fn spin(&mut self, hints: u32);
+    fn yield_now(&mut self);
+    fn park_timeout(&mut self, dur: Duration);
+}
+
+/// Waits with spin loop hints and the current thread.
+pub struct ThreadSleeper;
+
+impl Sleeper for ThreadSleeper {
+    fn spin(&mut self, hints: u32) {
+        for _ in 0..hints {
+            hint::spin_loop();
+        }
+    }
+
+    fn yield_now(&mut self) {
+        sched::yield_now()
+    }
+
+    fn park_timeout(&mut self, dur: Duration) {
+        sched::park_timeout(dur)
+    }
+}
+
+pub struct Backoff<S = ThreadSleeper> {
+    step: u32,
+    spun: u32,        // hints so far
+    yielded: u32,     // yields so far
+    spin_limit: u32,  // hints before yielding
+    yield_limit: u32, // yields before parking, or before is_completed
+    park: Option<Duration>,
+    sleeper: S,
+}
+
+impl Backoff {
+    /// Spins for up to 127 hints, then yields.
+    pub fn new() -> Self {
+        Backoff::with_limits(DEFAULT_SPINS, DEFAULT_YIELDS)
+    }
+
+    /// Spins for up to `spins` hints in all, then yields `yields` times,
+    /// after which it keeps yielding but `is_completed`.
+    pub fn with_limits(spins: u32, yields: u32) -> Self {
+        Backoff::with_sleeper(spins, yields, ThreadSleeper)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new()
+    }
+}
+
+impl<S: Sleeper> Backoff<S> {
+    pub fn with_sleeper(spins: u32, yields: u32, sleeper: S) -> Self {
+        Backoff {
+            step: 0,
+            spun: 0,
+            yielded: 0,
+            spin_limit: spins,
+            yield_limit: yields,
+            park: None,
+            sleeper,
+        }
+    }
+
+    /// Parks for `timeout` on each snooze once the yields are used up,
+    /// rather than yielding forever.
+    pub fn park_after(mut self, timeout: Duration) -> Self {
+        self.park = Some(timeout);
+        self
+    }
+
+    /// Waits a little longer than the last time.
+    pub fn snooze(&mut self) {
+        if self.spun < self.spin_limit {
+            let hints = cmp::min(1 << cmp::min(self.step, MAX_SPIN_SHIFT),
+                self.spin_limit - self.spun);
+            self.sleeper.spin(hints);
+            self.spun += hints;
+            self.step += 1;
+            return
+        }
+        match self.park {
+            Some(timeout) if self.yielded >= self.yield_limit =>
+                self.sleeper.park_timeout(timeout),
+            _ => {
+                self.sleeper.yield_now();
+                self.yielded = self.yielded.saturating_add(1);
+            }
+        }
+    }
+
+    /// Starts again from the shortest spin, after the wait is over.
+    pub fn reset(&mut self) {
+        self.step = 0;
+        self.spun = 0;
+        self.yielded = 0;
+    }
+
+    /// Whether the spinning is over, and snoozes now yield or park.
+    pub fn is_yielding(&self) -> bool {
+        self.spun >= self.spin_limit
+    }
+
+    /// Whether the yields are used up too, and a caller with a better way
+    /// to block should use it.
+    pub fn is_completed(&self) -> bool {
+        self.is_yielding() && self.yielded >= self.yield_limit
+    }
+
+    pub fn sleeper(&self) -> &S {
+        &self.sleeper
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{Backoff, Sleeper};
+
+    #[derive(Debug, PartialEq)]
+    enum Wait {
+        Spin(u32),
+        Yield,
+        Park(Duration),
+    }
+
+    #[derive(Default)]
+    struct Recorder(Vec<Wait>);
+
+    impl Sleeper for Recorder {
+        fn spin(&mut self, hints: u32) { self.0.push(Wait::Spin(hints)) }
+        fn yield_now(&mut self) { self.0.push(Wait::Yield) }
+        fn park_timeout(&mut self, dur: Duration) { self.0.push(Wait::Park(dur)) }
+    }
+
+    #[test]
+    fn escalates() {
+        let mut backoff = Backoff::with_sleeper(127, 2, Recorder::default());
+        for _ in 0..10 {
+            backoff.snooze();
+        }
+        assert_eq!(backoff.sleeper().0, [
+            Wait::Spin(1), Wait::Spin(2), Wait::Spin(4), Wait::Spin(8),
+            Wait::Spin(16), Wait::Spin(32), Wait::Spin(64),
+            Wait::Yield, Wait::Yield, Wait::Yield,
+        ]);
+    }
+
+    #[test]
+    fn spins_cap_and_trim() {
+        let mut backoff = Backoff::with_sleeper(200, 0, Recorder::default());
+        while !backoff.is_yielding() {
+            backoff.snooze();
+        }
+        // 127 doubling up to 64, then 64 at most each, the last cut short
+        assert_eq!(backoff.sleeper().0, [
+            Wait::Spin(1), Wait::Spin(2), Wait::Spin(4), Wait::Spin(8),
+            Wait::Spin(16), Wait::Spin(32), Wait::Spin(64), Wait::Spin(64),
+            Wait::Spin(9),
+        ]);
+    }
+
+    #[test]
+    fn parks_after_yields() {
+        let park = Duration::from_millis(1);
+        let mut backoff = Backoff::with_sleeper(1, 2, Recorder::default()).park_after(park);
+        assert!(!backoff.is_yielding());
+        backoff.snooze();
+        assert!(backoff.is_yielding());
+        backoff.snooze();
+        backoff.snooze();
+        assert!(backoff.is_completed());
+        backoff.snooze();
+        backoff.snooze();
+        assert_eq!(backoff.sleeper().0, [
+            Wait::Spin(1), Wait::Yield, Wait::Yield, Wait::Park(park), Wait::Park(park),
+        ]);
+    }
+
+    #[test]
+    fn reset() {
+        let mut backoff = Backoff::with_sleeper(3, 1, Recorder::default());
+        for _ in 0..4 {
+            backoff.snooze();
+        }
+        assert!(backoff.is_completed());
+        backoff.reset();
+        assert!(!backoff.is_yielding());
+        backoff.snooze();
+        assert_eq!(backoff.sleeper().0, [
+            Wait::Spin(1), Wait::Spin(2), Wait::Yield, Wait::Yield, Wait::Spin(1),
+        ]);
+    }
+
+    #[test]
+    fn no_spins() {
+        let mut backoff = Backoff::with_sleeper(0, 1, Recorder::default());
+        assert!(backoff.is_yielding());
+        assert!(!backoff.is_completed());
+        backoff.snooze();
+        assert!(backoff.is_completed());
+        assert_eq!(backoff.sleeper().0, [Wait::Yield]);
+    }
+}
@@ -0,0 +1,209 @@
+/// Request/response channels
+///
+/// A pair of stream2 packets, one carrying requests from a client to a server
+/// and one carrying responses back. Each end holds the sending half of one
+/// packet and the receiving half of the other, and dropping an end drops
+/// both, so the other end sees the disconnect whichever direction it next
+/// uses. As with stream2 there is exactly one of each end; neither can be
+/// cloned.
+
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use spsc;
+use stream2::{self, Packet, Message, SendError, RecvError, TryRecvError};
+
+type Half<T> = Arc<Packet<spsc::CNQueue<Message<T>>, T>>;
+
+pub struct ClientEnd<Req, Resp> {
+    requests: Half<Req>,
+    responses: Half<Resp>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+pub struct ServerEnd<Req, Resp> {
+    requests: Half<Req>,
+    responses: Half<Resp>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// Why a `call` failed: either the request couldn't be sent, and is handed
+/// back, or the server went away before responding.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CallError<Req> {
+    Send(SendError<Req>),
+    Recv(RecvError),
+}
+
+pub fn bi_channel<Req, Resp>() -> (ClientEnd<Req, Resp>, ServerEnd<Req, Resp>) {
+    let requests = Arc::new(Packet::new());
+    let responses = Arc::new(Packet::new());
+    let client = ClientEnd {
+        requests: requests.clone(),
+        responses: responses.clone(),
+        _not_sync: PhantomData,
+    };
+    let server = ServerEnd { requests, responses, _not_sync: PhantomData };
+    (client, server)
+}
+
+// Neither half is ever upgraded, as the ends can't be cloned.
+fn recv<T>(half: &Half<T>) -> Result<T, RecvError> {
+    match half.recv(None) {
+        Ok(t) => Ok(t),
+        Err(stream2::Disconnected) => Err(RecvError),
+        Err(stream2::Empty) | Err(stream2::Timeout) | Err(stream2::Upgraded(..)) => unreachable!(),
+    }
+}
+
+fn try_recv<T>(half: &Half<T>) -> Result<T, TryRecvError> {
+    match half.try_recv() {
+        Ok(t) => Ok(t),
+        Err(stream2::Empty) => Err(TryRecvError::Empty),
+        Err(stream2::Disconnected) => Err(TryRecvError::Disconnected),
+        Err(stream2::Timeout) | Err(stream2::Upgraded(..)) => unreachable!(),
+    }
+}
+
+impl<Req, Resp> ClientEnd<Req, Resp> {
+    pub fn send(&self, req: Req) -> Result<(), SendError<Req>> {
+        self.requests.send(req).map_err(SendError)
+    }
+
+    pub fn recv(&self) -> Result<Resp, RecvError> {
+        recv(&self.responses)
+    }
+
+    pub fn try_recv(&self) -> Result<Resp, TryRecvError> {
+        try_recv(&self.responses)
+    }
+
+    /// Sends a request and waits for the response to it. Any responses
+    /// already waiting are received first, so only use this if there are
+    /// none.
+    pub fn call(&self, req: Req) -> Result<Resp, CallError<Req>> {
+        self.send(req).map_err(CallError::Send)?;
+        self.recv().map_err(CallError::Recv)
+    }
+}
+
+impl<Req, Resp> ServerEnd<Req, Resp> {
+    pub fn send(&self, resp: Resp) -> Result<(), SendError<Resp>> {
+        self.responses.send(resp).map_err(SendError)
+    }
+
+    pub fn recv(&self) -> Result<Req, RecvError> {
+        recv(&self.requests)
+    }
+
+    pub fn try_recv(&self) -> Result<Req, TryRecvError> {
+        try_recv(&self.requests)
+    }
+}
+
+impl<Req, Resp> Drop for ClientEnd<Req, Resp> {
+    fn drop(&mut self) {
+        self.requests.drop_chan();
+        self.responses.drop_port();
+    }
+}
+
+impl<Req, Resp> Drop for ServerEnd<Req, Resp> {
+    fn drop(&mut self) {
+        self.responses.drop_chan();
+        self.requests.drop_port();
+    }
+}
+
+impl<Req, Resp> fmt::Debug for ClientEnd<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ClientEnd { .. }")
+    }
+}
+
+impl<Req, Resp> fmt::Debug for ServerEnd<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ServerEnd { .. }")
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn smoke() {
+        let (client, server) = bi_channel();
+        client.send(1).unwrap();
+        assert_eq!(server.recv(), Ok(1));
+        server.send("one").unwrap();
+        assert_eq!(client.recv(), Ok("one"));
+        assert_eq!(client.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(server.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn call() {
+        let (client, server) = bi_channel();
+        let t = thread::spawn(move|| {
+            while let Ok(req) = server.recv() {
+                server.send(req * 2).unwrap();
+            }
+        });
+        for i in 0..1000 {
+            assert_eq!(client.call(i), Ok(i * 2));
+        }
+        drop(client);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn client_drop() {
+        let (client, server) = bi_channel::<i32, i32>();
+        client.send(1).unwrap();
+        drop(client);
+        // What was sent is still delivered, then the disconnect.
+        assert_eq!(server.recv(), Ok(1));
+        assert_eq!(server.recv(), Err(RecvError));
+        assert_eq!(server.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(server.send(2), Err(SendError(2)));
+    }
+
+    #[test]
+    fn server_drop() {
+        let (client, server) = bi_channel::<i32, i32>();
+        server.send(1).unwrap();
+        drop(server);
+        assert_eq!(client.recv(), Ok(1));
+        assert_eq!(client.recv(), Err(RecvError));
+        assert_eq!(client.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(client.send(2), Err(SendError(2)));
+        assert_eq!(client.call(3), Err(CallError::Send(SendError(3))));
+    }
+
+    #[test]
+    fn server_drop_wakes_client() {
+        let (client, server) = bi_channel::<i32, i32>();
+        let t = thread::spawn(move|| {
+            assert_eq!(server.recv(), Ok(1));
+            // Drop the request without responding.
+        });
+        assert_eq!(client.call(1), Err(CallError::Recv(RecvError)));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn client_drop_wakes_server() {
+        let (client, server) = bi_channel::<i32, i32>();
+        let t = thread::spawn(move|| {
+            assert_eq!(server.recv(), Err(RecvError));
+        });
+        thread::yield_now();
+        drop(client);
+        t.join().unwrap();
+    }
+}
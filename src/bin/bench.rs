@@ -0,0 +1,2225 @@
+//! As of rustc 1.20.0 (f3d6973f4 2017-08-27) std::sync::mpsc is faster
+//! when used in shared mode than the its internal spsc stream mode.
+//! Note: this is partly disappears on 1.22.0-nightly (981ce7d8d 2017-09-03)
+//!       due to shared getting slower
+//!
+//! aws c4.2xlarge
+//! stable rustc 1.20.0 (f3d6973f4 2017-08-27)
+//! spsc stream      127.5086168
+//! spsc shared      66.2942756
+//!
+//! nightly rustc 1.22.0-nightly (981ce7d8d 2017-09-03)
+//! spsc stream      111.13214775
+//! spsc shared      61.84188775
+//! just queue       34.37997215
+//! aligned queue    43.47179525
+//! unbounded queue  29.22656035
+//! no cache queue   40.32926815
+//! u/a queue        23.9909434
+//! n/a              39.4851343
+//!
+//!
+#![cfg_attr(feature = "queue_experiments", feature(test))]
+#![allow(dead_code)]
+
+// based on crossbeam's bin/bench
+
+//using crossbeam for scoped threads
+extern crate crossbeam;
+extern crate std_spsc_is_slow;
+
+#[cfg(feature="queue_experiments")]
+extern crate test;
+
+use crossbeam::scope;
+
+#[cfg(feature="queue_experiments")]
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver};
+#[cfg(feature="queue_experiments")]
+use std::sync::mpsc::sync_channel;
+use std::time::Duration;
+
+#[cfg(feature="queue_experiments")]
+use test::black_box;
+
+#[cfg(not(feature="queue_experiments"))]
+fn black_box<T>(t: T) -> T { t }
+
+// The flavors under test, from the library
+#[cfg(feature="queue_experiments")]
+use std_spsc_is_slow::{spsc, spsc2, spsc_seg, spsc_epoch, mpmc, mpmc2, bounded_mpmc, blocking, oneshot, stream, stream2};
+#[cfg(feature="queue_experiments")]
+use std_spsc_is_slow::{shared, shared_orig, sync2, sync_orig, bichannel, verify, watch, byte_ring};
+#[cfg(feature="queue_experiments")]
+use std_spsc_is_slow::{clock, coalesce, factory, layout, intrusive, rate, split};
+#[cfg(feature="queue_experiments")]
+use std_spsc_is_slow::clock::{Clock, InstantClock};
+#[cfg(feature="queue_experiments")]
+use std_spsc_is_slow::cache_padded::Padding;
+#[cfg(feature="queue_experiments")]
+use std_spsc_is_slow::backoff::Backoff;
+#[cfg(feature="queue_experiments")]
+use std_spsc_is_slow::stalls::StallTracker;
+#[cfg(feature="futex")]
+use std_spsc_is_slow::futex;
+#[cfg(all(feature="shm", target_os="linux"))]
+use std_spsc_is_slow::shm;
+#[cfg(all(feature="hugepages", target_os="linux"))]
+use std_spsc_is_slow::arena::{self, Arena};
+
+fn main() {
+    #[cfg(feature="queue_experiments")]
+    {
+        if ::std::env::args().any(|a| a == "--verify-packet") {
+            verify_packets();
+            return
+        }
+        if ::std::env::args().any(|a| a == "--layout") {
+            print_layouts();
+            return
+        }
+        let args: Vec<String> = ::std::env::args().collect();
+        if args.iter().any(|a| a == "--rate" || a == "--rate-sweep") {
+            bench_rate_args(&args);
+            return
+        }
+        if let Some(i) = args.iter().position(|a| a == "--queue") {
+            match args.get(i + 1) {
+                Some(spec) => bench_queue_spec(spec),
+                None => {
+                    eprintln!("--queue needs a spec, e.g. spsc2:aligned:64");
+                    ::std::process::exit(2)
+                }
+            }
+            return
+        }
+    }
+    #[cfg(all(feature="shm", target_os="linux"))]
+    {
+        // bench_shm_processes runs this binary again as its consumer
+        let args: Vec<String> = ::std::env::args().collect();
+        if args.len() == 4 && args[1] == "--shm-consumer" {
+            shm_consumer(&args[2], args[3].parse().unwrap());
+            return
+        }
+    }
+
+    println!("spsc stream        {:>3.0} ns/send", bench_mpsc_stream());
+    println!("spsc shared        {:>3.0} ns/send", bench_mpsc_shared());
+
+    #[cfg(feature="queue_experiments")]
+    unsafe {
+        println!("----");
+        println!("mpmc baseline      {:>3.0} ns/send", bench_mpmc_queue(mpmc::Queue::new()));
+        println!("aligned            {:>3.0} ns/send", bench_mpmc_queue(mpmc::Queue::aligned()));
+        println!("node cache         {:>3.0} ns/send", bench_mpmc_queue(mpmc::Queue::with_node_cache(128)));
+        println!("aligned node cache {:>3.0} ns/send", bench_mpmc_queue(mpmc::Queue::aligned_with_node_cache(128)));
+        println!("intrusive          {:>3.0} ns/send", bench_intrusive_queue(intrusive::Queue::new()));
+        println!("intrusive, aligned {:>3.0} ns/send", bench_intrusive_queue(intrusive::Queue::aligned()));
+        println!("2 producers        {:>3.0} ns/send", bench_mpmc_mp_queue(mpmc::Queue::aligned(), 2, 1));
+        println!("4 producers        {:>3.0} ns/send", bench_mpmc_mp_queue(mpmc::Queue::aligned(), 4, 1));
+        println!("4p node cache      {:>3.0} ns/send", bench_mpmc_mp_queue(mpmc::Queue::aligned_with_node_cache(128), 4, 1));
+        println!("4p batch =  8      {:>3.0} ns/send", bench_mpmc_mp_queue(mpmc::Queue::aligned(), 4, 8));
+        println!("4p batch = 64      {:>3.0} ns/send", bench_mpmc_mp_queue(mpmc::Queue::aligned(), 4, 64));
+        println!("4p AcqRel swap     {:>3.0} ns/send", bench_mpmc_mp_queue(mpmc::Queue::<_, _, mpmc::AcqRelSwap>::aligned_with_ordering(), 4, 1));
+        println!("4p fenced swap     {:>3.0} ns/send", bench_mpmc_mp_queue(mpmc::Queue::<_, _, mpmc::FencedSwap>::aligned_with_ordering(), 4, 1));
+        println!("----");
+        println!("mpmc2 1p/1c        {:>3.0} ns/send", bench_mpmc2_queue(mpmc2::Queue::new(), 1, 1));
+        println!("aligned 1p/1c      {:>3.0} ns/send", bench_mpmc2_queue(mpmc2::Queue::aligned(), 1, 1));
+        println!("aligned 2p/2c      {:>3.0} ns/send", bench_mpmc2_queue(mpmc2::Queue::aligned(), 2, 2));
+        println!("aligned 4p/1c      {:>3.0} ns/send", bench_mpmc2_queue(mpmc2::Queue::aligned(), 4, 1));
+        println!("aligned 1p/4c      {:>3.0} ns/send", bench_mpmc2_queue(mpmc2::Queue::aligned(), 1, 4));
+        println!("aligned 4p/4c      {:>3.0} ns/send", bench_mpmc2_queue(mpmc2::Queue::aligned(), 4, 4));
+        println!("----");
+        println!("bounded  128 1p    {:>3.0} ns/send", bench_bounded_mpmc_queue(bounded_mpmc::Queue::new(128), 1));
+        println!("aligned  128 1p    {:>3.0} ns/send", bench_bounded_mpmc_queue(bounded_mpmc::Queue::aligned(128), 1));
+        println!("bounded 8192 1p    {:>3.0} ns/send", bench_bounded_mpmc_queue(bounded_mpmc::Queue::new(8192), 1));
+        println!("aligned 8192 1p    {:>3.0} ns/send", bench_bounded_mpmc_queue(bounded_mpmc::Queue::aligned(8192), 1));
+        println!("bounded  128 4p    {:>3.0} ns/send", bench_bounded_mpmc_queue(bounded_mpmc::Queue::new(128), 4));
+        println!("aligned  128 4p    {:>3.0} ns/send", bench_bounded_mpmc_queue(bounded_mpmc::Queue::aligned(128), 4));
+        println!("bounded 8192 4p    {:>3.0} ns/send", bench_bounded_mpmc_queue(bounded_mpmc::Queue::new(8192), 4));
+        println!("aligned 8192 4p    {:>3.0} ns/send", bench_bounded_mpmc_queue(bounded_mpmc::Queue::aligned(8192), 4));
+        #[cfg(all(feature="shm", target_os="linux"))]
+        {
+            println!("shm 8192 threads   {:>3.0} ns/send", bench_shm_threads());
+            println!("shm 8192 processes {:>3.0} ns/send", bench_shm_processes());
+        }
+        println!("----");
+        println!("spsc baseline      {:>3.0} ns/send", bench_spsc_queue(spsc::Queue::new(128)));
+        println!("bigger cache       {:>3.0} ns/send", bench_spsc_queue(spsc::Queue::new(1024)));
+        println!("aligned            {:>3.0} ns/send", bench_spsc_queue(spsc::Queue::aligned(128)));
+        println!("unbounded          {:>3.0} ns/send", bench_spsc_queue(spsc::Queue::new(0)));
+        println!("no cache           {:>3.0} ns/send", bench_spsc_queue(spsc::Queue::no_cache()));
+        println!("unbounded, aligned {:>3.0} ns/send", bench_spsc_queue(spsc::Queue::aligned(0)));
+        println!("no cache, aligned  {:>3.0} ns/send", bench_spsc_queue(spsc::Queue::aligned_no_cache()));
+        #[cfg(all(feature="hugepages", target_os="linux"))]
+        {
+            arena_row("arena, no cache   ", |arena| bench_spsc_queue(
+                spsc::CA_Queue::<_, Arc<Arena>>::no_cache_with_alloc(arena)));
+            arena_row("arena, aligned    ", |arena| bench_spsc_queue(
+                spsc::CAQueue::<_, Arc<Arena>>::with_alloc(128, arena)));
+        }
+        println!("----");
+        println!("less contention spsc {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::new(128)));
+        println!("aligned              {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(128)));
+        println!("aligned, size =    1 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(1)));
+        println!("aligned, size =    8 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(8)));
+        println!("aligned, size =   16 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(16)));
+        println!("aligned, size =   32 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(32)));
+        println!("aligned, size =   64 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(64)));
+        println!("aligned, size =  128 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(128)));
+        println!("aligned, size =  256 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(256)));
+        println!("aligned, size =  512 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(512)));
+        println!("aligned, size = 1024 {:>3.0} ns/send", bench_spsc2_queue(spsc2::Queue::aligned(1024)));
+        println!("----");
+        println!("segmented spsc       {:>3.0} ns/send", bench_spsc_seg_queue(spsc_seg::Queue::new()));
+        println!("aligned              {:>3.0} ns/send", bench_spsc_seg_queue(spsc_seg::Queue::aligned()));
+        println!("----");
+        println!("epoch reclaimed spsc {:>3.0} ns/send", bench_spsc_epoch_queue(spsc_epoch::Queue::new()));
+        println!("----");
+        latest_row("watch slot          ", bench_watch());
+        latest_row("spsc drained        ", bench_drain_to_latest(spsc::Queue::aligned(128)));
+        latest_row("spsc2 drained       ", bench_drain_to_latest2(spsc2::Queue::aligned(128)));
+        latest_row("coalescing 1k keys  ", bench_coalesce());
+        latest_row("spsc 1k keys        ", bench_keyed_spsc(spsc::Queue::aligned(128)));
+        println!("----");
+        for &chunk in &BYTE_CHUNKS {
+            println!("byte ring {:>5}B chunks  {:>6.0} MB/s", chunk, bench_byte_ring(chunk));
+            println!("  in place              {:>6.0} MB/s", bench_byte_ring_slices(chunk));
+        }
+        println!("----");
+        println!("stream baseline      {:>3.0} ns/send", bench_stream(stream::Packet::<spsc::_NQueue<_>, _>::new()));
+        println!("aligned              {:>3.0} ns/send", bench_stream(stream::Packet::<spsc::CNQueue<_>, _>::new()));
+        println!("no cache             {:>3.0} ns/send", bench_stream(stream::Packet::<spsc::__Queue<_>, _>::new()));
+        println!("aligned, no cache    {:>3.0} ns/send", bench_stream(stream::Packet::<spsc::C_Queue<_>, _>::new()));
+        println!("less contend         {:>3.0} ns/send", bench_stream(stream::Packet::<spsc2::_Queue<_>, _>::new()));
+        println!("less contend aligned {:>3.0} ns/send", bench_stream(stream::Packet::<spsc2::AQueue<_>, _>::new()));
+        println!("----");
+        println!("stream2 baseline     {:>3.0} ns/send", bench_stream2(stream2::Packet::<spsc::_NQueue<_>, _>::new()));
+        println!("aligned              {:>3.0} ns/send", bench_stream2(stream2::Packet::<spsc::CNQueue<_>, _>::new()));
+        println!("no cache             {:>3.0} ns/send", bench_stream2(stream2::Packet::<spsc::__Queue<_>, _>::new()));
+        println!("aligned, no cache    {:>3.0} ns/send", bench_stream2(stream2::Packet::<spsc::C_Queue<_>, _>::new()));
+        println!("less contend         {:>3.0} ns/send", bench_stream2(stream2::Packet::<spsc2::_Queue<_>, _>::new()));
+        println!("less contend aligned {:>3.0} ns/send", bench_stream2(stream2::Packet::<spsc2::AQueue<_>, _>::new()));
+        println!("----");
+        protocol_row("aligned             ",
+            bench_stream(stream::Packet::<spsc::CNQueue<_>, _>::new()),
+            bench_stream2(stream2::Packet::<spsc::CNQueue<_>, _>::new()));
+        protocol_row("less contend aligned",
+            bench_stream(stream::Packet::<spsc2::AQueue<_>, _>::new()),
+            bench_stream2(stream2::Packet::<spsc2::AQueue<_>, _>::new()));
+        println!("----");
+        packet_row("packet baseline     ", bench_packet_stream::<spsc::_NQueue<_>>(0), bench_spsc_queue(spsc::Queue::new(128)));
+        packet_row("aligned             ", bench_packet_stream::<spsc::CNQueue<_>>(0), bench_spsc_queue(spsc::Queue::aligned(128)));
+        packet_row("no cache            ", bench_packet_stream::<spsc::__Queue<_>>(0), bench_spsc_queue(spsc::Queue::no_cache()));
+        packet_row("aligned, no cache   ", bench_packet_stream::<spsc::C_Queue<_>>(0), bench_spsc_queue(spsc::Queue::aligned_no_cache()));
+        packet_row("less contend        ", bench_packet_stream::<spsc2::_Queue<_>>(0), bench_spsc2_queue(spsc2::Queue::new(128)));
+        packet_row("less contend aligned", bench_packet_stream::<spsc2::AQueue<_>>(0), bench_spsc2_queue(spsc2::Queue::aligned(128)));
+        packet_row("segmented           ", bench_packet_stream::<spsc_seg::_Queue<_>>(0), bench_spsc_seg_queue(spsc_seg::Queue::new()));
+        packet_row("segmented aligned   ", bench_packet_stream::<spsc_seg::AQueue<_>>(0), bench_spsc_seg_queue(spsc_seg::Queue::aligned()));
+        packet_row("epoch reclaimed     ", bench_packet_stream::<spsc_epoch::Queue<_>>(0), bench_spsc_epoch_queue(spsc_epoch::Queue::new()));
+        packet_row("polling aligned     ", bench_packet_polling::<spsc::CNQueue<_>>(), bench_spsc_queue(spsc::Queue::aligned(128)));
+        packet_row("polling less contend", bench_packet_polling::<spsc2::AQueue<_>>(), bench_spsc2_queue(spsc2::Queue::aligned(128)));
+        // build with and without the seqcst_channel feature to compare
+        println!("{:<20} {:>3.0} ns/send", format!("{:?} re-check", stream2::PORT_DROPPED_RECHECK),
+            bench_packet_stream::<spsc::CNQueue<_>>(0));
+        println!("----");
+        println!("std oneshot          {:>3.0} ns/msg", bench_std_oneshot());
+        println!("oneshot              {:>3.0} ns/msg", bench_oneshot());
+        println!("std oneshot upgrade  {:>3.0} ns/msg", bench_std_oneshot_upgrade());
+        println!("oneshot upgrade      {:>3.0} ns/msg", bench_oneshot_upgrade());
+        transition_rows("std    ", bench_std_transitions());
+        transition_rows("packets", bench_transitions());
+        println!("----");
+        println!("sync_channel    0    {:>3.0} ns/send", bench_sync_channel(0));
+        println!("sync_channel    1    {:>3.0} ns/send", bench_sync_channel(1));
+        println!("sync_channel  128    {:>3.0} ns/send", bench_sync_channel(128));
+        println!("sync_channel 8192    {:>3.0} ns/send", bench_sync_channel(8192));
+        println!("sync packet    1     {:>3.0} ns/send", bench_sync_packet(sync2::SyncPacket::<spsc::CNQueue<_>, _>::new(1)));
+        println!("sync packet  128     {:>3.0} ns/send", bench_sync_packet(sync2::SyncPacket::<spsc::CNQueue<_>, _>::new(128)));
+        println!("sync packet 8192     {:>3.0} ns/send", bench_sync_packet(sync2::SyncPacket::<spsc::CNQueue<_>, _>::new(8192)));
+        for &bound in &[0, 1, 128] {
+            println!("sync_orig {:>4}      {:>3.0} ns/send", bound, bench_sync_orig(bound));
+        }
+        println!("----");
+        for &senders in &[1, 2, 4, 8] {
+            println!("std shared    {}p     {:>3.0} ns/send", senders, bench_std_shared(senders));
+            println!("shared packet {}p     {:>3.0} ns/send", senders, bench_shared_packet(senders));
+            println!("shared_orig   {}p     {:>3.0} ns/send", senders, bench_shared_orig(shared_orig::Packet::new(), senders));
+            println!("shared_orig al. {}p   {:>3.0} ns/send", senders, bench_shared_orig(shared_orig::Packet::aligned(), senders));
+        }
+        println!("----");
+        wakeup_row("std wakeup          ", bench_std_wakeup_latency());
+        wakeup_row("wakeup aligned      ", bench_packet_wakeup_latency::<spsc::CNQueue<_>>(0));
+        wakeup_row("wakeup less contend ", bench_packet_wakeup_latency::<spsc2::AQueue<_>>(0));
+        #[cfg(feature="futex")]
+        wakeup_row("wakeup futex        ", bench_wakeup_latency_with::<spsc::CNQueue<_>, futex::FutexBlocking>(0, stream2::ParkSpin::Fixed(0)));
+        bench_signal_tokens();
+        println!("----");
+        for &(burst, gap_us) in &BURST_SHAPES {
+            let shape = format!("{:>4} every {:>4}us", burst, gap_us);
+            burst_row(&format!("std     {}", shape), bench_std_bursts(burst, gap_us));
+            burst_row(&format!("aligned {}", shape), bench_packet_bursts::<spsc::CNQueue<_>>(burst, gap_us, 0));
+            burst_row(&format!("less c. {}", shape), bench_packet_bursts::<spsc2::AQueue<_>>(burst, gap_us, 0));
+            burst_row(&format!("spin100 {}", shape), bench_packet_bursts::<spsc::CNQueue<_>>(burst, gap_us, 100));
+            let (per_msg, per_call) = bench_packet_bursts_many::<spsc::CNQueue<_>>(burst, gap_us, 1024);
+            println!("many    {} {:>6.0} ns/msg {:>7.1} msgs/call", shape, per_msg, per_call);
+        }
+        println!("----");
+        println!("std ping-pong        {:>5.0} ns/round trip", bench_std_ping_pong());
+        println!("bichannel ping-pong  {:>5.0} ns/round trip", bench_bichannel_ping_pong());
+        #[cfg(feature="stats")]
+        {
+            println!("----");
+            for &received in &[0, 100, 10_000] {
+                drain_sizes::<spsc::CNQueue<_>>(received);
+            }
+        }
+        println!("----");
+        for &budget in &[1, 1 << 10, 1 << 20] {
+            println!("steal budget {:>7} {:>3.0} ns/send", budget,
+                bench_stream2(stream2::Packet::<spsc::CNQueue<_>, _>::with_steal_budget(budget)));
+        }
+        println!("----");
+        println!("1KB recv      {:>5.0} ns/send", bench_packet_large(false));
+        println!("1KB recv_with {:>5.0} ns/send", bench_packet_large(true));
+        println!("----");
+        for &spin in &[0, 100, 10_000] {
+            println!("spin {:>6} stream    {:>3.0} ns/send", spin, bench_packet_stream::<spsc::CNQueue<_>>(spin));
+            wakeup_row(&format!("spin {:>6} wakeup   ", spin), bench_packet_wakeup_latency::<spsc::CNQueue<_>>(spin));
+        }
+        println!("----");
+        for &park_spin in &[stream2::ParkSpin::Fixed(0), stream2::ParkSpin::Fixed(100),
+                            stream2::ParkSpin::Fixed(10_000), stream2::ParkSpin::Adaptive] {
+            let name = format!("{:?}", park_spin);
+            println!("{:<20} {:>3.0} ns/send", format!("park {} stream", name),
+                bench_packet_stream_with::<spsc::CNQueue<_>>(0, park_spin));
+            wakeup_row(&format!("{:<20}", format!("park {} wakeup", name)),
+                bench_wakeup_latency_with::<spsc::CNQueue<_>, blocking::DefaultBlocking>(0, park_spin));
+        }
+    }
+
+}
+
+fn bench_mpsc_stream() -> f64 {
+    let (sender, reciever) = channel();
+    bench_spsc(sender, reciever)
+}
+
+fn bench_mpsc_shared() -> f64 {
+    let (sender, reciever) = channel();
+    // this clone forces the queue into shared mode and makes the benchmark faster
+    let _clone = sender.clone();
+    bench_spsc(sender, reciever)
+}
+
+const COUNT: u64 = 10_000_000;
+
+fn bench_spsc(tx: Sender<u64>, rx: Receiver<u64>) -> f64 {
+    // ensure that the channel is not in Once mode
+    tx.send(0).unwrap();
+    tx.send(0).unwrap();
+    rx.recv().unwrap();
+    rx.recv().unwrap();
+
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.send(x));
+            }
+        });
+
+        for _i in 0..(COUNT*2) {
+            let _ = black_box(rx.recv().unwrap());
+        }
+    });
+    let d = start.elapsed();
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+// Oneshots are only used once, so rather than streaming messages these create
+// a fresh channel for every one.
+#[cfg(feature="queue_experiments")]
+const ONESHOT_COUNT: u64 = COUNT / 10;
+
+#[cfg(feature="queue_experiments")]
+fn bench_std_oneshot() -> f64 {
+    let start = ::std::time::Instant::now();
+    for x in 0..ONESHOT_COUNT {
+        let (tx, rx) = channel();
+        tx.send(x).unwrap();
+        let _ = black_box(rx.recv().unwrap());
+    }
+    let d = start.elapsed();
+
+    nanos(d) / (ONESHOT_COUNT as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_oneshot() -> f64 {
+    let start = ::std::time::Instant::now();
+    for x in 0..ONESHOT_COUNT {
+        let packet = Arc::new(oneshot::Packet::new());
+        packet.send(x).unwrap();
+        let _ = black_box(packet.recv(None).ok().unwrap());
+        packet.drop_chan();
+        packet.drop_port();
+    }
+    let d = start.elapsed();
+
+    nanos(d) / (ONESHOT_COUNT as f64)
+}
+
+// The second send on a channel upgrades it from a oneshot to a stream.
+#[cfg(feature="queue_experiments")]
+fn bench_std_oneshot_upgrade() -> f64 {
+    let start = ::std::time::Instant::now();
+    for x in 0..ONESHOT_COUNT {
+        let (tx, rx) = channel();
+        tx.send(x).unwrap();
+        tx.send(x).unwrap();
+        let _ = black_box(rx.recv().unwrap());
+        let _ = black_box(rx.recv().unwrap());
+    }
+    let d = start.elapsed();
+
+    nanos(d) / (ONESHOT_COUNT as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_oneshot_upgrade() -> f64 {
+    let start = ::std::time::Instant::now();
+    for x in 0..ONESHOT_COUNT {
+        let packet = Arc::new(oneshot::Packet::new());
+        packet.send(x).unwrap();
+        // what std's Sender does on the second send
+        let (tx, rx) = stream2::channel();
+        match packet.upgrade(rx) {
+            oneshot::UpSuccess | oneshot::UpDisconnected => {},
+            oneshot::UpWoke(token) => { token.signal(); }
+        }
+        packet.drop_chan();
+        tx.send(x).unwrap();
+
+        let _ = black_box(packet.recv(None).ok().unwrap());
+        let rx = match packet.recv(None) {
+            Err(oneshot::Upgraded(rx)) => rx,
+            _ => panic!(),
+        };
+        packet.drop_port();
+        let _ = black_box(rx.recv().unwrap());
+    }
+    let d = start.elapsed();
+
+    nanos(d) / (ONESHOT_COUNT as f64)
+}
+
+// The flavor transitions happen once per channel, so rather than streaming
+// these time only the operation in question, on each of many fresh channels:
+// the first send and recv, the send which upgrades a oneshot to a stream, and
+// the clone which upgrades a stream to shared along with the first send after
+// it. The times include reading the clock.
+#[cfg(feature="queue_experiments")]
+const TRANSITION_COUNT: u32 = 10_000;
+
+#[cfg(feature="queue_experiments")]
+struct Transitions {
+    first: Duration,
+    upgrade: Duration,
+    clone: Duration,
+}
+
+#[cfg(feature="queue_experiments")]
+fn timed<R, F: FnOnce() -> R>(total: &mut Duration, f: F) -> R {
+    let start = ::std::time::Instant::now();
+    let r = f();
+    *total += start.elapsed();
+    r
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_std_transitions() -> Transitions {
+    let mut t = Transitions { first: Duration::new(0, 0), upgrade: Duration::new(0, 0), clone: Duration::new(0, 0) };
+    for x in 0..TRANSITION_COUNT {
+        let (tx, rx) = channel();
+        timed(&mut t.first, || {
+            tx.send(x).unwrap();
+            black_box(rx.recv().unwrap())
+        });
+        timed(&mut t.upgrade, || tx.send(x).unwrap());
+        let _ = black_box(rx.recv().unwrap());
+        let _clone = timed(&mut t.clone, || {
+            let clone = tx.clone();
+            tx.send(x).unwrap();
+            clone
+        });
+        let _ = black_box(rx.recv().unwrap());
+    }
+    t
+}
+
+// std's channel goes through each of these packets in turn, here they are put
+// together by hand.
+#[cfg(feature="queue_experiments")]
+fn bench_transitions() -> Transitions {
+    let mut t = Transitions { first: Duration::new(0, 0), upgrade: Duration::new(0, 0), clone: Duration::new(0, 0) };
+    for x in 0..TRANSITION_COUNT {
+        let packet = Arc::new(oneshot::Packet::new());
+        timed(&mut t.first, || {
+            packet.send(x).unwrap();
+            black_box(packet.recv(None).ok().unwrap())
+        });
+        // what std's Sender does on the second send
+        let tx = timed(&mut t.upgrade, || {
+            let (tx, rx) = stream2::channel();
+            match packet.upgrade(rx) {
+                oneshot::UpSuccess | oneshot::UpDisconnected => {},
+                oneshot::UpWoke(token) => { token.signal(); }
+            }
+            tx.send(x).unwrap();
+            tx
+        });
+        packet.drop_chan();
+        let rx = match packet.recv(None) {
+            Err(oneshot::Upgraded(rx)) => rx,
+            _ => panic!(),
+        };
+        packet.drop_port();
+        let _ = black_box(rx.recv().unwrap());
+        let _clone = timed(&mut t.clone, || {
+            let clone = tx.clone();
+            tx.send(x).unwrap();
+            clone
+        });
+        let _ = black_box(rx.recv().unwrap());
+    }
+    t
+}
+
+#[cfg(feature="queue_experiments")]
+fn transition_rows(name: &str, t: Transitions) {
+    let per = |d| nanos(d) / (TRANSITION_COUNT as f64);
+    println!("{} first send+recv {:>5.0} ns", name, per(t.first));
+    println!("{} upgrade send    {:>5.0} ns", name, per(t.upgrade));
+    println!("{} clone+send      {:>5.0} ns", name, per(t.clone));
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_sync_channel(bound: usize) -> f64 {
+    let (tx, rx) = sync_channel(bound);
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.send(x));
+            }
+        });
+
+        for _i in 0..(COUNT*2) {
+            let _ = black_box(rx.recv().unwrap());
+        }
+    });
+    let d = start.elapsed();
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_sync_packet<Q>(packet: sync2::SyncPacket<Q, u64>) -> f64
+where Q: stream2::Queue<u64> + Send + Sync {
+    let tx = Arc::new(packet);
+    let rx = tx.clone();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.send(x).unwrap());
+            }
+            tx.drop_chan();
+        });
+
+        for _i in 0..(COUNT*2) {
+            match black_box(rx.recv()) {
+                Ok(..) => {}
+                Err(e) => panic!("{:?} @ {}", e, _i),
+            }
+        }
+        rx.drop_port();
+    });
+    let d = start.elapsed();
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_sync_orig(bound: usize) -> f64 {
+    let tx = Arc::new(sync_orig::Packet::new(bound));
+    let rx = tx.clone();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.send(x).unwrap());
+            }
+            tx.drop_chan();
+        });
+
+        for _i in 0..(COUNT*2) {
+            match black_box(rx.recv(None)) {
+                Ok(..) => {}
+                Err(e) => panic!("{:?} @ {}", e, _i),
+            }
+        }
+        rx.drop_port();
+    });
+    let d = start.elapsed();
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+// `senders` threads split the messages between them, all sending through one
+// channel to a single receiver.
+#[cfg(feature="queue_experiments")]
+fn bench_std_shared(senders: u64) -> f64 {
+    let total = COUNT*2;
+    let (tx, rx) = channel();
+    // as in bench_mpsc_shared, a clone forces the channel into shared mode
+    // even when there's only one sender
+    let _clone = tx.clone();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        for s in 0..senders {
+            let tx = tx.clone();
+            scope.spawn(move || {
+                // the first sender picks up any remainder
+                let msgs = total / senders + if s == 0 { total % senders } else { 0 };
+                for x in 0..msgs {
+                    let _ = black_box(tx.send(x));
+                }
+            });
+        }
+
+        for _i in 0..total {
+            let _ = black_box(rx.recv().unwrap());
+        }
+    });
+    let d = start.elapsed();
+
+    nanos(d) / (total as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_shared_packet(senders: u64) -> f64 {
+    let total = COUNT*2;
+    let packet = &shared::SharedPacket::new();
+    // register every sender up front, so that one which finishes early
+    // doesn't disconnect the channel before the rest have started
+    for _ in 1..senders { packet.clone_chan() }
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        for s in 0..senders {
+            scope.spawn(move || {
+                // the first sender picks up any remainder
+                let msgs = total / senders + if s == 0 { total % senders } else { 0 };
+                for x in 0..msgs {
+                    let _ = black_box(packet.send(x).unwrap());
+                }
+                packet.drop_chan();
+            });
+        }
+
+        for _i in 0..total {
+            match black_box(packet.recv(None)) {
+                Ok(..) => {}
+                Err(e) => panic!("{:?} @ {}", e, _i),
+            }
+        }
+    });
+    let d = start.elapsed();
+    packet.drop_port();
+
+    nanos(d) / (total as f64)
+}
+
+// bench_shared_packet with std's shared protocol
+#[cfg(feature="queue_experiments")]
+fn bench_shared_orig<A: Padding>(packet: shared_orig::Packet<u64, A>, senders: u64) -> f64 {
+    let total = COUNT*2;
+    let packet = &packet;
+    for _ in 1..senders { packet.clone_chan() }
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        for s in 0..senders {
+            scope.spawn(move || {
+                let msgs = total / senders + if s == 0 { total % senders } else { 0 };
+                for x in 0..msgs {
+                    let _ = black_box(packet.send(x).unwrap());
+                }
+                packet.drop_chan();
+            });
+        }
+
+        for _i in 0..total {
+            match black_box(packet.recv(None)) {
+                Ok(..) => {}
+                Err(e) => panic!("{:?} @ {}", e, _i),
+            }
+        }
+    });
+    let d = start.elapsed();
+    packet.drop_port();
+
+    nanos(d) / (total as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_spsc_queue<A, C>(queue: spsc::Queue<u64, A, C>) -> f64
+where A: Padding, C: spsc::UseCache {
+    bench_split_queue(queue)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_spsc2_queue<A: Padding>(queue: spsc2::Queue<u64, A>) -> f64 {
+    bench_split_queue(queue)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_split_queue<Q: split::SplitQueue<u64> + Sync>(queue: Q) -> f64 {
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    split::run_split_with(queue, |tx| {
+        for x in 0..(COUNT*2) {
+            let _ = black_box(tx.push(x));
+        }
+    }, |rx| {
+        let mut backoff = Backoff::new();
+        for _i in 0..(COUNT*2) {
+            while let None = black_box(rx.pop()) { stalls.empty(); backoff.snooze() }
+            stalls.data();
+            backoff.reset();
+        }
+    });
+    let d = start.elapsed();
+    report_stalls(&stalls);
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_spsc_seg_queue<A: Padding>(queue: spsc_seg::Queue<u64, A>) -> f64 {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.push(x));
+            }
+        });
+
+        let mut backoff = Backoff::new();
+        for _i in 0..(COUNT*2) {
+            while let None = black_box(rx.pop()) { stalls.empty(); backoff.snooze() }
+            stalls.data();
+            backoff.reset();
+        }
+    });
+    let d = start.elapsed();
+    report_stalls(&stalls);
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_spsc_epoch_queue(queue: spsc_epoch::Queue<u64>) -> f64 {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.push(x));
+            }
+        });
+
+        let mut backoff = Backoff::new();
+        for _i in 0..(COUNT*2) {
+            while let None = black_box(rx.pop()) { stalls.empty(); backoff.snooze() }
+            stalls.data();
+            backoff.reset();
+        }
+    });
+    let d = start.elapsed();
+    report_stalls(&stalls);
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+// `--layout`: the size, alignment and field offsets of each queue and packet
+// in the table, flagging hot fields of different sides less than a line apart.
+#[cfg(feature="queue_experiments")]
+fn print_layouts() {
+    fn show<L: layout::Layout>(name: &str, value: &L) {
+        println!("{}", name);
+        print!("{}", layout::Report::of(value));
+    }
+    unsafe {
+        show("mpmc::Queue<u64, NoAlign>", &mpmc::Queue::<u64, _>::new());
+        show("mpmc::Queue<u64, CacheAligned>", &mpmc::Queue::<u64, _>::aligned());
+        show("mpmc2::Queue<u64, NoAlign>", &mpmc2::Queue::<u64, _>::new());
+        show("mpmc2::Queue<u64, CacheAligned>", &mpmc2::Queue::<u64, _>::aligned());
+        show("bounded_mpmc::Queue<u64, NoAlign>", &bounded_mpmc::Queue::<u64, _>::new(128));
+        show("bounded_mpmc::Queue<u64, CacheAligned>", &bounded_mpmc::Queue::<u64, _>::aligned(128));
+        show("spsc::_NQueue<u64>", &spsc::_NQueue::<u64>::new(128));
+        show("spsc::CNQueue<u64>", &spsc::CNQueue::<u64>::aligned(128));
+        show("spsc::__Queue<u64>", &spsc::__Queue::<u64>::no_cache());
+        show("spsc::C_Queue<u64>", &spsc::C_Queue::<u64>::aligned_no_cache());
+        show("spsc2::_Queue<u64>", &spsc2::_Queue::<u64>::new(128));
+        show("spsc2::AQueue<u64>", &spsc2::AQueue::<u64>::aligned(128));
+        show("spsc_seg::_Queue<u64>", &spsc_seg::_Queue::<u64>::new());
+        show("spsc_seg::AQueue<u64>", &spsc_seg::AQueue::<u64>::aligned());
+        show("spsc_epoch::Queue<u64>", &spsc_epoch::Queue::<u64>::new());
+    }
+    show("stream::Packet<spsc::_NQueue>", &stream::Packet::<spsc::_NQueue<_>, u64>::new());
+    show("stream::Packet<spsc::CNQueue>", &stream::Packet::<spsc::CNQueue<_>, u64>::new());
+    show("stream::Packet<spsc::__Queue>", &stream::Packet::<spsc::__Queue<_>, u64>::new());
+    show("stream::Packet<spsc::C_Queue>", &stream::Packet::<spsc::C_Queue<_>, u64>::new());
+    show("stream::Packet<spsc2::_Queue>", &stream::Packet::<spsc2::_Queue<_>, u64>::new());
+    show("stream::Packet<spsc2::AQueue>", &stream::Packet::<spsc2::AQueue<_>, u64>::new());
+    // the packets assert on drop that both ends hung up
+    fn show_stream2<Q>(name: &str, packet: stream2::Packet<Q, u64>)
+    where Q: stream2::Queue<stream2::Message<u64>> + layout::Layout {
+        show(name, &packet);
+        packet.drop_chan();
+        packet.drop_port();
+    }
+    fn show_shared(name: &str, packet: shared::SharedPacket<u64>) {
+        show(name, &packet);
+        packet.drop_chan();
+        packet.drop_port();
+    }
+    fn show_shared_orig<A: Padding>(name: &str, packet: shared_orig::Packet<u64, A>) {
+        show(name, &packet);
+        packet.drop_chan();
+        packet.drop_port();
+    }
+    show_stream2("stream2::Packet<spsc::_NQueue>", stream2::Packet::<spsc::_NQueue<_>, u64>::new());
+    show_stream2("stream2::Packet<spsc::CNQueue>", stream2::Packet::<spsc::CNQueue<_>, u64>::new());
+    show_stream2("stream2::Packet<spsc::__Queue>", stream2::Packet::<spsc::__Queue<_>, u64>::new());
+    show_stream2("stream2::Packet<spsc::C_Queue>", stream2::Packet::<spsc::C_Queue<_>, u64>::new());
+    show_stream2("stream2::Packet<spsc2::_Queue>", stream2::Packet::<spsc2::_Queue<_>, u64>::new());
+    show_stream2("stream2::Packet<spsc2::AQueue>", stream2::Packet::<spsc2::AQueue<_>, u64>::new());
+    show_stream2("stream2::Packet<spsc_seg::_Queue>", stream2::Packet::<spsc_seg::_Queue<_>, u64>::new());
+    show_stream2("stream2::Packet<spsc_seg::AQueue>", stream2::Packet::<spsc_seg::AQueue<_>, u64>::new());
+    show_shared("shared::SharedPacket", shared::SharedPacket::new());
+    show_shared_orig("shared_orig::Packet<u64, NoAlign>", shared_orig::Packet::new());
+    show_shared_orig("shared_orig::Packet<u64, CacheAligned>", shared_orig::Packet::aligned());
+}
+
+// `--queue <spec>`: the queue a factory::QueueKind spec names, built boxed,
+// then the same queue built statically, so the difference between the rows
+// is the cost of the dynamic dispatch.
+#[cfg(feature="queue_experiments")]
+fn bench_queue_spec(spec: &str) {
+    let kind: factory::QueueKind = match spec.parse() {
+        Ok(kind) => kind,
+        Err(e) => {
+            eprintln!("bad queue spec {:?}: {}", spec, e);
+            ::std::process::exit(2)
+        }
+    };
+    println!("{:<26} {:>3.0} ns/send", format!("{} dyn", kind), bench_dyn_queue(kind.build()));
+    if let Some(ns) = bench_static_queue(&kind) {
+        println!("{:<26} {:>3.0} ns/send", format!("{} static", kind), ns);
+    }
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_dyn_queue(queue: Box<dyn stream2::Queue<u64> + Send + Sync>) -> f64 {
+    let queue = &*queue;
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(queue.push(x));
+            }
+        });
+
+        let mut backoff = Backoff::new();
+        for _i in 0..(COUNT*2) {
+            while let None = black_box(queue.pop()) { stalls.empty(); backoff.snooze() }
+            stalls.data();
+            backoff.reset();
+        }
+    });
+    let d = start.elapsed();
+    report_stalls(&stalls);
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+// `--rate <msgs/s>` runs each of RATE_KINDS with its producer sending at
+// that rate, and `--rate-sweep`, optionally followed by a comma separated
+// list of rates, runs each at every rate in RATE_SWEEP or the list. Either
+// takes a `--queue <spec>` to run just that queue. Each row is the rate
+// asked for, the one the producer managed, which falls short once the rate
+// is more than it can send, and the latency to the pop from the send and,
+// corrected for coordinated omission, from when the message was due.
+// `--clock tsc` times them by rdtscp rather than Instant, with the tsc
+// feature on x86_64, falling back to Instant with a warning otherwise.
+#[cfg(feature="queue_experiments")]
+const RATE_KINDS: [&str; 4] = ["spsc:aligned:128", "spsc:aligned:nocache", "spsc2:aligned:128", "spsc_seg:aligned"];
+
+#[cfg(feature="queue_experiments")]
+const RATE_SWEEP: [f64; 8] = [1e5, 5e5, 1e6, 2e6, 4e6, 8e6, 16e6, 32e6];
+
+#[cfg(feature="queue_experiments")]
+fn bench_rate_args(args: &[String]) {
+    fn parse_rate(s: &str) -> f64 {
+        match s.parse::<f64>() {
+            Ok(rate) if rate > 0.0 => rate,
+            _ => {
+                eprintln!("bad rate {:?}, expected messages a second, e.g. 2e6", s);
+                ::std::process::exit(2)
+            }
+        }
+    }
+    let after = |flag: &str| args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1))
+        .filter(|a| !a.starts_with("--"));
+    let kinds: Vec<factory::QueueKind> = match after("--queue") {
+        Some(spec) => vec![spec.parse().unwrap_or_else(|e| {
+            eprintln!("bad queue spec {:?}: {}", spec, e);
+            ::std::process::exit(2)
+        })],
+        None => RATE_KINDS.iter().map(|spec| spec.parse().unwrap()).collect(),
+    };
+    let rates: Vec<f64> = if args.iter().any(|a| a == "--rate-sweep") {
+        match after("--rate-sweep") {
+            Some(list) => list.split(',').map(parse_rate).collect(),
+            None => RATE_SWEEP.to_vec(),
+        }
+    } else {
+        match after("--rate") {
+            Some(rate) => vec![parse_rate(rate)],
+            None => {
+                eprintln!("--rate needs messages a second, e.g. 2e6");
+                ::std::process::exit(2)
+            }
+        }
+    };
+    let clock: Box<dyn Clock> = match after("--clock").map(|a| a.as_str()) {
+        None | Some("instant") => Box::new(InstantClock::new()),
+        Some("tsc") => clock::tsc_or_instant(),
+        Some(other) => {
+            eprintln!("bad clock {:?}, expected instant or tsc", other);
+            ::std::process::exit(2)
+        }
+    };
+    for kind in &kinds {
+        println!("{}", kind);
+        println!("  {:>21}  {:>36}  {:>36}", "msgs/s", "from send, ns", "from due, ns");
+        println!("  {:>10} {:>10}  {:>11} {:>11} {:>12}  {:>11} {:>11} {:>12}",
+            "requested", "achieved", "p50", "p99", "max", "p50", "p99", "max");
+        for &r in &rates {
+            // half a second's worth, within reason
+            let count = ::std::cmp::min(::std::cmp::max((r / 2.0) as usize, 1_000), 4_000_000);
+            let run = rate::run_kind_at_rate(kind, &*clock, r, count);
+            let row = |h: &rate::Histogram| format!("{:>11} {:>11} {:>12}",
+                h.percentile(50.0), h.percentile(99.0), h.max());
+            println!("  {:>10.0} {:>10.0}  {}  {}", run.requested, run.achieved,
+                row(&run.uncorrected), row(&run.corrected));
+        }
+    }
+}
+
+// The static row for a kind, if the table has a bench for its queue.
+#[cfg(feature="queue_experiments")]
+fn bench_static_queue(kind: &factory::QueueKind) -> Option<f64> {
+    use std_spsc_is_slow::factory::{Variant, Alignment, CacheMode};
+    unsafe {
+        let ns = match (kind.variant, kind.alignment, kind.cache) {
+            (Variant::Spsc, Alignment::Plain, CacheMode::Bound(b)) => bench_spsc_queue(spsc::Queue::new(b)),
+            (Variant::Spsc, Alignment::Aligned, CacheMode::Bound(b)) => bench_spsc_queue(spsc::Queue::aligned(b)),
+            (Variant::Spsc, Alignment::Plain, CacheMode::NoCache) => bench_spsc_queue(spsc::Queue::no_cache()),
+            (Variant::Spsc, Alignment::Aligned, CacheMode::NoCache) => bench_spsc_queue(spsc::Queue::aligned_no_cache()),
+            (Variant::Spsc2, Alignment::Plain, CacheMode::Bound(b)) => bench_spsc2_queue(spsc2::Queue::new(b)),
+            (Variant::Spsc2, Alignment::Aligned, CacheMode::Bound(b)) => bench_spsc2_queue(spsc2::Queue::aligned(b)),
+            (Variant::SpscSeg, Alignment::Plain, CacheMode::Segment) => bench_spsc_seg_queue(spsc_seg::Queue::new()),
+            (Variant::SpscSeg, Alignment::Aligned, CacheMode::Segment) => bench_spsc_seg_queue(spsc_seg::Queue::aligned()),
+            _ => return None,
+        };
+        Some(ns)
+    }
+}
+
+// A consumer which only wants the newest value, reading it from a watch::Slot
+// or by draining a queue to its last message, against a producer sending as
+// fast as it can. Returns the time per send and the number of values the
+// consumer saw.
+#[cfg(feature="queue_experiments")]
+fn bench_watch() -> (f64, u64) {
+    let slot = watch::Slot::new();
+    let mut seen = 0;
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        let slot = &slot;
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                slot.publish(black_box(x));
+            }
+        });
+
+        loop {
+            if let Some(x) = black_box(slot.take()) {
+                seen += 1;
+                if x == COUNT*2 - 1 { break }
+            }
+        }
+    });
+    let d = start.elapsed();
+
+    (nanos(d) / ((COUNT*2) as f64), seen)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_drain_to_latest<A, C>(queue: spsc::Queue<u64, A, C>) -> (f64, u64)
+where A: Padding, C: spsc::UseCache {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    let mut seen = 0;
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.push(x));
+            }
+        });
+
+        loop {
+            let mut latest = None;
+            while let Some(x) = black_box(rx.pop()) { latest = Some(x) }
+            if let Some(x) = latest {
+                seen += 1;
+                if x == COUNT*2 - 1 { break }
+            }
+        }
+    });
+    let d = start.elapsed();
+
+    (nanos(d) / ((COUNT*2) as f64), seen)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_drain_to_latest2<A: Padding>(queue: spsc2::Queue<u64, A>) -> (f64, u64) {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    let mut seen = 0;
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.push(x));
+            }
+        });
+
+        loop {
+            let mut latest = None;
+            while let Some(x) = black_box(rx.pop()) { latest = Some(x) }
+            if let Some(x) = latest {
+                seen += 1;
+                if x == COUNT*2 - 1 { break }
+            }
+        }
+    });
+    let d = start.elapsed();
+
+    (nanos(d) / ((COUNT*2) as f64), seen)
+}
+
+// State updates over HOT_KEYS keys, COUNT in all, until the consumer has
+// each key's last value, as latest-per-key through coalesce against every
+// update through a plain queue.
+#[cfg(feature="queue_experiments")]
+const HOT_KEYS: u64 = 1000;
+
+#[cfg(feature="queue_experiments")]
+fn bench_coalesce() -> (f64, u64) {
+    let q = unsafe { coalesce::CoalescingQueue::new() };
+    let mut seen = 0;
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        let q = &q;
+        scope.spawn(move || {
+            for x in 0..COUNT {
+                black_box(q.update(x % HOT_KEYS, x));
+            }
+        });
+
+        let mut remaining = HOT_KEYS;
+        while remaining > 0 {
+            if let Some((_, x)) = black_box(q.pop()) {
+                seen += 1;
+                if x >= COUNT - HOT_KEYS { remaining -= 1 }
+            }
+        }
+    });
+    let d = start.elapsed();
+
+    (nanos(d) / (COUNT as f64), seen)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_keyed_spsc<A, C>(queue: spsc::Queue<(u64, u64), A, C>) -> (f64, u64)
+where A: Padding, C: spsc::UseCache {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    let mut seen = 0;
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..COUNT {
+                let _ = black_box(tx.push((x % HOT_KEYS, x)));
+            }
+        });
+
+        let mut remaining = HOT_KEYS;
+        while remaining > 0 {
+            if let Some((_, x)) = black_box(rx.pop()) {
+                seen += 1;
+                if x >= COUNT - HOT_KEYS { remaining -= 1 }
+            }
+        }
+    });
+    let d = start.elapsed();
+
+    (nanos(d) / (COUNT as f64), seen)
+}
+
+// Enough nodes for the producer to run well ahead of the consumer, past
+// which the queue mallocs.
+#[cfg(all(feature="hugepages", target_os="linux"))]
+const ARENA_SLOTS: usize = 1 << 20;
+
+// The row, then whatever the arena went without and how many nodes it
+// couldn't give, which make the row a measure of something less.
+#[cfg(all(feature="hugepages", target_os="linux"))]
+fn arena_row<F: FnOnce(Arc<Arena>) -> f64>(name: &str, bench: F) {
+    let arena = match Arena::for_nodes::<u64>(ARENA_SLOTS, arena::Options::default()) {
+        Ok(arena) => Arc::new(arena),
+        Err(e) => {
+            println!("{} no arena: {}", name, e);
+            return
+        }
+    };
+    let per_send = bench(arena.clone());
+    let mut notes: Vec<_> = arena.fallbacks().iter().map(|f| f.to_string()).collect();
+    if arena.exhausted() > 0 {
+        notes.push(format!("{} nodes malloced", arena.exhausted()));
+    }
+    if notes.is_empty() {
+        println!("{} {:>3.0} ns/send", name, per_send);
+    } else {
+        println!("{} {:>3.0} ns/send  warning: {}", name, per_send, notes.join(", "));
+    }
+}
+
+#[cfg(feature="queue_experiments")]
+fn latest_row(name: &str, (per_send, seen): (f64, u64)) {
+    println!("{} {:>3.0} ns/send {:>9} seen", name, per_send, seen);
+}
+
+// Streams bytes through a byte_ring in chunks of the given size, copying them
+// in with write and out with read, or filling and reading them in place
+// through the slices.
+#[cfg(feature="queue_experiments")]
+const BYTE_RING_BYTES: usize = 1 << 30;
+
+#[cfg(feature="queue_experiments")]
+const BYTE_RING_CAPACITY: usize = 1 << 20;
+
+#[cfg(feature="queue_experiments")]
+const BYTE_CHUNKS: [usize; 3] = [64, 4 << 10, 64 << 10];
+
+#[cfg(feature="queue_experiments")]
+fn bench_byte_ring(chunk: usize) -> f64 {
+    let ring = unsafe { byte_ring::ByteRing::with_capacity(BYTE_RING_CAPACITY) };
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        let ring = &ring;
+        scope.spawn(move || {
+            let data = vec![1u8; chunk];
+            let mut sent = 0;
+            while sent < BYTE_RING_BYTES {
+                sent += black_box(ring.write(&data[..chunk.min(BYTE_RING_BYTES - sent)]));
+            }
+        });
+
+        let mut out = vec![0u8; chunk];
+        let mut received = 0;
+        while received < BYTE_RING_BYTES {
+            received += ring.read(black_box(&mut out));
+        }
+    });
+    let d = start.elapsed();
+
+    mb_per_sec(BYTE_RING_BYTES, d)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_byte_ring_slices(chunk: usize) -> f64 {
+    let ring = unsafe { byte_ring::ByteRing::with_capacity(BYTE_RING_CAPACITY) };
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        let ring = &ring;
+        // one writer and one reader, each done with its slices before it
+        // commits or consumes
+        scope.spawn(move || {
+            let mut sent = 0;
+            while sent < BYTE_RING_BYTES {
+                let n = {
+                    let (first, _) = unsafe { ring.write_slices() };
+                    let n = first.len().min(chunk).min(BYTE_RING_BYTES - sent);
+                    for b in &mut first[..n] { *b = 1 }
+                    n
+                };
+                ring.commit(n);
+                sent += n;
+            }
+        });
+
+        let mut received = 0;
+        let mut sum = 0u64;
+        while received < BYTE_RING_BYTES {
+            let n = {
+                let (first, _) = unsafe { ring.read_slices() };
+                let n = first.len().min(chunk);
+                sum += first[..n].iter().map(|&b| b as u64).sum::<u64>();
+                n
+            };
+            ring.consume(n);
+            received += n;
+        }
+        black_box(sum);
+    });
+    let d = start.elapsed();
+
+    mb_per_sec(BYTE_RING_BYTES, d)
+}
+
+#[cfg(feature="queue_experiments")]
+fn mb_per_sec(bytes: usize, d: Duration) -> f64 {
+    (bytes as f64 / 1e6) / (nanos(d) / 1e9)
+}
+
+// The shared mapping ring, between two threads each with their own mapping
+// of the file, and between this process and a copy of it started with
+// --shm-consumer.
+#[cfg(all(feature="shm", target_os="linux"))]
+const SHM_CAPACITY: usize = 8192;
+
+#[cfg(all(feature="shm", target_os="linux"))]
+fn shm_path(name: &str) -> ::std::path::PathBuf {
+    let dir = ::std::path::Path::new("/dev/shm");
+    let dir = if dir.is_dir() { dir.to_path_buf() } else { ::std::env::temp_dir() };
+    dir.join(format!("std_spsc_is_slow_{}_{}", ::std::process::id(), name))
+}
+
+#[cfg(all(feature="shm", target_os="linux"))]
+fn bench_shm_threads() -> f64 {
+    let path = shm_path("threads");
+    let (tx, rx) = unsafe {
+        let tx = shm::Ring::<u64>::create(&path, SHM_CAPACITY).unwrap();
+        (tx, shm::Ring::<u64>::open(&path).unwrap())
+    };
+    let _ = ::std::fs::remove_file(&path);
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let mut x = x;
+                while let Err(back) = black_box(tx.try_push(x)) { x = back }
+            }
+        });
+
+        let mut backoff = Backoff::new();
+        for _i in 0..(COUNT*2) {
+            while let None = black_box(rx.pop()) { stalls.empty(); backoff.snooze() }
+            stalls.data();
+            backoff.reset();
+        }
+    });
+    let d = start.elapsed();
+    report_stalls(&stalls);
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+// The consumer pops a handshake first, so that the time doesn't include
+// starting it.
+#[cfg(all(feature="shm", target_os="linux"))]
+const SHM_HANDSHAKE: u64 = !0;
+
+#[cfg(all(feature="shm", target_os="linux"))]
+fn bench_shm_processes() -> f64 {
+    let path = shm_path("processes");
+    let tx = unsafe { shm::Ring::<u64>::create(&path, SHM_CAPACITY).unwrap() };
+    let mut consumer = ::std::process::Command::new(::std::env::current_exe().unwrap())
+        .arg("--shm-consumer").arg(&path).arg((COUNT*2).to_string())
+        .spawn().unwrap();
+    tx.try_push(SHM_HANDSHAKE).unwrap();
+    while tx.len() > 0 {
+        if let Some(status) = consumer.try_wait().unwrap() {
+            panic!("the shm consumer exited early with {}", status);
+        }
+        ::std::thread::yield_now();
+    }
+
+    let start = ::std::time::Instant::now();
+    for x in 0..(COUNT*2) {
+        let mut x = x;
+        while let Err(back) = black_box(tx.try_push(x)) { x = back }
+    }
+    while tx.len() > 0 {}
+    let d = start.elapsed();
+
+    assert!(consumer.wait().unwrap().success(), "the shm consumer failed");
+    let _ = ::std::fs::remove_file(&path);
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+#[cfg(all(feature="shm", target_os="linux"))]
+fn shm_consumer(path: &str, count: u64) {
+    let rx = unsafe { shm::Ring::<u64>::open(path).unwrap() };
+    loop {
+        match rx.pop() {
+            Some(SHM_HANDSHAKE) => break,
+            Some(x) => panic!("expected the handshake, got {}", x),
+            None => {}
+        }
+    }
+    for i in 0..count {
+        loop {
+            match black_box(rx.pop()) {
+                Some(x) => { assert_eq!(x, i); break }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_stream<Q>(queue: stream::Packet<Q, u64>) -> f64
+where Q: stream::Queue<stream::Message<u64>> + Send + Sync {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.send(x).unwrap());
+            }
+        });
+
+        for _i in 0..(COUNT*2) {
+            match black_box(rx.recv(None)) {
+                Ok(..) => {}
+                Err(..) => panic!(),
+            }
+        }
+    });
+    let d = start.elapsed();
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_stream2<Q>(queue: stream2::Packet<Q, u64>) -> f64
+where Q: stream2::Queue<stream2::Message<u64>> + Send + Sync {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.send(x).unwrap());
+            }
+            tx.drop_chan();
+        });
+
+        for _i in 0..(COUNT*2) {
+            match black_box(rx.recv(None)) {
+                Ok(..) => {}
+                Err(e) => panic!("{:?} @ {}", e, _i),
+            }
+        }
+        rx.drop_port();
+    });
+    let d = start.elapsed();
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+// Runs the full channel protocol, including disconnection, over the queue `Q`.
+// The receiver polls `spin` times before parking.
+#[cfg(feature="queue_experiments")]
+fn bench_packet_stream<Q>(spin: usize) -> f64
+where Q: stream2::Queue<stream2::Message<u64>> + Send + Sync {
+    bench_packet_stream_with::<Q>(spin, stream2::ParkSpin::Fixed(0))
+}
+
+// As bench_packet_stream, with the receiver spinning on its token as
+// `park_spin` says once it has stored it.
+#[cfg(feature="queue_experiments")]
+fn bench_packet_stream_with<Q>(spin: usize, park_spin: stream2::ParkSpin) -> f64
+where Q: stream2::Queue<stream2::Message<u64>> + Send + Sync {
+    let tx = Arc::new(stream2::Packet::<Q, u64>::new());
+    tx.set_spin(spin);
+    tx.set_park_spin(park_spin);
+    let rx = tx.clone();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.send(x).unwrap());
+            }
+            tx.drop_chan();
+        });
+
+        for _i in 0..(COUNT*2) {
+            match black_box(rx.recv(None)) {
+                Ok(..) => {}
+                Err(e) => panic!("{:?} @ {}", e, _i),
+            }
+        }
+        rx.drop_port();
+    });
+    let d = start.elapsed();
+
+    #[cfg(feature="stats")]
+    packet_stats(&rx, COUNT*2);
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+// Sends 1KB messages of which the receiver only reads the first byte, either
+// moving each out with recv or reading it in place with recv_with.
+#[cfg(feature="queue_experiments")]
+fn bench_packet_large(with: bool) -> f64 {
+    let count = COUNT / 100;
+    let tx = Arc::new(stream2::Packet::<spsc::CNQueue<_>, [u8; 1024]>::new());
+    let rx = tx.clone();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..count {
+                let _ = black_box(tx.send([x as u8; 1024]).unwrap());
+            }
+            tx.drop_chan();
+        });
+
+        for _i in 0..count {
+            let r = if with {
+                rx.recv_with(None, |b| b[0])
+            } else {
+                rx.recv(None).map(|b| b[0])
+            };
+            match black_box(r) {
+                Ok(..) => {}
+                Err(e) => panic!("{:?} @ {}", e, _i),
+            }
+        }
+        rx.drop_port();
+    });
+    let d = start.elapsed();
+
+    nanos(d) / (count as f64)
+}
+
+// `--verify-packet`: runs verify over each packet rather than benchmarking.
+// The senders stall before every message, so this sends far fewer than the
+// benchmarks do.
+#[cfg(feature="queue_experiments")]
+fn verify_packets() {
+    let count = COUNT as usize / 100;
+    let watchdog = Duration::from_secs(5);
+    let row = |name: &str, senders: usize, d: Duration| {
+        println!("verify {} {}x{} messages ok in {:>6.0} ms", name, senders, count, nanos(d) / 1e6);
+    };
+    row("spsc   ", 1, verify::verify(stream2::Packet::<spsc::CNQueue<_>, _>::new(), 1, count, watchdog));
+    row("spsc2  ", 1, verify::verify(stream2::Packet::<spsc2::AQueue<_>, _>::new(), 1, count, watchdog));
+    row("shared ", 2, verify::verify(shared::SharedPacket::new(), 2, count, watchdog));
+}
+
+// As bench_packet_stream, but the receiver polls rather than blocking, so it
+// never parks. This is the cost of a send when no one needs waking, which is
+// just the doorbell check on top of the push.
+#[cfg(feature="queue_experiments")]
+fn bench_packet_polling<Q>() -> f64
+where Q: stream2::Queue<stream2::Message<u64>> + Send + Sync {
+    let tx = Arc::new(stream2::Packet::<Q, u64>::new());
+    let rx = tx.clone();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.send(x).unwrap());
+            }
+            tx.drop_chan();
+        });
+
+        for _i in 0..(COUNT*2) {
+            loop {
+                match black_box(rx.try_recv()) {
+                    Ok(..) => break,
+                    Err(stream2::Empty) => continue,
+                    Err(e) => panic!("{:?} @ {}", e, _i),
+                }
+            }
+        }
+        rx.drop_port();
+    });
+    let d = start.elapsed();
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+#[cfg(feature="stats")]
+fn packet_stats<Q, T, W>(packet: &stream2::Packet<Q, T, W>, messages: u64)
+where Q: stream2::Queue<stream2::Message<T>>, W: blocking::Wakeup {
+    let stats = packet.stats();
+    println!("  {:>8.1} parks / 1M msgs, {:.2} spurious wakeups / park, {:.2} spin hits / park",
+        stats.parks as f64 * 1_000_000.0 / messages as f64,
+        stats.spurious_wakeups as f64 / stats.parks.max(1) as f64,
+        stats.spin_hits as f64 / stats.parks.max(1) as f64);
+    println!("  upgrades {} sent {} received, {} drained, {} disconnected sends",
+        stats.upgrades_sent, stats.upgrades_received, stats.drained, stats.disconnected_sends);
+}
+
+// How much a receiver throws away when it is dropped while the sender is
+// still going flat out: each round receives `received` messages and then
+// drops the port, and prints the spread of `drop_port`'s drain.
+#[cfg(feature="stats")]
+fn drain_sizes<Q>(received: usize)
+where Q: stream2::Queue<stream2::Message<u64>> + Send + Sync {
+    const ROUNDS: usize = 200;
+    let mut drained: Vec<u64> = (0..ROUNDS).map(|_| {
+        let tx = Arc::new(stream2::Packet::<Q, u64>::new());
+        let rx = tx.clone();
+        scope(|scope| {
+            scope.spawn(move || {
+                let mut x = 0;
+                while tx.send(x).is_ok() {
+                    x += 1;
+                }
+                tx.drop_chan();
+            });
+
+            for _ in 0..received {
+                rx.recv(None).ok().unwrap();
+            }
+            rx.drop_port();
+        });
+        rx.stats().drained
+    }).collect();
+    drained.sort();
+    println!("drained after {:>5} received  min {:>6} median {:>6} max {:>6} mean {:>8.1}",
+        received, drained[0], drained[ROUNDS / 2], drained[ROUNDS - 1],
+        drained.iter().sum::<u64>() as f64 / ROUNDS as f64);
+}
+
+// The two protocols over the same queue, in the same binary, so the
+// difference is only the protocol, and doesn't move with the toolchain's std.
+#[cfg(feature="queue_experiments")]
+fn protocol_row(name: &str, std_protocol: f64, stream2: f64) {
+    println!("{} stream (std protocol) {:>3.0} ns/send stream2 (this crate) {:>3.0} ns/send {:>+4.0}",
+        name, std_protocol, stream2, stream2 - std_protocol);
+}
+
+// The cost of the channel protocol is the difference between a packet and
+// the raw queue it is built on.
+#[cfg(feature="queue_experiments")]
+fn packet_row(name: &str, packet: f64, raw: f64) {
+    println!("{} {:>3.0} ns/send {:>+4.0} vs raw queue", name, packet, packet - raw);
+}
+
+// How long from `send` on an empty channel until a parked receiver returns
+// from `recv`. The sender sleeps between sends so that the receiver has
+// always parked by the time the next message arrives, and each message
+// carries the time it was sent.
+#[cfg(feature="queue_experiments")]
+const WAKEUP_COUNT: usize = 10_000;
+
+#[cfg(feature="queue_experiments")]
+const WAKEUP_PAUSE_NS: u32 = 100_000;
+
+#[cfg(feature="queue_experiments")]
+fn bench_std_wakeup_latency() -> (f64, f64) {
+    let (tx, rx) = channel();
+    let mut latencies = Vec::with_capacity(WAKEUP_COUNT);
+    scope(|scope| {
+        scope.spawn(move || {
+            for _ in 0..WAKEUP_COUNT {
+                ::std::thread::sleep(Duration::new(0, WAKEUP_PAUSE_NS));
+                tx.send(::std::time::Instant::now()).unwrap();
+            }
+        });
+
+        for _ in 0..WAKEUP_COUNT {
+            let sent = rx.recv().unwrap();
+            latencies.push(nanos(sent.elapsed()));
+        }
+    });
+
+    percentiles(latencies)
+}
+
+// A server which answers each request before the client sends the next, so
+// every message is a wakeup unless the other side is still running.
+#[cfg(feature="queue_experiments")]
+const PING_PONG_COUNT: u64 = COUNT / 100;
+
+#[cfg(feature="queue_experiments")]
+fn bench_std_ping_pong() -> f64 {
+    let (req_tx, req_rx) = channel();
+    let (resp_tx, resp_rx) = channel();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in req_rx {
+                let _ = black_box(resp_tx.send(x));
+            }
+        });
+
+        for x in 0..PING_PONG_COUNT {
+            req_tx.send(x).unwrap();
+            let _ = black_box(resp_rx.recv().unwrap());
+        }
+        drop(req_tx);
+    });
+    let d = start.elapsed();
+
+    nanos(d) / (PING_PONG_COUNT as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_bichannel_ping_pong() -> f64 {
+    let (client, server) = bichannel::bi_channel();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            while let Ok(x) = server.recv() {
+                let _ = black_box(server.send(x));
+            }
+        });
+
+        for x in 0..PING_PONG_COUNT {
+            let _ = black_box(client.call(x).unwrap());
+        }
+        drop(client);
+    });
+    let d = start.elapsed();
+
+    nanos(d) / (PING_PONG_COUNT as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_packet_wakeup_latency<Q>(spin: usize) -> (f64, f64)
+where Q: stream2::Queue<stream2::Message<::std::time::Instant>> + Send + Sync {
+    bench_wakeup_latency_with::<Q, blocking::DefaultBlocking>(spin, stream2::ParkSpin::Fixed(0))
+}
+
+// As bench_packet_wakeup_latency, with the receiver sleeping on `W`'s tokens,
+// after spinning on them as `park_spin` says.
+#[cfg(feature="queue_experiments")]
+fn bench_wakeup_latency_with<Q, W>(spin: usize, park_spin: stream2::ParkSpin) -> (f64, f64)
+where Q: stream2::Queue<stream2::Message<::std::time::Instant>> + Send + Sync,
+      W: blocking::Wakeup, W::Signal: Send {
+    let tx = Arc::new(stream2::Packet::<Q, _, W>::new());
+    tx.set_spin(spin);
+    tx.set_park_spin(park_spin);
+    let rx = tx.clone();
+    let mut latencies = Vec::with_capacity(WAKEUP_COUNT);
+    scope(|scope| {
+        scope.spawn(move || {
+            for _ in 0..WAKEUP_COUNT {
+                ::std::thread::sleep(Duration::new(0, WAKEUP_PAUSE_NS));
+                tx.send(::std::time::Instant::now()).unwrap();
+            }
+            tx.drop_chan();
+        });
+
+        for _i in 0..WAKEUP_COUNT {
+            match rx.recv(None) {
+                Ok(sent) => latencies.push(nanos(sent.elapsed())),
+                Err(e) => panic!("{:?} @ {}", e, _i),
+            }
+        }
+        rx.drop_port();
+    });
+
+    #[cfg(feature="stats")]
+    packet_stats(&rx, WAKEUP_COUNT as u64);
+
+    percentiles(latencies)
+}
+
+// The blocking primitives on their own, without a packet around them, to
+// split the wakeup rows above into the token's share and the protocol's. The
+// waiter hands its signal token over through an atomic slot, as a packet does
+// through `to_wake`, and sleeps. One-way is from just before the signal until
+// the waiter returns, with the signaller pausing first so the waiter is
+// asleep; a round trip is two threads waking each other back to back. There
+// is no condvar token to compare: `DefaultBlocking` parks, which on Linux is
+// a futex underneath.
+#[cfg(feature="queue_experiments")]
+fn bench_signal_tokens() {
+    signal_rows::<blocking::DefaultBlocking>("park   ");
+    #[cfg(feature="futex")]
+    signal_rows::<futex::FutexBlocking>("futex  ");
+    #[cfg(feature="eventfd")]
+    signal_rows::<blocking::eventfd::EventFdBlocking>("eventfd");
+}
+
+#[cfg(feature="queue_experiments")]
+fn signal_rows<W>(name: &str)
+where W: blocking::Wakeup, W::Signal: Send {
+    wakeup_row(&format!("token {} one-way   ", name), bench_signal_one_way::<W>());
+    wakeup_row(&format!("token {} round trip", name), bench_signal_round_trip::<W>());
+}
+
+#[cfg(feature="queue_experiments")]
+fn put_signal<W: blocking::Wakeup>(slot: &::std::sync::atomic::AtomicUsize, token: W::Signal) {
+    slot.store(unsafe { W::cast_to_usize(token) }, ::std::sync::atomic::Ordering::SeqCst);
+}
+
+// Waits for the other side to put its token in `slot`, yielding so that on a
+// single core it gets to.
+#[cfg(feature="queue_experiments")]
+fn take_signal<W: blocking::Wakeup>(slot: &::std::sync::atomic::AtomicUsize) -> W::Signal {
+    loop {
+        match slot.swap(0, ::std::sync::atomic::Ordering::SeqCst) {
+            0 => ::std::thread::yield_now(),
+            ptr => return unsafe { W::cast_from_usize(ptr) },
+        }
+    }
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_signal_one_way<W>() -> (f64, f64)
+where W: blocking::Wakeup, W::Signal: Send {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    let slot = AtomicUsize::new(0);
+    // when the last signal was sent, in ns since `base`
+    let sent = AtomicU64::new(0);
+    let base = ::std::time::Instant::now();
+    let mut latencies = Vec::with_capacity(WAKEUP_COUNT);
+    scope(|scope| {
+        let (slot, sent) = (&slot, &sent);
+        scope.spawn(move || {
+            for _ in 0..WAKEUP_COUNT {
+                let token = take_signal::<W>(slot);
+                ::std::thread::sleep(Duration::new(0, WAKEUP_PAUSE_NS));
+                sent.store(nanos(base.elapsed()) as u64, Ordering::SeqCst);
+                W::signal(&token);
+            }
+        });
+
+        for _ in 0..WAKEUP_COUNT {
+            let (wait, signal) = W::tokens();
+            put_signal::<W>(slot, signal);
+            W::wait(wait);
+            latencies.push(nanos(base.elapsed()) - sent.load(Ordering::SeqCst) as f64);
+        }
+    });
+
+    percentiles(latencies)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_signal_round_trip<W>() -> (f64, f64)
+where W: blocking::Wakeup, W::Signal: Send {
+    use std::sync::atomic::AtomicUsize;
+    let (ping, pong) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let mut latencies = Vec::with_capacity(WAKEUP_COUNT);
+    scope(|scope| {
+        let (ping, pong) = (&ping, &pong);
+        scope.spawn(move || {
+            for _ in 0..WAKEUP_COUNT {
+                let (wait, signal) = W::tokens();
+                put_signal::<W>(pong, signal);
+                W::wait(wait);
+                W::signal(&take_signal::<W>(ping));
+            }
+        });
+
+        for _ in 0..WAKEUP_COUNT {
+            let (wait, signal) = W::tokens();
+            put_signal::<W>(ping, signal);
+            let other = take_signal::<W>(pong);
+            let start = ::std::time::Instant::now();
+            W::signal(&other);
+            W::wait(wait);
+            latencies.push(nanos(start.elapsed()));
+        }
+    });
+
+    percentiles(latencies)
+}
+
+// Bursty arrivals, between the streaming benchmarks, where the receiver never
+// parks, and the wakeup ones, where it always does. The sender sends a burst
+// of messages back to back and then pauses, so the receiver parks once the
+// burst is drained and is woken by the next one. Reports ns per message over
+// the whole run, pauses included, and the p99 latency of the first message of
+// each burst, which is the one that has to wake the receiver.
+#[cfg(feature="queue_experiments")]
+const BURSTS: usize = 2_000;
+
+#[cfg(feature="queue_experiments")]
+const BURST_SHAPES: [(usize, u32); 3] = [(16, 50), (256, 200), (4096, 1_000)];
+
+#[cfg(feature="queue_experiments")]
+fn bench_std_bursts(burst: usize, gap_us: u32) -> (f64, f64) {
+    let (tx, rx) = channel();
+    let mut latencies = Vec::with_capacity(BURSTS);
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for _ in 0..BURSTS {
+                ::std::thread::sleep(Duration::new(0, gap_us * 1_000));
+                for _ in 0..burst {
+                    tx.send(::std::time::Instant::now()).unwrap();
+                }
+            }
+        });
+
+        for i in 0..(BURSTS * burst) {
+            let sent = rx.recv().unwrap();
+            if i % burst == 0 {
+                latencies.push(nanos(sent.elapsed()));
+            }
+        }
+    });
+    let d = start.elapsed();
+
+    (nanos(d) / ((BURSTS * burst) as f64), percentiles(latencies).1)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_packet_bursts<Q>(burst: usize, gap_us: u32, spin: usize) -> (f64, f64)
+where Q: stream2::Queue<stream2::Message<::std::time::Instant>> + Send + Sync {
+    let tx = Arc::new(stream2::Packet::<Q, _>::new());
+    tx.set_spin(spin);
+    let rx = tx.clone();
+    let mut latencies = Vec::with_capacity(BURSTS);
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for _ in 0..BURSTS {
+                ::std::thread::sleep(Duration::new(0, gap_us * 1_000));
+                for _ in 0..burst {
+                    tx.send(::std::time::Instant::now()).unwrap();
+                }
+            }
+            tx.drop_chan();
+        });
+
+        for i in 0..(BURSTS * burst) {
+            match rx.recv(None) {
+                Ok(sent) => if i % burst == 0 {
+                    latencies.push(nanos(sent.elapsed()));
+                },
+                Err(e) => panic!("{:?} @ {}", e, i),
+            }
+        }
+        rx.drop_port();
+    });
+    let d = start.elapsed();
+
+    #[cfg(feature="stats")]
+    packet_stats(&rx, (BURSTS * burst) as u64);
+
+    (nanos(d) / ((BURSTS * burst) as f64), percentiles(latencies).1)
+}
+
+// The same bursts, received with `recv_many`. Reports ns per message and how
+// many messages each call took.
+#[cfg(feature="queue_experiments")]
+fn bench_packet_bursts_many<Q>(burst: usize, gap_us: u32, max: usize) -> (f64, f64)
+where Q: stream2::Queue<stream2::Message<::std::time::Instant>> + Send + Sync {
+    let tx = Arc::new(stream2::Packet::<Q, _>::new());
+    let rx = tx.clone();
+    let mut calls = 0;
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for _ in 0..BURSTS {
+                ::std::thread::sleep(Duration::new(0, gap_us * 1_000));
+                for _ in 0..burst {
+                    tx.send(::std::time::Instant::now()).unwrap();
+                }
+            }
+            tx.drop_chan();
+        });
+
+        let mut out = Vec::with_capacity(max);
+        let mut received = 0;
+        while received < BURSTS * burst {
+            match rx.recv_many(&mut out, max, None) {
+                Ok(n) => received += n,
+                Err(e) => panic!("{:?} @ {}", e, received),
+            }
+            calls += 1;
+            let _ = black_box(out.drain(..).count());
+        }
+        rx.drop_port();
+    });
+    let d = start.elapsed();
+
+    #[cfg(feature="stats")]
+    packet_stats(&rx, (BURSTS * burst) as u64);
+
+    (nanos(d) / ((BURSTS * burst) as f64), (BURSTS * burst) as f64 / calls as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn burst_row(name: &str, (per_msg, p99): (f64, f64)) {
+    println!("{} {:>6.0} ns/msg first of burst p99 {:>7.0} ns", name, per_msg, p99);
+}
+
+// Returns the p50 and p99 of `samples`.
+#[cfg(feature="queue_experiments")]
+fn percentiles(mut samples: Vec<f64>) -> (f64, f64) {
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let at = |p: usize| samples[(samples.len() - 1) * p / 100];
+    (at(50), at(99))
+}
+
+// The rows are printed once their bench returns, so a consumer's stalls
+// come out above its row, like the stats counts.
+#[cfg(feature="queue_experiments")]
+fn report_stalls(stalls: &StallTracker) {
+    if stalls.stalls() > 0 {
+        println!("  {}", stalls);
+    }
+}
+
+#[cfg(feature="queue_experiments")]
+fn wakeup_row(name: &str, (p50, p99): (f64, f64)) {
+    println!("{} p50 {:>6.0} ns p99 {:>6.0} ns", name, p50, p99);
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_mpmc_queue<Align: Padding>(queue: mpmc::Queue<u64, Align>) -> f64 {
+    let tx = Arc::new(queue);
+    let rx = tx.clone();
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        scope.spawn(move || {
+            for x in 0..(COUNT*2) {
+                let _ = black_box(tx.push(x));
+            }
+        });
+
+        let mut backoff = Backoff::new();
+        for _i in 0..(COUNT*2) {
+            loop {
+                match black_box(rx.pop()) {
+                    mpmc::Data(..) => { stalls.data(); break }
+                    _ => { stalls.empty(); backoff.snooze() }
+                }
+            }
+            backoff.reset();
+        }
+    });
+    let d = start.elapsed();
+    report_stalls(&stalls);
+
+    nanos(d) / ((COUNT*2) as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+struct BenchMessage {
+    link: intrusive::Link,
+    value: u64,
+}
+
+#[cfg(feature="queue_experiments")]
+std_spsc_is_slow::intrusive_adapter!(BenchAdapter = BenchMessage { link });
+
+// The messages are allocated up front and sent round after round, the
+// consumer draining each round before the producer starts the next, so that
+// nothing is allocated while the clock runs. A round is long enough that
+// starting its producer doesn't show.
+#[cfg(feature="queue_experiments")]
+const INTRUSIVE_MESSAGES: u64 = 1 << 16;
+
+#[cfg(feature="queue_experiments")]
+fn bench_intrusive_queue<Align: Padding>(queue: intrusive::Queue<BenchAdapter, Align>) -> f64 {
+    let mut messages: Vec<_> = (0..INTRUSIVE_MESSAGES)
+        .map(|i| BenchMessage { link: intrusive::Link::new(), value: i })
+        .collect();
+    // an address, so the producer can take it to its thread
+    let base = messages.as_mut_ptr() as usize;
+    let rounds = (COUNT*2) / INTRUSIVE_MESSAGES;
+    let queue = &queue;
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    for _ in 0..rounds {
+        scope(|scope| {
+            scope.spawn(move || {
+                for i in 0..INTRUSIVE_MESSAGES {
+                    unsafe {
+                        let m = (base as *mut BenchMessage).add(i as usize);
+                        queue.push(::std::ptr::NonNull::new_unchecked(m));
+                    }
+                }
+            });
+
+            let mut backoff = Backoff::new();
+            for _i in 0..INTRUSIVE_MESSAGES {
+                loop {
+                    // the only consumer
+                    match black_box(unsafe { queue.try_pop() }) {
+                        mpmc::Data(m) => { stalls.data(); black_box(unsafe { m.as_ref().value }); break }
+                        _ => { stalls.empty(); backoff.snooze() }
+                    }
+                }
+                backoff.reset();
+            }
+        });
+    }
+    let d = start.elapsed();
+    report_stalls(&stalls);
+
+    nanos(d) / ((rounds * INTRUSIVE_MESSAGES) as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_mpmc_mp_queue<Align: Padding, O>(queue: mpmc::Queue<verify::Stamped, Align, O>, producers: u64, batch: u64) -> f64
+where O: mpmc::PushOrdering {
+    let total = COUNT*2;
+    let queue = &queue;
+    // each producer sends the same number, so that the checker can tell
+    // when each one is done
+    let msgs = total / producers;
+    let mut checker = verify::StreamChecker::new(producers as usize);
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        for p in 0..producers {
+            scope.spawn(move || {
+                let stamp = |x| verify::Stamped::new(p as u16, x);
+                if batch == 1 {
+                    for x in 0..msgs {
+                        let _ = black_box(queue.push(stamp(x)));
+                    }
+                } else {
+                    let mut x = 0;
+                    while x < msgs {
+                        let end = ::std::cmp::min(x + batch, msgs);
+                        let _ = black_box(queue.push_batch((x..end).map(stamp)));
+                        x = end;
+                    }
+                }
+            });
+        }
+
+        let mut backoff = Backoff::new();
+        for _i in 0..msgs * producers {
+            loop {
+                match black_box(queue.pop()) {
+                    mpmc::Data(s) => { stalls.data(); checker.check(s); break }
+                    _ => { stalls.empty(); backoff.snooze() }
+                }
+            }
+            backoff.reset();
+        }
+    });
+    let d = start.elapsed();
+    report_stalls(&stalls);
+    checker.finish(msgs);
+
+    #[cfg(feature="stats")]
+    {
+        let stats = queue.pop_stats();
+        let pops = stats.data + stats.empty + stats.inconsistent;
+        println!("  {:>8.1} inconsistent / 1M pops, retries {:?}",
+            stats.inconsistent as f64 * 1_000_000.0 / pops as f64, stats.retries);
+    }
+
+    nanos(d) / ((msgs * producers) as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_mpmc2_queue<Align: Padding>(queue: mpmc2::Queue<u64, Align>, producers: u64, consumers: u64) -> f64 {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let total = COUNT*2;
+    let queue = &queue;
+    let received = &AtomicUsize::new(0);
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        for p in 0..producers {
+            scope.spawn(move || {
+                // the first producer picks up any remainder
+                let msgs = total / producers + if p == 0 { total % producers } else { 0 };
+                for x in 0..msgs {
+                    let _ = black_box(queue.push(x));
+                }
+            });
+        }
+
+        for _ in 0..consumers {
+            scope.spawn(move || {
+                let mut backoff = Backoff::new();
+                while received.load(Ordering::Relaxed) < total as usize {
+                    match black_box(queue.pop()) {
+                        mpmc2::Data(..) => {
+                            received.fetch_add(1, Ordering::Relaxed);
+                            backoff.reset();
+                        }
+                        _ => backoff.snooze(),
+                    }
+                }
+            });
+        }
+    });
+    let d = start.elapsed();
+
+    nanos(d) / (total as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+fn bench_bounded_mpmc_queue<Align: Padding>(queue: bounded_mpmc::Queue<u64, Align>, producers: u64) -> f64 {
+    let total = COUNT*2;
+    let queue = &queue;
+    let mut stalls = StallTracker::new();
+    let start = ::std::time::Instant::now();
+    scope(|scope| {
+        for p in 0..producers {
+            scope.spawn(move || {
+                // the first producer picks up any remainder
+                let msgs = total / producers + if p == 0 { total % producers } else { 0 };
+                for x in 0..msgs {
+                    let mut x = x;
+                    while let Err(back) = black_box(queue.push(x)) { x = back }
+                }
+            });
+        }
+
+        let mut backoff = Backoff::new();
+        for _i in 0..total {
+            while let None = black_box(queue.pop()) { stalls.empty(); backoff.snooze() }
+            stalls.data();
+            backoff.reset();
+        }
+    });
+    let d = start.elapsed();
+    report_stalls(&stalls);
+
+    nanos(d) / (total as f64)
+}
+
+fn nanos(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000000000f64 + (d.subsec_nanos() as f64)
+}
+
+#[cfg(feature="queue_experiments")]
+mod bench {
+    #![allow(non_snake_case)]
+
+    use test::{Bencher, black_box};
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crossbeam::scope;
+
+    use ::{spsc, mpmc, Padding};
+
+    #[bench]
+    fn mpmc_base_send(b: &mut Bencher) {
+        bench_mpmc_queue(mpmc::Queue::new(), b)
+    }
+
+    #[bench]
+    fn mpmc_alinged_send(b: &mut Bencher) {
+        bench_mpmc_queue(mpmc::Queue::aligned(), b)
+    }
+
+    #[bench]
+    fn spsc_base_send(b: &mut Bencher) {
+        unsafe { bench_spsc_queue(spsc::Queue::new(128), b) }
+    }
+
+    #[bench]
+    fn spsc_aligned_send(b: &mut Bencher) {
+        unsafe { bench_spsc_queue(spsc::Queue::aligned(128), b) }
+    }
+
+    #[bench]
+    fn spsc_unbounded_send(b: &mut Bencher) {
+        unsafe { bench_spsc_queue(spsc::Queue::new(0), b) }
+    }
+
+    #[bench]
+    fn spsc__no_cache__send(b: &mut Bencher) {
+        unsafe { bench_spsc_queue(spsc::Queue::no_cache(), b) }
+    }
+
+    #[bench]
+    fn spsc_unbounded_aligned_send(b: &mut Bencher) {
+        unsafe { bench_spsc_queue(spsc::Queue::aligned(0), b) }
+    }
+
+    #[bench]
+    fn spsc__no_cache__aligned_send(b: &mut Bencher) {
+        unsafe { bench_spsc_queue(spsc::Queue::aligned_no_cache(), b) }
+    }
+
+    fn bench_spsc_queue<A, C>(queue: spsc::Queue<u64, A, C>, b: &mut Bencher)
+    where A: Padding, C: spsc::UseCache {
+        let tx = Arc::new(queue);
+        let rx = tx.clone();
+        let done = AtomicBool::new(false);
+        scope(|scope| {
+            let done = &done;
+            scope.spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let _ = rx.pop();
+                }
+            });
+
+            let mut x = 0;
+            b.iter(|| { black_box(tx.push(x)); x += 1 });
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    fn bench_mpmc_queue<A: Padding>(queue: mpmc::Queue<u64, A>, b: &mut Bencher) {
+        let tx = Arc::new(queue);
+        let rx = tx.clone();
+        let done = AtomicBool::new(false);
+        scope(|scope| {
+            let done = &done;
+            scope.spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let _ = rx.pop();
+                }
+            });
+
+            let mut x = 0;
+            b.iter(|| { black_box(tx.push(x)); x += 1 });
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+}
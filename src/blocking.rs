@@ -10,15 +10,150 @@
 
 //! Generic support for building blocking abstractions.
 
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::thread::{self, Thread};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+#[cfg(debug_assertions)]
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
 use std::mem;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use backoff::Backoff;
+use sched;
+#[cfg(feature = "async")]
+use std::task::Waker;
+
+/// Tokens and a waker which signal through an eventfd.
+#[cfg(feature = "eventfd")]
+#[path = "eventfd.rs"]
+pub mod eventfd;
 
 struct Inner {
-    thread: Thread,
+    wake: Wake,
     woken: AtomicBool,
+    check: Check,
+}
+
+impl Inner {
+    fn new(wake: Wake) -> Arc<Inner> {
+        Arc::new(Inner { wake, woken: AtomicBool::new(false), check: Check::new() })
+    }
+}
+
+// Debug builds check a SignalToken's trips through a usize. Each Inner has a
+// canary, overwritten when it's freed, and a generation, odd while a usize
+// owns the token, whose low bits ride in the usize's alignment bits. So a
+// decode of a value which was already decoded, or of one from an earlier
+// trip, panics, as does a second encode while a usize has the token. These
+// are reliable while something else keeps the Inner alive, as the WaitToken
+// does while its thread waits; after that the canary only catches a decode
+// of freed memory which hasn't been reused yet. Release builds keep none of
+// this, and the usize is just the pointer.
+#[cfg(debug_assertions)]
+struct Check {
+    canary: AtomicUsize,
+    generation: AtomicUsize,
+}
+
+#[cfg(not(debug_assertions))]
+struct Check;
+
+#[cfg(debug_assertions)]
+const CANARY: usize = 0x5167_7ac5;
+#[cfg(debug_assertions)]
+const DEAD: usize = 0xdead_70c5;
+
+// The bits of an encoded token which carry its generation.
+#[cfg(debug_assertions)]
+const TAG_MASK: usize = mem::align_of::<usize>() - 1;
+#[cfg(not(debug_assertions))]
+const TAG_MASK: usize = 0;
+
+// Successful decodes, so the stress tests can tell the checks ran.
+#[cfg(debug_assertions)]
+static CHECKED_DECODES: AtomicUsize = AtomicUsize::new(0);
+
+/// How many `SignalToken` decodes have passed the debug checks so far.
+#[cfg(debug_assertions)]
+pub fn checked_decodes() -> usize {
+    CHECKED_DECODES.load(Ordering::SeqCst)
+}
+
+#[cfg(debug_assertions)]
+impl Check {
+    fn new() -> Check {
+        Check { canary: AtomicUsize::new(CANARY), generation: AtomicUsize::new(0) }
+    }
+
+    fn live(&self, what: &str) {
+        match self.canary.load(Ordering::SeqCst) {
+            CANARY => {}
+            DEAD => panic!("{} a SignalToken which was already freed", what),
+            _ => panic!("{} something which isn't a SignalToken", what),
+        }
+    }
+
+    // Returns the tag for the usize.
+    fn encode(&self) -> usize {
+        self.live("encoded");
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        assert!(!generation.is_multiple_of(2),
+            "encoded a SignalToken which is already encoded, its earlier usize is still live");
+        generation & TAG_MASK
+    }
+
+    fn decode(&self, tag: usize) -> Result<(), String> {
+        match self.canary.load(Ordering::SeqCst) {
+            CANARY => {}
+            DEAD => return Err("decoded a SignalToken which was already freed".to_string()),
+            _ => return Err("decoded something which isn't a SignalToken".to_string()),
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        if generation.is_multiple_of(2) {
+            return Err("decoded a SignalToken twice, its usize was already decoded".to_string())
+        }
+        if generation & TAG_MASK != tag {
+            return Err(format!("decoded a stale SignalToken, encoded at generation {} but now at {}",
+                tag, generation & TAG_MASK))
+        }
+        match self.generation.compare_exchange(generation, generation + 1,
+            Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => {}
+            Err(_) => return Err("decoded a SignalToken twice, concurrently".to_string()),
+        }
+        CHECKED_DECODES.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for Check {
+    fn drop(&mut self) {
+        self.canary.store(DEAD, Ordering::SeqCst);
+    }
+}
+
+#[cfg(not(debug_assertions))]
+impl Check {
+    #[inline]
+    fn new() -> Check { Check }
+    #[inline]
+    fn live(&self, _what: &str) {}
+    #[inline]
+    fn encode(&self) -> usize { 0 }
+    #[inline]
+    fn decode(&self, _tag: usize) -> Result<(), String> { Ok(()) }
+}
+
+// What a signal wakes: a thread parked on the matching WaitToken, or, for a
+// token made by `task_token`, a task to be polled again.
+enum Wake {
+    Thread(Thread),
+    #[cfg(feature = "async")]
+    Task(Waker),
 }
 
 unsafe impl Send for Inner {}
@@ -38,10 +173,7 @@ pub struct WaitToken {
 //impl !Sync for WaitToken {}
 
 pub fn tokens() -> (WaitToken, SignalToken) {
-    let inner = Arc::new(Inner {
-        thread: thread::current(),
-        woken: AtomicBool::new(false),
-    });
+    let inner = Inner::new(Wake::Thread(thread::current()));
     let wait_token = WaitToken {
         inner: inner.clone(),
     };
@@ -51,46 +183,781 @@ pub fn tokens() -> (WaitToken, SignalToken) {
     (wait_token, signal_token)
 }
 
+// How many pairs `cached_tokens` keeps per thread. A woken thread can be
+// back to park before its waker has dropped the token it signalled with, so
+// one isn't enough; with two the waker has until the park after next.
+const CACHED_PAIRS: usize = 2;
+
+thread_local! {
+    // The thread's pairs from `cached_tokens`, and which to replace next if
+    // neither is free.
+    static CACHED: RefCell<([Option<Arc<Inner>>; CACHED_PAIRS], usize)> =
+        const { RefCell::new(([None, None], 0)) };
+}
+
+/// As `tokens`, but reusing the allocation of one of the thread's recent
+/// pairs from here, once nothing else holds either half of it: the waiter
+/// is done with it, and whoever signalled it has dropped theirs, or never
+/// took it out of wherever it was stored. So a thread which parks over and
+/// over allocates a couple of times, rather than once a park. A pair which
+/// is still held elsewhere is left alone.
+pub fn cached_tokens() -> (WaitToken, SignalToken) {
+    let cached = CACHED.try_with(|cached| {
+        let mut cached = cached.borrow_mut();
+        let (ref mut pairs, ref mut next) = *cached;
+        let free = pairs.iter_mut().position(|pair| match *pair {
+            Some(ref mut inner) => match Arc::get_mut(inner) {
+                Some(inner) => {
+                    inner.woken = AtomicBool::new(false);
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        });
+        let i = match free {
+            Some(i) => i,
+            None => {
+                let i = *next;
+                *next = (i + 1) % CACHED_PAIRS;
+                pairs[i] = Some(Inner::new(Wake::Thread(thread::current())));
+                i
+            }
+        };
+        let inner = pairs[i].as_ref().unwrap();
+        (WaitToken { inner: inner.clone() }, SignalToken { inner: inner.clone() })
+    });
+    // Only fails once the thread is on its way out.
+    cached.unwrap_or_else(|_| tokens())
+}
+
+/// A token which wakes `waker` when signalled. There's no `WaitToken`, the
+/// task finds out it was woken by being polled.
+#[cfg(feature = "async")]
+pub fn task_token(waker: Waker) -> SignalToken {
+    SignalToken {
+        inner: Inner::new(Wake::Task(waker)),
+    }
+}
+
 impl SignalToken {
     pub fn signal(&self) -> bool {
+        self.inner.check.live("signalled");
         let wake = !self.inner.woken.compare_and_swap(false, true, Ordering::SeqCst);
         if wake {
-            self.inner.thread.unpark();
+            match self.inner.wake {
+                Wake::Thread(ref thread) => thread.unpark(),
+                #[cfg(feature = "async")]
+                Wake::Task(ref waker) => waker.wake_by_ref(),
+            }
         }
         wake
     }
 
     /// Convert to an unsafe usize value. Useful for storing in a pipe's state
-    /// flag.
+    /// flag. Never 0, and in debug builds checked, see `Check`.
+    ///
+    /// # Safety
+    ///
+    /// The usize owns the token: it must be passed to `cast_from_usize`
+    /// exactly once, or the token leaks.
     #[inline]
     pub unsafe fn cast_to_usize(self) -> usize {
-        mem::transmute(self.inner)
+        let tag = self.inner.check.encode();
+        mem::transmute::<Arc<Inner>, usize>(self.inner) | tag
     }
 
     /// Convert from an unsafe usize value. Useful for retrieving a pipe's state
-    /// flag.
+    /// flag. In debug builds this panics, rather than corrupting memory, on a
+    /// value which was already decoded or is from an earlier encode.
+    ///
+    /// # Safety
+    ///
+    /// `signal_ptr` must come from `cast_to_usize`, and not have been decoded
+    /// already.
     #[inline]
     pub unsafe fn cast_from_usize(signal_ptr: usize) -> SignalToken {
-        SignalToken { inner: mem::transmute(signal_ptr) }
+        let token = SignalToken { inner: mem::transmute::<usize, Arc<Inner>>(signal_ptr & !TAG_MASK) };
+        if let Err(msg) = token.inner.check.decode(signal_ptr & TAG_MASK) {
+            // the count it holds isn't ours to give back
+            mem::forget(token);
+            panic!("{}", msg)
+        }
+        token
     }
 }
 
 impl WaitToken {
     pub fn wait(self) {
         while !self.inner.woken.load(Ordering::SeqCst) {
-            thread::park()
+            sched::park()
         }
     }
 
-    /// Returns true if we wake up normally, false otherwise.
+    /// Returns true if we were signalled, false if `end` passed first. A
+    /// signal which arrived by the time we look wins, even if `end` has
+    /// passed. Spurious unparks are waited out.
     pub fn wait_max_until(self, end: Instant) -> bool {
         while !self.inner.woken.load(Ordering::SeqCst) {
             let now = Instant::now();
             if now >= end {
                 return false;
             }
-            thread::park_timeout(end - now)
+            sched::park_timeout(end - now)
         }
         true
     }
-}
\ No newline at end of file
+
+    /// Spins on the flag, then yields, then parks, see `spin_wait`. Returns
+    /// true if the signal arrived before we had to park.
+    pub fn wait_spin_then_park(self, spin_iters: u32) -> bool {
+        if spin_wait::<DefaultBlocking>(&self, spin_iters) {
+            return true
+        }
+        self.wait();
+        false
+    }
+
+    /// As `wait_max_until`, waiting at most `dur` from now.
+    pub fn wait_timeout(self, dur: Duration) -> bool {
+        self.wait_max_until(Instant::now() + dur)
+    }
+
+    /// The number of `SignalToken`s for this token still alive, for checking
+    /// that a channel doesn't leak the one it was given.
+    #[cfg(test)]
+    pub fn signal_tokens(&self) -> usize {
+        Arc::strong_count(&self.inner) - 1
+    }
+}
+
+/// How a blocked receiver sleeps and is woken, so that a channel's blocking
+/// protocol can run over something other than the tokens above. The signal
+/// half is what the channel stores, as a `usize`, while the receiver sleeps.
+pub trait Wakeup {
+    /// Kept by the thread which blocks.
+    type Wait;
+    /// Handed to whoever wakes it.
+    type Signal;
+
+    /// A new pair for the current thread.
+    fn tokens() -> (Self::Wait, Self::Signal);
+    /// Wakes the waiter, returning false if it had already been woken.
+    fn signal(token: &Self::Signal) -> bool;
+    /// Blocks until signalled.
+    fn wait(token: Self::Wait);
+    /// Blocks until signalled or `end`, returning false if it timed out.
+    fn wait_max_until(token: Self::Wait, end: Instant) -> bool;
+    /// Whether the token has been signalled, without blocking.
+    fn is_signalled(token: &Self::Wait) -> bool;
+    /// Turns the signal token into a usize, for storing in a packet's state.
+    ///
+    /// # Safety
+    ///
+    /// The usize owns the token: it must be passed to `cast_from_usize`
+    /// exactly once, or the token leaks.
+    unsafe fn cast_to_usize(token: Self::Signal) -> usize;
+    /// Gets the signal token back from `cast_to_usize`'s usize.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `cast_to_usize` on the same `Wakeup`, and not
+    /// have been decoded already: each encode gets exactly one decode.
+    unsafe fn cast_from_usize(ptr: usize) -> Self::Signal;
+}
+
+/// Parks the thread, with `cached_tokens`.
+pub struct DefaultBlocking;
+
+impl Wakeup for DefaultBlocking {
+    type Wait = WaitToken;
+    type Signal = SignalToken;
+
+    fn tokens() -> (WaitToken, SignalToken) { cached_tokens() }
+    fn signal(token: &SignalToken) -> bool { token.signal() }
+    fn wait(token: WaitToken) { token.wait() }
+    fn wait_max_until(token: WaitToken, end: Instant) -> bool { token.wait_max_until(end) }
+    fn is_signalled(token: &WaitToken) -> bool { token.inner.woken.load(Ordering::SeqCst) }
+    unsafe fn cast_to_usize(token: SignalToken) -> usize { token.cast_to_usize() }
+    unsafe fn cast_from_usize(ptr: usize) -> SignalToken { SignalToken::cast_from_usize(ptr) }
+}
+
+// How many times `spin_wait` yields once it's done spinning.
+const SPIN_YIELDS: u32 = 10;
+
+/// Checks `token` with a `Backoff` of up to `spin_iters` spin loop hints in
+/// between, then a few more times yielding in between, so that on a busy
+/// machine the signaller gets to run. Returns whether it was signalled. A
+/// waiter which expects a signal soon can call this before it parks, to save
+/// the cost of parking and being unparked.
+pub fn spin_wait<W: Wakeup>(token: &W::Wait, spin_iters: u32) -> bool {
+    let mut backoff = Backoff::with_limits(spin_iters, SPIN_YIELDS);
+    while !backoff.is_completed() {
+        if W::is_signalled(token) { return true }
+        backoff.snooze();
+    }
+    W::is_signalled(token)
+}
+
+const ADAPTIVE_MIN_SPIN: u32 = 16;
+const ADAPTIVE_MAX_SPIN: u32 = 1 << 14;
+const ADAPTIVE_START_SPIN: u32 = 256;
+
+/// A spin budget for `spin_wait` which follows how waits have been going:
+/// it doubles each time the signal arrives while spinning, and halves each
+/// time the waiter has to park anyway. It never drops below a small floor,
+/// so a waiter which stops having to park can still find out. Tokens only
+/// last one wait, so this is kept by the waiter, across its tokens.
+pub struct AdaptiveSpin {
+    spins: Cell<u32>,
+}
+
+impl AdaptiveSpin {
+    pub fn new() -> Self {
+        AdaptiveSpin { spins: Cell::new(ADAPTIVE_START_SPIN) }
+    }
+
+    /// How many spins the next wait gets.
+    pub fn budget(&self) -> u32 {
+        self.spins.get()
+    }
+
+    /// Adjusts the budget after a wait, `hit` if it ended while spinning.
+    pub fn record(&self, hit: bool) {
+        let spins = self.spins.get();
+        self.spins.set(if hit {
+            cmp::min(spins * 2, ADAPTIVE_MAX_SPIN)
+        } else {
+            cmp::max(spins / 2, ADAPTIVE_MIN_SPIN)
+        });
+    }
+
+    /// `wait_spin_then_park` with this budget, adjusting it afterwards.
+    pub fn wait(&self, token: WaitToken) -> bool {
+        let hit = token.wait_spin_then_park(self.budget());
+        self.record(hit);
+        hit
+    }
+}
+
+impl Default for AdaptiveSpin {
+    fn default() -> Self {
+        AdaptiveSpin::new()
+    }
+}
+
+/// Any number of threads waiting for the same event, woken one at a time or
+/// all together. The tokens are one-to-one, so each waiter queues a signal
+/// token of its own under a lock, and a notify takes tokens off the queue and
+/// signals them. It's generic over `Wakeup` so that it can stand behind a
+/// packet, or select, as well as plain threads.
+///
+/// A waiter which times out takes its token back off the queue, so a later
+/// notify neither wakes it nor is spent on it. If a notify took the token
+/// first the waiter counts as woken, so no notification is lost to a
+/// timeout.
+pub struct WaitQueue<W: Wakeup = DefaultBlocking> {
+    waiters: Mutex<Waiters<W::Signal>>,
+}
+
+struct Waiters<S> {
+    next_id: usize, // tells waiters apart when one comes to take itself off
+    queue: VecDeque<(usize, S)>,
+}
+
+impl<W: Wakeup> WaitQueue<W> {
+    pub fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(Waiters { next_id: 0, queue: VecDeque::new() }) }
+    }
+
+    /// Blocks until notified.
+    pub fn wait(&self) {
+        self.wait_until(None);
+    }
+
+    /// Blocks until notified or `end` passes, returning false if it timed out.
+    pub fn wait_until(&self, end: Option<Instant>) -> bool {
+        let (wait_token, signal_token) = W::tokens();
+        let id = {
+            let mut waiters = self.waiters.lock().unwrap();
+            let id = waiters.next_id;
+            waiters.next_id = id.wrapping_add(1);
+            waiters.queue.push_back((id, signal_token));
+            id
+        };
+        let end = match end {
+            Some(end) => end,
+            None => {
+                W::wait(wait_token);
+                return true
+            }
+        };
+        if W::wait_max_until(wait_token, end) {
+            return true
+        }
+        let mut waiters = self.waiters.lock().unwrap();
+        match waiters.queue.iter().position(|&(i, _)| i == id) {
+            Some(pos) => {
+                waiters.queue.remove(pos);
+                false
+            }
+            // A notify took our token as we timed out, so it's ours.
+            None => true,
+        }
+    }
+
+    /// Wakes the longest waiting thread, returning false if there were none.
+    pub fn notify_one(&self) -> bool {
+        let waiter = self.waiters.lock().unwrap().queue.pop_front();
+        match waiter {
+            Some((_, token)) => {
+                W::signal(&token);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting thread, returning how many there were.
+    pub fn notify_all(&self) -> usize {
+        let waiters = mem::take(&mut self.waiters.lock().unwrap().queue);
+        for (_, token) in &waiters {
+            W::signal(token);
+        }
+        waiters.len()
+    }
+
+    /// How many threads are waiting.
+    pub fn len(&self) -> usize {
+        self.waiters.lock().unwrap().queue.len()
+    }
+
+    /// Whether no threads are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().unwrap().queue.is_empty()
+    }
+}
+
+impl<W: Wakeup> Default for WaitQueue<W> {
+    fn default() -> Self {
+        WaitQueue::new()
+    }
+}
+
+/// A `Wakeup` for driving a channel's protocol from a single thread in tests.
+/// Waiting never blocks: a signalled token returns straight away, and one
+/// which isn't times out straight away, or without a deadline panics, since
+/// with one thread nothing could ever signal it. Signals and waits are
+/// counted per thread, see `MockWakeup::counts`.
+#[cfg(test)]
+pub struct MockWakeup;
+
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MockCounts {
+    pub signals: usize,
+    pub waits: usize,
+    pub timeouts: usize,
+}
+
+#[cfg(test)]
+thread_local! {
+    static MOCK_COUNTS: ::std::cell::Cell<MockCounts> = ::std::cell::Cell::new(MockCounts::default());
+}
+
+#[cfg(test)]
+impl MockWakeup {
+    /// What this thread has done with mock tokens so far.
+    pub fn counts() -> MockCounts {
+        MOCK_COUNTS.with(|c| c.get())
+    }
+
+    fn count<F: FnOnce(&mut MockCounts)>(f: F) {
+        MOCK_COUNTS.with(|c| {
+            let mut counts = c.get();
+            f(&mut counts);
+            c.set(counts);
+        })
+    }
+}
+
+#[cfg(test)]
+impl Wakeup for MockWakeup {
+    type Wait = Arc<AtomicBool>;
+    type Signal = Arc<AtomicBool>;
+
+    fn tokens() -> (Arc<AtomicBool>, Arc<AtomicBool>) {
+        let woken = Arc::new(AtomicBool::new(false));
+        (woken.clone(), woken)
+    }
+
+    fn signal(token: &Arc<AtomicBool>) -> bool {
+        MockWakeup::count(|c| c.signals += 1);
+        !token.swap(true, Ordering::SeqCst)
+    }
+
+    fn wait(token: Arc<AtomicBool>) {
+        MockWakeup::count(|c| c.waits += 1);
+        assert!(token.load(Ordering::SeqCst), "waiting on a token no one will signal");
+    }
+
+    fn is_signalled(token: &Arc<AtomicBool>) -> bool {
+        token.load(Ordering::SeqCst)
+    }
+
+    fn wait_max_until(token: Arc<AtomicBool>, _end: Instant) -> bool {
+        let woken = token.load(Ordering::SeqCst);
+        MockWakeup::count(|c| {
+            c.waits += 1;
+            if !woken { c.timeouts += 1 }
+        });
+        woken
+    }
+
+    unsafe fn cast_to_usize(token: Arc<AtomicBool>) -> usize {
+        Arc::into_raw(token) as usize
+    }
+
+    unsafe fn cast_from_usize(ptr: usize) -> Arc<AtomicBool> {
+        Arc::from_raw(ptr as *const AtomicBool)
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use super::{tokens, cached_tokens, AdaptiveSpin, DefaultBlocking, SignalToken, WaitQueue, WaitToken, Wakeup};
+    use super::{ADAPTIVE_MAX_SPIN, ADAPTIVE_MIN_SPIN};
+    #[cfg(debug_assertions)]
+    use super::checked_decodes;
+    use std::{mem, ptr};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn signalled_before_deadline() {
+        let (tx, rx) = channel();
+        let waiter = thread::spawn(move|| {
+            let (wait, signal) = tokens();
+            tx.send(signal).unwrap();
+            wait.wait_timeout(Duration::from_secs(10))
+        });
+        let signal = rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert!(signal.signal());
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn deadline_passes() {
+        let (wait, _signal) = tokens();
+        let start = Instant::now();
+        assert!(!wait.wait_timeout(Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn signal_at_deadline() {
+        // already signalled when the deadline has already passed, the
+        // signal wins
+        let (wait, signal) = tokens();
+        assert!(signal.signal());
+        assert!(wait.wait_max_until(Instant::now()));
+
+        // and with no signal an expired deadline returns straight away
+        let (wait, _signal) = tokens();
+        assert!(!wait.wait_max_until(Instant::now()));
+    }
+
+    #[test]
+    fn spurious_unpark_then_signal() {
+        let done = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = channel();
+        let waiter = {
+            let done = done.clone();
+            thread::spawn(move|| {
+                let (wait, signal) = tokens();
+                tx.send(signal).unwrap();
+                let woken = wait.wait_timeout(Duration::from_secs(10));
+                done.store(true, Ordering::SeqCst);
+                woken
+            })
+        };
+        let signal = rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        // an unpark which isn't a signal doesn't end the wait
+        waiter.thread().unpark();
+        thread::sleep(Duration::from_millis(20));
+        assert!(!done.load(Ordering::SeqCst));
+        assert!(signal.signal());
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn spin_then_park() {
+        // signalled while spinning
+        let (wait, signal) = tokens();
+        assert!(signal.signal());
+        assert!(wait.wait_spin_then_park(100));
+
+        // signalled once parked
+        let (tx, rx) = channel();
+        let waiter = thread::spawn(move|| {
+            let (wait, signal) = tokens();
+            tx.send(signal).unwrap();
+            wait.wait_spin_then_park(100)
+        });
+        let signal = rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert!(signal.signal());
+        assert!(!waiter.join().unwrap());
+    }
+
+    #[test]
+    fn usize_round_trip() {
+        let (wait, signal) = tokens();
+        for _ in 0..10 {
+            let ptr = unsafe { signal.clone().cast_to_usize() };
+            assert!(ptr != 0);
+            drop(unsafe { SignalToken::cast_from_usize(ptr) });
+        }
+        let ptr = unsafe { signal.cast_to_usize() };
+        assert!(unsafe { SignalToken::cast_from_usize(ptr) }.signal());
+        assert_eq!(wait.signal_tokens(), 0);
+        wait.wait();
+    }
+
+    #[test]
+    fn cached_tokens_reused() {
+        let (wait, signal) = cached_tokens();
+        let first = Arc::as_ptr(&wait.inner);
+        assert!(signal.signal());
+        wait.wait();
+        drop(signal);
+
+        // nothing else holds it, so it comes back, unsignalled
+        let (wait, signal) = cached_tokens();
+        assert_eq!(Arc::as_ptr(&wait.inner), first);
+        assert!(!DefaultBlocking::is_signalled(&wait));
+
+        // while a usize holds it, the other pair is used
+        let ptr = unsafe { signal.cast_to_usize() };
+        drop(wait);
+        let (wait, signal) = cached_tokens();
+        let second = Arc::as_ptr(&wait.inner);
+        assert!(second != first);
+
+        // and while both are held, a new one is made
+        let (wait3, signal3) = cached_tokens();
+        let third = Arc::as_ptr(&wait3.inner);
+        assert!(third != first && third != second);
+        drop((wait3, signal3, wait, signal));
+        let old = unsafe { SignalToken::cast_from_usize(ptr) };
+        assert!(old.signal());
+        drop(old);
+
+        // which took the place of one of the cached ones, and the round
+        // trip works as well for a reused pair
+        let (wait, signal) = cached_tokens();
+        let reused = Arc::as_ptr(&wait.inner);
+        assert!(reused == second || reused == third);
+        let ptr = unsafe { signal.cast_to_usize() };
+        assert!(unsafe { SignalToken::cast_from_usize(ptr) }.signal());
+        wait.wait();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "decoded a SignalToken twice")]
+    fn decode_twice() {
+        let (_wait, signal) = tokens();
+        let ptr = unsafe { signal.cast_to_usize() };
+        let first = unsafe { SignalToken::cast_from_usize(ptr) };
+        first.signal();
+        unsafe { SignalToken::cast_from_usize(ptr) }.signal();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "decoded a stale SignalToken")]
+    fn decode_stale() {
+        let (_wait, signal) = tokens();
+        let stale = unsafe { signal.clone().cast_to_usize() };
+        drop(unsafe { SignalToken::cast_from_usize(stale) });
+        let _live = unsafe { signal.cast_to_usize() };
+        unsafe { SignalToken::cast_from_usize(stale) }.signal();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "already encoded")]
+    fn encode_twice() {
+        let (_wait, signal) = tokens();
+        let _first = unsafe { signal.clone().cast_to_usize() };
+        let _second = unsafe { signal.cast_to_usize() };
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn checked_decodes_counted() {
+        let before = checked_decodes();
+        let (_wait, signal) = tokens();
+        drop(unsafe { SignalToken::cast_from_usize(signal.cast_to_usize()) });
+        assert!(checked_decodes() > before);
+    }
+
+    #[test]
+    fn notify_all_wakes_all() {
+        let queue = Arc::new(WaitQueue::<DefaultBlocking>::new());
+        let waiters: Vec<_> = (0..8).map(|_| {
+            let queue = queue.clone();
+            thread::spawn(move|| queue.wait())
+        }).collect();
+        while queue.len() < 8 { thread::yield_now() }
+        assert_eq!(queue.notify_all(), 8);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert!(queue.is_empty());
+        assert_eq!(queue.notify_all(), 0);
+    }
+
+    #[test]
+    fn notify_one_wakes_one() {
+        let queue = Arc::new(WaitQueue::<DefaultBlocking>::new());
+        let woken = Arc::new(AtomicUsize::new(0));
+        let waiters: Vec<_> = (0..3).map(|_| {
+            let (queue, woken) = (queue.clone(), woken.clone());
+            thread::spawn(move|| {
+                queue.wait();
+                woken.fetch_add(1, Ordering::SeqCst);
+            })
+        }).collect();
+        while queue.len() < 3 { thread::yield_now() }
+        assert!(queue.notify_one());
+        while woken.load(Ordering::SeqCst) < 1 { thread::yield_now() }
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(woken.load(Ordering::SeqCst), 1);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.notify_all(), 2);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    }
+
+    // Park tokens whose signal halves count how many are alive and how many
+    // times they've been signalled, for the test below.
+    struct Counted;
+
+    static COUNTED_LIVE: AtomicUsize = AtomicUsize::new(0);
+    static COUNTED_SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+    struct CountedSignal(SignalToken);
+
+    impl Drop for CountedSignal {
+        fn drop(&mut self) {
+            COUNTED_LIVE.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Wakeup for Counted {
+        type Wait = WaitToken;
+        type Signal = CountedSignal;
+
+        fn tokens() -> (WaitToken, CountedSignal) {
+            let (wait, signal) = tokens();
+            COUNTED_LIVE.fetch_add(1, Ordering::SeqCst);
+            (wait, CountedSignal(signal))
+        }
+        fn signal(token: &CountedSignal) -> bool {
+            COUNTED_SIGNALS.fetch_add(1, Ordering::SeqCst);
+            token.0.signal()
+        }
+        fn wait(token: WaitToken) { token.wait() }
+        fn wait_max_until(token: WaitToken, end: Instant) -> bool { token.wait_max_until(end) }
+        fn is_signalled(token: &WaitToken) -> bool { DefaultBlocking::is_signalled(token) }
+        unsafe fn cast_to_usize(token: CountedSignal) -> usize {
+            // the usize holds the token now, so it's still live
+            let signal = ptr::read(&token.0);
+            mem::forget(token);
+            signal.cast_to_usize()
+        }
+        unsafe fn cast_from_usize(ptr: usize) -> CountedSignal {
+            CountedSignal(SignalToken::cast_from_usize(ptr))
+        }
+    }
+
+    #[test]
+    fn timed_out_waiter_removed() {
+        let queue = Arc::new(WaitQueue::<Counted>::new());
+        assert!(!queue.wait_until(Some(Instant::now() + Duration::from_millis(10))));
+        // the waiter's token went with it, rather than staying for a notify
+        assert!(queue.is_empty());
+        assert_eq!(COUNTED_LIVE.load(Ordering::SeqCst), 0);
+        assert!(!queue.notify_one());
+        assert_eq!(COUNTED_SIGNALS.load(Ordering::SeqCst), 0);
+
+        // Waiters with tiny deadlines against a notifier, so that timeouts
+        // race notifies. Every notify which found a waiter was received by
+        // exactly one, and every token was freed.
+        let done = Arc::new(AtomicUsize::new(0));
+        let waiters: Vec<_> = (0..4).map(|_| {
+            let (queue, done) = (queue.clone(), done.clone());
+            thread::spawn(move|| {
+                let mut woken = 0;
+                for i in 0..200 {
+                    let end = Instant::now() + Duration::new(0, (i % 7) * 10_000);
+                    if queue.wait_until(Some(end)) { woken += 1 }
+                }
+                done.fetch_add(1, Ordering::SeqCst);
+                woken
+            })
+        }).collect();
+        let mut notified = 0;
+        while done.load(Ordering::SeqCst) < 4 {
+            if queue.notify_one() { notified += 1 }
+            thread::yield_now();
+        }
+        let woken: usize = waiters.into_iter().map(|w| w.join().unwrap()).sum();
+        assert_eq!(woken, notified);
+        assert_eq!(COUNTED_SIGNALS.load(Ordering::SeqCst), notified);
+        assert_eq!(COUNTED_LIVE.load(Ordering::SeqCst), 0);
+    }
+
+    // Every signal arrives before the wait, so every wait is a hit and the
+    // budget climbs to the cap.
+    #[test]
+    fn adaptive_all_fast() {
+        let spin = AdaptiveSpin::new();
+        for _ in 0..20 {
+            let (wait, signal) = tokens();
+            assert!(signal.signal());
+            assert!(spin.wait(wait));
+        }
+        assert_eq!(spin.budget(), ADAPTIVE_MAX_SPIN);
+    }
+
+    // Every signal arrives long after the spin is over, so every wait parks
+    // and the budget falls to the floor.
+    #[test]
+    fn adaptive_all_slow() {
+        let spin = AdaptiveSpin::new();
+        let (tx, rx) = channel::<SignalToken>();
+        let signaller = thread::spawn(move|| {
+            for signal in rx.iter() {
+                thread::sleep(Duration::from_millis(2));
+                assert!(signal.signal());
+            }
+        });
+        for _ in 0..20 {
+            let (wait, signal) = tokens();
+            tx.send(signal).unwrap();
+            assert!(!spin.wait(wait));
+        }
+        drop(tx);
+        signaller.join().unwrap();
+        assert_eq!(spin.budget(), ADAPTIVE_MIN_SPIN);
+    }
+}
@@ -0,0 +1,276 @@
+//! A bounded multi-producer multi-consumer ring, after Dmitry Vyukov's.
+//!
+//! Every slot carries a sequence number which says whose turn it is: a slot
+//! whose sequence equals a producer's position is free for that producer, and
+//! one whose sequence is one past a consumer's position holds that consumer's
+//! value. Producers and consumers each claim a position with a CAS on their
+//! own counter, so the only shared writes are the two counters and the slots
+//! themselves. There is no allocation or pointer chasing after construction,
+//! which makes this a useful baseline against the linked-list queues.
+
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cache_padded::Padding;
+use layout::{Field, Layout, Side};
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+pub use cache_padded::{NoAlign, CacheAligned};
+
+pub struct Queue<T, Align: Padding> {
+    buffer: Box<[Slot<T>]>,
+    mask: usize,
+
+    enqueue_pos: Align::Padded<AtomicUsize>, // next position to push to
+    dequeue_pos: Align::Padded<AtomicUsize>, // next position to pop from
+}
+
+unsafe impl<T: Send, Align: Padding> Send for Queue<T, Align> { }
+unsafe impl<T: Send, Align: Padding> Sync for Queue<T, Align> { }
+
+fn buffer<T>(capacity: usize) -> Box<[Slot<T>]> {
+    // With a single slot "full" and "free for the next lap" look the same.
+    assert!(capacity >= 2 && capacity.is_power_of_two(),
+        "capacity must be a power of two of at least 2");
+    (0..capacity).map(|i| Slot {
+        sequence: AtomicUsize::new(i),
+        value: UnsafeCell::new(None),
+    }).collect::<Vec<_>>().into_boxed_slice()
+}
+
+impl<T> Queue<T, NoAlign> {
+    /// Creates a new queue which can hold up to `capacity` values.
+    /// `capacity` must be a power of two, and at least 2.
+    pub fn new(capacity: usize) -> Self {
+        Queue {
+            buffer: buffer(capacity),
+            mask: capacity - 1,
+            enqueue_pos: NoAlign::pad(AtomicUsize::new(0)),
+            dequeue_pos: NoAlign::pad(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<T> Queue<T, CacheAligned> {
+    pub fn aligned(capacity: usize) -> Self {
+        Queue {
+            buffer: buffer(capacity),
+            mask: capacity - 1,
+            enqueue_pos: CacheAligned::pad(AtomicUsize::new(0)),
+            dequeue_pos: CacheAligned::pad(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<T, Align: Padding> Queue<T, Align> {
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Pushes a value onto the queue, handing it back if the queue is full.
+    pub fn push(&self, t: T) -> Result<(), T> {
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.buffer[pos & self.mask];
+            let seq = slot.sequence.load(Ordering::Acquire);
+            let dif = (seq as isize).wrapping_sub(pos as isize);
+            if dif == 0 {
+                // The slot is free for this position, try to claim it.
+                match self.enqueue_pos.compare_exchange_weak(
+                    pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => unsafe {
+                        *slot.value.get() = Some(t);
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(())
+                    },
+                    Err(cur) => pos = cur,
+                }
+            } else if dif < 0 {
+                // The slot still holds the value from a lap ago, we're full.
+                return Err(t)
+            } else {
+                // Another producer got here first.
+                pos = self.enqueue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pops a value from the queue, returning `None` if it is empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.dequeue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.buffer[pos & self.mask];
+            let seq = slot.sequence.load(Ordering::Acquire);
+            let dif = (seq as isize).wrapping_sub(pos.wrapping_add(1) as isize);
+            if dif == 0 {
+                match self.dequeue_pos.compare_exchange_weak(
+                    pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => unsafe {
+                        let ret = (*slot.value.get()).take();
+                        assert!(ret.is_some());
+                        // Hand the slot to the producer one lap ahead.
+                        slot.sequence.store(pos.wrapping_add(self.mask + 1), Ordering::Release);
+                        return ret
+                    },
+                    Err(cur) => pos = cur,
+                }
+            } else if dif < 0 {
+                // No producer has filled this slot yet, we're empty.
+                return None
+            } else {
+                pos = self.dequeue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T, Align: Padding> Layout for Queue<T, Align> {
+    fn fields(&self) -> Vec<Field> {
+        vec![
+            Field::new("buffer", Side::Cold, self, ptr::addr_of!(self.buffer)),
+            Field::new("mask", Side::Cold, self, ptr::addr_of!(self.mask)),
+            Field::new("enqueue_pos", Side::Producer, self, ptr::addr_of!(self.enqueue_pos)),
+            Field::new("dequeue_pos", Side::Consumer, self, ptr::addr_of!(self.dequeue_pos)),
+        ]
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
+mod tests {
+    use super::Queue;
+
+    #[test]
+    fn drop_full() {
+        let q: Queue<Box<_>, _> = Queue::new(2);
+        q.push(Box::new(1)).unwrap();
+        q.push(Box::new(2)).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn capacity_power_of_two() {
+        let _: Queue<u8, _> = Queue::new(3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn capacity_at_least_two() {
+        let _: Queue<u8, _> = Queue::new(1);
+    }
+
+    #[test]
+    fn full_and_empty() {
+        let q = Queue::aligned(4);
+        assert_eq!(q.capacity(), 4);
+        assert_eq!(q.pop(), None);
+        for lap in 0..3 {
+            for i in 0..4 {
+                assert_eq!(q.push(lap * 4 + i), Ok(()));
+            }
+            assert_eq!(q.push(100), Err(100));
+            for i in 0..4 {
+                assert_eq!(q.pop(), Some(lap * 4 + i));
+            }
+            assert_eq!(q.pop(), None);
+        }
+
+        let q = Queue::new(2);
+        assert_eq!(q.push(1), Ok(()));
+        assert_eq!(q.push(2), Ok(()));
+        assert_eq!(q.push(3), Err(3));
+        assert_eq!(q.pop(), Some(1));
+        assert_eq!(q.push(3), Ok(()));
+        assert_eq!(q.pop(), Some(2));
+        assert_eq!(q.pop(), Some(3));
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn positions_cross_isize_max() {
+        // A 32 bit target gets here after 2^31 pushes, start the queue just
+        // short of it instead.
+        use std::sync::atomic::Ordering;
+        let q = Queue::new(4);
+        let start = isize::max_value() as usize - 1;
+        q.enqueue_pos.store(start, Ordering::Relaxed);
+        q.dequeue_pos.store(start, Ordering::Relaxed);
+        for i in 0..4 {
+            let pos = start.wrapping_add(i);
+            q.buffer[pos & q.mask].sequence.store(pos, Ordering::Relaxed);
+        }
+        for i in 0..10 {
+            assert_eq!(q.push(i), Ok(()));
+            assert_eq!(q.pop(), Some(i));
+        }
+        for i in 0..4 {
+            assert_eq!(q.push(i), Ok(()));
+        }
+        assert_eq!(q.push(4), Err(4));
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments", not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use super::Queue;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use verify::{ChecksumAccumulator, Stamped};
+
+    #[test]
+    fn exactly_once() {
+        let nproducers = 4;
+        let nconsumers = 4;
+        let nmsgs = 10000;
+        // small enough that producers regularly see it full
+        let q = Arc::new(Queue::aligned(8));
+        let received = Arc::new(AtomicUsize::new(0));
+
+        let producers: Vec<_> = (0..nproducers).map(|p| {
+            let q = q.clone();
+            thread::spawn(move|| {
+                for i in 0..nmsgs {
+                    let mut v = Stamped::new(p, i);
+                    while let Err(back) = q.push(v) {
+                        v = back;
+                        thread::yield_now();
+                    }
+                }
+            })
+        }).collect();
+
+        let consumers: Vec<_> = (0..nconsumers).map(|_| {
+            let q = q.clone();
+            let received = received.clone();
+            thread::spawn(move|| {
+                let mut sums = ChecksumAccumulator::new(nproducers as usize);
+                while received.load(Ordering::SeqCst) < (nproducers as u64 * nmsgs) as usize {
+                    match q.pop() {
+                        Some(s) => {
+                            sums.add(s);
+                            received.fetch_add(1, Ordering::SeqCst);
+                        }
+                        None => thread::yield_now(),
+                    }
+                }
+                sums
+            })
+        }).collect();
+
+        for p in producers {
+            p.join().unwrap();
+        }
+        let mut sums = ChecksumAccumulator::new(nproducers as usize);
+        for c in consumers {
+            sums.merge(&c.join().unwrap());
+        }
+        sums.finish(nmsgs);
+        assert_eq!(q.pop(), None);
+    }
+}
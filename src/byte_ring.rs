@@ -0,0 +1,391 @@
+//! An spsc ring of bytes, for streaming serialized data between threads
+//! rather than passing messages.
+//!
+//! The producer and consumer indices count every byte ever written and read,
+//! and are masked into the buffer, so the buffer's length is a power of two.
+//! As in the other queues each side has its own cache aligned line, with a
+//! copy of the other side's index which it only refreshes when that copy
+//! says there's no room, or nothing to read.
+//!
+//! `channel` splits a ring into a `ByteWriter` and a `ByteReader`, which
+//! implement `io::Write` and `io::Read` by spinning while the ring is full or
+//! empty.
+
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::io;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use cache_padded::CachePadded;
+
+pub struct ByteRing {
+    // consumer fields
+    consumer: CachePadded<Consumer>,
+
+    // producer fields
+    producer: CachePadded<Producer>,
+
+    buf: Box<[UnsafeCell<u8>]>,
+    mask: usize,
+
+    // set as the halves from `channel` are dropped
+    writer_dropped: AtomicBool,
+    reader_dropped: AtomicBool,
+}
+
+struct Consumer {
+    head: AtomicUsize,           // bytes read
+    tail_copy: UnsafeCell<usize>, // bytes written, as of the last look
+}
+
+struct Producer {
+    tail: AtomicUsize,           // bytes written
+    head_copy: UnsafeCell<usize>, // bytes read, as of the last look
+}
+
+unsafe impl Send for ByteRing { }
+unsafe impl Sync for ByteRing { }
+
+impl ByteRing {
+    /// Creates a ring holding at least `capacity` bytes, rounded up to a
+    /// power of two.
+    ///
+    /// This is unsafe as the type system doesn't enforce a single
+    /// consumer-producer relationship, see `channel` for one which does.
+    pub unsafe fn with_capacity(capacity: usize) -> Self {
+        let capacity = cmp::max(capacity, 1).next_power_of_two();
+        ByteRing {
+            consumer: CachePadded::new(Consumer {
+                head: AtomicUsize::new(0),
+                tail_copy: UnsafeCell::new(0),
+            }),
+            producer: CachePadded::new(Producer {
+                tail: AtomicUsize::new(0),
+                head_copy: UnsafeCell::new(0),
+            }),
+            buf: (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+            mask: capacity - 1,
+            writer_dropped: AtomicBool::new(false),
+            reader_dropped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    fn at(&self, index: usize) -> *mut u8 {
+        unsafe { (self.buf.as_ptr() as *mut u8).add(index & self.mask) }
+    }
+
+    // The free space, refreshing our copy of the consumer's index if it
+    // shows less than `wanted`.
+    unsafe fn free(&self, wanted: usize) -> usize {
+        let tail = self.producer.tail.load(Ordering::Relaxed);
+        let head_copy = self.producer.head_copy.get();
+        if self.capacity() - tail.wrapping_sub(*head_copy) < wanted {
+            *head_copy = self.consumer.head.load(Ordering::Acquire);
+        }
+        self.capacity() - tail.wrapping_sub(*head_copy)
+    }
+
+    // The bytes written and unread, refreshing our copy of the producer's
+    // index if it shows less than `wanted`.
+    unsafe fn filled(&self, wanted: usize) -> usize {
+        let head = self.consumer.head.load(Ordering::Relaxed);
+        let tail_copy = self.consumer.tail_copy.get();
+        if (*tail_copy).wrapping_sub(head) < wanted {
+            *tail_copy = self.producer.tail.load(Ordering::Acquire);
+        }
+        (*tail_copy).wrapping_sub(head)
+    }
+
+    // Splits the `len` bytes from `index` into the part before the end of
+    // the buffer and the part wrapped around to its start.
+    fn regions(&self, index: usize, len: usize) -> ((*mut u8, usize), (*mut u8, usize)) {
+        let first = cmp::min(len, self.capacity() - (index & self.mask));
+        ((self.at(index), first), (self.at(0), len - first))
+    }
+
+    /// Copies as much of `data` into the ring as fits, returning how much
+    /// that was. Note that to use this function safely, it must be
+    /// externally guaranteed that there is only one writer.
+    pub fn write(&self, data: &[u8]) -> usize {
+        unsafe {
+            let n = cmp::min(self.free(data.len()), data.len());
+            let tail = self.producer.tail.load(Ordering::Relaxed);
+            let ((first, first_len), (second, second_len)) = self.regions(tail, n);
+            ptr::copy_nonoverlapping(data.as_ptr(), first, first_len);
+            ptr::copy_nonoverlapping(data.as_ptr().add(first_len), second, second_len);
+            self.producer.tail.store(tail.wrapping_add(n), Ordering::Release);
+            n
+        }
+    }
+
+    /// Copies as many bytes as are available, up to `out.len()`, out of the
+    /// ring, returning how many that was. Remember that to use this type
+    /// safely you must ensure that there is only one reader at a time.
+    pub fn read(&self, out: &mut [u8]) -> usize {
+        unsafe {
+            let n = cmp::min(self.filled(out.len()), out.len());
+            let head = self.consumer.head.load(Ordering::Relaxed);
+            let ((first, first_len), (second, second_len)) = self.regions(head, n);
+            ptr::copy_nonoverlapping(first, out.as_mut_ptr(), first_len);
+            ptr::copy_nonoverlapping(second, out.as_mut_ptr().add(first_len), second_len);
+            self.consumer.head.store(head.wrapping_add(n), Ordering::Release);
+            n
+        }
+    }
+
+    /// Returns the free space as up to two contiguous regions, in order, for
+    /// the writer to fill in place before `commit`ting what it wrote.
+    /// `ByteWriter::write_slices` is the safe way to get at them.
+    ///
+    /// # Safety
+    ///
+    /// There must be only one writer, and the slices must be gone before it
+    /// calls `write_slices` again, which would hand out the same bytes, or
+    /// `commit`s, after which the reader may read them.
+    pub unsafe fn write_slices(&self) -> (&mut [u8], &mut [u8]) {
+        let free = self.free(self.capacity());
+        let tail = self.producer.tail.load(Ordering::Relaxed);
+        let ((first, first_len), (second, second_len)) = self.regions(tail, free);
+        (slice::from_raw_parts_mut(first, first_len),
+            slice::from_raw_parts_mut(second, second_len))
+    }
+
+    /// Hands the first `n` bytes of the `write_slices` to the reader.
+    pub fn commit(&self, n: usize) {
+        unsafe {
+            assert!(n <= self.free(n), "committed more than was free");
+            let tail = self.producer.tail.load(Ordering::Relaxed);
+            self.producer.tail.store(tail.wrapping_add(n), Ordering::Release);
+        }
+    }
+
+    /// Returns the bytes available as up to two contiguous regions, in order,
+    /// for the reader to use in place before `consume`ing them.
+    /// `ByteReader::read_slices` is the safe way to get at them.
+    ///
+    /// # Safety
+    ///
+    /// There must be only one reader, and the slices must be gone before it
+    /// `consume`s, after which the writer may overwrite them.
+    pub unsafe fn read_slices(&self) -> (&[u8], &[u8]) {
+        let filled = self.filled(self.capacity());
+        let head = self.consumer.head.load(Ordering::Relaxed);
+        let ((first, first_len), (second, second_len)) = self.regions(head, filled);
+        (slice::from_raw_parts(first, first_len), slice::from_raw_parts(second, second_len))
+    }
+
+    /// Hands the first `n` bytes of the `read_slices` back to the writer.
+    pub fn consume(&self, n: usize) {
+        unsafe {
+            assert!(n <= self.filled(n), "consumed more than was available");
+            let head = self.consumer.head.load(Ordering::Relaxed);
+            self.consumer.head.store(head.wrapping_add(n), Ordering::Release);
+        }
+    }
+}
+
+/// Splits a new ring of at least `capacity` bytes into its writing and
+/// reading halves.
+pub fn channel(capacity: usize) -> (ByteWriter, ByteReader) {
+    let ring = Arc::new(unsafe { ByteRing::with_capacity(capacity) });
+    (ByteWriter { ring: ring.clone() }, ByteReader { ring: ring })
+}
+
+/// The producer half of a ring. Its writes wait for room, and fail with
+/// `BrokenPipe` once the reader is gone.
+pub struct ByteWriter {
+    ring: Arc<ByteRing>,
+}
+
+/// The consumer half of a ring. Its reads wait for data, and return 0, the
+/// end of the stream, once the writer is gone and everything it wrote has
+/// been read.
+pub struct ByteReader {
+    ring: Arc<ByteRing>,
+}
+
+// The slices borrow the half mutably, so they're gone by the time it can
+// call `commit`, or ask for them again.
+impl ByteWriter {
+    pub fn write_slices(&mut self) -> (&mut [u8], &mut [u8]) {
+        unsafe { self.ring.write_slices() }
+    }
+
+    pub fn commit(&mut self, n: usize) {
+        self.ring.commit(n)
+    }
+}
+
+impl ByteReader {
+    pub fn read_slices(&mut self) -> (&[u8], &[u8]) {
+        unsafe { self.ring.read_slices() }
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        self.ring.consume(n)
+    }
+}
+
+impl io::Write for ByteWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        loop {
+            if self.ring.reader_dropped.load(Ordering::Acquire) {
+                return Err(io::ErrorKind::BrokenPipe.into())
+            }
+            let n = self.ring.write(data);
+            if n > 0 || data.is_empty() {
+                return Ok(n)
+            }
+            thread::yield_now();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for ByteReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // Look for the writer's drop before reading, so that no write
+            // can land between a last empty read and seeing it gone.
+            let writer_dropped = self.ring.writer_dropped.load(Ordering::Acquire);
+            let n = self.ring.read(out);
+            if n > 0 || out.is_empty() || writer_dropped {
+                return Ok(n)
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+impl Drop for ByteWriter {
+    fn drop(&mut self) {
+        self.ring.writer_dropped.store(true, Ordering::Release);
+    }
+}
+
+impl Drop for ByteReader {
+    fn drop(&mut self) {
+        self.ring.reader_dropped.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use super::{channel, ByteRing};
+
+    #[test]
+    fn smoke() {
+        unsafe {
+            let ring = ByteRing::with_capacity(8);
+            assert_eq!(ring.write(b"hello"), 5);
+            let mut out = [0; 8];
+            assert_eq!(ring.read(&mut out[..3]), 3);
+            assert_eq!(&out[..3], b"hel");
+            assert_eq!(ring.read(&mut out), 2);
+            assert_eq!(&out[..2], b"lo");
+            assert_eq!(ring.read(&mut out), 0);
+        }
+    }
+
+    #[test]
+    fn capacity_rounds_up() {
+        unsafe {
+            assert_eq!(ByteRing::with_capacity(0).capacity(), 1);
+            assert_eq!(ByteRing::with_capacity(5).capacity(), 8);
+            assert_eq!(ByteRing::with_capacity(64).capacity(), 64);
+        }
+    }
+
+    #[test]
+    fn write_until_full() {
+        unsafe {
+            let ring = ByteRing::with_capacity(8);
+            assert_eq!(ring.write(b"0123456789"), 8);
+            assert_eq!(ring.write(b"x"), 0);
+            let mut out = [0; 4];
+            assert_eq!(ring.read(&mut out), 4);
+            assert_eq!(ring.write(b"abcdef"), 4);
+            let mut out = [0; 8];
+            assert_eq!(ring.read(&mut out), 8);
+            assert_eq!(&out, b"4567abcd");
+        }
+    }
+
+    // After wrapping the regions come in two parts, in order.
+    #[test]
+    fn slices_wrap() {
+        unsafe {
+            let ring = ByteRing::with_capacity(8);
+            assert_eq!(ring.write(b"012345"), 6);
+            let mut out = [0; 4];
+            assert_eq!(ring.read(&mut out), 4);
+            {
+                let (first, second) = ring.write_slices();
+                assert_eq!((first.len(), second.len()), (2, 4));
+                first.copy_from_slice(b"ab");
+                second[..2].copy_from_slice(b"cd");
+            }
+            ring.commit(4);
+            {
+                let (first, second) = ring.read_slices();
+                assert_eq!(first, b"45ab");
+                assert_eq!(second, b"cd");
+            }
+            ring.consume(5);
+            assert_eq!(ring.read_slices(), (&b"d"[..], &b""[..]));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn commit_too_much() {
+        unsafe {
+            let ring = ByteRing::with_capacity(8);
+            ring.write(b"0123");
+            ring.commit(5);
+        }
+    }
+
+    #[test]
+    fn halves_slices() {
+        let (mut tx, mut rx) = channel(8);
+        {
+            let (first, second) = tx.write_slices();
+            assert_eq!((first.len(), second.len()), (8, 0));
+            first[..3].copy_from_slice(b"abc");
+        }
+        tx.commit(3);
+        assert_eq!(rx.read_slices(), (&b"abc"[..], &b""[..]));
+        rx.consume(2);
+        assert_eq!(rx.read_slices(), (&b"c"[..], &b""[..]));
+    }
+
+    #[test]
+    fn reader_sees_end() {
+        let (mut tx, mut rx) = channel(8);
+        tx.write_all(b"abc").unwrap();
+        drop(tx);
+        let mut out = vec![];
+        rx.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abc");
+    }
+
+    #[test]
+    fn writer_sees_reader_gone() {
+        let (mut tx, rx) = channel(8);
+        drop(rx);
+        assert!(tx.write(b"abc").is_err());
+    }
+}
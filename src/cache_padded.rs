@@ -0,0 +1,122 @@
+//! Padding values out to their own cache line.
+//!
+//! `CachePadded<T>` is aligned to a line, and since a type's size is always
+//! a multiple of its alignment, takes up whole lines too, so nothing else
+//! can be put on the line after it.
+//!
+//! The queues which compare padded against unpadded layouts take one of
+//! `NoAlign` or `CacheAligned` as a type parameter, and wrap their hot fields
+//! in its `Padding::Padded`.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// The line size `CachePadded` pads to.
+pub const CACHE_LINE: usize = 64;
+
+#[repr(align(64))]
+#[derive(Default)]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    pub fn new(value: T) -> Self {
+        CachePadded { value }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("CachePadded").field(&self.value).finish()
+    }
+}
+
+/// A value left wherever the compiler puts it, `NoAlign`'s padding.
+#[derive(Default)]
+pub struct Unpadded<T> {
+    value: T,
+}
+
+impl<T> Deref for Unpadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Unpadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// Whether a queue's hot fields get their own lines.
+pub trait Padding {
+    type Padded<T>: Deref<Target = T> + DerefMut;
+    fn pad<T>(value: T) -> Self::Padded<T>;
+}
+
+pub struct NoAlign;
+
+pub struct CacheAligned;
+
+impl Padding for NoAlign {
+    type Padded<T> = Unpadded<T>;
+    fn pad<T>(value: T) -> Unpadded<T> {
+        Unpadded { value }
+    }
+}
+
+impl Padding for CacheAligned {
+    type Padded<T> = CachePadded<T>;
+    fn pad<T>(value: T) -> CachePadded<T> {
+        CachePadded::new(value)
+    }
+}
+
+/// The line `offset` bytes into a line aligned struct is on.
+#[cfg(all(test, feature = "queue_experiments"))]
+pub fn line(offset: usize) -> usize {
+    offset / CACHE_LINE
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
+mod tests {
+    use std::mem;
+    use super::{CachePadded, Unpadded, CACHE_LINE};
+
+    #[test]
+    fn pads_size_and_alignment() {
+        assert_eq!(mem::align_of::<CachePadded<u8>>(), CACHE_LINE);
+        assert_eq!(mem::size_of::<CachePadded<u8>>(), CACHE_LINE);
+        assert_eq!(mem::size_of::<CachePadded<[u8; 65]>>(), 2 * CACHE_LINE);
+        assert_eq!(mem::size_of::<[CachePadded<bool>; 2]>(), 2 * CACHE_LINE);
+        assert_eq!(mem::size_of::<Unpadded<u8>>(), 1);
+    }
+
+    #[test]
+    fn derefs() {
+        let mut p = CachePadded::new(1);
+        *p += 1;
+        assert_eq!(*p, 2);
+        assert_eq!(p.into_inner(), 2);
+    }
+}
@@ -0,0 +1,198 @@
+//! A snapshot of spsc's or spsc2's chain of nodes, for when a stress test
+//! fails and the question is what the queue looked like.
+//!
+//! Both queues keep one list, in order from the producer's `first` to its
+//! `head`: the nodes it can reuse straight away, up to `tail_copy`, then the
+//! ones the consumer has finished with but the producer hasn't yet seen
+//! returned, then the consumer's `tail`, the sentinel, and after it the
+//! values still to be popped. `debug_chain` on either queue walks the list
+//! and classifies each node by where it falls; `Display` draws the result.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Where a node falls in the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    /// Before `tail_copy`, for the producer to reuse on its next pushes.
+    Cached,
+    /// Popped, from `tail_copy` up to `tail`, which the producer won't reuse
+    /// until it next refreshes `tail_copy`.
+    Free,
+    /// The consumer's `tail`, whose value was the last popped.
+    Sentinel,
+    /// After `tail`, holding a value yet to be popped.
+    Live,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeReport {
+    /// The node's position counting from `first`.
+    pub index: usize,
+    pub ptr: usize,
+    pub state: NodeState,
+    pub has_value: bool,
+    /// The queue's pointers to this node, of `first`, `tail_copy`,
+    /// `tail_prev`, `tail` and `head`, in that order.
+    pub marks: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainReport {
+    pub nodes: Vec<NodeReport>,
+    /// The index of the node the last one's `next` leads back to, if the
+    /// chain loops rather than ending in null.
+    pub cycle_to: Option<usize>,
+    /// The queue's pointers which weren't found in the chain.
+    pub missing: Vec<&'static str>,
+}
+
+/// A queue's pointers into its chain.
+pub struct Pointers<N> {
+    pub first: *mut N,
+    pub tail_copy: *mut N,
+    pub tail_prev: *mut N,
+    pub tail: *mut N,
+    pub head: *mut N,
+}
+
+impl ChainReport {
+    /// Walks the chain from `pointers.first`, with `next` giving a node's
+    /// successor and `has_value` whether it holds a value.
+    ///
+    /// This is unsafe as every node reached must be live, and nothing may
+    /// change the chain during the walk.
+    pub unsafe fn walk<N, F, V>(pointers: &Pointers<N>, next: F, has_value: V) -> ChainReport
+    where F: Fn(*mut N) -> *mut N, V: Fn(*mut N) -> bool {
+        let named = [
+            ("first", pointers.first),
+            ("tail_copy", pointers.tail_copy),
+            ("tail_prev", pointers.tail_prev),
+            ("tail", pointers.tail),
+            ("head", pointers.head),
+        ];
+        let mut nodes: Vec<NodeReport> = Vec::new();
+        let mut seen = HashMap::new();
+        let mut cycle_to = None;
+        let mut state = NodeState::Cached;
+        let mut node = pointers.first;
+        while !node.is_null() {
+            if let Some(&index) = seen.get(&(node as usize)) {
+                cycle_to = Some(index);
+                break
+            }
+            let index = nodes.len();
+            seen.insert(node as usize, index);
+            state = match state {
+                _ if node == pointers.tail => NodeState::Sentinel,
+                NodeState::Sentinel => NodeState::Live,
+                NodeState::Cached if node == pointers.tail_copy => NodeState::Free,
+                state => state,
+            };
+            nodes.push(NodeReport {
+                index,
+                ptr: node as usize,
+                state,
+                has_value: has_value(node),
+                marks: named.iter().filter(|&&(_, p)| p == node).map(|&(name, _)| name).collect(),
+            });
+            node = next(node);
+        }
+        let missing = named.iter()
+            .filter(|&&(_, p)| !seen.contains_key(&(p as usize)))
+            .map(|&(name, _)| name)
+            .collect();
+        ChainReport { nodes, cycle_to, missing }
+    }
+
+    pub fn states(&self) -> Vec<NodeState> {
+        self.nodes.iter().map(|n| n.state).collect()
+    }
+}
+
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            NodeState::Cached => "cached",
+            NodeState::Free => "free",
+            NodeState::Sentinel => "sentinel",
+            NodeState::Live => "live",
+        };
+        f.pad(name)
+    }
+}
+
+// Each node on its own line, joined by `|`s down to the null, or to the
+// node the chain loops back to.
+impl fmt::Display for ChainReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f, "       |")?;
+            }
+            writeln!(f, "  {:>3}  {:#018x}  {:<8}  {:<5}  {}", node.index, node.ptr, node.state,
+                if node.has_value { "value" } else { "-" }, node.marks.join(", "))?;
+        }
+        match self.cycle_to {
+            Some(index) => writeln!(f, "       `-> back to {}", index)?,
+            None => writeln!(f, "       `-> null")?,
+        }
+        for name in &self.missing {
+            writeln!(f, "  !! {} is not in the chain", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
+mod tests {
+    use std::ptr;
+
+    use super::{ChainReport, NodeState, Pointers};
+
+    struct Node {
+        value: bool,
+        next: *mut Node,
+    }
+
+    // Links `nodes` into a chain, the first `empty` of them without a value.
+    fn chain(nodes: &mut Vec<Node>, empty: usize) -> Vec<*mut Node> {
+        for i in 0..nodes.len() {
+            nodes[i].value = i >= empty;
+        }
+        let ptrs: Vec<*mut Node> = nodes.iter_mut().map(|n| n as *mut Node).collect();
+        for w in ptrs.windows(2) {
+            unsafe { (*w[0]).next = w[1] }
+        }
+        ptrs
+    }
+
+    fn nodes(n: usize) -> Vec<Node> {
+        (0..n).map(|_| Node { value: false, next: ptr::null_mut() }).collect()
+    }
+
+    unsafe fn walk(pointers: &Pointers<Node>) -> ChainReport {
+        ChainReport::walk(pointers, |n| (*n).next, |n| (*n).value)
+    }
+
+    #[test]
+    fn cycle_and_missing() {
+        let mut nodes = nodes(3);
+        let ptrs = chain(&mut nodes, 2);
+        let mut stray = Node { value: false, next: ptr::null_mut() };
+        let stray = &mut stray as *mut Node;
+        unsafe {
+            let last = ptrs[2];
+            (*last).next = ptrs[1];
+            let report = walk(&Pointers {
+                first: ptrs[0], tail_copy: ptrs[0], tail_prev: ptrs[0], tail: ptrs[1], head: stray,
+            });
+            assert_eq!(report.states(), vec![NodeState::Free, NodeState::Sentinel, NodeState::Live]);
+            assert_eq!(report.cycle_to, Some(1));
+            assert_eq!(report.missing, vec!["head"]);
+            let drawn = report.to_string();
+            assert!(drawn.contains("back to 1"), "{}", drawn);
+            assert!(drawn.contains("!! head is not in the chain"), "{}", drawn);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Timestamps for per-message latencies, from `Instant` or from the TSC.
+//!
+//! `Instant::now` goes through the vDSO and costs 20 to 30ns, as much as a
+//! push and a pop, so timing every message with it slows down what is being
+//! timed. With the `tsc` feature on x86_64, `TscClock` reads `rdtscp`
+//! instead and converts ticks to ns at a rate calibrated against `Instant`
+//! when it's created. That is only right if the TSC ticks at a constant rate
+//! through frequency changes and sleep states, which the CPU reports as an
+//! invariant TSC; without one `TscClock::calibrate` refuses.
+//!
+//! `tsc_or_instant` is for callers which asked for the TSC but can do with
+//! `Instant`: it checks a calibrated clock against `Instant` over a short
+//! sleep, and falls back, with a warning, if there's no TSC clock or it
+//! doesn't agree.
+
+use std::error;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A clock, giving ns since it was created.
+pub trait Clock: Sync {
+    fn now(&self) -> u64;
+}
+
+pub struct InstantClock {
+    start: Instant,
+}
+
+impl InstantClock {
+    pub fn new() -> Self {
+        InstantClock { start: Instant::now() }
+    }
+}
+
+impl Default for InstantClock {
+    fn default() -> Self {
+        InstantClock::new()
+    }
+}
+
+impl Clock for InstantClock {
+    fn now(&self) -> u64 {
+        nanos(self.start.elapsed())
+    }
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+/// Why there's no `TscClock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoTsc {
+    /// Built without the `tsc` feature, or not for x86_64.
+    NotBuilt,
+    /// The CPU doesn't report an invariant TSC.
+    NotInvariant,
+    /// A calibrated clock disagreed with `Instant` over a sleep.
+    Disagrees(String),
+}
+
+impl fmt::Display for NoTsc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NoTsc::NotBuilt => f.write_str("the TSC clock needs the tsc feature on x86_64"),
+            NoTsc::NotInvariant => f.write_str("the CPU doesn't report an invariant TSC"),
+            NoTsc::Disagrees(ref why) => write!(f, "the TSC clock disagrees with Instant: {}", why),
+        }
+    }
+}
+
+impl error::Error for NoTsc {}
+
+#[cfg(all(feature="tsc", target_arch="x86_64"))]
+pub use self::tsc::TscClock;
+
+#[cfg(all(feature="tsc", target_arch="x86_64"))]
+mod tsc {
+    use std::arch::x86_64::{__cpuid, __rdtscp};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{nanos, Clock, NoTsc};
+
+    // Long enough that the two reads at either end are a rounding error.
+    const CALIBRATION: Duration = Duration::from_millis(20);
+
+    /// `rdtscp` ticks, scaled to ns.
+    #[derive(Debug, Clone)]
+    pub struct TscClock {
+        start: u64,
+        ns_per_tick: f64,
+    }
+
+    impl TscClock {
+        /// Measures the TSC's rate against `Instant` over a 20ms sleep.
+        pub fn calibrate() -> Result<TscClock, NoTsc> {
+            if !invariant() {
+                return Err(NoTsc::NotInvariant)
+            }
+            let (instant, ticks) = (Instant::now(), rdtscp());
+            thread::sleep(CALIBRATION);
+            let ns = nanos(instant.elapsed());
+            let ticks = rdtscp() - ticks;
+            Ok(TscClock { start: rdtscp(), ns_per_tick: ns as f64 / ticks as f64 })
+        }
+
+        pub fn ns_per_tick(&self) -> f64 {
+            self.ns_per_tick
+        }
+    }
+
+    impl Clock for TscClock {
+        #[inline]
+        fn now(&self) -> u64 {
+            (rdtscp().wrapping_sub(self.start) as f64 * self.ns_per_tick) as u64
+        }
+    }
+
+    #[inline]
+    fn rdtscp() -> u64 {
+        let mut aux = 0;
+        // rdtscp is in every x86_64 CPU with an invariant TSC, which
+        // calibrate checks for before the first read
+        unsafe { __rdtscp(&mut aux) }
+    }
+
+    // CPUID 0x8000_0007, EDX bit 8
+    fn invariant() -> bool {
+        let max_extended = __cpuid(0x8000_0000).eax;
+        max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
+
+/// Checks `clock` against `Instant` over a `sleep`, the two agreeing to
+/// within 1% or 50us, whichever is more.
+pub fn check_against_instant<C: Clock + ?Sized>(clock: &C, sleep: Duration) -> Result<(), String> {
+    let instant = InstantClock::new();
+    let (start, instant_start) = (clock.now(), instant.now());
+    thread::sleep(sleep);
+    let (end, instant_end) = (clock.now(), instant.now());
+    let (measured, expected) = (end.wrapping_sub(start) as i64, (instant_end - instant_start) as i64);
+    let tolerance = ::std::cmp::max(expected / 100, 50_000);
+    if (measured - expected).abs() <= tolerance {
+        Ok(())
+    } else {
+        Err(format!("a {:?} sleep took {} ns by it and {} ns by Instant", sleep, measured, expected))
+    }
+}
+
+/// The TSC clock, if it can be had and agrees with `Instant`, and otherwise
+/// `Instant`'s, after a warning on stderr saying why.
+pub fn tsc_or_instant() -> Box<dyn Clock> {
+    match tsc() {
+        Ok(clock) => clock,
+        Err(e) => {
+            eprintln!("warning: {}, timing with Instant instead", e);
+            Box::new(InstantClock::new())
+        }
+    }
+}
+
+fn tsc() -> Result<Box<dyn Clock>, NoTsc> {
+    #[cfg(all(feature="tsc", target_arch="x86_64"))]
+    {
+        let clock = TscClock::calibrate()?;
+        check_against_instant(&clock, Duration::from_millis(10)).map_err(NoTsc::Disagrees)?;
+        Ok(Box::new(clock))
+    }
+    #[cfg(not(all(feature="tsc", target_arch="x86_64")))]
+    Err(NoTsc::NotBuilt)
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::time::Duration;
+
+    use super::{check_against_instant, Clock, InstantClock};
+
+    #[test]
+    fn instant() {
+        let clock = InstantClock::new();
+        let (a, b) = (clock.now(), clock.now());
+        assert!(a <= b);
+        check_against_instant(&clock, Duration::from_millis(5)).unwrap();
+    }
+
+    // The self-test: a sleep timed by the TSC and by Instant, which only
+    // runs where the CPU has an invariant TSC.
+    #[cfg(all(feature="tsc", target_arch="x86_64"))]
+    #[test]
+    fn tsc_agrees_with_instant() {
+        use super::{NoTsc, TscClock};
+
+        let clock = match TscClock::calibrate() {
+            Ok(clock) => clock,
+            Err(NoTsc::NotInvariant) => {
+                println!("no invariant TSC, skipped");
+                return
+            }
+            Err(e) => panic!("{}", e),
+        };
+        assert!(clock.ns_per_tick() > 0.0);
+        for &ms in &[1, 20] {
+            check_against_instant(&clock, Duration::from_millis(ms)).unwrap();
+        }
+        let (a, b) = (clock.now(), clock.now());
+        assert!(a <= b);
+    }
+}
@@ -0,0 +1,264 @@
+//! A keyed spsc queue which keeps only the latest value per key, for streams
+//! of state updates where the consumer only cares about where each key ended
+//! up, not every step on the way.
+//!
+//! It is a linked list of nodes as in spsc, each holding a key and a value.
+//! The producer keeps a map from each key to its node still in the queue, so
+//! an update to a key which is already queued overwrites that node's value
+//! rather than pushing another. Keys pop out in the order of their first
+//! update since they were last popped.
+//!
+//! Every node carries a state saying who may touch its value: the producer
+//! while it's `WRITING` over it, the consumer once it has `TAKEN` it, or
+//! neither while it is `PENDING`. A node which the consumer has taken is
+//! superseded, and the producer's next update to its key pushes a new one.
+//! The producer also frees the nodes, once the consumer has moved past them,
+//! so the pointers in its map never dangle.
+
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+
+use cache_padded::CachePadded;
+
+// A node's state
+const PENDING: usize = 0; // queued, and neither side is using the value
+const WRITING: usize = 1; // the producer is overwriting the value
+const TAKEN: usize = 2;   // the consumer has popped the node, it's superseded
+
+struct Node<K, V> {
+    key: Option<K>,             // None only for the first stub
+    value: UnsafeCell<Option<V>>,
+    state: AtomicUsize,
+    next: AtomicPtr<Node<K, V>>,
+}
+
+pub struct CoalescingQueue<K, V> {
+    // consumer fields
+    consumer: CachePadded<Consumer<K, V>>,
+
+    // producer fields
+    producer: CachePadded<Producer<K, V>>,
+}
+
+struct Consumer<K, V> {
+    tail: AtomicPtr<Node<K, V>>, // the last node popped, whose next is the next to pop
+}
+
+struct Producer<K, V> {
+    head: UnsafeCell<*mut Node<K, V>>,  // where to push to
+    first: UnsafeCell<*mut Node<K, V>>, // the oldest node not yet freed
+    queued: UnsafeCell<HashMap<K, *mut Node<K, V>>>, // the newest node of each key, until it's freed
+}
+
+unsafe impl<K: Send, V: Send> Send for CoalescingQueue<K, V> { }
+unsafe impl<K: Send, V: Send> Sync for CoalescingQueue<K, V> { }
+
+impl<K, V> Node<K, V> {
+    fn new(key: Option<K>, value: Option<V>, state: usize) -> *mut Node<K, V> {
+        Box::into_raw(box Node {
+            key,
+            value: UnsafeCell::new(value),
+            state: AtomicUsize::new(state),
+            next: AtomicPtr::new(ptr::null_mut()),
+        })
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> CoalescingQueue<K, V> {
+    /// Creates a new queue.
+    ///
+    /// This is unsafe as the type system doesn't enforce a single
+    /// consumer-producer relationship.
+    pub unsafe fn new() -> Self {
+        let stub = Node::new(None, None, TAKEN);
+        CoalescingQueue {
+            consumer: CachePadded::new(Consumer {
+                tail: AtomicPtr::new(stub),
+            }),
+            producer: CachePadded::new(Producer {
+                head: UnsafeCell::new(stub),
+                first: UnsafeCell::new(stub),
+                queued: UnsafeCell::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Sets `key`'s value, overwriting the value of a node for `key` which is
+    /// still queued, or pushing a new one if there isn't one. Returns whether
+    /// it overwrote. Note that to use this function safely, it must be
+    /// externally guaranteed that there is only one pusher.
+    pub fn update(&self, key: K, value: V) -> bool {
+        unsafe {
+            self.free_popped();
+            let queued = &mut *self.producer.queued.get();
+            if let Some(&node) = queued.get(&key) {
+                if (*node).state.compare_exchange(
+                    PENDING, WRITING, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                    *(*node).value.get() = Some(value);
+                    (*node).state.store(PENDING, Ordering::Release);
+                    return true
+                }
+            }
+            let node = Node::new(Some(key.clone()), Some(value), PENDING);
+            queued.insert(key, node);
+            (**self.producer.head.get()).next.store(node, Ordering::Release);
+            *self.producer.head.get() = node;
+            false
+        }
+    }
+
+    /// Pops the key which was first updated, with its latest value. Note that
+    /// to use this function safely, it must be externally guaranteed that
+    /// there is only one popper.
+    pub fn pop(&self) -> Option<(K, V)> {
+        unsafe {
+            let tail = self.consumer.tail.load(Ordering::Relaxed);
+            let next = (*tail).next.load(Ordering::Acquire);
+            if next.is_null() {
+                return None
+            }
+            // The producer may be part way through an overwrite, in which
+            // case we have to wait for the value it's writing.
+            while (*next).state.compare_exchange_weak(
+                PENDING, TAKEN, Ordering::Acquire, Ordering::Relaxed).is_err() {
+                thread::yield_now();
+            }
+            let value = (*(*next).value.get()).take().unwrap();
+            let key = (*next).key.clone().unwrap();
+            self.consumer.tail.store(next, Ordering::Release);
+            Some((key, value))
+        }
+    }
+
+    // Frees the nodes before the consumer's tail, forgetting any which are
+    // still the newest for their keys.
+    unsafe fn free_popped(&self) {
+        let tail = self.consumer.tail.load(Ordering::Acquire);
+        let first = self.producer.first.get();
+        let queued = &mut *self.producer.queued.get();
+        while *first != tail {
+            let node = *first;
+            *first = (*node).next.load(Ordering::Relaxed);
+            if let Some(ref key) = (*node).key {
+                if queued.get(key) == Some(&node) {
+                    queued.remove(key);
+                }
+            }
+            let _: Box<Node<K, V>> = Box::from_raw(node);
+        }
+    }
+}
+
+impl<K, V> Drop for CoalescingQueue<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let mut cur = *self.producer.first.get();
+            while !cur.is_null() {
+                let next = (*cur).next.load(Ordering::Relaxed);
+                let _: Box<Node<K, V>> = Box::from_raw(cur);
+                cur = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use super::CoalescingQueue;
+
+    #[test]
+    fn smoke() {
+        unsafe {
+            let q = CoalescingQueue::new();
+            assert_eq!(q.pop(), None);
+            assert!(!q.update("a", 1));
+            assert!(!q.update("b", 2));
+            assert!(q.update("a", 3));
+            assert!(!q.update("c", 4));
+            assert_eq!(q.pop(), Some(("a", 3)));
+            assert_eq!(q.pop(), Some(("b", 2)));
+            // popped, so this is queued again behind c
+            assert!(!q.update("a", 5));
+            assert!(q.update("a", 6));
+            assert_eq!(q.pop(), Some(("c", 4)));
+            assert_eq!(q.pop(), Some(("a", 6)));
+            assert_eq!(q.pop(), None);
+        }
+    }
+
+    #[test]
+    fn popped_keys_are_forgotten() {
+        unsafe {
+            let q = CoalescingQueue::new();
+            for i in 0..100 {
+                q.update(i, i);
+            }
+            for _ in 0..100 {
+                q.pop().unwrap();
+            }
+            q.update(0, 0);
+            // 0, and 99 whose node is the consumer's tail until the next pop
+            assert_eq!((*q.producer.queued.get()).len(), 2);
+        }
+    }
+
+    #[test]
+    fn drops() {
+        let value = Rc::new(());
+        unsafe {
+            let q = CoalescingQueue::new();
+            q.update(1, value.clone());
+            q.update(1, value.clone());
+            q.update(2, value.clone());
+            assert_eq!(Rc::strong_count(&value), 3);
+            drop(q.pop());
+            assert_eq!(Rc::strong_count(&value), 2);
+        }
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::CoalescingQueue;
+
+    #[test]
+    fn one_entry_per_key() {
+        // Every update's value is its index, so a key's values must rise
+        // from one pop to the next; seeing one twice would mean two entries
+        // for the key were queued at once.
+        let keys = 64;
+        let updates = 200_000;
+        let q = Arc::new(unsafe { CoalescingQueue::new() });
+        let producer = {
+            let q = q.clone();
+            thread::spawn(move || {
+                for i in 0..updates {
+                    q.update(i % keys, i);
+                }
+            })
+        };
+        let mut latest = vec![None; keys];
+        let mut pops = 0;
+        while latest.iter().enumerate().any(|(k, l)| *l != Some(updates - keys + k)) {
+            if let Some((k, i)) = q.pop() {
+                assert_eq!(i % keys, k);
+                if let Some(prev) = latest[k] {
+                    assert!(i > prev, "key {} popped {} after {}", k, i, prev);
+                }
+                latest[k] = Some(i);
+                pops += 1;
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(q.pop(), None);
+        assert!(pops <= updates);
+    }
+}
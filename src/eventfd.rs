@@ -0,0 +1,272 @@
+//! Tokens which signal through an eventfd, so that a receiver can sleep in
+//! an event loop's epoll (or plain `poll`) rather than in `recv`.
+//!
+//! `EventFd` is the fd itself. A notify adds 1 to its counter, which makes it
+//! readable, and a read takes the counter back to 0. It's non-blocking, so a
+//! loop can clear it without risk of hanging.
+//!
+//! `EventFdBlocking` is a `Wakeup` whose waits sleep on the thread's own
+//! eventfd, made the first time the thread parks and reused after that. As
+//! the fd outlives any one token a notification can be left over from an
+//! earlier park, so each token also has its own flag, and a wait only ends
+//! once its token was signalled.
+//!
+//! An `Arc<EventFd>` is also a `Waker`, which is how `Receiver::readiness_fd`
+//! parks: it polls the packet as an async receiver would, with a waker which
+//! notifies the fd.
+
+use std::io;
+use std::os::raw::{c_int, c_uint, c_ulong, c_void};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::Wake;
+use std::time::Instant;
+
+use super::Wakeup;
+
+const EFD_NONBLOCK: c_int = 0o4000;
+const EFD_CLOEXEC: c_int = 0o2000000;
+const POLLIN: i16 = 1;
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: i16,
+    revents: i16,
+}
+
+// std links libc anyway, so there's no need for the crate to reach these.
+extern "C" {
+    fn eventfd(initval: c_uint, flags: c_int) -> c_int;
+    fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+    fn write(fd: c_int, buf: *const c_void, count: usize) -> isize;
+    fn close(fd: c_int) -> c_int;
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+pub struct EventFd {
+    fd: RawFd,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<EventFd> {
+        let fd = unsafe { eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(EventFd { fd: fd })
+    }
+
+    /// Makes the fd readable.
+    pub fn notify(&self) {
+        let one: u64 = 1;
+        // This only fails if the counter would overflow, in which case the
+        // fd is as readable as it will ever be.
+        unsafe { write(self.fd, &one as *const u64 as *const c_void, 8) };
+    }
+
+    /// Clears the fd, returning whether it was readable.
+    pub fn reset(&self) -> bool {
+        let mut count: u64 = 0;
+        unsafe { read(self.fd, &mut count as *mut u64 as *mut c_void, 8) == 8 }
+    }
+
+    /// Blocks until the fd is readable or `end` passes, clearing it. Returns
+    /// false if it timed out.
+    pub fn wait_until(&self, end: Option<Instant>) -> bool {
+        loop {
+            if self.reset() {
+                return true
+            }
+            let timeout = match end {
+                None => -1,
+                Some(end) => {
+                    let now = Instant::now();
+                    if now >= end {
+                        return false
+                    }
+                    // Rounded up, so we don't wake just before `end` and spin.
+                    let left = end - now;
+                    let ms = left.as_secs() * 1000 + (left.subsec_nanos() as u64 + 999_999) / 1_000_000;
+                    if ms > c_int::MAX as u64 { c_int::MAX } else { ms as c_int }
+                }
+            };
+            let mut fds = PollFd { fd: self.fd, events: POLLIN, revents: 0 };
+            unsafe { poll(&mut fds, 1, timeout) };
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+impl Wake for EventFd {
+    fn wake(self: Arc<Self>) {
+        self.notify()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.notify()
+    }
+}
+
+thread_local! {
+    static THREAD_FD: Arc<EventFd> = Arc::new(EventFd::new().expect("couldn't create an eventfd"));
+}
+
+struct Inner {
+    fd: Arc<EventFd>,
+    woken: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct EventFdSignal {
+    inner: Arc<Inner>,
+}
+
+pub struct EventFdWait {
+    inner: Arc<Inner>,
+}
+
+pub fn tokens() -> (EventFdWait, EventFdSignal) {
+    let inner = Arc::new(Inner {
+        fd: THREAD_FD.with(|fd| fd.clone()),
+        woken: AtomicBool::new(false),
+    });
+    (EventFdWait { inner: inner.clone() }, EventFdSignal { inner: inner })
+}
+
+impl EventFdSignal {
+    pub fn signal(&self) -> bool {
+        let wake = !self.inner.woken.swap(true, Ordering::SeqCst);
+        if wake {
+            self.inner.fd.notify();
+        }
+        wake
+    }
+
+    pub unsafe fn cast_to_usize(self) -> usize {
+        Arc::into_raw(self.inner) as usize
+    }
+
+    pub unsafe fn cast_from_usize(ptr: usize) -> EventFdSignal {
+        EventFdSignal { inner: Arc::from_raw(ptr as *const Inner) }
+    }
+}
+
+impl EventFdWait {
+    pub fn wait(self) {
+        self.wait_until(None);
+    }
+
+    /// Returns true if we were signalled, false if `end` passed first.
+    pub fn wait_max_until(self, end: Instant) -> bool {
+        self.wait_until(Some(end))
+    }
+
+    fn wait_until(&self, end: Option<Instant>) -> bool {
+        // A notification for an earlier token wakes us without setting ours,
+        // so go back to sleep on it.
+        while !self.inner.woken.load(Ordering::SeqCst) {
+            if !self.inner.fd.wait_until(end) {
+                return self.inner.woken.load(Ordering::SeqCst)
+            }
+        }
+        true
+    }
+}
+
+/// Sleeps on the thread's eventfd.
+pub struct EventFdBlocking;
+
+impl Wakeup for EventFdBlocking {
+    type Wait = EventFdWait;
+    type Signal = EventFdSignal;
+
+    fn tokens() -> (EventFdWait, EventFdSignal) { tokens() }
+    fn signal(token: &EventFdSignal) -> bool { token.signal() }
+    fn wait(token: EventFdWait) { token.wait() }
+    fn wait_max_until(token: EventFdWait, end: Instant) -> bool { token.wait_max_until(end) }
+    fn is_signalled(token: &EventFdWait) -> bool { token.inner.woken.load(Ordering::SeqCst) }
+    unsafe fn cast_to_usize(token: EventFdSignal) -> usize { token.cast_to_usize() }
+    unsafe fn cast_from_usize(ptr: usize) -> EventFdSignal { EventFdSignal::cast_from_usize(ptr) }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use super::{tokens, EventFd, EventFdBlocking};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use spsc;
+    use stream2::Packet;
+
+    #[test]
+    fn notify_reset() {
+        let fd = EventFd::new().unwrap();
+        assert!(!fd.reset());
+        fd.notify();
+        fd.notify();
+        // one read clears any number of notifications
+        assert!(fd.reset());
+        assert!(!fd.reset());
+        assert!(!fd.wait_until(Some(Instant::now())));
+    }
+
+    #[test]
+    fn fd_reused_across_tokens() {
+        let (wait1, signal1) = tokens();
+        let (wait2, signal2) = tokens();
+        assert_eq!(Arc::as_ptr(&wait1.inner.fd), Arc::as_ptr(&wait2.inner.fd));
+        assert!(signal1.signal());
+        assert!(wait1.wait_max_until(Instant::now()));
+        // a notification left over from the first token doesn't end a
+        // wait on the second
+        signal1.inner.fd.notify();
+        assert!(!wait2.wait_max_until(Instant::now() + Duration::from_millis(10)));
+        drop(signal2);
+    }
+
+    #[test]
+    fn signal_from_another_thread() {
+        let (wait, signal) = tokens();
+        let t = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(10));
+            assert!(signal.signal());
+        });
+        assert!(wait.wait_max_until(Instant::now() + Duration::from_secs(10)));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn packet() {
+        let p = Arc::new(Packet::<spsc::CNQueue<_>, _, EventFdBlocking>::new());
+        let p2 = p.clone();
+        let sender = thread::spawn(move|| {
+            for i in 0..1_000 {
+                if i % 100 == 0 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                p2.send(i).unwrap();
+            }
+            p2.drop_chan();
+        });
+        for i in 0..1_000 {
+            assert_eq!(p.recv(None).ok(), Some(i));
+        }
+        assert!(p.recv(None).is_err());
+        sender.join().unwrap();
+        p.drop_port();
+    }
+}
@@ -0,0 +1,253 @@
+//! Picking a queue at runtime: a `QueueKind` names one of the stream2 queues
+//! with its options, parsed from a spec like `spsc2:aligned:64`, and builds
+//! it boxed behind `stream2::Queue`.
+//!
+//! The specs are `<variant>:<alignment>[:<cache>]`, where the alignment is
+//! `plain` or `aligned` and the cache depends on the variant:
+//!
+//! * `spsc:<alignment>:<bound>` or `spsc:<alignment>:nocache`, a bound of 0
+//!   being an unbounded node cache,
+//! * `spsc2:<alignment>:<bound>`,
+//! * `spsc_seg:<alignment>`, which always caches one segment.
+//!
+//! Every call through the box is dynamically dispatched, which the static
+//! queues don't pay, so this is for trying things out, not for comparing
+//! queues with each other.
+
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use spsc;
+use spsc2;
+use spsc_seg;
+use stream2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Spsc,
+    Spsc2,
+    SpscSeg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Plain,
+    Aligned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// A node cache of up to this many nodes, 0 for unbounded.
+    Bound(usize),
+    /// spsc's `no_cache`.
+    NoCache,
+    /// spsc_seg's one cached segment, its only mode.
+    Segment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueKind {
+    pub variant: Variant,
+    pub alignment: Alignment,
+    pub cache: CacheMode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    UnknownVariant(String),
+    UnknownAlignment(String),
+    MissingAlignment,
+    MissingCache(Variant),
+    BadCache(Variant, String),
+    Trailing(String),
+}
+
+impl QueueKind {
+    /// Every variant, alignment and cache mode, with `bounds` for the ones
+    /// which take a bound.
+    pub fn all(bounds: &[usize]) -> Vec<QueueKind> {
+        let mut kinds = Vec::new();
+        for &alignment in &[Alignment::Plain, Alignment::Aligned] {
+            for &bound in bounds {
+                kinds.push(QueueKind { variant: Variant::Spsc, alignment, cache: CacheMode::Bound(bound) });
+            }
+            kinds.push(QueueKind { variant: Variant::Spsc, alignment, cache: CacheMode::NoCache });
+            for &bound in bounds {
+                kinds.push(QueueKind { variant: Variant::Spsc2, alignment, cache: CacheMode::Bound(bound) });
+            }
+            kinds.push(QueueKind { variant: Variant::SpscSeg, alignment, cache: CacheMode::Segment });
+        }
+        kinds
+    }
+
+    /// Builds the queue this names.
+    pub fn build<T: Send + 'static>(&self) -> Box<dyn stream2::Queue<T> + Send + Sync> {
+        use self::Alignment::*;
+        use self::CacheMode::*;
+        use self::Variant::*;
+        match (self.variant, self.alignment, self.cache) {
+            (Spsc, Plain, Bound(b)) => boxed::<T, spsc::Queue<T, spsc::NoAlign, spsc::NormalNodeCache>>(b),
+            (Spsc, Aligned, Bound(b)) => boxed::<T, spsc::Queue<T, spsc::CacheAligned, spsc::NormalNodeCache>>(b),
+            (Spsc, Plain, NoCache) => boxed::<T, spsc::Queue<T, spsc::NoAlign, spsc::NoNodeCache>>(0),
+            (Spsc, Aligned, NoCache) => boxed::<T, spsc::Queue<T, spsc::CacheAligned, spsc::NoNodeCache>>(0),
+            (Spsc2, Plain, Bound(b)) => boxed::<T, spsc2::Queue<T, spsc2::NoAlign>>(b),
+            (Spsc2, Aligned, Bound(b)) => boxed::<T, spsc2::Queue<T, spsc2::CacheAligned>>(b),
+            (SpscSeg, Plain, Segment) => boxed::<T, spsc_seg::Queue<T, spsc_seg::NoAlign>>(0),
+            (SpscSeg, Aligned, Segment) => boxed::<T, spsc_seg::Queue<T, spsc_seg::CacheAligned>>(0),
+            _ => panic!("no such queue {:?}", self),
+        }
+    }
+}
+
+fn boxed<T, Q>(bound: usize) -> Box<dyn stream2::Queue<T> + Send + Sync>
+where Q: stream2::Queue<T> + Send + Sync + 'static {
+    box Q::new(bound)
+}
+
+impl FromStr for QueueKind {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<QueueKind, ParseError> {
+        let mut fields = s.split(':');
+        let variant = match fields.next() {
+            Some("") | None => return Err(ParseError::Empty),
+            Some("spsc") => Variant::Spsc,
+            Some("spsc2") => Variant::Spsc2,
+            Some("spsc_seg") => Variant::SpscSeg,
+            Some(other) => return Err(ParseError::UnknownVariant(other.to_string())),
+        };
+        let alignment = match fields.next() {
+            None => return Err(ParseError::MissingAlignment),
+            Some("plain") => Alignment::Plain,
+            Some("aligned") => Alignment::Aligned,
+            Some(other) => return Err(ParseError::UnknownAlignment(other.to_string())),
+        };
+        let cache = match (variant, fields.next()) {
+            (Variant::SpscSeg, None) => CacheMode::Segment,
+            (Variant::SpscSeg, Some(other)) => return Err(ParseError::Trailing(other.to_string())),
+            (_, None) => return Err(ParseError::MissingCache(variant)),
+            (Variant::Spsc, Some("nocache")) => CacheMode::NoCache,
+            (_, Some(bound)) => match bound.parse() {
+                Ok(bound) => CacheMode::Bound(bound),
+                Err(..) => return Err(ParseError::BadCache(variant, bound.to_string())),
+            },
+        };
+        if let Some(other) = fields.next() {
+            return Err(ParseError::Trailing(other.to_string()))
+        }
+        Ok(QueueKind { variant, alignment, cache })
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Variant::Spsc => "spsc",
+            Variant::Spsc2 => "spsc2",
+            Variant::SpscSeg => "spsc_seg",
+        })
+    }
+}
+
+impl fmt::Display for QueueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alignment = match self.alignment {
+            Alignment::Plain => "plain",
+            Alignment::Aligned => "aligned",
+        };
+        match self.cache {
+            CacheMode::Bound(bound) => write!(f, "{}:{}:{}", self.variant, alignment, bound),
+            CacheMode::NoCache => write!(f, "{}:{}:nocache", self.variant, alignment),
+            CacheMode::Segment => write!(f, "{}:{}", self.variant, alignment),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Empty => f.write_str("empty queue spec"),
+            ParseError::UnknownVariant(ref v) =>
+                write!(f, "unknown queue {:?}, expected spsc, spsc2 or spsc_seg", v),
+            ParseError::UnknownAlignment(ref a) =>
+                write!(f, "unknown alignment {:?}, expected plain or aligned", a),
+            ParseError::MissingAlignment => f.write_str("missing alignment, plain or aligned"),
+            ParseError::MissingCache(Variant::Spsc) =>
+                f.write_str("missing cache for spsc, a bound or nocache"),
+            ParseError::MissingCache(v) => write!(f, "missing bound for {}", v),
+            ParseError::BadCache(Variant::Spsc, ref c) =>
+                write!(f, "bad cache {:?} for spsc, expected a bound or nocache", c),
+            ParseError::BadCache(v, ref c) => write!(f, "bad bound {:?} for {}", c, v),
+            ParseError::Trailing(ref t) => write!(f, "unexpected {:?} at the end of the spec", t),
+        }
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        "invalid queue spec"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueKind, ParseError, Variant, Alignment, CacheMode};
+
+    #[test]
+    fn parse() {
+        assert_eq!("spsc2:aligned:64".parse(), Ok(QueueKind {
+            variant: Variant::Spsc2, alignment: Alignment::Aligned, cache: CacheMode::Bound(64),
+        }));
+        assert_eq!("spsc:plain:nocache".parse(), Ok(QueueKind {
+            variant: Variant::Spsc, alignment: Alignment::Plain, cache: CacheMode::NoCache,
+        }));
+        assert_eq!("spsc_seg:aligned".parse(), Ok(QueueKind {
+            variant: Variant::SpscSeg, alignment: Alignment::Aligned, cache: CacheMode::Segment,
+        }));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |s: &str| s.parse::<QueueKind>().unwrap_err();
+        assert_eq!(err(""), ParseError::Empty);
+        assert_eq!(err("mpmc:aligned"), ParseError::UnknownVariant("mpmc".to_string()));
+        assert_eq!(err("spsc2"), ParseError::MissingAlignment);
+        assert_eq!(err("spsc2:wide:64"), ParseError::UnknownAlignment("wide".to_string()));
+        assert_eq!(err("spsc:aligned"), ParseError::MissingCache(Variant::Spsc));
+        assert_eq!(err("spsc2:plain"), ParseError::MissingCache(Variant::Spsc2));
+        assert_eq!(err("spsc2:plain:nocache"),
+            ParseError::BadCache(Variant::Spsc2, "nocache".to_string()));
+        assert_eq!(err("spsc:plain:-1"), ParseError::BadCache(Variant::Spsc, "-1".to_string()));
+        assert_eq!(err("spsc:plain:"), ParseError::BadCache(Variant::Spsc, "".to_string()));
+        assert_eq!(err("spsc_seg:plain:64"), ParseError::Trailing("64".to_string()));
+        assert_eq!(err("spsc2:plain:64:1"), ParseError::Trailing("1".to_string()));
+    }
+
+    #[test]
+    fn round_trips() {
+        let kinds = QueueKind::all(&[0, 1, 128]);
+        assert_eq!(kinds.len(), 2 * (3 + 1 + 3 + 1));
+        for kind in kinds {
+            let spec = kind.to_string();
+            assert_eq!(spec.parse(), Ok(kind), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn builds() {
+        for kind in QueueKind::all(&[0, 1, 128]) {
+            let q = kind.build();
+            assert_eq!(q.pop(), None, "{}", kind);
+            for i in 0..100 {
+                q.push(i);
+            }
+            assert_eq!(q.peek().map(|i| *i), Some(0), "{}", kind);
+            for i in 0..100 {
+                assert_eq!(q.pop(), Some(i), "{}", kind);
+            }
+            assert_eq!(q.pop(), None, "{}", kind);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! A C interface to the spsc queue, so that it can be driven from C and C++
+//! benchmarks. `include/spsc.h` declares it.
+//!
+//! There are two queues: one of `u64`s and one of byte strings, which are
+//! copied in on push and out on pop. Either is created aligned or not, with
+//! the node cache bound `spsc::Queue::new` takes. Every function checks its
+//! pointers for null and catches panics rather than unwinding into C,
+//! returning false (or `SPSC_ERROR`) for either.
+//!
+//! As with the Rust queue, the caller must keep to one pushing thread and
+//! one popping thread at a time, and free a queue only once neither is using
+//! it.
+
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use spsc;
+
+/// A queue of `u64`s, opaque to C.
+pub enum SpscU64 {
+    Plain(spsc::_NQueue<u64>),
+    Aligned(spsc::CNQueue<u64>),
+}
+
+/// A queue of byte strings, opaque to C.
+pub enum SpscBytes {
+    Plain(spsc::_NQueue<Box<[u8]>>),
+    Aligned(spsc::CNQueue<Box<[u8]>>),
+}
+
+/// `spsc_bytes_pop` results.
+pub const SPSC_POPPED: c_int = 1;
+pub const SPSC_EMPTY: c_int = 0;
+pub const SPSC_TOO_SMALL: c_int = -1;
+pub const SPSC_ERROR: c_int = -2;
+
+// Runs `f`, turning a panic into `on_panic`.
+fn guard<R, F: FnOnce() -> R>(on_panic: R, f: F) -> R {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+/// Creates a queue of `u64`s, or returns null if that fails.
+#[no_mangle]
+pub extern "C" fn spsc_u64_new(bound: usize, aligned: bool) -> *mut SpscU64 {
+    guard(ptr::null_mut(), || {
+        let queue = unsafe {
+            if aligned {
+                SpscU64::Aligned(spsc::Queue::aligned(bound))
+            } else {
+                SpscU64::Plain(spsc::Queue::new(bound))
+            }
+        };
+        Box::into_raw(box queue)
+    })
+}
+
+/// Pushes `v`, returning false only if `q` is null.
+///
+/// # Safety
+///
+/// `q` must be null or come from `spsc_u64_new`, and not have been freed.
+/// Only one thread may push to a queue at a time.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_u64_push(q: *mut SpscU64, v: u64) -> bool {
+    if q.is_null() {
+        return false
+    }
+    guard(false, || {
+        match *q {
+            SpscU64::Plain(ref q) => q.push(v),
+            SpscU64::Aligned(ref q) => q.push(v),
+        }
+        true
+    })
+}
+
+/// Pops into `out`, returning false if the queue is empty or either pointer
+/// is null.
+///
+/// # Safety
+///
+/// `q` must be null or come from `spsc_u64_new`, and not have been freed, and
+/// `out` must be null or point to a `u64` to write. Only one thread may pop
+/// from a queue at a time.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_u64_pop(q: *mut SpscU64, out: *mut u64) -> bool {
+    if q.is_null() || out.is_null() {
+        return false
+    }
+    guard(false, || {
+        let v = match *q {
+            SpscU64::Plain(ref q) => q.pop(),
+            SpscU64::Aligned(ref q) => q.pop(),
+        };
+        match v {
+            Some(v) => { *out = v; true }
+            None => false,
+        }
+    })
+}
+
+/// Frees a queue and anything left in it. Null is ignored.
+///
+/// # Safety
+///
+/// `q` must be null or come from `spsc_u64_new`, and is freed only once,
+/// after neither the pushing nor the popping thread will use it again.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_u64_free(q: *mut SpscU64) {
+    if !q.is_null() {
+        guard((), || drop(Box::from_raw(q)))
+    }
+}
+
+/// Creates a queue of byte strings, or returns null if that fails.
+#[no_mangle]
+pub extern "C" fn spsc_bytes_new(bound: usize, aligned: bool) -> *mut SpscBytes {
+    guard(ptr::null_mut(), || {
+        let queue = unsafe {
+            if aligned {
+                SpscBytes::Aligned(spsc::Queue::aligned(bound))
+            } else {
+                SpscBytes::Plain(spsc::Queue::new(bound))
+            }
+        };
+        Box::into_raw(box queue)
+    })
+}
+
+/// Pushes a copy of the `len` bytes at `data`, which may be null if `len`
+/// is 0. Returns false if `q` is null, or `data` is null with a `len`.
+///
+/// # Safety
+///
+/// `q` must be null or come from `spsc_bytes_new`, and not have been freed,
+/// and `data`, if not null, must point to `len` readable bytes. Only one
+/// thread may push to a queue at a time.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_bytes_push(q: *mut SpscBytes, data: *const u8, len: usize) -> bool {
+    if q.is_null() || (data.is_null() && len > 0) {
+        return false
+    }
+    guard(false, || {
+        let bytes: Box<[u8]> = if len == 0 {
+            Box::new([])
+        } else {
+            slice::from_raw_parts(data, len).into()
+        };
+        match *q {
+            SpscBytes::Plain(ref q) => q.push(bytes),
+            SpscBytes::Aligned(ref q) => q.push(bytes),
+        }
+        true
+    })
+}
+
+/// Pops the next byte string into the `cap` bytes at `buf`, storing its
+/// length in `len`, and returns `SPSC_POPPED`. Returns `SPSC_EMPTY` if there
+/// is none, and `SPSC_TOO_SMALL`, leaving it queued, if it's longer than
+/// `cap`, with the length it needs in `len`. `buf` may be null if `cap` is 0.
+/// Returns `SPSC_ERROR` if `q` or `len` is null.
+///
+/// # Safety
+///
+/// `q` must be null or come from `spsc_bytes_new`, and not have been freed.
+/// `buf`, if not null, must point to `cap` writable bytes, and `len`, if not
+/// null, to a `usize` to write. Only one thread may pop from a queue at a
+/// time.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_bytes_pop(q: *mut SpscBytes, buf: *mut u8, cap: usize, len: *mut usize)
+-> c_int {
+    if q.is_null() || len.is_null() || (buf.is_null() && cap > 0) {
+        return SPSC_ERROR
+    }
+    guard(SPSC_ERROR, || {
+        let needed = match *q {
+            SpscBytes::Plain(ref q) => q.peek().map(|b| b.len()),
+            SpscBytes::Aligned(ref q) => q.peek().map(|b| b.len()),
+        };
+        let needed = match needed {
+            Some(needed) => needed,
+            None => return SPSC_EMPTY,
+        };
+        *len = needed;
+        if needed > cap {
+            return SPSC_TOO_SMALL
+        }
+        let bytes = match *q {
+            SpscBytes::Plain(ref q) => q.pop(),
+            SpscBytes::Aligned(ref q) => q.pop(),
+        }.unwrap();
+        if needed > 0 {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), buf, needed);
+        }
+        SPSC_POPPED
+    })
+}
+
+/// Frees a queue and anything left in it. Null is ignored.
+///
+/// # Safety
+///
+/// `q` must be null or come from `spsc_bytes_new`, and is freed only once,
+/// after neither the pushing nor the popping thread will use it again.
+#[no_mangle]
+pub unsafe extern "C" fn spsc_bytes_free(q: *mut SpscBytes) {
+    if !q.is_null() {
+        guard((), || drop(Box::from_raw(q)))
+    }
+}
@@ -0,0 +1,263 @@
+//! A `Wakeup` over a raw futex, for Linux.
+//!
+//! `blocking`'s tokens sleep with `thread::park`, which goes through the
+//! thread's own parker, and wake with `unpark`, which has to find it through
+//! the `Thread` handle. Here a token is a single `AtomicU32` the waiter
+//! sleeps on directly: a signal is one swap, plus a `FUTEX_WAKE` only if the
+//! waiter is actually asleep. Deadlines use `FUTEX_WAIT_BITSET`, which takes
+//! an absolute `CLOCK_MONOTONIC` time, so a wait which is woken spuriously
+//! goes back to sleep until the same deadline without recomputing it.
+//!
+//! Elsewhere `FutexBlocking` is just `DefaultBlocking`.
+
+#[cfg(not(target_os = "linux"))]
+pub use blocking::DefaultBlocking as FutexBlocking;
+
+#[cfg(target_os = "linux")]
+pub use self::imp::*;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::os::raw::{c_int, c_long};
+    use std::ptr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+
+    use blocking::Wakeup;
+
+    // A token's states. The waiter moves it from EMPTY to SLEEPING before it
+    // goes into the kernel, so a signal only has to make the wake syscall if
+    // it swaps out SLEEPING.
+    const EMPTY: u32 = 0;
+    const SLEEPING: u32 = 1;
+    const SIGNALLED: u32 = 2;
+
+    #[cfg(target_arch = "x86_64")]
+    const SYS_FUTEX: c_long = 202;
+    #[cfg(target_arch = "aarch64")]
+    const SYS_FUTEX: c_long = 98;
+    #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+    const SYS_FUTEX: c_long = 240;
+
+    const FUTEX_WAKE: c_int = 1;
+    const FUTEX_WAIT_BITSET: c_int = 9;
+    const FUTEX_PRIVATE_FLAG: c_int = 128;
+    const FUTEX_BITSET_MATCH_ANY: u32 = !0;
+    const CLOCK_MONOTONIC: c_int = 1;
+
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: c_long,
+        tv_nsec: c_long,
+    }
+
+    // std links libc anyway, so there's no need for the crate to reach these.
+    extern "C" {
+        fn syscall(num: c_long, ...) -> c_long;
+        fn clock_gettime(clock: c_int, ts: *mut Timespec) -> c_int;
+    }
+
+    // Sleeps while `word` is `expected`, until woken or the absolute
+    // monotonic time `end`. Returns on spurious wakeups and interrupts too,
+    // the caller re-checks.
+    fn futex_wait(word: &AtomicU32, expected: u32, end: Option<&Timespec>) {
+        let end = end.map_or(ptr::null(), |end| end as *const Timespec);
+        unsafe {
+            syscall(SYS_FUTEX, word as *const AtomicU32, FUTEX_WAIT_BITSET | FUTEX_PRIVATE_FLAG,
+                expected, end, ptr::null::<u32>(), FUTEX_BITSET_MATCH_ANY);
+        }
+    }
+
+    fn futex_wake(word: &AtomicU32) {
+        unsafe {
+            syscall(SYS_FUTEX, word as *const AtomicU32, FUTEX_WAKE | FUTEX_PRIVATE_FLAG, 1 as c_int);
+        }
+    }
+
+    // `end` as a CLOCK_MONOTONIC timespec. Instant doesn't expose its clock,
+    // so this goes through the time left, which loses whatever passes
+    // between the two reads.
+    fn monotonic(end: Instant) -> Timespec {
+        let mut now = Timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { clock_gettime(CLOCK_MONOTONIC, &mut now) };
+        let left = end.saturating_duration_since(Instant::now());
+        let now = Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+        let end = now + left;
+        Timespec { tv_sec: end.as_secs() as c_long, tv_nsec: end.subsec_nanos() as c_long }
+    }
+
+    struct Inner {
+        state: AtomicU32,
+    }
+
+    #[derive(Clone)]
+    pub struct FutexSignal {
+        inner: Arc<Inner>,
+    }
+
+    pub struct FutexWait {
+        inner: Arc<Inner>,
+    }
+
+    pub fn tokens() -> (FutexWait, FutexSignal) {
+        let inner = Arc::new(Inner { state: AtomicU32::new(EMPTY) });
+        (FutexWait { inner: inner.clone() }, FutexSignal { inner: inner })
+    }
+
+    impl FutexSignal {
+        pub fn signal(&self) -> bool {
+            match self.inner.state.swap(SIGNALLED, Ordering::Release) {
+                EMPTY => true,
+                SLEEPING => {
+                    futex_wake(&self.inner.state);
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        pub unsafe fn cast_to_usize(self) -> usize {
+            Arc::into_raw(self.inner) as usize
+        }
+
+        pub unsafe fn cast_from_usize(ptr: usize) -> FutexSignal {
+            FutexSignal { inner: Arc::from_raw(ptr as *const Inner) }
+        }
+    }
+
+    impl FutexWait {
+        pub fn wait(self) {
+            self.wait_until(None);
+        }
+
+        /// Returns true if we were signalled, false if `end` passed first.
+        pub fn wait_max_until(self, end: Instant) -> bool {
+            self.wait_until(Some(end))
+        }
+
+        fn wait_until(&self, end: Option<Instant>) -> bool {
+            let state = &self.inner.state;
+            let deadline = end.map(monotonic);
+            loop {
+                match state.compare_exchange(EMPTY, SLEEPING, Ordering::Acquire, Ordering::Acquire) {
+                    Ok(_) | Err(SLEEPING) => {}
+                    Err(_) => return true,
+                }
+                if let Some(end) = end {
+                    if Instant::now() >= end {
+                        return state.load(Ordering::Acquire) == SIGNALLED
+                    }
+                }
+                futex_wait(state, SLEEPING, deadline.as_ref());
+            }
+        }
+    }
+
+    /// Blocks on the futex tokens above.
+    pub struct FutexBlocking;
+
+    impl Wakeup for FutexBlocking {
+        type Wait = FutexWait;
+        type Signal = FutexSignal;
+
+        fn tokens() -> (FutexWait, FutexSignal) { tokens() }
+        fn signal(token: &FutexSignal) -> bool { token.signal() }
+        fn wait(token: FutexWait) { token.wait() }
+        fn wait_max_until(token: FutexWait, end: Instant) -> bool { token.wait_max_until(end) }
+        fn is_signalled(token: &FutexWait) -> bool {
+            token.inner.state.load(Ordering::Acquire) == SIGNALLED
+        }
+        unsafe fn cast_to_usize(token: FutexSignal) -> usize { token.cast_to_usize() }
+        unsafe fn cast_from_usize(ptr: usize) -> FutexSignal { FutexSignal::cast_from_usize(ptr) }
+    }
+
+    #[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+    mod tests {
+        use super::{tokens, FutexBlocking, FutexSignal};
+        use std::sync::Arc;
+        use std::sync::mpsc::channel;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        use spsc;
+        use stream2::Packet;
+
+        #[test]
+        fn usize_round_trip() {
+            let (wait, signal) = tokens();
+            let ptr = unsafe { signal.cast_to_usize() };
+            let signal = unsafe { FutexSignal::cast_from_usize(ptr) };
+            assert!(signal.signal());
+            assert!(!signal.signal());
+            wait.wait();
+            assert_eq!(Arc::strong_count(&signal.inner), 1);
+        }
+
+        #[test]
+        fn signal_before_wait() {
+            for _ in 0..10_000 {
+                let (wait, signal) = tokens();
+                assert!(signal.signal());
+                // even with the deadline already gone
+                assert!(wait.wait_max_until(Instant::now()));
+            }
+        }
+
+        #[test]
+        fn timeout() {
+            let (wait, _signal) = tokens();
+            let start = Instant::now();
+            assert!(!wait.wait_max_until(start + Duration::from_millis(20)));
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        }
+
+        // The signaller yields a varying number of times before signalling,
+        // so some signals land before the wait, some while the waiter is
+        // going to sleep, and some once it's asleep.
+        #[test]
+        fn wait_then_signal() {
+            let (tx, rx) = channel::<FutexSignal>();
+            let signaller = thread::spawn(move|| {
+                for (i, signal) in rx.iter().enumerate() {
+                    for _ in 0..(i % 4) {
+                        thread::yield_now();
+                    }
+                    assert!(signal.signal());
+                }
+            });
+            for i in 0..10_000 {
+                let (wait, signal) = tokens();
+                tx.send(signal).unwrap();
+                if i % 2 == 0 {
+                    wait.wait();
+                } else {
+                    assert!(wait.wait_max_until(Instant::now() + Duration::from_secs(10)));
+                }
+            }
+            drop(tx);
+            signaller.join().unwrap();
+        }
+
+        #[test]
+        fn packet() {
+            let p = Arc::new(Packet::<spsc::CNQueue<_>, _, FutexBlocking>::new());
+            let p2 = p.clone();
+            let sender = thread::spawn(move|| {
+                for i in 0..10_000 {
+                    if i % 100 == 0 {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    p2.send(i).unwrap();
+                }
+                p2.drop_chan();
+            });
+            for i in 0..10_000 {
+                assert_eq!(p.recv(None).ok(), Some(i));
+            }
+            assert!(p.recv(None).is_err());
+            sender.join().unwrap();
+            p.drop_port();
+        }
+    }
+}
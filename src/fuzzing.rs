@@ -0,0 +1,289 @@
+//! Operation scripts run against the queues and checked against a `VecDeque`,
+//! for the fuzz targets in fuzz/ and for canned scripts in the tests.
+//!
+//! The fuzzer's bytes decode to a script of pushes, pops, peeks, batches of
+//! either, and drops of the queue with whatever it still holds. `check` runs
+//! a script on one thread, comparing every result with the model. `check_split`
+//! gives the pushes to a second thread and runs the rest on this one, which
+//! can't know what a pop will find, only that whatever it finds is the next
+//! value pushed. Either panics on the first difference.
+//!
+//! The values are numbered in push order rather than taken from the input,
+//! and count themselves live, so that a value lost, duplicated or reordered
+//! shows up as the wrong number and a leaked or doubly dropped one as the
+//! wrong count.
+//!
+//! Every queue with a `stream2::Queue` impl is a target, at bounds on either
+//! side of the cache boundaries.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+use crossbeam;
+
+use factory::QueueKind;
+use spsc_epoch;
+use stream2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Pushes the next value.
+    Push,
+    /// Pops, checking the value against the model's.
+    Pop,
+    Peek,
+    PushBatch(u8),
+    PopBatch(u8),
+    /// Drops the queue, with whatever it still holds, and starts a new one.
+    Recreate,
+}
+
+/// Decodes a script: the top three bits of each byte pick the operation,
+/// and a batch takes its length from the byte after. Any input decodes.
+pub fn decode(data: &[u8]) -> Vec<Op> {
+    let mut ops = Vec::new();
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        ops.push(match b >> 5 {
+            0 | 1 => Op::Push,
+            2 | 3 => Op::Pop,
+            4 => Op::Peek,
+            5 => Op::PushBatch(bytes.next().cloned().unwrap_or(0)),
+            6 => Op::PopBatch(bytes.next().cloned().unwrap_or(0)),
+            _ => Op::Recreate,
+        });
+    }
+    ops
+}
+
+/// The bytes `decode` turns back into `ops`, for writing corpus inputs.
+pub fn encode(ops: &[Op]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for op in ops {
+        match *op {
+            Op::Push => data.push(0x00),
+            Op::Pop => data.push(0x40),
+            Op::Peek => data.push(0x80),
+            Op::PushBatch(n) => data.extend_from_slice(&[0xa0, n]),
+            Op::PopBatch(n) => data.extend_from_slice(&[0xc0, n]),
+            Op::Recreate => data.push(0xe0),
+        }
+    }
+    data
+}
+
+/// A value in a checked queue: its place in push order, and the count of
+/// values alive it keeps itself in.
+pub struct Value {
+    seq: u32,
+    live: Arc<AtomicIsize>,
+}
+
+impl Value {
+    fn new(seq: u32, live: &Arc<AtomicIsize>) -> Self {
+        live.fetch_add(1, Ordering::Relaxed);
+        Value { seq: seq, live: live.clone() }
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Value({})", self.seq)
+    }
+}
+
+pub type DynQueue = Box<dyn stream2::Queue<Value> + Send + Sync>;
+
+/// A queue to run scripts against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Kind(QueueKind),
+    Epoch,
+}
+
+impl Target {
+    pub fn build(&self) -> DynQueue {
+        match *self {
+            Target::Kind(kind) => kind.build(),
+            Target::Epoch => {
+                let queue = unsafe { spsc_epoch::Queue::new() };
+                box queue
+            }
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Kind(kind) => kind.fmt(f),
+            Target::Epoch => f.write_str("spsc_epoch"),
+        }
+    }
+}
+
+/// Every target. A bound of 1 and 2 puts the cache boundary a node or two
+/// in, and 64 is also spsc_seg's segment size.
+pub fn targets() -> Vec<Target> {
+    let mut targets: Vec<_> = QueueKind::all(&[0, 1, 2, 64]).into_iter().map(Target::Kind).collect();
+    targets.push(Target::Epoch);
+    targets
+}
+
+/// Runs `ops` against `target` on this thread, checking each pop and peek
+/// against a `VecDeque`, and that dropping the queue drops what it held.
+pub fn check(target: Target, ops: &[Op]) {
+    let live = Arc::new(AtomicIsize::new(0));
+    let mut queue = target.build();
+    let mut model = VecDeque::new();
+    let mut next = 0;
+    for (i, op) in ops.iter().enumerate() {
+        let pushes = match *op {
+            Op::Push => 1,
+            Op::PushBatch(n) => n as usize,
+            _ => 0,
+        };
+        for _ in 0..pushes {
+            queue.push(Value::new(next, &live));
+            model.push_back(next);
+            next += 1;
+        }
+        let pops = match *op {
+            Op::Pop => 1,
+            Op::PopBatch(n) => n as usize,
+            _ => 0,
+        };
+        for _ in 0..pops {
+            let got = queue.pop().map(|v| v.seq);
+            assert_eq!(got, model.pop_front(), "{}: pop at op {} of {:?}", target, i, ops);
+        }
+        if *op == Op::Peek {
+            let got = queue.peek().map(|v| v.seq);
+            assert_eq!(got, model.front().cloned(), "{}: peek at op {} of {:?}", target, i, ops);
+        }
+        assert_eq!(live.load(Ordering::Relaxed), model.len() as isize,
+            "{}: live values after op {} of {:?}", target, i, ops);
+        if *op == Op::Recreate {
+            queue = target.build();
+            model.clear();
+            assert_eq!(live.load(Ordering::Relaxed), 0,
+                "{}: values left alive by the drop at op {} of {:?}", target, i, ops);
+        }
+    }
+    drop(queue);
+    assert_eq!(live.load(Ordering::Relaxed), 0, "{}: values left alive by the last drop of {:?}", target, ops);
+}
+
+/// Runs `ops` with the pushes on a second thread, racing the pops and peeks
+/// on this one. A `Recreate` waits for both halves to get to it, then drops
+/// the queue with whatever the pops didn't get to. After the last part the
+/// rest is drained instead, and must be everything not yet popped.
+pub fn check_split(target: Target, ops: &[Op]) {
+    let live = Arc::new(AtomicIsize::new(0));
+    let mut next = 0;
+    let parts = ops.split(|op| *op == Op::Recreate).count();
+    for (i, part) in ops.split(|op| *op == Op::Recreate).enumerate() {
+        let queue = target.build();
+        let pushes: usize = part.iter().map(|op| match *op {
+            Op::Push => 1,
+            Op::PushBatch(n) => n as usize,
+            _ => 0,
+        }).sum();
+        let first = next;
+        next += pushes as u32;
+
+        let mut expect = first;
+        crossbeam::scope(|scope| {
+            let (queue, live) = (&queue, &live);
+            scope.spawn(move || {
+                for seq in first..(first + pushes as u32) {
+                    queue.push(Value::new(seq, live));
+                }
+            });
+            for op in part {
+                let pops = match *op {
+                    Op::Pop => 1,
+                    Op::PopBatch(n) => n as usize,
+                    _ => 0,
+                };
+                for _ in 0..pops {
+                    if let Some(v) = queue.pop() {
+                        assert_eq!(v.seq, expect, "{}: split pop of {:?}", target, ops);
+                        expect += 1;
+                    }
+                }
+                if *op == Op::Peek {
+                    if let Some(v) = queue.peek() {
+                        assert_eq!(v.seq, expect, "{}: split peek of {:?}", target, ops);
+                    }
+                }
+            }
+        });
+        if i + 1 == parts {
+            // The producer is done, what's left must be the rest, in order.
+            while let Some(v) = queue.pop() {
+                assert_eq!(v.seq, expect, "{}: split drain of {:?}", target, ops);
+                expect += 1;
+            }
+            assert_eq!(expect, next, "{}: values lost in {:?}", target, ops);
+        }
+        drop(queue);
+        assert_eq!(live.load(Ordering::Relaxed), 0, "{}: values left alive by {:?}", target, ops);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, check_split, decode, encode, targets, Op};
+
+    // The inputs checked in under fuzz/corpus, as regression tests.
+    const CORPUS: [(&str, &[u8]); 3] = [
+        ("cache_bound", include_bytes!("../fuzz/corpus/spsc_model/cache_bound")),
+        ("empty_peek", include_bytes!("../fuzz/corpus/spsc_model/empty_peek")),
+        ("drop_backlog", include_bytes!("../fuzz/corpus/spsc_model/drop_backlog")),
+    ];
+
+    #[test]
+    fn round_trips() {
+        let ops = [Op::Push, Op::PushBatch(200), Op::Peek, Op::Pop, Op::PopBatch(0), Op::Recreate];
+        assert_eq!(decode(&encode(&ops)), ops);
+        // any byte decodes, a batch missing its length is empty
+        assert_eq!(decode(&[0x1f, 0x7f, 0xa5]), [Op::Push, Op::Pop, Op::PushBatch(0)]);
+    }
+
+    #[test]
+    fn corpus() {
+        for &(name, data) in CORPUS.iter() {
+            let ops = decode(data);
+            assert!(!ops.is_empty(), "{} is empty", name);
+            for target in targets() {
+                check(target, &ops);
+                check_split(target, &ops);
+            }
+        }
+    }
+}
+
+// These spawn threads, which emscripten and wasm32 don't have
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use super::{check_split, targets, Op};
+
+    // Long batches, so the producer gets well ahead of the pops and behind.
+    #[test]
+    fn split_batches() {
+        let ops = [Op::PushBatch(255), Op::PopBatch(100), Op::Peek, Op::PushBatch(255),
+            Op::PopBatch(255), Op::Recreate, Op::PushBatch(130), Op::PopBatch(10)];
+        for target in targets() {
+            check_split(target, &ops);
+        }
+    }
+}
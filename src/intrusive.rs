@@ -0,0 +1,427 @@
+//! An intrusive mpsc queue: the caller's objects carry the links, so pushing
+//! and popping never allocate.
+//!
+//! An object goes in the queue through a `Link` embedded in it, and an
+//! `Adapter` gets from the object to its link and back, usually written with
+//! `intrusive_adapter!`. The queue is Vyukov's intrusive mpsc, mpmc.rs's
+//! queue with the nodes turned inside out: producers swap their link into
+//! `head` and then link the previous head to it, and the consumer follows the
+//! links from `tail`. The queue's own stub link takes the place of mpmc's
+//! empty stub node, re-pushed whenever the consumer would otherwise pop the
+//! last object, which must stay in the queue as the one `head` points to.
+//!
+//! `Queue` deals in raw pointers and leaves the objects' lifetimes, and
+//! keeping to one consumer, to the caller. `BoxQueue` is a safe wrapper for
+//! `Box`ed objects, which owns them while they're queued, split into
+//! producers and the one consumer.
+
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::ptr::{self, NonNull};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use backoff::Backoff;
+use cache_padded::Padding;
+use mpmc::{Data, Empty, Inconsistent, PopResult};
+
+pub use cache_padded::{NoAlign, CacheAligned};
+
+/// The link an object is queued by, embedded in it.
+#[derive(Debug, Default)]
+pub struct Link {
+    next: AtomicPtr<Link>,
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Link { next: AtomicPtr::new(ptr::null_mut()) }
+    }
+}
+
+/// Maps an object to the `Link` embedded in it and back.
+///
+/// This is unsafe to implement as the queue trusts it: `link` must return
+/// the same link for an object every time, and `object` must undo it.
+pub unsafe trait Adapter {
+    type Object;
+
+    fn link(object: NonNull<Self::Object>) -> NonNull<Link>;
+
+    /// The object `link` is embedded in.
+    ///
+    /// This is unsafe as `link` must have come from `Self::link`.
+    unsafe fn object(link: NonNull<Link>) -> NonNull<Self::Object>;
+}
+
+/// Implements `Adapter` for the link in `$field` of `$object`:
+///
+/// ```ignore
+/// struct Message { link: Link, body: String }
+/// intrusive_adapter!(pub MessageAdapter = Message { link });
+/// ```
+#[macro_export]
+macro_rules! intrusive_adapter {
+    ($vis:vis $adapter:ident = $object:ty { $field:ident }) => {
+        $vis struct $adapter;
+
+        unsafe impl $crate::intrusive::Adapter for $adapter {
+            type Object = $object;
+
+            fn link(object: ::std::ptr::NonNull<$object>)
+            -> ::std::ptr::NonNull<$crate::intrusive::Link> {
+                unsafe {
+                    ::std::ptr::NonNull::new_unchecked(
+                        ::std::ptr::addr_of_mut!((*object.as_ptr()).$field))
+                }
+            }
+
+            unsafe fn object(link: ::std::ptr::NonNull<$crate::intrusive::Link>)
+            -> ::std::ptr::NonNull<$object> {
+                // the field's offset, from where it would be in an object
+                // which is never initialized or read
+                let object = ::std::mem::MaybeUninit::<$object>::uninit();
+                let base = object.as_ptr();
+                let field = ::std::ptr::addr_of!((*base).$field);
+                let offset = field as usize - base as usize;
+                ::std::ptr::NonNull::new_unchecked(
+                    (link.as_ptr() as *mut u8).sub(offset) as *mut $object)
+            }
+        }
+    };
+}
+
+/// The intrusive multi-producer single-consumer queue. Like mpmc's, it may be
+/// shared so long as there's only one popper at a time, which is why the
+/// consumer's methods are unsafe.
+pub struct Queue<A: Adapter, Align: Padding> {
+    head: Align::Padded<AtomicPtr<Link>>,
+    tail: Align::Padded<UnsafeCell<*mut Link>>,
+    // boxed so that the queue can move, `head` and `tail` may point at it
+    stub: Box<Link>,
+    _adapter: PhantomData<A>,
+}
+
+unsafe impl<A: Adapter, Align: Padding> Send for Queue<A, Align> where A::Object: Send { }
+unsafe impl<A: Adapter, Align: Padding> Sync for Queue<A, Align> where A::Object: Send { }
+
+impl<A: Adapter> Queue<A, NoAlign> {
+    pub fn new() -> Self {
+        Queue::build()
+    }
+}
+
+impl<A: Adapter> Queue<A, CacheAligned> {
+    pub fn aligned() -> Self {
+        Queue::build()
+    }
+}
+
+impl<A: Adapter, Align: Padding> Queue<A, Align> {
+    fn build() -> Self {
+        let stub = Box::new(Link::new());
+        let s = &*stub as *const Link as *mut Link;
+        Queue {
+            head: Align::pad(AtomicPtr::new(s)),
+            tail: Align::pad(UnsafeCell::new(s)),
+            stub: stub,
+            _adapter: PhantomData,
+        }
+    }
+
+    fn stub(&self) -> *mut Link {
+        &*self.stub as *const Link as *mut Link
+    }
+
+    /// Pushes `object` onto the queue.
+    ///
+    /// This is unsafe as the queue only borrows the object: it must stay where
+    /// it is, and not be pushed again, until it's popped, and the queue must
+    /// not be dropped holding it unless it's to be leaked.
+    pub unsafe fn push(&self, object: NonNull<A::Object>) {
+        self.push_link(A::link(object).as_ptr());
+    }
+
+    unsafe fn push_link(&self, link: *mut Link) {
+        // Relaxed: carried to the consumer by the Release below, and to the
+        // next producer by the swap.
+        (*link).next.store(ptr::null_mut(), Ordering::Relaxed);
+        // AcqRel, as in mpmc: Release for our link's initialization, which the
+        // next producer writes to, Acquire for the previous head's.
+        let prev = self.head.swap(link, Ordering::AcqRel);
+        // Release: pairs with the consumer's Acquire of `next`, publishing
+        // the object along with the link.
+        (*prev).next.store(link, Ordering::Release);
+    }
+
+    /// Pops an object, or returns `Inconsistent` if a producer is between its
+    /// swap and linking itself in, as mpmc's `pop` does.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may be calling `try_pop`, `pop` or `is_empty` at a
+    /// time, as the consumer's `tail` is unsynchronized.
+    pub unsafe fn try_pop(&self) -> PopResult<NonNull<A::Object>> {
+        let stub = self.stub();
+        let mut tail = *self.tail.get();
+        let mut next = (*tail).next.load(Ordering::Acquire);
+        // Skip the stub, if it's at the front.
+        if tail == stub {
+            if next.is_null() {
+                return if self.head.load(Ordering::Acquire) == stub { Empty } else { Inconsistent }
+            }
+            *self.tail.get() = next;
+            tail = next;
+            next = (*next).next.load(Ordering::Acquire);
+        }
+        if !next.is_null() {
+            *self.tail.get() = next;
+            return Data(A::object(NonNull::new_unchecked(tail)))
+        }
+        // `tail` is the last object, and `head` points at it. To pop it
+        // the stub has to go in behind it, unless a producer has already
+        // swapped in.
+        if self.head.load(Ordering::Acquire) != tail {
+            return Inconsistent
+        }
+        self.push_link(stub);
+        next = (*tail).next.load(Ordering::Acquire);
+        if !next.is_null() {
+            *self.tail.get() = next;
+            return Data(A::object(NonNull::new_unchecked(tail)))
+        }
+        // a producer swapped in between the check and the stub
+        Inconsistent
+    }
+
+    /// Pops an object, spinning through any `Inconsistent`s, or returns
+    /// `None` if the queue is empty.
+    ///
+    /// # Safety
+    ///
+    /// As for `try_pop`.
+    pub unsafe fn pop(&self) -> Option<NonNull<A::Object>> {
+        let mut backoff = Backoff::new();
+        loop {
+            match self.try_pop() {
+                Data(object) => return Some(object),
+                Empty => return None,
+                Inconsistent => backoff.snooze(),
+            }
+        }
+    }
+
+    /// Whether there is nothing to pop, and no push in flight.
+    ///
+    /// # Safety
+    ///
+    /// As for `try_pop`.
+    pub unsafe fn is_empty(&self) -> bool {
+        let stub = self.stub();
+        *self.tail.get() == stub && self.head.load(Ordering::Acquire) == stub
+    }
+}
+
+/// A `Queue` of `Box`ed objects, which owns them from `push` to `pop`, and
+/// drops any left in it when it's dropped. It's used through the halves
+/// `split` hands out.
+pub struct BoxQueue<A: Adapter, Align: Padding> {
+    queue: Queue<A, Align>,
+}
+
+impl<A: Adapter> BoxQueue<A, NoAlign> {
+    pub fn new() -> Self {
+        BoxQueue { queue: Queue::new() }
+    }
+}
+
+impl<A: Adapter> BoxQueue<A, CacheAligned> {
+    pub fn aligned() -> Self {
+        BoxQueue { queue: Queue::aligned() }
+    }
+}
+
+impl<A: Adapter, Align: Padding> BoxQueue<A, Align> {
+    /// Splits the queue into a producer, which may be cloned and shared, and
+    /// the one consumer, which may be sent to another thread but not shared.
+    pub fn split(self) -> (BoxProducer<A, Align>, BoxConsumer<A, Align>) {
+        let queue = Arc::new(self);
+        (BoxProducer { queue: queue.clone() }, BoxConsumer { queue: queue, _not_sync: PhantomData })
+    }
+}
+
+impl<A: Adapter, Align: Padding> Drop for BoxQueue<A, Align> {
+    fn drop(&mut self) {
+        // with `&mut self` no push can be in flight, so this sees them all,
+        // and we're the only consumer
+        while let Some(object) = unsafe { self.queue.pop() } {
+            drop(unsafe { Box::from_raw(object.as_ptr()) });
+        }
+    }
+}
+
+pub struct BoxProducer<A: Adapter, Align: Padding> {
+    queue: Arc<BoxQueue<A, Align>>,
+}
+
+impl<A: Adapter, Align: Padding> BoxProducer<A, Align> {
+    pub fn push(&self, object: Box<A::Object>) {
+        unsafe { self.queue.queue.push(NonNull::new_unchecked(Box::into_raw(object))) }
+    }
+}
+
+impl<A: Adapter, Align: Padding> Clone for BoxProducer<A, Align> {
+    fn clone(&self) -> Self {
+        BoxProducer { queue: self.queue.clone() }
+    }
+}
+
+// Cell is Send but not Sync, which is what the consumer should be.
+pub struct BoxConsumer<A: Adapter, Align: Padding> {
+    queue: Arc<BoxQueue<A, Align>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+// There's only ever the one consumer, and it isn't Sync, so the queue has a
+// single popper, as its consumer's methods need.
+impl<A: Adapter, Align: Padding> BoxConsumer<A, Align> {
+    /// As `Queue::try_pop`.
+    pub fn try_pop(&self) -> PopResult<Box<A::Object>> {
+        match unsafe { self.queue.queue.try_pop() } {
+            Data(object) => Data(unsafe { Box::from_raw(object.as_ptr()) }),
+            Empty => Empty,
+            Inconsistent => Inconsistent,
+        }
+    }
+
+    /// As `Queue::pop`.
+    pub fn pop(&self) -> Option<Box<A::Object>> {
+        unsafe { self.queue.queue.pop() }.map(|object| unsafe { Box::from_raw(object.as_ptr()) })
+    }
+
+    /// As `Queue::is_empty`.
+    pub fn is_empty(&self) -> bool {
+        unsafe { self.queue.queue.is_empty() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BoxQueue, Link, Queue};
+    use mpmc::{Data, Empty};
+    use std::ptr::NonNull;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Message {
+        value: usize,
+        link: Link,
+    }
+
+    intrusive_adapter!(MessageAdapter = Message { link });
+
+    #[test]
+    fn smoke() {
+        let mut messages: Vec<_> = (0..4).map(|i| Message { value: i, link: Link::new() }).collect();
+        let q = Queue::<MessageAdapter, _>::new();
+        unsafe {
+            assert!(q.is_empty());
+            assert!(q.pop().is_none());
+            for m in messages.iter_mut() {
+                q.push(NonNull::from(m));
+            }
+            assert!(!q.is_empty());
+            for i in 0..4 {
+                assert_eq!(q.pop().map(|m| m.as_ref().value), Some(i));
+            }
+            assert!(q.is_empty());
+            // the same objects can go round again, the stub having moved
+            for m in messages.iter_mut().rev() {
+                let value = m.value;
+                q.push(NonNull::from(m));
+                match q.try_pop() {
+                    Data(p) => assert_eq!(p.as_ref().value, value),
+                    _ => panic!(),
+                }
+                match q.try_pop() { Empty => {}, _ => panic!() }
+            }
+        }
+    }
+
+    struct Counted {
+        link: Link,
+        drops: Arc<AtomicUsize>,
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    intrusive_adapter!(CountedAdapter = Counted { link });
+
+    #[test]
+    fn drops_what_it_owns_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = BoxQueue::<CountedAdapter, _>::aligned().split();
+        for _ in 0..10 {
+            tx.push(Box::new(Counted { link: Link::new(), drops: drops.clone() }));
+        }
+        drop(rx.pop());
+        drop(rx.pop());
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        // the queue goes with the last half
+        drop(rx);
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        drop(tx);
+        assert_eq!(drops.load(Ordering::SeqCst), 10);
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use super::{BoxQueue, Link};
+    use std::thread;
+    use verify::stress_count;
+
+    struct Message {
+        link: Link,
+        producer: usize,
+        seq: usize,
+    }
+
+    intrusive_adapter!(MessageAdapter = Message { link });
+
+    // Every message arrives once, and each producer's in order.
+    #[test]
+    fn exactly_once() {
+        const PRODUCERS: usize = 4;
+        let count = stress_count(100_000);
+        let (tx, rx) = BoxQueue::<MessageAdapter, _>::aligned().split();
+        let producers: Vec<_> = (0..PRODUCERS).map(|p| {
+            let tx = tx.clone();
+            thread::spawn(move|| {
+                for seq in 0..count {
+                    tx.push(Box::new(Message { link: Link::new(), producer: p, seq: seq }));
+                }
+            })
+        }).collect();
+        let mut next = [0; PRODUCERS];
+        let mut received = 0;
+        while received < count * PRODUCERS {
+            match rx.pop() {
+                Some(m) => {
+                    assert_eq!(m.seq, next[m.producer], "producer {} out of order", m.producer);
+                    next[m.producer] += 1;
+                    received += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        for p in producers {
+            p.join().unwrap();
+        }
+        assert!(rx.pop().is_none());
+    }
+}
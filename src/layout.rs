@@ -0,0 +1,187 @@
+//! Where the queues' and packets' fields end up in memory, so that whether
+//! `aligned()` really separates the producer's fields from the consumer's
+//! can be checked rather than guessed at.
+//!
+//! Each type lists its fields, with their offsets taken with `ptr::addr_of!`
+//! on a real instance and the side which touches them on the hot path. Any
+//! two hot fields of different sides which may share a line are reported by
+//! `contended`, as candidates for false sharing: in a line aligned value
+//! that's fields on the same line, otherwise any starting within a line of
+//! each other, as where the lines fall depends on where the value is.
+
+use std::fmt;
+use std::mem;
+
+use cache_padded::CACHE_LINE;
+
+/// Who touches a field when sending and receiving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Producer,
+    Consumer,
+    /// Written by both sides, so contended with either side's fields.
+    Both,
+    /// Configuration, or only touched on setup and teardown.
+    Cold,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub side: Side,
+    pub offset: usize,
+    pub size: usize,
+}
+
+pub trait Layout {
+    /// The fields of `self`, in declaration order, with offsets from `self`.
+    fn fields(&self) -> Vec<Field>;
+}
+
+impl Field {
+    /// The field at `field` in the value at `base`.
+    pub fn new<S, F>(name: &str, side: Side, base: &S, field: *const F) -> Field {
+        Field {
+            name: name.to_string(),
+            side,
+            offset: offset(base, field),
+            size: mem::size_of::<F>(),
+        }
+    }
+}
+
+/// The offset of `field`, taken with `ptr::addr_of!`, in the value at `base`.
+pub fn offset<S, F>(base: &S, field: *const F) -> usize {
+    field as usize - base as *const S as usize
+}
+
+/// Appends `inner`'s fields, which lives at `at` in the value at `base`, as
+/// `name.<field>`.
+pub fn nest<S, L: Layout>(fields: &mut Vec<Field>, name: &str, base: &S, at: &L) {
+    let shift = at as *const L as usize - base as *const S as usize;
+    fields.extend(at.fields().into_iter().map(|f| Field {
+        name: format!("{}.{}", name, f.name),
+        offset: f.offset + shift,
+        ..f
+    }));
+}
+
+impl Side {
+    fn contends(self, other: Side) -> bool {
+        self != Side::Cold && other != Side::Cold && self != other
+    }
+}
+
+/// The pairs of fields, by index, which are hot for different sides and may
+/// share a line in a value aligned to `align`.
+pub fn contended(fields: &[Field], align: usize) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    for (i, a) in fields.iter().enumerate() {
+        for (j, b) in fields.iter().enumerate().skip(i + 1) {
+            let close = if align >= CACHE_LINE {
+                a.offset / CACHE_LINE == b.offset / CACHE_LINE
+            } else {
+                let distance = if a.offset > b.offset { a.offset - b.offset } else { b.offset - a.offset };
+                distance < CACHE_LINE
+            };
+            if close && a.side.contends(b.side) {
+                pairs.push((i, j));
+            }
+        }
+    }
+    pairs
+}
+
+/// The fields of one value, with its size and alignment, for printing.
+pub struct Report {
+    pub size: usize,
+    pub align: usize,
+    pub fields: Vec<Field>,
+}
+
+impl Report {
+    pub fn of<L: Layout>(value: &L) -> Report {
+        let mut fields = value.fields();
+        fields.sort_by_key(|f| f.offset);
+        Report {
+            size: mem::size_of_val(value),
+            align: mem::align_of_val(value),
+            fields,
+        }
+    }
+
+    pub fn contended(&self) -> Vec<(usize, usize)> {
+        contended(&self.fields, self.align)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  size {}, align {}", self.size, self.align)?;
+        for field in &self.fields {
+            writeln!(f, "  {:>5} {:<32} {:<8} {:>3} bytes",
+                field.offset, field.name, format!("{:?}", field.side).to_lowercase(), field.size)?;
+        }
+        for (i, j) in self.contended() {
+            let (a, b) = (&self.fields[i], &self.fields[j]);
+            writeln!(f, "  !! {} ({:?}) and {} ({:?}) may share a line",
+                a.name, a.side, b.name, b.side)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
+mod tests {
+    use super::{contended, Field, Report, Side};
+    use {spsc, spsc2, mpmc2, bounded_mpmc, stream2};
+
+    fn field(name: &str, side: Side, offset: usize) -> Field {
+        Field { name: name.to_string(), side, offset, size: 8 }
+    }
+
+    #[test]
+    fn flags_close_sides() {
+        let fields = [
+            field("head", Side::Producer, 0),
+            field("first", Side::Producer, 8),
+            field("bound", Side::Cold, 16),
+            field("tail", Side::Consumer, 56),
+            field("count", Side::Both, 64),
+            field("other", Side::Both, 72),
+            field("far", Side::Consumer, 200),
+        ];
+        // unaligned, anything closer than a line may share one
+        assert_eq!(contended(&fields, 8), [(0, 3), (1, 3), (1, 4), (3, 4), (3, 5)]);
+        // aligned, only the fields on the first line do
+        assert_eq!(contended(&fields, 64), [(0, 3), (1, 3)]);
+    }
+
+    #[test]
+    fn aligned_queues() {
+        unsafe {
+            assert!(Report::of(&spsc2::AQueue::<u64>::aligned(128)).contended().is_empty());
+            assert!(!Report::of(&spsc2::_Queue::<u64>::new(128)).contended().is_empty());
+            // std's cache counters are on one line, whatever the alignment
+            let report = Report::of(&spsc::CNQueue::<u64>::aligned(128));
+            let names: Vec<_> = report.contended().into_iter()
+                .map(|(i, j)| (&report.fields[i].name[..], &report.fields[j].name[..]))
+                .collect();
+            assert_eq!(names, [("cache.cache_additions", "cache.cache_subtractions")]);
+        }
+        assert!(Report::of(&mpmc2::Queue::<u64, _>::aligned()).contended().is_empty());
+        assert!(Report::of(&bounded_mpmc::Queue::<u64, _>::aligned(64)).contended().is_empty());
+        assert!(!Report::of(&bounded_mpmc::Queue::<u64, _>::new(64)).contended().is_empty());
+    }
+
+    #[test]
+    fn nested_queue() {
+        let packet = stream2::Packet::<spsc2::AQueue<_>, u64>::new();
+        let report = Report::of(&packet);
+        assert!(report.fields.iter().any(|f| f.name == "queue.consumer.tail"));
+        assert!(report.fields.iter().all(|f| f.offset + f.size <= report.size));
+        assert!(report.contended().is_empty(), "{}", report);
+        packet.drop_chan();
+        packet.drop_port();
+    }
+}
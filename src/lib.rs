@@ -0,0 +1,179 @@
+//! The queues and channel flavors compared by the `bench` binary, as a
+//! library, so that other crates and the tests can use them.
+//!
+//! All but `blocking` are copies or variants of libstd's internals, and need
+//! nightly and the `queue_experiments` feature, except for the queues
+//! themselves. Those, spsc, spsc2, spsc_seg, spsc_epoch, mpmc, mpmc2 and
+//! bounded_mpmc, build on stable with just the `queues` feature, for the
+//! criterion bench; their tests still need `queue_experiments`.
+
+#![cfg_attr(feature = "queue_experiments", feature(repr_align, attr_literals, box_syntax))]
+#![cfg_attr(feature = "async", feature(async_iterator))]
+#![cfg_attr(feature = "queue_experiments", feature(cfg_sanitize))]
+#![allow(dead_code)]
+
+#[cfg(feature="queues")]
+extern crate crossbeam;
+
+// Padding values out to their own cache lines
+#[cfg(feature="queues")]
+pub mod cache_padded;
+
+// A copy of libstd/sync/mpsc/spsc_queue.rs to test various optimazations on
+#[cfg(feature="queues")]
+pub mod spsc;
+
+// A version of spsc where all infmation on chache size is maintained exclusively by the consumer
+#[cfg(feature="queues")]
+pub mod spsc2;
+
+// An spsc of linked segments of slots, between spsc's nodes and a ring
+#[cfg(feature="queues")]
+pub mod spsc_seg;
+
+// An spsc which frees its nodes through crossbeam's epochs instead of caching them
+#[cfg(feature="queues")]
+pub mod spsc_epoch;
+
+// A copy of libstd/sync/mpsc/mpsc_queue.rs to compare with spsc
+// the effects of false sharing
+#[cfg(feature="queues")]
+pub mod mpmc;
+
+// A variant of mpmc where the tail is claimed with a CAS so that any number
+// of consumers may pop
+#[cfg(feature="queues")]
+pub mod mpmc2;
+
+// Vyukov's bounded array based mpmc, to compare against the linked-list queues
+#[cfg(feature="queues")]
+pub mod bounded_mpmc;
+
+// An mpsc queue threaded through the caller's objects, which never allocates
+#[cfg(feature="queue_experiments")]
+#[macro_use]
+pub mod intrusive;
+
+// Spin, then yield, then maybe park, for polling loops
+pub mod backoff;
+
+// Long runs of empty polls in a consumer loop, counted and timed
+pub mod stalls;
+
+pub mod blocking;
+
+// the atomics spsc and stream2 use, which are yield points with yield_points
+pub mod sched;
+
+#[cfg(feature="queue_experiments")]
+pub mod oneshot;
+
+// std's stream flavor (the cnt/steals protocol) over this crate's queues
+#[cfg(feature="queue_experiments")]
+pub mod stream;
+
+// The rewrite of that protocol
+#[cfg(feature="queue_experiments")]
+pub mod stream2;
+
+// Snapshots of spsc's and spsc2's node chains, for debugging
+#[cfg(feature="queues")]
+pub mod chain;
+
+// Choosing one of stream2's queues at runtime from a spec string
+#[cfg(feature="queue_experiments")]
+pub mod factory;
+
+// One producer thread and one consumer over a queue, without its unsafe constructors
+#[cfg(feature="queue_experiments")]
+pub mod split;
+
+// Timestamps for the rate mode's latencies, from Instant or rdtscp
+#[cfg(feature="queue_experiments")]
+pub mod clock;
+
+// Sending at a fixed rate, and the latencies at that load
+#[cfg(feature="queue_experiments")]
+pub mod rate;
+
+// A shared flavor for stream2 to upgrade to
+#[cfg(feature="queue_experiments")]
+pub mod shared;
+
+// A copy of libstd/sync/mpsc/shared.rs, to bisect the shared slowdown on
+#[cfg(feature="queue_experiments")]
+pub mod shared_orig;
+
+// A bounded stream2, to compare with sync_channel
+#[cfg(feature="queue_experiments")]
+pub mod sync2;
+
+// A copy of libstd/sync/mpsc/sync.rs, the mutex based bounded flavor
+#[cfg(feature="queue_experiments")]
+pub mod sync_orig;
+
+// stream2 under std::sync::mpsc's names, for trying it in applications
+#[cfg(feature="queue_experiments")]
+#[allow(unused_imports)]
+pub mod mpsc_compat;
+
+// Checking several queues for data without blocking
+#[cfg(feature="queue_experiments")]
+pub mod poll;
+
+// Request/response pairs of stream2 packets
+#[cfg(feature="queue_experiments")]
+pub mod bichannel;
+
+// High and low spsc lanes behind one consumer
+#[cfg(feature="queue_experiments")]
+pub mod priority;
+
+// A slot of the latest value, against draining a queue to the newest
+#[cfg(feature="queue_experiments")]
+pub mod watch;
+
+// A keyed spsc queue which only keeps the latest value of each key
+#[cfg(feature="queue_experiments")]
+pub mod coalesce;
+
+// An spsc ring of bytes, with io::Read and io::Write halves
+#[cfg(feature="queue_experiments")]
+pub mod byte_ring;
+
+// A thread sending from an iterator down a bounded channel, joined on drop
+#[cfg(feature="queue_experiments")]
+pub mod producer;
+
+// Futures over the raw spsc and bounded queues, woken through a waker slot
+#[cfg(feature="async")]
+pub mod spsc_async;
+
+// Wakeups over a raw futex
+#[cfg(feature="futex")]
+pub mod futex;
+
+// A ring in a shared mapping, for producers and consumers in other processes
+#[cfg(all(feature="shm", target_os="linux"))]
+pub mod shm;
+
+// Pre-faulted, locked, huge page nodes for spsc, on Linux
+#[cfg(all(feature="hugepages", target_os="linux"))]
+pub mod arena;
+
+// Field offsets of the queues and packets, for checking their padding
+#[cfg(feature="queues")]
+pub mod layout;
+
+// Exactly once, in order delivery checks over the packets' blocking paths,
+// and the stamped payloads the multi-producer tests and benches check with
+#[cfg(feature="queue_experiments")]
+pub mod verify;
+
+// Operation scripts checking the queues against a model, for fuzz/
+#[cfg(feature="fuzzing")]
+pub mod fuzzing;
+
+// A C interface to spsc, see include/spsc.h
+#[cfg(feature="queue_experiments")]
+pub mod ffi;
@@ -3,9 +3,15 @@
 pub use self::PopResult::*;
 
 use std::ptr;
-use std::cell::UnsafeCell;
+use std::cell::{RefCell, UnsafeCell};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
 
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize, Ordering};
+
+use backoff::Backoff;
+use cache_padded::Padding;
+use layout::{Field, Layout, Side};
 
 /// A result of the `pop` function.
 pub enum PopResult<T> {
@@ -20,36 +26,227 @@ pub enum PopResult<T> {
     Inconsistent,
 }
 
+/// Counts of `pop` outcomes, see `Queue::pop_stats`.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Default)]
+pub struct PopStats {
+    pub data: usize,
+    pub empty: usize,
+    pub inconsistent: usize,
+    /// How many `Inconsistent` pops were seen in a row before data appeared.
+    /// Bucket `i` counts streaks of length `2^i` up to `2^(i+1) - 1`, the last
+    /// bucket counts everything longer.
+    pub retries: [usize; RETRY_BUCKETS],
+}
+
+#[cfg(feature = "stats")]
+pub const RETRY_BUCKETS: usize = 8;
+
+// Only written by the consumer, atomic so that they can be read from anywhere.
+#[cfg(feature = "stats")]
+#[derive(Default)]
+struct Stats {
+    data: AtomicUsize,
+    empty: AtomicUsize,
+    inconsistent: AtomicUsize,
+    streak: AtomicUsize, // current run of `Inconsistent`s
+    retries: [AtomicUsize; RETRY_BUCKETS],
+}
+
+#[cfg(feature = "stats")]
+impl Stats {
+    fn bump(counter: &AtomicUsize) {
+        counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+
+    fn record<T>(&self, result: &PopResult<T>) {
+        match *result {
+            Data(..) => self.record_data(1),
+            Empty => {
+                Stats::bump(&self.empty);
+                // a producer finished, and the queue was drained, without us
+                // seeing data, don't charge this streak to the next pop
+                self.streak.store(0, Ordering::Relaxed);
+            }
+            Inconsistent => {
+                Stats::bump(&self.inconsistent);
+                Stats::bump(&self.streak);
+            }
+        }
+    }
+
+    fn record_data(&self, n: usize) {
+        self.data.store(self.data.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+        let streak = self.streak.load(Ordering::Relaxed);
+        if streak > 0 {
+            let bucket = (0usize.leading_zeros() - streak.leading_zeros() - 1) as usize;
+            Stats::bump(&self.retries[bucket.min(RETRY_BUCKETS - 1)]);
+            self.streak.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> PopStats {
+        let mut retries = [0; RETRY_BUCKETS];
+        for (r, counter) in retries.iter_mut().zip(self.retries.iter()) {
+            *r = counter.load(Ordering::Relaxed);
+        }
+        PopStats {
+            data: self.data.load(Ordering::Relaxed),
+            empty: self.empty.load(Ordering::Relaxed),
+            inconsistent: self.inconsistent.load(Ordering::Relaxed),
+            retries: retries,
+        }
+    }
+}
+
+/// What the consumer would see if it popped, see `Queue::state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueState {
+    /// There is nothing in the queue, and no push has started since the last
+    /// pop. A consumer may park, so long as producers check for it after
+    /// pushing.
+    Empty,
+    /// A `pop` would return `Data`.
+    HasData,
+    /// A push has swapped itself into `head` but has not yet linked itself
+    /// in. A `pop` would return `Inconsistent`, data will be available once
+    /// the producer runs again, so the consumer should spin rather than park.
+    InFlight,
+}
+
 struct Node<T> {
     next: AtomicPtr<Node<T>>,
-    value: Option<T>,
+    // Whether this is initialized is carried by the node's position: the node
+    // at `tail` (the stub) and spare nodes never hold a value, every node
+    // after `tail` always does.
+    value: MaybeUninit<T>,
+    #[cfg(debug_assertions)]
+    has_value: bool,
+}
+
+/// Returned by `Queue::pop_disconnected` once every sender is gone and the
+/// queue has been drained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+pub use cache_padded::{NoAlign, CacheAligned};
+
+/// The orderings used by `push` to swap itself into `head`.
+///
+/// `push` needs the swap to both release the new node's initialization to the
+/// next producer, which writes to its `next`, and acquire the previous
+/// producer's node before writing to its `next` in turn. `AcqRelSwap` and
+/// `FencedSwap` both provide that; a `swap(Acquire)` alone would not, the next
+/// producer's store to `next` racing with the node's initialization. On x86
+/// every RMW is a full barrier so both compile to the same `xchg`, the
+/// differences only show up on weaker architectures such as ARM.
+pub trait PushOrdering {
+    const SWAP: Ordering;
+    /// Whether to surround the swap with a release and an acquire fence.
+    const FENCED: bool;
 }
 
-struct AlignedPtr<T, Align>(UnsafeCell<*mut Node<T>>, [Align; 0]);
+/// The orderings std uses, `swap(AcqRel)`.
+pub struct AcqRelSwap;
 
-pub struct NoAlign;
+/// A release fence, a `Relaxed` swap, and then an acquire fence.
+pub struct FencedSwap;
 
-#[repr(align(64))]
-pub struct CacheAligned;
+impl PushOrdering for AcqRelSwap {
+    const SWAP: Ordering = Ordering::AcqRel;
+    const FENCED: bool = false;
+}
+
+impl PushOrdering for FencedSwap {
+    const SWAP: Ordering = Ordering::Relaxed;
+    const FENCED: bool = true;
+}
 
 /// The multi-producer single-consumer structure. This is not cloneable, but it
 /// may be safely shared so long as it is guaranteed that there is only one
 /// popper at a time (many pushers are allowed).
-pub struct Queue<T, Align> {
+pub struct Queue<T, Align: Padding, O = AcqRelSwap> {
     head: AtomicPtr<Node<T>>,
+    pushed: AtomicUsize, // number of pushes started, only used for `len`
 
-    tail: AlignedPtr<T, Align>,
+    tail: Align::Padded<UnsafeCell<*mut Node<T>>>,
+    popped: AtomicUsize, // number of successful pops, only written by the consumer
+
+    cache: Align::Padded<NodeCache<T>>,
+
+    // number of live senders, only touched when senders come and go, so it
+    // gets its own line rather than being next to `head` or `tail`
+    senders: Align::Padded<AtomicUsize>,
+
+    #[cfg(feature = "stats")]
+    stats: Stats,
+
+    _ordering: PhantomData<O>,
+}
+
+// Nodes freed by the consumer are pushed onto a Treiber stack, which feeds a
+// per-thread cache on the producer side. A producer allocates from its own
+// cache, and only once that is empty takes the whole stack at once, with a
+// swap rather than a pop, so there is no ABA and producers never wait on each
+// other. Popping one node at a time, every producer let in by the same pop
+// would race for the top node, and all but one of them malloc.
+struct NodeCache<T> {
+    bound: usize, // maximum number of spare nodes on the stack, 0 disables the cache
+    id: usize, // tells this cache's nodes apart in a thread's `LOCAL_NODES`
+    spare: AtomicPtr<Node<T>>, // top of the stack of spare nodes
+    spare_count: AtomicUsize, // number of nodes on the stack
+    #[cfg(test)]
+    allocations: AtomicUsize,
 }
 
-unsafe impl<T: Send, Align> Send for Queue<T, Align> { }
-unsafe impl<T: Send, Align> Sync for Queue<T, Align> { }
+static NEXT_CACHE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// How many queues' nodes a thread keeps at once. Taking nodes for another
+// frees those of the queue it first took nodes from longest ago.
+const LOCAL_CACHES: usize = 4;
+
+thread_local! {
+    // The nodes this thread took from each `NodeCache`'s stack, oldest first.
+    // `thread_local!` can't be generic, so they are type erased.
+    static LOCAL_NODES: RefCell<Vec<LocalNodes>> = const { RefCell::new(Vec::new()) };
+}
+
+// A chain of spare nodes, linked by `next`, which belongs to one thread.
+struct LocalNodes {
+    cache: usize,
+    top: *mut u8,
+    free: unsafe fn(*mut u8),
+}
+
+unsafe fn free_chain<T>(top: *mut u8) {
+    let mut cur = top as *mut Node<T>;
+    while !cur.is_null() {
+        let next = (*cur).next.load(Ordering::Relaxed);
+        let _: Box<Node<T>> = Box::from_raw(cur);
+        cur = next;
+    }
+}
+
+impl Drop for LocalNodes {
+    fn drop(&mut self) {
+        unsafe { (self.free)(self.top) }
+    }
+}
+
+unsafe impl<T: Send, Align: Padding, O> Send for Queue<T, Align, O> { }
+unsafe impl<T: Send, Align: Padding, O> Sync for Queue<T, Align, O> { }
 
 impl<T> Node<T> {
     unsafe fn new(v: Option<T>) -> *mut Node<T> {
-        Box::into_raw(box Node {
+        Box::into_raw(Box::new(Node {
             next: AtomicPtr::new(ptr::null_mut()),
-            value: v,
-        })
+            #[cfg(debug_assertions)]
+            has_value: v.is_some(),
+            value: match v {
+                Some(t) => MaybeUninit::new(t),
+                None => MaybeUninit::uninit(),
+            },
+        }))
     }
 }
 
@@ -57,35 +254,196 @@ impl<T> Queue<T, NoAlign> {
     /// Creates a new queue that is safe to share among multiple producers and
     /// one consumer.
     pub fn new() -> Self {
-        let stub = unsafe { Node::new(None) };
-        Queue {
-            head: AtomicPtr::new(stub),
-            tail: AlignedPtr(UnsafeCell::new(stub), []),
-        }
+        Self::with_node_cache(0)
+    }
+
+    /// Creates a new queue which keeps up to `bound` nodes freed by the
+    /// consumer for reuse by the producers. A `bound` of 0 disables the cache
+    /// and every push allocates, as in `new`.
+    pub fn with_node_cache(bound: usize) -> Self {
+        Queue::build(bound)
     }
 }
 
 impl<T> Queue<T, CacheAligned> {
     pub fn aligned() -> Self {
+        Self::aligned_with_node_cache(0)
+    }
+
+    pub fn aligned_with_node_cache(bound: usize) -> Self {
+        Queue::build(bound)
+    }
+}
+
+impl<T, O> Queue<T, CacheAligned, O>
+where O: PushOrdering {
+    /// Creates an aligned queue whose `push` uses the orderings from `O`.
+    pub fn aligned_with_ordering() -> Self {
+        Queue::build(0)
+    }
+}
+
+impl<T, Align: Padding, O> Queue<T, Align, O> {
+    fn build(bound: usize) -> Self {
         let stub = unsafe { Node::new(None) };
         Queue {
             head: AtomicPtr::new(stub),
-            tail: AlignedPtr(UnsafeCell::new(stub), []),
+            pushed: AtomicUsize::new(0),
+            tail: Align::pad(UnsafeCell::new(stub)),
+            popped: AtomicUsize::new(0),
+            cache: Align::pad(NodeCache::new(bound)),
+            senders: Align::pad(AtomicUsize::new(0)),
+            #[cfg(feature = "stats")]
+            stats: Stats::default(),
+            _ordering: PhantomData,
         }
     }
 }
 
-impl<T, Align> Queue<T, Align> {
+impl<T> NodeCache<T> {
+    fn new(bound: usize) -> Self {
+        NodeCache {
+            bound,
+            id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            spare: AtomicPtr::new(ptr::null_mut()),
+            spare_count: AtomicUsize::new(0),
+            #[cfg(test)]
+            allocations: AtomicUsize::new(0),
+        }
+    }
+
+    unsafe fn alloc(&self, t: T) -> *mut Node<T> {
+        if self.bound > 0 {
+            // A thread exiting may still push from another thread local's
+            // destructor, after ours is gone, in which case it mallocs.
+            let top = LOCAL_NODES.try_with(|local| self.take_local(&mut local.borrow_mut()));
+            if let Ok(Some(top)) = top {
+                #[cfg(debug_assertions)]
+                assert!(!(*top).has_value);
+                (*top).next.store(ptr::null_mut(), Ordering::Relaxed);
+                (*top).value = MaybeUninit::new(t);
+                #[cfg(debug_assertions)]
+                { (*top).has_value = true; }
+                return top
+            }
+        }
+        #[cfg(test)]
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Node::new(Some(t))
+    }
+
+    // Pops a node from this thread's nodes for this cache, refilling them
+    // from the stack if they've run out.
+    unsafe fn take_local(&self, local: &mut Vec<LocalNodes>) -> Option<*mut Node<T>> {
+        let i = match local.iter().position(|nodes| nodes.cache == self.id) {
+            Some(i) => i,
+            None => {
+                if self.spare.load(Ordering::Relaxed).is_null() {
+                    return None
+                }
+                if local.len() == LOCAL_CACHES {
+                    local.remove(0);
+                }
+                local.push(LocalNodes { cache: self.id, top: ptr::null_mut(), free: free_chain::<T> });
+                local.len() - 1
+            }
+        };
+        let nodes = &mut local[i];
+        if nodes.top.is_null() {
+            let top = self.spare.swap(ptr::null_mut(), Ordering::Acquire);
+            if top.is_null() {
+                return None
+            }
+            // `free` counts a node before pushing it, so this never takes
+            // the count below the number still on the stack.
+            let mut taken = 0;
+            let mut cur = top;
+            while !cur.is_null() {
+                taken += 1;
+                cur = (*cur).next.load(Ordering::Relaxed);
+            }
+            self.spare_count.fetch_sub(taken, Ordering::Relaxed);
+            nodes.top = top as *mut u8;
+        }
+        let top = nodes.top as *mut Node<T>;
+        nodes.top = (*top).next.load(Ordering::Relaxed) as *mut u8;
+        Some(top)
+    }
+
+    // Only called by the consumer, with a node no producer can reference.
+    unsafe fn free(&self, n: *mut Node<T>) {
+        if self.bound == 0 || self.spare_count.load(Ordering::Relaxed) >= self.bound {
+            let _: Box<Node<T>> = Box::from_raw(n);
+            return
+        }
+        self.spare_count.fetch_add(1, Ordering::Relaxed);
+        let mut top = self.spare.load(Ordering::Relaxed);
+        loop {
+            (*n).next.store(top, Ordering::Relaxed);
+            match self.spare.compare_exchange_weak(top, n, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(cur) => top = cur,
+            }
+        }
+    }
+}
+
+impl<T, Align: Padding, O> Queue<T, Align, O>
+where O: PushOrdering {
 
     /// Pushes a new value onto this queue.
     pub fn push(&self, t: T) {
         unsafe {
-            let n = Node::new(Some(t));
-            let prev = self.head.swap(n, Ordering::AcqRel);
+            // This must happen before the node is visible to the consumer so
+            // that `popped` can never overtake `pushed`.
+            self.pushed.fetch_add(1, Ordering::Relaxed);
+            let n = self.cache.alloc(t);
+            let prev = self.swap_head(n);
             (*prev).next.store(n, Ordering::Release);
         }
     }
 
+    #[inline(always)]
+    fn swap_head(&self, n: *mut Node<T>) -> *mut Node<T> {
+        if O::FENCED {
+            atomic::fence(Ordering::Release);
+        }
+        let prev = self.head.swap(n, O::SWAP);
+        if O::FENCED {
+            atomic::fence(Ordering::Acquire);
+        }
+        prev
+    }
+
+    /// Pushes every value from `iter` onto this queue as one contiguous run.
+    ///
+    /// The nodes are linked together privately first and then spliced in
+    /// with a single `swap` on `head`, so under contention this costs one
+    /// round trip on the shared line for the whole batch rather than one per
+    /// value. The values are popped in iteration order, with no values from
+    /// other producers between them.
+    pub fn push_batch<I: IntoIterator<Item = T>>(&self, iter: I) {
+        unsafe {
+            let mut iter = iter.into_iter();
+            let first = match iter.next() {
+                Some(t) => self.cache.alloc(t),
+                None => return,
+            };
+            let mut last = first;
+            let mut n = 1;
+            for t in iter {
+                let node = self.cache.alloc(t);
+                (*last).next.store(node, Ordering::Relaxed);
+                last = node;
+                n += 1;
+            }
+            self.pushed.fetch_add(n, Ordering::Relaxed);
+            let prev = self.swap_head(last);
+            // Release publishes the links within the chain as well.
+            (*prev).next.store(first, Ordering::Release);
+        }
+    }
+
     /// Pops some data from this queue.
     ///
     /// Note that the current implementation means that this function cannot
@@ -97,51 +455,551 @@ impl<T, Align> Queue<T, Align> {
     /// This inconsistent state means that this queue does indeed have data, but
     /// it does not currently have access to it at this time.
     pub fn pop(&self) -> PopResult<T> {
+        let ret = self.do_pop();
+        #[cfg(feature = "stats")]
+        self.stats.record(&ret);
+        ret
+    }
+
+    /// Returns how often `pop` has produced each result so far.
+    #[cfg(feature = "stats")]
+    pub fn pop_stats(&self) -> PopStats {
+        self.stats.snapshot()
+    }
+
+    fn do_pop(&self) -> PopResult<T> {
         unsafe {
-            let tail = *self.tail.0.get();
+            let tail = *self.tail.get();
             let next = (*tail).next.load(Ordering::Acquire);
 
             if !next.is_null() {
-                *self.tail.0.get() = next;
-                assert!((*tail).value.is_none());
-                assert!((*next).value.is_some());
-                let ret = (*next).value.take().unwrap();
-                let _: Box<Node<T>> = Box::from_raw(tail);
+                let ret = self.take_next(tail, next);
+                let popped = self.popped.load(Ordering::Relaxed);
+                self.popped.store(popped + 1, Ordering::Release);
                 return Data(ret);
             }
 
             if self.head.load(Ordering::Acquire) == tail {Empty} else {Inconsistent}
         }
     }
+
+    // Makes `next` the new stub, taking its value and freeing the old stub,
+    // `tail`. This leaves updating `popped` to the caller.
+    unsafe fn take_next(&self, tail: *mut Node<T>, next: *mut Node<T>) -> T {
+        *self.tail.get() = next;
+        #[cfg(debug_assertions)]
+        assert!(!(*tail).has_value);
+        #[cfg(debug_assertions)]
+        assert!((*next).has_value);
+        // `next` is the new stub, so its value is never read again.
+        let ret = ptr::read((*next).value.as_ptr());
+        #[cfg(debug_assertions)]
+        { (*next).has_value = false; }
+        self.cache.free(tail);
+        ret
+    }
+
+    /// Attempts to peek at the next value in the queue, returning `None` if
+    /// the queue is either empty or inconsistent. Like `pop` this must only
+    /// be called from the consumer.
+    ///
+    /// # Warning
+    /// The reference returned is invalid if it is not used before the consumer
+    /// pops the value off the queue. With the node cache enabled, a producer
+    /// may then reuse the node and overwrite the value pointed to by the
+    /// reference. `peek_with` avoids this by not letting the reference escape.
+    pub fn peek(&self) -> Option<&T> {
+        // This is essentially the same as `pop` with all the popping bits
+        // stripped out.
+        unsafe {
+            let tail = *self.tail.get();
+            let next = (*tail).next.load(Ordering::Acquire);
+            if next.is_null() {
+                None
+            } else {
+                #[cfg(debug_assertions)]
+                assert!((*next).has_value);
+                Some(&*(*next).value.as_ptr())
+            }
+        }
+    }
+
+    /// Calls `f` with the next value in the queue, if there is one, without
+    /// removing it.
+    pub fn peek_with<F, R>(&self, f: F) -> Option<R>
+    where F: FnOnce(&T) -> R {
+        self.peek().map(f)
+    }
+
+    /// Returns an iterator which pops values until the queue is empty.
+    ///
+    /// If the queue is `Inconsistent` the iterator spins for a little while
+    /// waiting for the stalled producer, and ends if it does not make
+    /// progress. Like `pop` this must only be used from the consumer.
+    pub fn try_iter<'a>(&'a self) -> TryIter<'a, T, Align, O> {
+        TryIter { queue: self }
+    }
+
+    /// Pops every value which is currently reachable from the tail into
+    /// `out`, returning how many there were. This stops at the first gap in
+    /// the chain, so values behind a stalled producer are left in the queue.
+    ///
+    /// This does a single pass over the chain and only updates the pop count
+    /// (and stats) once at the end.
+    pub fn drain_available(&self, out: &mut Vec<T>) -> usize {
+        let mut n = 0;
+        unsafe {
+            let mut tail = *self.tail.get();
+            loop {
+                let next = (*tail).next.load(Ordering::Acquire);
+                if next.is_null() { break }
+                out.push(self.take_next(tail, next));
+                tail = next;
+                n += 1;
+            }
+        }
+        if n > 0 {
+            let popped = self.popped.load(Ordering::Relaxed);
+            self.popped.store(popped + n, Ordering::Release);
+            #[cfg(feature = "stats")]
+            self.stats.record_data(n);
+        }
+        n
+    }
+
+    /// Registers a new sender. The queue starts with no senders, so whoever
+    /// creates it must add one before the consumer starts checking for
+    /// disconnection.
+    pub fn add_sender(&self) {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Unregisters a sender, returning true if it was the last one. The
+    /// sender must not push after this.
+    pub fn remove_sender(&self) -> bool {
+        // Release so that any pushes done by this sender are visible to a
+        // consumer which sees the count reach zero.
+        let prev = self.senders.fetch_sub(1, Ordering::Release);
+        assert!(prev > 0);
+        prev == 1
+    }
+
+    /// Pops some data from this queue, as `pop`, but reports `Disconnected`
+    /// instead of `Empty` once the last sender has been removed.
+    ///
+    /// The sender count is read before popping, so every push made before
+    /// the last `remove_sender` is guaranteed to be popped before
+    /// `Disconnected` is returned.
+    pub fn pop_disconnected(&self) -> Result<PopResult<T>, Disconnected> {
+        let disconnected = self.senders.load(Ordering::Acquire) == 0;
+        match self.pop() {
+            Empty if disconnected => Err(Disconnected),
+            ret => Ok(ret),
+        }
+    }
+
+    /// Returns the approximate number of values in the queue.
+    ///
+    /// The count includes pushes which have started but are not yet visible
+    /// to the consumer, so a queue which `pop`s `Inconsistent` can have a
+    /// non-zero length. Under concurrency the result is only a snapshot, it
+    /// may be stale by the time it is returned, but it never underflows: a
+    /// value is counted as pushed before it can be popped, and the pop count
+    /// is read first.
+    pub fn len(&self) -> usize {
+        let popped = self.popped.load(Ordering::Acquire);
+        let pushed = self.pushed.load(Ordering::Relaxed);
+        pushed - popped
+    }
+
+    /// Reports what `pop` would return without consuming anything. Like
+    /// `pop` this must only be called from the consumer.
+    ///
+    /// `InFlight` is returned exactly when some producer has executed the
+    /// `swap` on `head` in `push`, but not the store to the previous head's
+    /// `next` which links the new node in, _and_ every push before it has been
+    /// popped. This window is normally a couple of instructions wide, but is
+    /// unbounded if the producer is pre-empted inside it. Pushes which complete
+    /// while an earlier one is still in the window also report `InFlight`,
+    /// since the chain from `tail` is broken at the stalled node.
+    ///
+    /// `Empty` is only reported when `head == tail`, i.e. no push has even
+    /// started swapping since the last pop. A producer which starts a push
+    /// after this returns will not be seen, so parking on `Empty` still needs
+    /// the producer side to check for a sleeper after pushing.
+    pub fn state(&self) -> QueueState {
+        unsafe {
+            let tail = *self.tail.get();
+            let next = (*tail).next.load(Ordering::Acquire);
+            if !next.is_null() {
+                return QueueState::HasData
+            }
+            if self.head.load(Ordering::Acquire) == tail {
+                QueueState::Empty
+            } else {
+                QueueState::InFlight
+            }
+        }
+    }
+
+    /// Returns true if there is no value, complete or in-flight, in the
+    /// queue. This reads the tail, so like `pop` it must only be called from
+    /// the consumer.
+    pub fn is_empty(&self) -> bool {
+        unsafe { self.head.load(Ordering::Acquire) == *self.tail.get() }
+    }
+}
+
+/// An iterator over the values currently in a queue, see `Queue::try_iter`.
+pub struct TryIter<'a, T: 'a, Align: 'a + Padding, O: 'a> {
+    queue: &'a Queue<T, Align, O>,
+}
+
+impl<'a, T, Align: Padding, O> Iterator for TryIter<'a, T, Align, O>
+where O: PushOrdering {
+    type Item = T;
+
+    // An `Inconsistent` queue is retried until the backoff would yield,
+    // then treated as empty.
+    fn next(&mut self) -> Option<T> {
+        let mut backoff = Backoff::new();
+        loop {
+            match self.queue.pop() {
+                Data(t) => return Some(t),
+                Empty => return None,
+                Inconsistent if backoff.is_yielding() => return None,
+                Inconsistent => backoff.snooze(),
+            }
+        }
+    }
 }
 
-impl<T, Align> Drop for Queue<T, Align> {
+impl<T, Align: Padding, O> Drop for Queue<T, Align, O> {
     fn drop(&mut self) {
         unsafe {
-            let mut cur = *self.tail.0.get();
+            // the stub at `tail` is empty, everything after it holds a value
+            let stub = *self.tail.get();
+            let mut cur = (*stub).next.load(Ordering::Relaxed);
+            let _: Box<Node<T>> = Box::from_raw(stub);
             while !cur.is_null() {
                 let next = (*cur).next.load(Ordering::Relaxed);
+                #[cfg(debug_assertions)]
+                assert!((*cur).has_value);
+                ptr::drop_in_place((*cur).value.as_mut_ptr());
                 let _: Box<Node<T>> = Box::from_raw(cur);
                 cur = next;
             }
+            free_chain::<T>(self.cache.spare.load(Ordering::Relaxed) as *mut u8);
+            // Other producers' nodes are freed once they take nodes for
+            // enough other queues, or exit, but ours can go now.
+            if self.cache.bound > 0 {
+                let id = self.cache.id;
+                let _ = LOCAL_NODES.try_with(|local| local.borrow_mut().retain(|nodes| nodes.cache != id));
+            }
         }
     }
 }
 
-#[cfg(all(test, not(target_os = "emscripten")))]
+impl<T, Align: Padding, O> Layout for Queue<T, Align, O> {
+    fn fields(&self) -> Vec<Field> {
+        vec![
+            Field::new("head", Side::Producer, self, ptr::addr_of!(self.head)),
+            Field::new("pushed", Side::Producer, self, ptr::addr_of!(self.pushed)),
+            Field::new("tail", Side::Consumer, self, ptr::addr_of!(self.tail)),
+            Field::new("popped", Side::Consumer, self, ptr::addr_of!(self.popped)),
+            Field::new("cache.bound", Side::Cold, self, ptr::addr_of!(self.cache.bound)),
+            Field::new("cache.spare", Side::Both, self, ptr::addr_of!(self.cache.spare)),
+            Field::new("cache.spare_count", Side::Both, self, ptr::addr_of!(self.cache.spare_count)),
+            Field::new("cache.id", Side::Cold, self, ptr::addr_of!(self.cache.id)),
+            Field::new("senders", Side::Cold, self, ptr::addr_of!(self.senders)),
+        ]
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
 mod tests {
-    use std::sync::mpsc::channel;
-    use super::{Queue, Data, Empty, Inconsistent};
+    use super::{Queue, QueueState, Disconnected, Data, Empty, Inconsistent};
     use std::sync::Arc;
-    use std::thread;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use verify::DropCounter;
 
     #[test]
     fn test_full() {
         let q: Queue<Box<_>, _> = Queue::new();
-        q.push(box 1);
-        q.push(box 2);
+        q.push(Box::new(1));
+        q.push(Box::new(2));
+    }
+
+    #[test]
+    fn len() {
+        let q = Queue::new();
+        assert_eq!(q.len(), 0);
+        assert!(q.is_empty());
+        q.push(1);
+        q.push(2);
+        assert_eq!(q.len(), 2);
+        assert!(!q.is_empty());
+        match q.pop() { Data(1) => {}, _ => panic!() }
+        assert_eq!(q.len(), 1);
+        match q.pop() { Data(2) => {}, _ => panic!() }
+        assert_eq!(q.len(), 0);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn node_cache_smoke() {
+        let q = Queue::with_node_cache(2);
+        for i in 0..10 {
+            q.push(i);
+            match q.pop() { Data(j) => assert_eq!(i, j), _ => panic!() }
+        }
+        // the first push allocates, after that the node freed by each pop is
+        // reused by the next push
+        assert_eq!(q.cache.allocations.load(Ordering::SeqCst), 1);
+
+        let q: Queue<Box<_>, _> = Queue::aligned_with_node_cache(2);
+        q.push(Box::new(1));
+        q.push(Box::new(2));
+        q.push(Box::new(3));
+        match q.pop() { Data(..) => {}, _ => panic!() }
+        match q.pop() { Data(..) => {}, _ => panic!() }
+        assert_eq!(q.cache.spare_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn no_node_cache_allocates() {
+        let q = Queue::new();
+        for i in 0..10 {
+            q.push(i);
+            match q.pop() { Data(..) => {}, _ => panic!() }
+        }
+        assert_eq!(q.cache.allocations.load(Ordering::SeqCst), 10);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn pop_stats() {
+        let q = Queue::new();
+        match q.pop() { Empty => {}, _ => panic!() }
+        q.push(1);
+        match q.pop() { Data(1) => {}, _ => panic!() }
+
+        // fake a stalled producer by swapping a node into head without
+        // linking it
+        unsafe {
+            let n = super::Node::new(Some(2));
+            let prev = q.head.swap(n, Ordering::AcqRel);
+            for _ in 0..3 {
+                match q.pop() { Inconsistent => {}, _ => panic!() }
+            }
+            (*prev).next.store(n, Ordering::Release);
+        }
+        match q.pop() { Data(2) => {}, _ => panic!() }
+
+        let stats = q.pop_stats();
+        assert_eq!(stats.data, 2);
+        assert_eq!(stats.empty, 1);
+        assert_eq!(stats.inconsistent, 3);
+        // a streak of 3 lands in the 2..=3 bucket
+        assert_eq!(stats.retries, [0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn state() {
+        let q = Queue::new();
+        assert_eq!(q.state(), QueueState::Empty);
+        q.push(1);
+        assert_eq!(q.state(), QueueState::HasData);
+        match q.pop() { Data(1) => {}, _ => panic!() }
+        assert_eq!(q.state(), QueueState::Empty);
+    }
+
+    #[test]
+    fn state_in_flight() {
+        let q = Queue::new();
+        q.push(1);
+        unsafe {
+            // stall a "producer" between the swap and the link, exactly as
+            // `push` does it
+            let n = super::Node::new(Some(2));
+            let prev = q.head.swap(n, Ordering::AcqRel);
+
+            // earlier pushes are still visible
+            assert_eq!(q.state(), QueueState::HasData);
+            match q.pop() { Data(1) => {}, _ => panic!() }
+
+            assert_eq!(q.state(), QueueState::InFlight);
+            match q.pop() { Inconsistent => {}, _ => panic!() }
+
+            // a push which completes behind the stalled one is still hidden
+            q.push(3);
+            assert_eq!(q.state(), QueueState::InFlight);
+
+            (*prev).next.store(n, Ordering::Release);
+        }
+        assert_eq!(q.state(), QueueState::HasData);
+        match q.pop() { Data(2) => {}, _ => panic!() }
+        match q.pop() { Data(3) => {}, _ => panic!() }
+        assert_eq!(q.state(), QueueState::Empty);
+    }
+
+    #[test]
+    fn drop_with_backlog() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        {
+            let q = Queue::new();
+            for _ in 0..10 {
+                q.push(DropCounter(drops.clone()));
+            }
+            match q.pop() { Data(d) => drop(d), _ => panic!() }
+            assert_eq!(drops.load(Ordering::SeqCst), 1);
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 10);
     }
 
+    #[test]
+    fn drop_after_drain() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        {
+            let q = Queue::with_node_cache(4);
+            for _ in 0..10 {
+                q.push(DropCounter(drops.clone()));
+            }
+            for _ in 0..10 {
+                match q.pop() { Data(d) => drop(d), _ => panic!() }
+            }
+            assert_eq!(drops.load(Ordering::SeqCst), 10);
+            // reused nodes must not drop their previous values
+            q.push(DropCounter(drops.clone()));
+            match q.pop() { Data(d) => drop(d), _ => panic!() }
+            assert_eq!(drops.load(Ordering::SeqCst), 11);
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 11);
+    }
+
+    #[test]
+    fn try_iter_and_drain() {
+        let q = Queue::new();
+        assert_eq!(q.try_iter().count(), 0);
+        let mut out = vec![];
+        assert_eq!(q.drain_available(&mut out), 0);
+
+        for i in 0..10 {
+            q.push(i);
+        }
+        assert_eq!(q.try_iter().take(3).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(q.drain_available(&mut out), 7);
+        assert_eq!(out, [3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(q.len(), 0);
+        assert!(q.is_empty());
+
+        // both stop at a stalled producer
+        q.push(10);
+        unsafe {
+            let n = super::Node::new(Some(11));
+            let prev = q.head.swap(n, Ordering::AcqRel);
+            assert_eq!(q.try_iter().collect::<Vec<_>>(), [10]);
+            assert_eq!(q.drain_available(&mut out), 0);
+            (*prev).next.store(n, Ordering::Release);
+        }
+        assert_eq!(q.try_iter().collect::<Vec<_>>(), [11]);
+    }
+
+    #[test]
+    fn peek() {
+        let q = Queue::new();
+        assert!(q.peek().is_none());
+        assert_eq!(q.peek_with(|v: &Vec<i32>| v.len()), None);
+
+        q.push(vec![1]);
+        q.push(vec![2]);
+        // Ensure the borrowchecker works
+        match q.peek() {
+            Some(vec) => assert_eq!(&**vec, &[1]),
+            None => unreachable!()
+        }
+        assert_eq!(q.peek_with(|v| v.len()), Some(1));
+        match q.pop() {
+            Data(vec) => assert_eq!(&*vec, &[1]),
+            _ => unreachable!()
+        }
+        assert_eq!(q.peek_with(|v| v[0]), Some(2));
+        match q.pop() { Data(..) => {}, _ => panic!() }
+        assert!(q.peek().is_none());
+    }
+
+    #[test]
+    fn peek_in_flight() {
+        let q = Queue::new();
+        unsafe {
+            let n = super::Node::new(Some(1));
+            let prev = q.head.swap(n, Ordering::AcqRel);
+            assert_eq!(q.peek(), None);
+            match q.pop() { Inconsistent => {}, _ => panic!() }
+            (*prev).next.store(n, Ordering::Release);
+        }
+        assert_eq!(q.peek(), Some(&1));
+        match q.pop() { Data(1) => {}, _ => panic!() }
+    }
+
+    #[test]
+    fn disconnect() {
+        let q = Queue::new();
+        q.add_sender();
+        q.add_sender();
+        match q.pop_disconnected() { Ok(Empty) => {}, _ => panic!() }
+        q.push(1);
+        assert!(!q.remove_sender());
+        q.push(2);
+        assert!(q.remove_sender());
+        match q.pop_disconnected() { Ok(Data(1)) => {}, _ => panic!() }
+        match q.pop_disconnected() { Ok(Data(2)) => {}, _ => panic!() }
+        match q.pop_disconnected() { Err(Disconnected) => {}, _ => panic!() }
+    }
+
+    #[test]
+    fn push_batch() {
+        let q = Queue::with_node_cache(4);
+        q.push_batch(Vec::new());
+        match q.pop() { Empty => {}, _ => panic!() }
+        q.push(0);
+        q.push_batch(1..5);
+        q.push(5);
+        assert_eq!(q.len(), 6);
+        let mut out = vec![];
+        q.drain_available(&mut out);
+        assert_eq!(out, [0, 1, 2, 3, 4, 5]);
+        match q.pop() { Empty => {}, _ => panic!() }
+    }
+
+    #[test]
+    fn aligned_layout() {
+        use std::ptr::addr_of;
+        use cache_padded::line;
+        use layout::offset;
+        let q = Queue::<u64, _>::aligned();
+        let head = line(offset(&q, addr_of!(q.head)));
+        let tail = line(offset(&q, addr_of!(q.tail)));
+        let cache = line(offset(&q, addr_of!(q.cache)));
+        let senders = line(offset(&q, addr_of!(q.senders)));
+        for &(a, b) in &[(head, tail), (head, cache), (head, senders),
+                         (tail, cache), (tail, senders), (cache, senders)] {
+            assert!(a != b, "{:?}", (head, tail, cache, senders));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments", not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use std::sync::mpsc::channel;
+    use super::{Queue, QueueState, Disconnected, Data, Empty, Inconsistent};
+    use super::{PushOrdering, FencedSwap, Padding};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use backoff::Backoff;
+    use verify::{stress_count, Stamped, StreamChecker};
+
     #[test]
     fn test() {
         let nthreads = 8;
@@ -154,27 +1012,261 @@ mod tests {
         let (tx, rx) = channel();
         let q = Arc::new(q);
 
-        for _ in 0..nthreads {
+        for id in 0..nthreads {
             let tx = tx.clone();
             let q = q.clone();
             thread::spawn(move|| {
                 for i in 0..nmsgs {
-                    q.push(i);
+                    q.push(Stamped::new(id, i));
                 }
                 tx.send(()).unwrap();
             });
         }
 
-        let mut i = 0;
-        while i < nthreads * nmsgs {
+        let mut checker = StreamChecker::new(nthreads as usize);
+        let mut backoff = Backoff::new();
+        while checker.total() < nthreads as u64 * nmsgs {
             match q.pop() {
-                Empty | Inconsistent => {},
-                Data(_) => { i += 1 }
+                Empty | Inconsistent => backoff.snooze(),
+                Data(s) => { checker.check(s); backoff.reset() }
             }
         }
+        checker.finish(nmsgs);
         drop(tx);
         for _ in 0..nthreads {
             rx.recv().unwrap();
         }
     }
+
+    #[test]
+    fn len_stress() {
+        let nthreads = 8;
+        let nmsgs = 10000;
+        let q = Arc::new(Queue::new());
+        let done = Arc::new(AtomicBool::new(false));
+
+        // an observer that is neither producer nor consumer, `len` is
+        // allowed from anywhere
+        let observer = {
+            let q = q.clone();
+            let done = done.clone();
+            thread::spawn(move|| {
+                while !done.load(Ordering::SeqCst) {
+                    // `len` subtracts unchecked, so an underflow panics here
+                    assert!(q.len() <= nthreads * nmsgs);
+                }
+            })
+        };
+
+        let producers: Vec<_> = (0..nthreads).map(|_| {
+            let q = q.clone();
+            thread::spawn(move|| {
+                for i in 0..nmsgs {
+                    q.push(i);
+                }
+            })
+        }).collect();
+
+        let mut i = 0;
+        let mut backoff = Backoff::new();
+        while i < nthreads * nmsgs {
+            assert!(q.len() <= nthreads * nmsgs - i);
+            match q.pop() {
+                Empty | Inconsistent => backoff.snooze(),
+                Data(_) => { i += 1; backoff.reset() }
+            }
+        }
+        for p in producers {
+            p.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        observer.join().unwrap();
+        assert_eq!(q.len(), 0);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn node_cache_stress() {
+        let nthreads = 4;
+        let nmsgs = stress_count(100000);
+        let window = 64;
+        let q = Arc::new(Queue::with_node_cache(128));
+
+        // The producers keep to a window, as a channel's would to a consumer
+        // which keeps up. Otherwise they're done before the consumer has
+        // freed a node for them.
+        let producers: Vec<_> = (0..nthreads).map(|id| {
+            let q = q.clone();
+            thread::spawn(move|| {
+                for i in 0..nmsgs {
+                    while q.len() >= window { thread::yield_now() }
+                    q.push(Stamped::new(id as u16, i as u64));
+                }
+            })
+        }).collect();
+
+        let mut checker = StreamChecker::new(nthreads);
+        let mut backoff = Backoff::new();
+        while checker.total() < (nthreads * nmsgs) as u64 {
+            match q.pop() {
+                Empty | Inconsistent => backoff.snooze(),
+                Data(s) => { checker.check(s); backoff.reset() }
+            }
+        }
+        checker.finish(nmsgs as u64);
+        for p in producers {
+            p.join().unwrap();
+        }
+        // At most `window` nodes are ever in the queue, so once every
+        // producer's cache has some, pushes should hardly ever allocate.
+        let allocations = q.cache.allocations.load(Ordering::SeqCst);
+        assert!(allocations * 10 <= nthreads * nmsgs,
+            "{} allocations for {} pushes", allocations, nthreads * nmsgs);
+        assert!(q.cache.spare_count.load(Ordering::SeqCst) <= 128);
+    }
+
+    #[test]
+    fn state_in_flight_thread() {
+        use std::sync::Barrier;
+
+        // the same window, but with a real second thread stalled in it
+        let q = Arc::new(Queue::new());
+        let stalled = Arc::new(Barrier::new(2));
+        let resume = Arc::new(Barrier::new(2));
+        let producer = {
+            let (q, stalled, resume) = (q.clone(), stalled.clone(), resume.clone());
+            thread::spawn(move|| unsafe {
+                let n = super::Node::new(Some(1));
+                let prev = q.head.swap(n, Ordering::AcqRel);
+                stalled.wait();
+                resume.wait();
+                (*prev).next.store(n, Ordering::Release);
+            })
+        };
+        stalled.wait();
+        assert_eq!(q.state(), QueueState::InFlight);
+        resume.wait();
+        producer.join().unwrap();
+        assert_eq!(q.state(), QueueState::HasData);
+        match q.pop() { Data(1) => {}, _ => panic!() }
+    }
+
+    #[test]
+    fn per_producer_fifo() {
+        for &(nthreads, nmsgs) in &[(1, 100000), (2, 50000), (4, 25000), (8, 10000), (16, 1000)] {
+            let nmsgs = stress_count(nmsgs);
+            fifo_run(Queue::new(), nthreads, nmsgs);
+            fifo_run(Queue::aligned_with_node_cache(64), nthreads, nmsgs);
+            // TSan doesn't model fences, so it can't see FencedSwap's
+            // ordering.
+            if !cfg!(sanitize = "thread") {
+                fifo_run(Queue::<_, _, FencedSwap>::aligned_with_ordering(), nthreads, nmsgs);
+            }
+        }
+
+        fn fifo_run<A, O>(q: Queue<Stamped, A, O>, nthreads: usize, nmsgs: usize)
+        where A: Padding + Send + Sync + 'static, O: PushOrdering + Send + Sync + 'static {
+            let q = Arc::new(q);
+            let producers: Vec<_> = (0..nthreads).map(|id| {
+                let q = q.clone();
+                thread::spawn(move|| {
+                    // odd producers mix in batches of varying size
+                    let mut seq = 0;
+                    while seq < nmsgs {
+                        let batch = if id % 2 == 0 { 1 } else { 1 + seq % 7 };
+                        let end = ::std::cmp::min(seq + batch, nmsgs);
+                        if batch == 1 {
+                            q.push(Stamped::new(id as u16, seq as u64));
+                        } else {
+                            q.push_batch((seq..end).map(|seq| Stamped::new(id as u16, seq as u64)));
+                        }
+                        seq = end;
+                    }
+                })
+            }).collect();
+
+            let mut checker = StreamChecker::new(nthreads);
+            let mut backoff = Backoff::new();
+            while checker.total() < (nthreads * nmsgs) as u64 {
+                match q.pop() {
+                    Empty | Inconsistent => backoff.snooze(),
+                    Data(s) => { checker.check(s); backoff.reset() }
+                }
+            }
+            for p in producers {
+                p.join().unwrap();
+            }
+            match q.pop() {
+                Empty => {}
+                Inconsistent | Data(..) => panic!()
+            }
+            checker.finish(nmsgs as u64);
+        }
+    }
+
+    #[test]
+    fn drain_in_chunks() {
+        let nthreads = 4;
+        let nmsgs = 20000;
+        let q = Arc::new(Queue::with_node_cache(16));
+
+        let producers: Vec<_> = (0..nthreads).map(|id| {
+            let q = q.clone();
+            thread::spawn(move|| {
+                for seq in 0..nmsgs {
+                    q.push(Stamped::new(id, seq));
+                }
+            })
+        }).collect();
+
+        let mut checker = StreamChecker::new(nthreads as usize);
+        let mut chunk = vec![];
+        let mut use_drain = false;
+        while checker.total() < nthreads as u64 * nmsgs {
+            chunk.clear();
+            if use_drain {
+                q.drain_available(&mut chunk);
+            } else {
+                chunk.extend(q.try_iter().take(100));
+            }
+            use_drain = !use_drain;
+            for &s in &chunk {
+                checker.check(s);
+            }
+        }
+        for p in producers {
+            p.join().unwrap();
+        }
+        checker.finish(nmsgs);
+        assert_eq!(q.len(), 0);
+    }
+
+    #[test]
+    fn disconnect_after_last_push() {
+        // the last sender pushes and immediately leaves, the consumer must
+        // see the message before the disconnect
+        for _ in 0..1000 {
+            let q = Arc::new(Queue::new());
+            q.add_sender();
+            let sender = {
+                let q = q.clone();
+                thread::spawn(move|| {
+                    q.push(1);
+                    assert!(q.remove_sender());
+                })
+            };
+            let mut got = false;
+            let mut backoff = Backoff::new();
+            loop {
+                match q.pop_disconnected() {
+                    Ok(Data(1)) => { assert!(!got); got = true }
+                    Ok(Data(..)) => panic!(),
+                    Ok(Empty) | Ok(Inconsistent) => backoff.snooze(),
+                    Err(Disconnected) => break,
+                }
+            }
+            assert!(got);
+            sender.join().unwrap();
+        }
+    }
 }
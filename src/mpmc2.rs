@@ -0,0 +1,246 @@
+//! A genuinely multi-consumer variant of std's mpsc queue.
+//!
+//! The node layout and the push side are identical to `mpmc`, the difference
+//! is in `pop`: instead of the tail being owned by a single consumer, a
+//! consumer claims the tail by CASing it to a `POPPING` sentinel, pops exactly
+//! as the single consumer queue would, and then publishes the new tail. Only
+//! the consumer holding the claim ever dereferences the tail node, so freeing
+//! it is just as safe as in the single consumer case, at the cost of all
+//! consumers contending on the tail's cache line.
+
+pub use self::PopResult::*;
+
+use std::ptr;
+
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use cache_padded::Padding;
+use layout::{Field, Layout, Side};
+
+/// A result of the `pop` function.
+pub enum PopResult<T> {
+    /// Some data has been popped
+    Data(T),
+    /// The queue is empty
+    Empty,
+    /// The queue is in an inconsistent state. Either some pushers have yet to
+    /// make enough progress in order allow a pop to succeed, or another
+    /// consumer is currently popping. It is recommended that a pop() occur
+    /// "in the near future" in order to see if progress has been made.
+    Inconsistent,
+}
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    value: Option<T>,
+}
+
+pub use cache_padded::{NoAlign, CacheAligned};
+
+/// The multi-producer multi-consumer structure. This is not cloneable, but it
+/// may be safely shared among any number of pushers and poppers.
+pub struct Queue<T, Align: Padding> {
+    head: AtomicPtr<Node<T>>,
+
+    tail: Align::Padded<AtomicPtr<Node<T>>>,
+}
+
+unsafe impl<T: Send, Align: Padding> Send for Queue<T, Align> { }
+unsafe impl<T: Send, Align: Padding> Sync for Queue<T, Align> { }
+
+impl<T> Node<T> {
+    unsafe fn new(v: Option<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value: v,
+        }))
+    }
+}
+
+// The value stored in the tail while a consumer is popping. No real node can
+// live at this address.
+fn popping<T>() -> *mut Node<T> {
+    1 as *mut Node<T>
+}
+
+impl<T> Queue<T, NoAlign> {
+    /// Creates a new queue that is safe to share among multiple producers and
+    /// multiple consumers.
+    pub fn new() -> Self {
+        let stub = unsafe { Node::new(None) };
+        Queue {
+            head: AtomicPtr::new(stub),
+            tail: NoAlign::pad(AtomicPtr::new(stub)),
+        }
+    }
+}
+
+impl<T> Queue<T, CacheAligned> {
+    pub fn aligned() -> Self {
+        let stub = unsafe { Node::new(None) };
+        Queue {
+            head: AtomicPtr::new(stub),
+            tail: CacheAligned::pad(AtomicPtr::new(stub)),
+        }
+    }
+}
+
+impl<T, Align: Padding> Queue<T, Align> {
+
+    /// Pushes a new value onto this queue.
+    pub fn push(&self, t: T) {
+        unsafe {
+            let n = Node::new(Some(t));
+            let prev = self.head.swap(n, Ordering::AcqRel);
+            (*prev).next.store(n, Ordering::Release);
+        }
+    }
+
+    /// Pops some data from this queue.
+    ///
+    /// As with `mpmc::Queue` this can return `Inconsistent` when a pusher has
+    /// been pre-empted mid-push. Additionally, if another consumer is in the
+    /// middle of a pop this returns `Inconsistent` rather than waiting for it.
+    pub fn pop(&self) -> PopResult<T> {
+        unsafe {
+            let tail = self.tail.load(Ordering::Acquire);
+            if tail == popping() {
+                return Inconsistent
+            }
+            if self.tail.compare_exchange(tail, popping(), Ordering::Acquire, Ordering::Relaxed).is_err() {
+                return Inconsistent
+            }
+
+            // We now own the tail, no other consumer can observe it until we
+            // publish a new one.
+            let next = (*tail).next.load(Ordering::Acquire);
+
+            if !next.is_null() {
+                assert!((*tail).value.is_none());
+                assert!((*next).value.is_some());
+                // Take the value before publishing `next` as the tail, after
+                // that another consumer may claim it.
+                let ret = (*next).value.take().unwrap();
+                self.tail.store(next, Ordering::Release);
+                let _: Box<Node<T>> = Box::from_raw(tail);
+                return Data(ret);
+            }
+
+            let ret = if self.head.load(Ordering::Acquire) == tail {Empty} else {Inconsistent};
+            self.tail.store(tail, Ordering::Release);
+            ret
+        }
+    }
+}
+
+impl<T, Align: Padding> Drop for Queue<T, Align> {
+    fn drop(&mut self) {
+        unsafe {
+            let mut cur = self.tail.load(Ordering::Relaxed);
+            while !cur.is_null() {
+                let next = (*cur).next.load(Ordering::Relaxed);
+                let _: Box<Node<T>> = Box::from_raw(cur);
+                cur = next;
+            }
+        }
+    }
+}
+
+impl<T, Align: Padding> Layout for Queue<T, Align> {
+    fn fields(&self) -> Vec<Field> {
+        vec![
+            Field::new("head", Side::Producer, self, ptr::addr_of!(self.head)),
+            Field::new("tail", Side::Consumer, self, ptr::addr_of!(self.tail)),
+        ]
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
+mod tests {
+    use super::{Queue, Data, Empty, Inconsistent};
+
+    #[test]
+    fn test_full() {
+        let q: Queue<Box<_>, _> = Queue::new();
+        q.push(Box::new(1));
+        q.push(Box::new(2));
+    }
+
+    #[test]
+    fn smoke() {
+        let q = Queue::aligned();
+        match q.pop() {
+            Empty => {}
+            Inconsistent | Data(..) => panic!()
+        }
+        q.push(1);
+        q.push(2);
+        match q.pop() { Data(1) => {}, _ => panic!() }
+        match q.pop() { Data(2) => {}, _ => panic!() }
+        match q.pop() {
+            Empty => {}
+            Inconsistent | Data(..) => panic!()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments", not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use super::{Queue, Data, Empty, Inconsistent};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use backoff::Backoff;
+    use verify::{ChecksumAccumulator, Stamped};
+
+    #[test]
+    fn exactly_once() {
+        let nproducers = 4;
+        let nconsumers = 4;
+        let nmsgs = 10000;
+        let q = Arc::new(Queue::new());
+        let received = Arc::new(AtomicUsize::new(0));
+
+        let producers: Vec<_> = (0..nproducers).map(|p| {
+            let q = q.clone();
+            thread::spawn(move|| {
+                for i in 0..nmsgs {
+                    q.push(Stamped::new(p, i));
+                }
+            })
+        }).collect();
+
+        let consumers: Vec<_> = (0..nconsumers).map(|_| {
+            let q = q.clone();
+            let received = received.clone();
+            thread::spawn(move|| {
+                let mut sums = ChecksumAccumulator::new(nproducers as usize);
+                let mut backoff = Backoff::new();
+                while received.load(Ordering::SeqCst) < (nproducers as u64 * nmsgs) as usize {
+                    match q.pop() {
+                        Empty | Inconsistent => backoff.snooze(),
+                        Data(s) => {
+                            backoff.reset();
+                            sums.add(s);
+                            received.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                }
+                sums
+            })
+        }).collect();
+
+        for p in producers {
+            p.join().unwrap();
+        }
+        let mut sums = ChecksumAccumulator::new(nproducers as usize);
+        for c in consumers {
+            sums.merge(&c.join().unwrap());
+        }
+        sums.finish(nmsgs);
+        match q.pop() {
+            Empty => {}
+            Inconsistent | Data(..) => panic!()
+        }
+    }
+}
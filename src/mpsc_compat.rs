@@ -0,0 +1,73 @@
+/// A drop-in for `std::sync::mpsc`
+///
+/// The same names and signatures as `std::sync::mpsc`, built on stream2's
+/// channel (which upgrades to `shared` when the sender is cloned), so that an
+/// application can be pointed at these channels by changing its `use` line.
+///
+/// What std has and this crate does not yet (currently `sync_channel`) is
+/// still here, so the application compiles, but panics when it is used.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use spsc;
+use stream2::{self, Message};
+
+pub use stream2::{channel, SendError, RecvError, TryRecvError, RecvTimeoutError, TrySendError};
+
+type Queue<T> = spsc::CNQueue<Message<T>>;
+
+pub type Sender<T> = stream2::Sender<T, Queue<T>>;
+pub type Receiver<T> = stream2::Receiver<T, Queue<T>>;
+pub type Iter<'a, T> = stream2::Iter<'a, T, Queue<T>>;
+pub type TryIter<'a, T> = stream2::TryIter<'a, T, Queue<T>>;
+pub type IntoIter<T> = stream2::IntoIter<T, Queue<T>>;
+
+fn unimplemented(what: &str) -> ! {
+    panic!("{} is not implemented in std_spsc_is_slow", what)
+}
+
+/// Panics, bounded channels are not implemented here yet.
+pub fn sync_channel<T>(_bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    unimplemented("sync_channel")
+}
+
+/// Can't be created, as `sync_channel` panics, but lets code naming it build.
+pub struct SyncSender<T> {
+    _pd: PhantomData<*const T>,
+}
+
+unsafe impl<T: Send> Send for SyncSender<T> {}
+
+impl<T> SyncSender<T> {
+    pub fn send(&self, _t: T) -> Result<(), SendError<T>> {
+        unimplemented("SyncSender::send")
+    }
+
+    pub fn try_send(&self, _t: T) -> Result<(), TrySendError<T>> {
+        unimplemented("SyncSender::try_send")
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        unimplemented("SyncSender::clone")
+    }
+}
+
+impl<T> fmt::Debug for SyncSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SyncSender { .. }")
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "not implemented in std_spsc_is_slow")]
+    fn sync_channel_panics() {
+        let _ = sync_channel::<i32>(1);
+    }
+}
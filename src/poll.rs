@@ -0,0 +1,172 @@
+/// Polling several queues
+///
+/// A non-blocking check of which of several queues, or stream2 packets, has
+/// something to receive, for a consumer multiplexing them by hand until there
+/// is a full select. Everything is checked with `peek`, so nothing is
+/// consumed, and like `peek` these may only be called by the consumer of
+/// every queue polled.
+///
+/// `poll_multiple` always starts from the first queue, so a queue which is
+/// never empty hides all those after it. `FairPoll` remembers which queue it
+/// returned last and starts after it the next time.
+
+use stream2::{Packet, Queue, Message};
+
+/// Returns the index of the first queue with data, if any has some.
+pub fn poll_multiple<T, Q: Queue<T>>(queues: &[&Q]) -> Option<usize> {
+    first_ready(queues.len(), 0, |i| queues[i].peek().is_some())
+}
+
+/// Returns the index of the first packet whose receiver has something to
+/// receive, be it data, an upgrade or the disconnect, if any has.
+pub fn poll_packets<Q, T>(packets: &[&Packet<Q, T>]) -> Option<usize>
+where Q: Queue<Message<T>> {
+    first_ready(packets.len(), 0, |i| packets[i].can_recv())
+}
+
+/// Polls round robin, starting after whichever queue it returned last.
+#[derive(Debug, Default)]
+pub struct FairPoll {
+    next: usize,
+}
+
+impl FairPoll {
+    pub fn new() -> Self {
+        FairPoll { next: 0 }
+    }
+
+    pub fn poll<T, Q: Queue<T>>(&mut self, queues: &[&Q]) -> Option<usize> {
+        let ready = first_ready(queues.len(), self.next, |i| queues[i].peek().is_some());
+        self.advance(ready)
+    }
+
+    pub fn poll_packets<Q, T>(&mut self, packets: &[&Packet<Q, T>]) -> Option<usize>
+    where Q: Queue<Message<T>> {
+        let ready = first_ready(packets.len(), self.next, |i| packets[i].can_recv());
+        self.advance(ready)
+    }
+
+    fn advance(&mut self, ready: Option<usize>) -> Option<usize> {
+        if let Some(i) = ready {
+            self.next = i + 1;
+        }
+        ready
+    }
+}
+
+// Checks each of `len` queues once, beginning at `start` and wrapping around.
+fn first_ready<F: Fn(usize) -> bool>(len: usize, start: usize, ready: F) -> Option<usize> {
+    if len == 0 { return None }
+    (0..len).map(|i| (start + i) % len).find(|&i| ready(i))
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use shared::SharedPacket;
+    use spsc;
+    use stream2;
+
+    type Q<T> = spsc::CNQueue<T>;
+
+    fn queues(n: usize) -> Vec<Q<i32>> {
+        (0..n).map(|_| Queue::new(128)).collect()
+    }
+
+    #[test]
+    fn empty() {
+        let qs = queues(4);
+        let refs: Vec<_> = qs.iter().collect();
+        assert_eq!(poll_multiple(&refs), None);
+        assert_eq!(FairPoll::new().poll(&refs), None);
+        assert_eq!(poll_multiple::<i32, Q<i32>>(&[]), None);
+        assert_eq!(FairPoll::new().poll::<i32, Q<i32>>(&[]), None);
+    }
+
+    #[test]
+    fn first_with_data() {
+        let qs = queues(4);
+        let refs: Vec<_> = qs.iter().collect();
+        qs[2].push(1);
+        assert_eq!(poll_multiple(&refs), Some(2));
+        // Nothing was consumed.
+        assert_eq!(poll_multiple(&refs), Some(2));
+        qs[1].push(2);
+        assert_eq!(poll_multiple(&refs), Some(1));
+        assert_eq!(qs[1].pop(), Some(2));
+        assert_eq!(poll_multiple(&refs), Some(2));
+        assert_eq!(qs[2].pop(), Some(1));
+        assert_eq!(poll_multiple(&refs), None);
+    }
+
+    #[test]
+    fn fair_rotates() {
+        let qs = queues(4);
+        let refs: Vec<_> = qs.iter().collect();
+        for q in &qs {
+            q.push(0);
+        }
+        // Every queue stays ready, so the plain poll only ever sees the first
+        // but the fair one goes round them all in turn.
+        let mut fair = FairPoll::new();
+        let polled: Vec<_> = (0..8).map(|_| fair.poll(&refs).unwrap()).collect();
+        assert_eq!(polled, vec![0, 1, 2, 3, 0, 1, 2, 3]);
+        assert_eq!(poll_multiple(&refs), Some(0));
+    }
+
+    #[test]
+    fn fair_skips_empty() {
+        let qs = queues(4);
+        let refs: Vec<_> = qs.iter().collect();
+        qs[0].push(0);
+        qs[2].push(0);
+        let mut fair = FairPoll::new();
+        let polled: Vec<_> = (0..4).map(|_| fair.poll(&refs).unwrap()).collect();
+        assert_eq!(polled, vec![0, 2, 0, 2]);
+        // A miss doesn't move the starting point.
+        assert_eq!(qs[0].pop(), Some(0));
+        assert_eq!(qs[2].pop(), Some(0));
+        assert_eq!(fair.poll(&refs), None);
+        qs[1].push(0);
+        qs[3].push(0);
+        assert_eq!(fair.poll(&refs), Some(3));
+        assert_eq!(fair.poll(&refs), Some(1));
+    }
+
+    #[test]
+    fn packets() {
+        let ps: Vec<_> = (0..3).map(|_| stream2::Packet::<Q<_>, i32>::new()).collect();
+        let refs: Vec<_> = ps.iter().collect();
+        assert_eq!(poll_packets(&refs), None);
+
+        // A GoUp at the head is something to receive, if not data.
+        let up = Arc::new(SharedPacket::new());
+        match ps[1].upgrade(up.clone()) {
+            stream2::UpSuccess => {}
+            _ => panic!(),
+        }
+        assert_eq!(poll_packets(&refs), Some(1));
+        assert_eq!(FairPoll::new().poll_packets(&refs), Some(1));
+        match ps[1].try_recv() {
+            Err(stream2::Upgraded(..)) => {}
+            _ => panic!(),
+        }
+        assert_eq!(poll_packets(&refs), None);
+
+        // As is the disconnect.
+        ps[2].drop_chan();
+        assert_eq!(poll_packets(&refs), Some(2));
+
+        ps[0].send(1).unwrap();
+        assert_eq!(poll_packets(&refs), Some(0));
+
+        for p in &ps {
+            p.drop_chan();
+            p.drop_port();
+        }
+        up.drop_chan();
+        up.drop_port();
+    }
+}
@@ -0,0 +1,440 @@
+//! Two spsc queues, high and low, behind one consumer which always takes
+//! from high first, so that control messages overtake bulk data.
+//!
+//! `pop` looks at low before it looks at high. Whatever was pushed to high
+//! before the value it finds in low is then visible too, so a high value is
+//! never popped after a low value which was pushed after it. `pop_fair` lets
+//! a low value through after every `ratio` high ones, so that a busy high
+//! lane can't starve the low one.
+//!
+//! `channel` splits a queue into a `Producer` and a `Consumer`, which see
+//! each other go.
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use spsc;
+use stream2;
+
+pub struct PriorityQueue<T, Q = spsc::CNQueue<T>> {
+    high: Q,
+    low: Q,
+    // high values popped since the last low one, for pop_fair
+    high_streak: UnsafeCell<usize>,
+
+    // set as the halves from `channel` are dropped
+    producer_dropped: AtomicBool,
+    consumer_dropped: AtomicBool,
+    _pd: PhantomData<T>,
+}
+
+unsafe impl<T: Send, Q: Send> Send for PriorityQueue<T, Q> { }
+unsafe impl<T: Send, Q: Sync> Sync for PriorityQueue<T, Q> { }
+
+/// The lane a value was popped from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    High,
+    Low,
+}
+
+impl<T, Q: stream2::Queue<T>> PriorityQueue<T, Q> {
+    /// Creates a new queue, each lane with a node cache of `bound`.
+    ///
+    /// This is unsafe as the type system doesn't enforce a single
+    /// consumer-producer relationship, see `channel` for one which does.
+    pub unsafe fn new(bound: usize) -> Self {
+        PriorityQueue {
+            high: Q::new(bound),
+            low: Q::new(bound),
+            high_streak: UnsafeCell::new(0),
+            producer_dropped: AtomicBool::new(false),
+            consumer_dropped: AtomicBool::new(false),
+            _pd: PhantomData,
+        }
+    }
+
+    /// Pushes a value onto the high lane. Note that to use this function
+    /// safely, it must be externally guaranteed that there is only one
+    /// pusher.
+    pub fn push_high(&self, t: T) {
+        self.high.push(t)
+    }
+
+    /// Pushes a value onto the low lane. Note that to use this function
+    /// safely, it must be externally guaranteed that there is only one
+    /// pusher.
+    pub fn push_low(&self, t: T) {
+        self.low.push(t)
+    }
+
+    /// Pops a value, from the high lane if it has any, and says which lane
+    /// that was.
+    pub fn pop_lane(&self) -> Option<(T, Lane)> {
+        // Look at low first, see the module docs.
+        if self.low.peek().is_none() {
+            return self.high.pop().map(|t| (t, Lane::High))
+        }
+        match self.high.pop() {
+            Some(t) => Some((t, Lane::High)),
+            None => self.low.pop().map(|t| (t, Lane::Low)),
+        }
+    }
+
+    /// Pops a value, from the high lane if it has any. Note that to use this
+    /// function safely, it must be externally guaranteed that there is only
+    /// one popper.
+    pub fn pop(&self) -> Option<T> {
+        self.pop_lane().map(|(t, _)| t)
+    }
+
+    /// Pops as `pop` does, except that after `ratio` high values in a row
+    /// the next pop takes from the low lane, if it has anything.
+    pub fn pop_fair(&self, ratio: usize) -> Option<T> {
+        assert!(ratio > 0, "ratio must be at least 1");
+        unsafe {
+            let streak = &mut *self.high_streak.get();
+            if *streak >= ratio {
+                if let Some(t) = self.low.pop() {
+                    *streak = 0;
+                    return Some(t)
+                }
+            }
+            match self.pop_lane() {
+                Some((t, Lane::High)) => {
+                    *streak += 1;
+                    Some(t)
+                }
+                Some((t, Lane::Low)) => {
+                    *streak = 0;
+                    Some(t)
+                }
+                None => None,
+            }
+        }
+    }
+
+    /// Returns the value the next `pop` would, from the high lane if it has
+    /// any. The same single consumer rules as `pop` apply.
+    pub fn peek(&self) -> Option<&mut T> {
+        if self.low.peek().is_none() {
+            return self.high.peek()
+        }
+        match self.high.peek() {
+            Some(t) => Some(t),
+            None => self.low.peek(),
+        }
+    }
+}
+
+// A plain push is a low priority one, so that a Packet's own messages
+// queue behind anything pushed high.
+impl<T, Q: stream2::Queue<T>> stream2::Queue<T> for PriorityQueue<T, Q> {
+    fn new(bound: usize) -> Self {
+        unsafe { PriorityQueue::new(bound) }
+    }
+
+    fn push(&self, t: T) {
+        self.push_low(t)
+    }
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
+
+    fn peek(&self) -> Option<&mut T> {
+        self.peek()
+    }
+}
+
+/// Returned by `Consumer::pop` once the producer is gone and both lanes
+/// are empty.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+/// Splits a new queue, each lane with a node cache of `bound`, into its
+/// producing and consuming halves.
+pub fn channel<T, Q>(bound: usize) -> (Producer<T, Q>, Consumer<T, Q>)
+where Q: stream2::Queue<T> {
+    let queue = Arc::new(unsafe { PriorityQueue::new(bound) });
+    (Producer { queue: queue.clone() }, Consumer { queue: queue })
+}
+
+/// The producer half of a queue. Its pushes hand the value back once the
+/// consumer is gone.
+pub struct Producer<T, Q = spsc::CNQueue<T>>
+where Q: stream2::Queue<T> {
+    queue: Arc<PriorityQueue<T, Q>>,
+}
+
+/// The consumer half of a queue.
+pub struct Consumer<T, Q = spsc::CNQueue<T>>
+where Q: stream2::Queue<T> {
+    queue: Arc<PriorityQueue<T, Q>>,
+}
+
+impl<T, Q: stream2::Queue<T>> Producer<T, Q> {
+    pub fn push_high(&mut self, t: T) -> Result<(), T> {
+        if self.queue.consumer_dropped.load(Ordering::Acquire) {
+            return Err(t)
+        }
+        self.queue.push_high(t);
+        Ok(())
+    }
+
+    pub fn push_low(&mut self, t: T) -> Result<(), T> {
+        if self.queue.consumer_dropped.load(Ordering::Acquire) {
+            return Err(t)
+        }
+        self.queue.push_low(t);
+        Ok(())
+    }
+}
+
+impl<T, Q: stream2::Queue<T>> Consumer<T, Q> {
+    /// Pops a value, from the high lane if it has any, returning
+    /// `Ok(None)` if both are empty and `Err(Disconnected)` if they will
+    /// stay that way.
+    pub fn pop(&mut self) -> Result<Option<T>, Disconnected> {
+        self.pop_with(|queue| queue.pop())
+    }
+
+    /// `pop`, also saying which lane the value came from.
+    pub fn pop_lane(&mut self) -> Result<Option<(T, Lane)>, Disconnected> {
+        self.pop_with(|queue| queue.pop_lane())
+    }
+
+    /// `pop`, letting a low value through after every `ratio` high ones.
+    pub fn pop_fair(&mut self, ratio: usize) -> Result<Option<T>, Disconnected> {
+        self.pop_with(|queue| queue.pop_fair(ratio))
+    }
+
+    fn pop_with<U, F>(&mut self, pop: F) -> Result<Option<U>, Disconnected>
+    where F: FnOnce(&PriorityQueue<T, Q>) -> Option<U> {
+        // Look for the producer's drop before popping, so that no push can
+        // land between a last empty pop and seeing it gone.
+        let producer_dropped = self.queue.producer_dropped.load(Ordering::Acquire);
+        match pop(&self.queue) {
+            Some(t) => Ok(Some(t)),
+            None if producer_dropped => Err(Disconnected),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T, Q: stream2::Queue<T>> Drop for Producer<T, Q> {
+    fn drop(&mut self) {
+        self.queue.producer_dropped.store(true, Ordering::Release);
+    }
+}
+
+impl<T, Q: stream2::Queue<T>> Drop for Consumer<T, Q> {
+    fn drop(&mut self) {
+        self.queue.consumer_dropped.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use spsc;
+    use spsc_seg;
+    use super::{channel, Disconnected, Lane, PriorityQueue};
+    use verify::DropCounter;
+
+    type Queue<T> = PriorityQueue<T, spsc::CNQueue<T>>;
+
+    #[test]
+    fn lanes_are_fifo() {
+        unsafe {
+            let q = Queue::new(0);
+            for i in 0..5 {
+                q.push_low(100 + i);
+                q.push_high(i);
+            }
+            for i in 0..5 {
+                assert_eq!(q.pop_lane(), Some((i, Lane::High)));
+            }
+            assert_eq!(*q.peek().unwrap(), 100);
+            for i in 0..5 {
+                assert_eq!(q.pop_lane(), Some((100 + i, Lane::Low)));
+            }
+            assert_eq!(q.pop(), None);
+            assert!(q.peek().is_none());
+        }
+    }
+
+    #[test]
+    fn high_overtakes_low() {
+        unsafe {
+            let q = Queue::new(0);
+            q.push_low(1);
+            q.push_low(2);
+            assert_eq!(q.pop(), Some(1));
+            q.push_high(10);
+            assert_eq!(*q.peek().unwrap(), 10);
+            assert_eq!(q.pop(), Some(10));
+            assert_eq!(q.pop(), Some(2));
+        }
+    }
+
+    #[test]
+    fn pop_fair_ratio() {
+        unsafe {
+            let q: Queue<(Lane, usize)> = Queue::new(0);
+            for i in 0..20 {
+                q.push_high((Lane::High, i));
+                q.push_low((Lane::Low, i));
+            }
+            let mut lanes = vec![];
+            while let Some((lane, _)) = q.pop_fair(3) {
+                lanes.push(lane);
+            }
+            // 3 high, 1 low until high runs out, then the rest of low
+            let mut expected = vec![];
+            for _ in 0..6 {
+                expected.extend(&[Lane::High, Lane::High, Lane::High, Lane::Low]);
+            }
+            expected.extend(&[Lane::High, Lane::High]);
+            expected.extend((0..14).map(|_| Lane::Low));
+            assert_eq!(lanes, expected);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn pop_fair_zero_ratio() {
+        unsafe {
+            let q: Queue<u8> = Queue::new(0);
+            q.pop_fair(0);
+        }
+    }
+
+    #[test]
+    fn producer_drop_drains_both_lanes() {
+        let (mut tx, mut rx) = channel::<_, spsc::CNQueue<_>>(0);
+        tx.push_low(2).unwrap();
+        tx.push_high(1).unwrap();
+        assert_eq!(rx.pop(), Ok(Some(1)));
+        tx.push_high(3).unwrap();
+        drop(tx);
+        assert_eq!(rx.pop(), Ok(Some(3)));
+        assert_eq!(rx.pop_fair(1), Ok(Some(2)));
+        assert_eq!(rx.pop(), Err(Disconnected));
+    }
+
+    #[test]
+    fn consumer_drop() {
+        let (mut tx, rx) = channel::<_, spsc_seg::Queue<_, spsc_seg::NoAlign>>(0);
+        drop(rx);
+        assert_eq!(tx.push_high(1), Err(1));
+        assert_eq!(tx.push_low(2), Err(2));
+    }
+
+    #[test]
+    fn drop_with_both_lanes_full() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        {
+            let (mut tx, mut rx) = channel::<_, spsc::CNQueue<_>>(0);
+            for _ in 0..5 {
+                assert!(tx.push_high(DropCounter(drops.clone())).is_ok());
+                assert!(tx.push_low(DropCounter(drops.clone())).is_ok());
+            }
+            drop(rx.pop().unwrap());
+            assert_eq!(drops.load(Ordering::SeqCst), 1);
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 10);
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use std::thread;
+    use spsc;
+    use stream2;
+    use super::{channel, Disconnected, Lane, PriorityQueue};
+    use verify::stress_count;
+
+    #[test]
+    fn high_beats_low() {
+        const COUNT: usize = stress_count(100_000);
+        // Each low value is pushed after the high one with the same number,
+        // so must come out after it.
+        let (mut tx, mut rx) = channel::<_, spsc::CNQueue<_>>(128);
+        let producer = thread::spawn(move || {
+            for i in 0..COUNT {
+                tx.push_high(i).unwrap();
+                tx.push_low(i).unwrap();
+            }
+        });
+        let (mut next_high, mut next_low) = (0, 0);
+        loop {
+            match rx.pop_lane() {
+                Ok(Some((i, Lane::High))) => {
+                    assert_eq!(i, next_high);
+                    next_high += 1;
+                }
+                Ok(Some((i, Lane::Low))) => {
+                    assert_eq!(i, next_low);
+                    assert!(i < next_high, "low {} before its high", i);
+                    next_low += 1;
+                }
+                Ok(None) => {}
+                Err(Disconnected) => break,
+            }
+        }
+        assert_eq!((next_high, next_low), (COUNT, COUNT));
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn pop_fair_under_load() {
+        // Low always has something, so at least every 5th pop is low
+        // however fast high is filled.
+        let ratio = 4;
+        let pops = 50_000;
+        let (mut tx, mut rx) = channel::<_, spsc::CNQueue<_>>(128);
+        for _ in 0..pops {
+            tx.push_low(Lane::Low).unwrap();
+        }
+        let producer = thread::spawn(move || {
+            for _ in 0..pops * 2 {
+                let _ = tx.push_high(Lane::High);
+            }
+        });
+        let mut lows = 0;
+        let mut streak = 0;
+        for _ in 0..pops {
+            loop {
+                match rx.pop_fair(ratio).unwrap() {
+                    Some(Lane::Low) => { lows += 1; streak = 0 }
+                    Some(Lane::High) => streak += 1,
+                    None => continue,
+                }
+                break
+            }
+            assert!(streak <= ratio);
+        }
+        assert!(lows >= pops / (ratio + 1), "{} low of {}", lows, pops);
+        drop(rx);
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn backs_a_packet() {
+        type Q<T> = PriorityQueue<stream2::Message<T>, spsc::CNQueue<stream2::Message<T>>>;
+        let (tx, rx) = stream2::channel_with_queue::<_, Q<_>>();
+        let producer = thread::spawn(move || {
+            for i in 0..10_000 {
+                tx.send(i).unwrap();
+            }
+        });
+        for i in 0..10_000 {
+            assert_eq!(rx.recv(), Ok(i));
+        }
+        producer.join().unwrap();
+        assert!(rx.recv().is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! A thread which sends everything from an iterator down a bounded channel,
+//! for the common case of a queue fed by one background producer.
+//!
+//! The channel is a `sync2::SyncPacket` over `spsc::CNQueue`, so the producer
+//! blocks once it is `bound` values ahead, rather than filling memory, and
+//! its next send fails once the `Consumer` is dropped. The producer hangs up
+//! when the iterator runs out, or panics, after which the `Consumer` gets
+//! whatever was already sent and then `Disconnected`.
+
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use spsc;
+use sync2::{Failure, SyncPacket};
+
+type Packet<T> = SyncPacket<spsc::CNQueue<T>, T>;
+
+/// The producer has hung up and everything it sent has been received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+/// Spawns a thread sending everything from `iter` to the returned `Consumer`,
+/// blocking whenever it is `bound` values ahead.
+///
+/// Like any spawned thread the iterator and its items must be `'static`.
+///
+/// # Panics
+///
+/// If `bound` is 0.
+pub fn spawn_producer<T, I>(bound: usize, iter: I) -> (Consumer<T>, ProducerHandle)
+where T: Send + 'static, I: IntoIterator<Item = T> + Send + 'static {
+    let packet = Arc::new(Packet::new(bound));
+    let hang_up = HangUp(packet.clone());
+    let thread = thread::spawn(move|| {
+        // dropped however the loop ends, so a panicking iterator still
+        // disconnects the consumer instead of leaving it blocked
+        let hang_up = hang_up;
+        for t in iter {
+            // the consumer is gone, or we were aborted
+            if hang_up.0.send(t).is_err() { break }
+        }
+    });
+    let abort_packet = packet.clone();
+    let handle = ProducerHandle {
+        thread: Some(thread),
+        abort: Box::new(move|| abort_packet.drop_port()),
+    };
+    (Consumer { packet: packet }, handle)
+}
+
+struct HangUp<T: Send>(Arc<Packet<T>>);
+
+impl<T: Send> Drop for HangUp<T> {
+    fn drop(&mut self) {
+        self.0.drop_chan()
+    }
+}
+
+/// The receiving half of `spawn_producer`'s channel.
+pub struct Consumer<T: Send> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T: Send> Consumer<T> {
+    /// Blocks until the producer sends a value, or hangs up.
+    pub fn recv(&self) -> Result<T, Disconnected> {
+        self.packet.recv().map_err(|_| Disconnected)
+    }
+
+    /// Receives a value if one is ready, without blocking.
+    pub fn try_recv(&self) -> Result<Option<T>, Disconnected> {
+        match self.packet.try_recv() {
+            Ok(t) => Ok(Some(t)),
+            Err(Failure::Empty) => Ok(None),
+            Err(Failure::Disconnected) => Err(Disconnected),
+        }
+    }
+}
+
+impl<T: Send> Iterator for Consumer<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv().ok()
+    }
+}
+
+impl<T: Send> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        // wakes the producer if it's blocked on a full channel
+        self.packet.drop_port()
+    }
+}
+
+/// Owns the producer thread, joining it on drop.
+///
+/// Joining waits for the producer to finish, and it only finishes by running
+/// out of values, or by finding the consumer gone or itself aborted. Dropping
+/// the handle while its `Consumer` is alive, but no longer receiving, on the
+/// same thread blocks forever once the channel fills; `abort` first, or drop
+/// the `Consumer` first.
+pub struct ProducerHandle {
+    thread: Option<JoinHandle<()>>,
+    // the handle isn't generic over the item type, so the packet is hidden
+    // behind its one use
+    abort: Box<dyn Fn() + Send + Sync>,
+}
+
+impl ProducerHandle {
+    /// Stops the producer at its next send, waking it if it is blocked on a
+    /// full channel. What it already sent can still be received, and then the
+    /// `Consumer` gets `Disconnected`.
+    ///
+    /// This can't interrupt the iterator itself: a producer blocked inside
+    /// `next` stops only once that returns.
+    pub fn abort(&self) {
+        (self.abort)()
+    }
+
+    /// Waits for the producer to finish, handing back its panic if the
+    /// iterator panicked.
+    pub fn join(mut self) -> thread::Result<()> {
+        self.thread.take().expect("only taken here and in drop").join()
+    }
+}
+
+impl Drop for ProducerHandle {
+    /// Joins the producer, resuming its panic on this thread unless this
+    /// thread is already panicking.
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if let Err(panic) = thread.join() {
+                if !thread::panicking() {
+                    ::std::panic::resume_unwind(panic)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use super::{spawn_producer, Disconnected};
+    use verify::stress_count;
+
+    #[test]
+    fn exhausted() {
+        let count = stress_count(100_000);
+        for &bound in &[1, 2, 64] {
+            let (consumer, producer) = spawn_producer(bound, 0..count);
+            for i in 0..count {
+                assert_eq!(consumer.recv(), Ok(i));
+            }
+            assert_eq!(consumer.recv(), Err(Disconnected));
+            assert_eq!(consumer.try_recv(), Err(Disconnected));
+            producer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn iterates() {
+        let (consumer, _producer) = spawn_producer(4, (0..100).map(|i| i * 2));
+        assert_eq!(consumer.collect::<Vec<_>>(), (0..100).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    // The iterator never ends, so the producer is only stopped by finding
+    // the consumer gone, including when it is blocked on a full channel.
+    #[test]
+    fn consumer_dropped() {
+        for &bound in &[1, 2, 64] {
+            let sent = Arc::new(AtomicUsize::new(0));
+            let counter = sent.clone();
+            let (consumer, producer) = spawn_producer(bound, (0..).inspect(move|_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }));
+            for i in 0..10 {
+                assert_eq!(consumer.recv(), Ok(i));
+            }
+            // wait for the producer to block
+            while sent.load(Ordering::Relaxed) < 10 + bound {
+                thread::yield_now();
+            }
+            drop(consumer);
+            producer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn dropped_handle_joins() {
+        let (consumer, producer) = spawn_producer(8, 0..1_000);
+        let received = thread::spawn(move|| consumer.count());
+        drop(producer);
+        assert_eq!(received.join().unwrap(), 1_000);
+    }
+
+    #[test]
+    fn abort() {
+        let (consumer, producer) = spawn_producer(4, 0..);
+        assert_eq!(consumer.recv(), Ok(0));
+        producer.abort();
+        producer.join().unwrap();
+        // whatever was sent before the abort is still there
+        let rest: Vec<_> = consumer.collect();
+        assert!(rest.len() <= 4, "{:?}", rest);
+        assert!(rest.iter().cloned().eq(1..rest.len() as u64 + 1), "{:?}", rest);
+    }
+
+    #[test]
+    fn iterator_panics() {
+        let (consumer, producer) = spawn_producer(2, (0..10).map(|i| {
+            if i == 5 { panic!("iterator panicked at {}", i) }
+            i
+        }));
+        // the consumer sees the values before the panic, then the hang up
+        assert_eq!(consumer.collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        let panic = producer.join().unwrap_err();
+        assert_eq!(panic.downcast_ref::<String>().map(|s| &**s), Some("iterator panicked at 5"));
+    }
+
+    #[test]
+    fn panic_resumed_on_drop() {
+        let (consumer, producer) = spawn_producer(2, (0..10).map(|i| {
+            if i == 3 { panic!("iterator panicked") }
+            i
+        }));
+        assert_eq!(consumer.count(), 3);
+        let resumed = panic::catch_unwind(AssertUnwindSafe(move|| drop(producer)));
+        assert!(resumed.is_err());
+    }
+}
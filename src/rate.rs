@@ -0,0 +1,385 @@
+//! Sending at a fixed rate, for latency against load rather than latency at
+//! whatever rate the producer can manage.
+//!
+//! A `Pacer` gives each message a due time, `interval` after the last one's,
+//! and waits for it: with a sleep while it is far enough off that waking up
+//! late won't matter, then spinning on the clock. Due times are counted from
+//! the start, not from the previous send, so a producer which falls behind
+//! sends back to back until it catches up, and one which can't keep up at
+//! all just sends flat out, its achieved rate falling short of the one asked
+//! for.
+//!
+//! `run_at_rate` runs a queue's two halves at a rate and measures each
+//! message's latency twice. The uncorrected latency is from when it was sent
+//! to when it was popped. That under-reports whenever the producer itself is
+//! held up: it sends nothing while it's stalled, so the stall shows up in at
+//! most one message, and the ones which should have gone out meanwhile are
+//! timed from when they finally did. The corrected latency is from when the
+//! message was due, `start + i / rate`, which charges the stall to every
+//! message it delayed, as a client sending at that rate would see it. Each
+//! message carries its index for that, and its send time for the other.
+//!
+//! All of the times are read from a `Clock`, `Instant`'s or the TSC's, as ns
+//! since it started.
+
+use std::cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backoff::Backoff;
+use clock::Clock;
+use factory::QueueKind;
+use split::{self, Consumer, Producer, SplitQueue};
+
+/// A wait is only slept through if it is at least this much longer than
+/// the sleep overshoots; anything shorter is spun.
+const MIN_SLEEP: Duration = Duration::from_micros(50);
+
+/// Waits for each message's due time in turn, by `clock`.
+pub struct Pacer<'c, C: ?Sized + Clock + 'c> {
+    clock: &'c C,
+    start: u64,
+    interval: f64, // ns
+    sent: u64,
+    overshoot: Duration,
+}
+
+impl<'c, C: ?Sized + Clock> Pacer<'c, C> {
+    /// Paces `rate` messages a second, starting now.
+    ///
+    /// # Panics
+    ///
+    /// If `rate` isn't positive.
+    pub fn new(clock: &'c C, rate: f64) -> Self {
+        assert!(rate > 0.0, "a rate of {} messages a second", rate);
+        // calibrated first, so that the first messages aren't already late
+        let overshoot = sleep_overshoot();
+        Pacer { clock, start: clock.now(), interval: 1e9 / rate, sent: 0, overshoot }
+    }
+
+    /// The time the `i`th message is due.
+    pub fn due(&self, i: u64) -> u64 {
+        self.start + (i as f64 * self.interval) as u64
+    }
+
+    /// Waits until the next message is due, returning when that was.
+    pub fn wait(&mut self) -> u64 {
+        let due = self.due(self.sent);
+        self.sent += 1;
+        loop {
+            let now = self.clock.now();
+            if now >= due { return due }
+            let left = Duration::from_nanos(due - now);
+            if left > MIN_SLEEP + self.overshoot {
+                thread::sleep(left - self.overshoot)
+            } else {
+                ::std::hint::spin_loop()
+            }
+        }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+// by hand, as derive would want C: Clone
+impl<'c, C: ?Sized + Clock> Clone for Pacer<'c, C> {
+    fn clone(&self) -> Self {
+        Pacer { clock: self.clock, ..*self }
+    }
+}
+
+// How late the longest of a few short sleeps woke up, so that a sleep can
+// end that much early and spin the rest.
+fn sleep_overshoot() -> Duration {
+    (0..5).map(|_| {
+        let start = Instant::now();
+        thread::sleep(MIN_SLEEP);
+        start.elapsed().checked_sub(MIN_SLEEP).unwrap_or(Duration::new(0, 0))
+    }).max().unwrap()
+}
+
+// Values below this go in a bucket each, and each power of two above it is
+// split into this many buckets, so a bucket is at most 1/64 of its values.
+const SUB_BUCKET_BITS: u32 = 6;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// A histogram of latencies in ns, in fixed memory however large the
+/// outliers get, accurate to within 1/64 of each value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    len: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram { counts: vec![0; bucket(u64::max_value()) + 1], len: 0, max: 0 }
+    }
+
+    pub fn record(&mut self, ns: u64) {
+        self.counts[bucket(ns)] += 1;
+        self.len += 1;
+        self.max = cmp::max(self.max, ns);
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The latency `p` percent of the values are at or below, rounded up to
+    /// the top of its bucket, or 0 if there are none.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let rank = cmp::max((p / 100.0 * self.len as f64).ceil() as u64, 1);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return cmp::min(bucket_top(i), self.max)
+            }
+        }
+        0
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+fn bucket(v: u64) -> usize {
+    if v < SUB_BUCKETS {
+        return v as usize
+    }
+    let shift = 63 - v.leading_zeros() - SUB_BUCKET_BITS;
+    // the top SUB_BUCKET_BITS + 1 bits, in SUB_BUCKETS..2 * SUB_BUCKETS
+    let sub = v >> shift;
+    ((shift as u64 + 1) * SUB_BUCKETS + sub - SUB_BUCKETS) as usize
+}
+
+fn bucket_top(i: usize) -> u64 {
+    let i = i as u64;
+    if i < SUB_BUCKETS {
+        return i
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    let sub = i % SUB_BUCKETS + SUB_BUCKETS;
+    // the top bucket's top is u64::MAX, which doesn't fit the shift
+    ((sub + 1) << shift).wrapping_sub(1)
+}
+
+/// A message: its index, from which its due time follows, and when it was
+/// actually sent, in ns by the run's clock.
+#[derive(Debug, Clone, Copy)]
+pub struct Sent {
+    pub index: u64,
+    pub at: u64,
+}
+
+/// The result of a run: the rate asked for and the one managed, both in
+/// messages a second, and the latencies, both from the due time and from
+/// the send.
+#[derive(Debug, Clone)]
+pub struct RateRun {
+    pub requested: f64,
+    pub achieved: f64,
+    pub corrected: Histogram,
+    pub uncorrected: Histogram,
+}
+
+/// Sends `count` messages through `queue` at `rate` a second, timed by
+/// `clock`.
+pub fn run_at_rate<Q, C>(queue: Q, clock: &C, rate: f64, count: usize) -> RateRun
+where Q: SplitQueue<Sent> + Sync, C: ?Sized + Clock {
+    let pacer = Pacer::new(clock, rate);
+    let due = pacer.clone();
+    let sending = AtomicU64::new(0);
+    let (corrected, uncorrected) = split::run_split_with(queue,
+        |tx| produce(tx, pacer, count, &sending),
+        |rx| consume(rx, &due, count));
+    run(rate, count, &sending, corrected, uncorrected)
+}
+
+/// `run_at_rate` over the queue `kind` names.
+pub fn run_kind_at_rate<C: ?Sized + Clock>(kind: &QueueKind, clock: &C, rate: f64, count: usize)
+-> RateRun {
+    let pacer = Pacer::new(clock, rate);
+    let due = pacer.clone();
+    let sending = AtomicU64::new(0);
+    let (corrected, uncorrected) = split::run_split(kind,
+        |tx| produce(tx, pacer, count, &sending),
+        |rx| consume(rx, &due, count));
+    run(rate, count, &sending, corrected, uncorrected)
+}
+
+fn run(rate: f64, count: usize, sending: &AtomicU64, corrected: Histogram, uncorrected: Histogram)
+-> RateRun {
+    // the first message is due at the start, so there are count - 1
+    // intervals
+    let intervals = cmp::max(count, 2) - 1;
+    let ns = cmp::max(sending.load(Ordering::Relaxed), 1);
+    RateRun {
+        requested: rate,
+        achieved: intervals as f64 * 1e9 / ns as f64,
+        corrected,
+        uncorrected,
+    }
+}
+
+// Leaves the time from the first message's due time to the last send in
+// `sending`, in ns.
+fn produce<Q, C>(tx: Producer<Sent, Q>, mut pacer: Pacer<C>, count: usize, sending: &AtomicU64)
+where Q: ?Sized + SplitQueue<Sent>, C: ?Sized + Clock {
+    for i in 0..count {
+        pacer.wait();
+        tx.push(Sent { index: i as u64, at: pacer.clock.now() });
+    }
+    sending.store(pacer.clock.now() - pacer.start(), Ordering::Relaxed);
+}
+
+fn consume<Q, C>(rx: Consumer<Sent, Q>, pacer: &Pacer<C>, count: usize) -> (Histogram, Histogram)
+where Q: ?Sized + SplitQueue<Sent>, C: ?Sized + Clock {
+    let (mut corrected, mut uncorrected) = (Histogram::new(), Histogram::new());
+    let mut backoff = Backoff::new();
+    while uncorrected.len() < count as u64 {
+        match rx.pop() {
+            Some(sent) => {
+                let now = pacer.clock.now();
+                // saturating, as the send may have been timed on a core
+                // whose TSC is a little ahead of this one's
+                corrected.record(now.saturating_sub(pacer.due(sent.index)));
+                uncorrected.record(now.saturating_sub(sent.at));
+                backoff.reset();
+            }
+            None => backoff.snooze(),
+        }
+    }
+    (corrected, uncorrected)
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{bucket, bucket_top, run_at_rate, Histogram, Pacer, Sent};
+    use clock::{Clock, InstantClock};
+    use spsc;
+    use split::SplitQueue;
+
+    #[test]
+    fn paced() {
+        // long enough intervals that both the sleep and the spin are used
+        let clock = InstantClock::new();
+        let start = Instant::now();
+        let mut pacer = Pacer::new(&clock, 5_000.0);
+        for i in 0..100 {
+            let due = pacer.wait();
+            assert_eq!(due, pacer.due(i));
+            assert!(clock.now() >= due);
+        }
+        assert!(clock.now() - pacer.start() >= 99 * 200_000);
+        assert!(start.elapsed() >= Duration::from_micros(99 * 200));
+    }
+
+    #[test]
+    fn behind() {
+        // far faster than the producer can go, so it never waits
+        let run = run_at_rate(unsafe { spsc::CNQueue::aligned(128) }, &InstantClock::new(), 1e12,
+            10_000);
+        assert_eq!(run.uncorrected.len(), 10_000);
+        assert!(run.achieved < run.requested / 10.0, "{} of {}", run.achieved, run.requested);
+    }
+
+    #[test]
+    fn achieved() {
+        let run = run_at_rate(unsafe { spsc::CNQueue::aligned(128) }, &InstantClock::new(), 20_000.0,
+            1_000);
+        assert_eq!(run.corrected.len(), 1_000);
+        // never ahead of the rate, and not far behind it either
+        assert!(run.achieved <= 20_000.0 * 1.01, "{}", run.achieved);
+        assert!(run.achieved > 20_000.0 / 2.0, "{}", run.achieved);
+    }
+
+    #[test]
+    fn buckets() {
+        for &v in &[0, 1, 63, 64, 65, 127, 128, 1_000, 999_999, 1 << 40, u64::max_value()] {
+            let i = bucket(v);
+            assert!(v <= bucket_top(i), "{} over the top of bucket {}", v, i);
+            assert!(i == 0 || v > bucket_top(i - 1), "{} under the top of bucket {}", v, i - 1);
+            // within 1/64 of the value
+            assert!(bucket_top(i) - v <= v / 64, "{} in bucket {} up to {}", v, i, bucket_top(i));
+        }
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut h = Histogram::new();
+        assert_eq!(h.percentile(99.0), 0);
+        for ns in 1..1_001 {
+            h.record(ns);
+        }
+        // a few multi-second outliers don't overflow or swamp anything
+        for _ in 0..5 {
+            h.record(5_000_000_000);
+        }
+        assert_eq!(h.len(), 1_005);
+        assert_eq!(h.max(), 5_000_000_000);
+        let p50 = h.percentile(50.0);
+        assert!(p50 >= 503 && p50 <= 503 + 503 / 64, "{}", p50);
+        assert_eq!(h.percentile(100.0), 5_000_000_000);
+    }
+
+    // Sleeps in the `at`th push, after the message is stamped, as a
+    // producer descheduled for a while would.
+    struct Stalling<Q> {
+        queue: Q,
+        pushes: AtomicUsize,
+        at: usize,
+        stall: Duration,
+    }
+
+    impl<Q: SplitQueue<Sent>> SplitQueue<Sent> for Stalling<Q> {
+        fn push(&self, t: Sent) {
+            if self.pushes.fetch_add(1, Ordering::Relaxed) == self.at {
+                thread::sleep(self.stall)
+            }
+            self.queue.push(t)
+        }
+
+        fn pop(&self) -> Option<Sent> {
+            self.queue.pop()
+        }
+    }
+
+    // The messages due during the stall go out late, back to back, and are
+    // received promptly, so only the corrected latency sees it. At 10k a
+    // second, 1_000 of the 2_000 are due in the 100ms stall.
+    #[test]
+    fn coordinated_omission() {
+        let stall = Duration::from_millis(100);
+        let queue = Stalling {
+            queue: unsafe { spsc::CNQueue::aligned(128) },
+            pushes: AtomicUsize::new(0),
+            at: 100,
+            stall,
+        };
+        let run = run_at_rate(queue, &InstantClock::new(), 10_000.0, 2_000);
+        let corrected = run.corrected.percentile(99.0);
+        let uncorrected = run.uncorrected.percentile(99.0);
+        assert!(corrected >= 90_000_000, "corrected p99 {} ns", corrected);
+        assert!(corrected > uncorrected * 4,
+            "corrected p99 {} ns, uncorrected {} ns", corrected, uncorrected);
+        // the stalled message itself is the only one which sees it uncorrected
+        assert!(run.uncorrected.max() >= 100_000_000 * 9 / 10);
+    }
+}
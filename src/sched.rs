@@ -0,0 +1,432 @@
+//! Deterministic schedules for the queues' races, with the `yield_points`
+//! feature.
+//!
+//! spsc and stream2's `Packet` take their atomics from `sched::atomic`, and
+//! `blocking` and `backoff` park and yield through here. Without the feature those are
+//! std's own, and this module is nothing else. With it each atomic op is a
+//! yield point: on a thread run by `explore`, it hands control to the
+//! scheduler, which lets one thread run at a time and picks, from a seeded
+//! random number generator, where to preempt the running thread and which
+//! thread runs next. A seed and a preemption budget make a `Schedule`, which
+//! replays the same interleaving every time, so a race found in one run can
+//! be stepped through in the next. Threads outside `explore` pay a
+//! thread-local lookup per op and are otherwise left alone.
+//!
+//! This explores interleavings only. The ops themselves run one at a time,
+//! so everything is sequentially consistent and a missing Acquire won't show;
+//! that is for a model checker. What it does find is the multi-step races
+//! which are too long for one and too rare for the stress tests: a sender's
+//! push landing between a receiver's `to_wake` store and its re-check, or a
+//! producer refreshing `tail_copy` in the middle of a pop.
+//!
+//! A parked thread, or one spinning in `yield_now`, always hands over to
+//! another thread, so a wakeup which never comes shows up as every thread
+//! left waiting, and the run gives up after `MAX_STEPS`.
+
+#[cfg(not(feature = "yield_points"))]
+pub mod atomic {
+    pub use std::sync::atomic::{fence, AtomicBool, AtomicIsize, AtomicPtr, AtomicUsize, Ordering};
+}
+
+#[cfg(not(feature = "yield_points"))]
+pub use std::thread::{park, park_timeout, yield_now};
+
+#[cfg(feature = "yield_points")]
+pub use self::points::*;
+
+#[cfg(feature = "yield_points")]
+mod points {
+    use std::any::Any;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fmt;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Steps a schedule may take before it's taken to be stuck.
+    pub const MAX_STEPS: usize = 100_000;
+
+    /// Preemptions `explore` allows each schedule, before shrinking.
+    pub const PREEMPTIONS: usize = 8;
+
+    /// The environment variable which replays one schedule, as printed by a
+    /// failing `explore`.
+    pub const REPLAY_VAR: &str = "SCHED_REPLAY";
+
+    pub mod atomic {
+        use std::sync::atomic as std_atomic;
+
+        pub use std::sync::atomic::Ordering;
+
+        use super::yield_point;
+
+        pub fn fence(order: Ordering) {
+            yield_point();
+            std_atomic::fence(order)
+        }
+
+        macro_rules! shim {
+            ($name:ident, $ty:ty) => {
+                /// std's, with a yield point before each op.
+                #[repr(transparent)]
+                #[derive(Default)]
+                pub struct $name(std_atomic::$name);
+
+                impl $name {
+                    pub const fn new(v: $ty) -> Self { $name(std_atomic::$name::new(v)) }
+                    pub fn get_mut(&mut self) -> &mut $ty { self.0.get_mut() }
+                    pub fn into_inner(self) -> $ty { self.0.into_inner() }
+
+                    pub fn load(&self, order: Ordering) -> $ty {
+                        yield_point();
+                        self.0.load(order)
+                    }
+
+                    pub fn store(&self, v: $ty, order: Ordering) {
+                        yield_point();
+                        self.0.store(v, order)
+                    }
+
+                    pub fn swap(&self, v: $ty, order: Ordering) -> $ty {
+                        yield_point();
+                        self.0.swap(v, order)
+                    }
+
+                    pub fn compare_exchange(&self, current: $ty, new: $ty, success: Ordering, failure: Ordering)
+                    -> Result<$ty, $ty> {
+                        yield_point();
+                        self.0.compare_exchange(current, new, success, failure)
+                    }
+
+                    pub fn compare_exchange_weak(&self, current: $ty, new: $ty, success: Ordering, failure: Ordering)
+                    -> Result<$ty, $ty> {
+                        yield_point();
+                        self.0.compare_exchange_weak(current, new, success, failure)
+                    }
+                }
+            }
+        }
+
+        macro_rules! shim_int {
+            ($name:ident, $ty:ty) => {
+                shim!($name, $ty);
+
+                impl $name {
+                    pub fn fetch_add(&self, v: $ty, order: Ordering) -> $ty {
+                        yield_point();
+                        self.0.fetch_add(v, order)
+                    }
+
+                    pub fn fetch_sub(&self, v: $ty, order: Ordering) -> $ty {
+                        yield_point();
+                        self.0.fetch_sub(v, order)
+                    }
+                }
+            }
+        }
+
+        shim!(AtomicBool, bool);
+        shim_int!(AtomicUsize, usize);
+        shim_int!(AtomicIsize, isize);
+
+        /// std's, with a yield point before each op.
+        #[repr(transparent)]
+        pub struct AtomicPtr<T>(std_atomic::AtomicPtr<T>);
+
+        impl<T> AtomicPtr<T> {
+            pub const fn new(p: *mut T) -> Self { AtomicPtr(std_atomic::AtomicPtr::new(p)) }
+            pub fn get_mut(&mut self) -> &mut *mut T { self.0.get_mut() }
+            pub fn into_inner(self) -> *mut T { self.0.into_inner() }
+
+            pub fn load(&self, order: Ordering) -> *mut T {
+                yield_point();
+                self.0.load(order)
+            }
+
+            pub fn store(&self, p: *mut T, order: Ordering) {
+                yield_point();
+                self.0.store(p, order)
+            }
+
+            pub fn swap(&self, p: *mut T, order: Ordering) -> *mut T {
+                yield_point();
+                self.0.swap(p, order)
+            }
+
+            pub fn compare_exchange(&self, current: *mut T, new: *mut T, success: Ordering, failure: Ordering)
+            -> Result<*mut T, *mut T> {
+                yield_point();
+                self.0.compare_exchange(current, new, success, failure)
+            }
+
+            pub fn compare_exchange_weak(&self, current: *mut T, new: *mut T, success: Ordering, failure: Ordering)
+            -> Result<*mut T, *mut T> {
+                yield_point();
+                self.0.compare_exchange_weak(current, new, success, failure)
+            }
+        }
+    }
+
+    /// A seed for the scheduler's choices, and how many times it may preempt
+    /// a thread which could have kept running.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Schedule {
+        pub seed: u64,
+        pub preemptions: usize,
+    }
+
+    impl fmt::Display for Schedule {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}:{}", self.seed, self.preemptions)
+        }
+    }
+
+    impl Schedule {
+        /// Parses the `seed:preemptions` a `Schedule` displays as.
+        pub fn parse(s: &str) -> Option<Schedule> {
+            let mut parts = s.trim().splitn(2, ':');
+            let seed = parts.next()?.parse().ok()?;
+            let preemptions = parts.next()?.parse().ok()?;
+            Some(Schedule { seed: seed, preemptions: preemptions })
+        }
+    }
+
+    /// The threads of one run, fresh for each schedule.
+    pub type Threads = Vec<Box<dyn FnOnce() + Send>>;
+
+    /// Which thread ran after each switch, with the step it switched at.
+    pub type Trace = Vec<(usize, usize)>;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Status {
+        Runnable,
+        Finished,
+    }
+
+    struct State {
+        current: usize,
+        threads: Vec<Status>,
+        rng: u64,
+        // one in this many yield points preempts, while the budget lasts
+        odds: u64,
+        preemptions: usize,
+        steps: usize,
+        trace: Trace,
+        failure: Option<String>,
+    }
+
+    struct Scheduler {
+        state: Mutex<State>,
+        switched: Condvar,
+    }
+
+    // Unwinds the threads still running once another has failed.
+    struct Aborted;
+
+    thread_local! {
+        static CURRENT: RefCell<Option<(Arc<Scheduler>, usize)>> = const { RefCell::new(None) };
+    }
+
+    // splitmix64
+    fn next(rng: &mut u64) -> u64 {
+        *rng = rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    impl State {
+        // Picks the next thread, other than `me` if `away`, and makes it
+        // current. None if there's no other thread to run.
+        fn switch(&mut self, me: usize, away: bool) -> Option<usize> {
+            let others: Vec<usize> = (0..self.threads.len())
+                .filter(|&t| self.threads[t] == Status::Runnable && !(away && t == me))
+                .collect();
+            if others.is_empty() {
+                return None
+            }
+            let to = others[(next(&mut self.rng) % others.len() as u64) as usize];
+            self.current = to;
+            self.trace.push((self.steps, to));
+            Some(to)
+        }
+    }
+
+    impl Scheduler {
+        fn lock<'a>(&'a self) -> MutexGuard<'a, State> {
+            self.state.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        // Waits until it's `me`'s turn, unwinding if the run has failed.
+        fn wait_turn<'a>(&'a self, mut state: MutexGuard<'a, State>, me: usize) {
+            while state.current != me && state.failure.is_none() {
+                state = self.switched.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            if state.failure.is_some() {
+                drop(state);
+                panic::resume_unwind(Box::new(Aborted));
+            }
+        }
+
+        // A yield point on thread `me`. `away` hands over to another thread
+        // if there is one, otherwise it's up to the seed and the budget.
+        fn step(&self, me: usize, away: bool) {
+            let mut state = self.lock();
+            if state.failure.is_some() {
+                drop(state);
+                panic::resume_unwind(Box::new(Aborted));
+            }
+            state.steps += 1;
+            if state.steps > MAX_STEPS {
+                drop(state);
+                panic!("no progress after {} steps, is every thread waiting on a lost wakeup?", MAX_STEPS);
+            }
+            let preempt = !away && state.preemptions > 0 && next(&mut state.rng) % state.odds == 0;
+            if !(away || preempt) || state.switch(me, true).is_none() {
+                return
+            }
+            if preempt {
+                state.preemptions -= 1;
+            }
+            self.switched.notify_all();
+            self.wait_turn(state, me);
+        }
+
+        fn finish(&self, me: usize, panic: Option<Box<dyn Any + Send>>) {
+            let mut state = self.lock();
+            state.threads[me] = Status::Finished;
+            if let Some(panic) = panic {
+                if !panic.is::<Aborted>() && state.failure.is_none() {
+                    state.failure = Some(message(&*panic));
+                }
+            }
+            state.switch(me, true);
+            self.switched.notify_all();
+        }
+    }
+
+    fn message(panic: &(dyn Any + Send)) -> String {
+        if let Some(s) = panic.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = panic.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "a panic".to_string()
+        }
+    }
+
+    fn context() -> Option<(Arc<Scheduler>, usize)> {
+        if thread::panicking() {
+            return None
+        }
+        CURRENT.try_with(|c| c.borrow().clone()).ok().and_then(|c| c)
+    }
+
+    /// A point where the scheduler may switch threads, called by each op in
+    /// `atomic`.
+    pub fn yield_point() {
+        if let Some((sched, me)) = context() {
+            sched.step(me, false)
+        }
+    }
+
+    /// Lets another thread run. Under a schedule it always switches, if
+    /// there's another thread, so a spin on this always makes progress.
+    pub fn yield_now() {
+        match context() {
+            Some((sched, me)) => sched.step(me, true),
+            None => thread::yield_now(),
+        }
+    }
+
+    /// `thread::park`, which under a schedule is a switch to another thread
+    /// and back: a spurious wakeup, which the parker has to handle anyway.
+    pub fn park() {
+        match context() {
+            Some((sched, me)) => sched.step(me, true),
+            None => thread::park(),
+        }
+    }
+
+    pub fn park_timeout(dur: Duration) {
+        match context() {
+            Some((sched, me)) => sched.step(me, true),
+            None => thread::park_timeout(dur),
+        }
+    }
+
+    /// Runs the threads `setup` makes under `schedule`, returning the
+    /// switches it made, or the first panic's message.
+    pub fn run<S: Fn() -> Threads>(schedule: Schedule, setup: &S) -> Result<Trace, String> {
+        let threads = setup();
+        let mut rng = schedule.seed;
+        let odds = 2 + next(&mut rng) % 63;
+        let first = (next(&mut rng) % threads.len().max(1) as u64) as usize;
+        let sched = Arc::new(Scheduler {
+            state: Mutex::new(State {
+                current: first,
+                threads: vec![Status::Runnable; threads.len()],
+                rng: rng,
+                odds: odds,
+                preemptions: schedule.preemptions,
+                steps: 0,
+                trace: vec![(0, first)],
+                failure: None,
+            }),
+            switched: Condvar::new(),
+        });
+        let handles: Vec<_> = threads.into_iter().enumerate().map(|(me, body)| {
+            let sched = sched.clone();
+            thread::spawn(move|| {
+                CURRENT.with(|c| *c.borrow_mut() = Some((sched.clone(), me)));
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    sched.wait_turn(sched.lock(), me);
+                    body()
+                }));
+                CURRENT.with(|c| *c.borrow_mut() = None);
+                sched.finish(me, result.err());
+            })
+        }).collect();
+        for handle in handles {
+            let _ = handle.join();
+        }
+        let mut state = sched.lock();
+        match state.failure.take() {
+            Some(failure) => Err(failure),
+            None => Ok(state.trace.split_off(0)),
+        }
+    }
+
+    /// Runs the threads `setup` makes under `schedules` schedules derived
+    /// from `seed`. On a failure, retries its seed with fewer preemptions
+    /// for the smallest which still fails, and panics with how to replay it.
+    /// With `SCHED_REPLAY` set, runs only the schedule it names.
+    pub fn explore<S: Fn() -> Threads>(seed: u64, schedules: u64, setup: S) {
+        let replay = env::var(REPLAY_VAR).ok().and_then(|s| Schedule::parse(&s));
+        let failed = match replay {
+            Some(schedule) => run(schedule, &setup).err().map(|e| (schedule, e)),
+            None => (0..schedules).filter_map(|i| {
+                let mut rng = seed ^ i.wrapping_mul(0xd1b5_4a32_d192_ed03);
+                let schedule = Schedule { seed: next(&mut rng), preemptions: PREEMPTIONS };
+                run(schedule, &setup).err().map(|e| shrink(schedule, e, &setup))
+            }).next(),
+        };
+        if let Some((schedule, failure)) = failed {
+            panic!("{}\nfailed on schedule {}, replay it with {}={}",
+                failure, schedule, REPLAY_VAR, schedule);
+        }
+    }
+
+    fn shrink<S: Fn() -> Threads>(failed: Schedule, failure: String, setup: &S) -> (Schedule, String) {
+        for preemptions in 0..failed.preemptions {
+            let schedule = Schedule { seed: failed.seed, preemptions: preemptions };
+            if let Err(failure) = run(schedule, setup) {
+                return (schedule, failure)
+            }
+        }
+        (failed, failure)
+    }
+}
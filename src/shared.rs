@@ -0,0 +1,520 @@
+// Copyright 2013-2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// Shared channels
+///
+/// This is the flavor of channels which are not necessarily optimized for any
+/// particular use case, but are the most general in how they are used. Shared
+/// channels are cloneable allowing for multiple senders, and this is what a
+/// stream2 channel upgrades to when its sender is cloned.
+///
+/// The queue is `mpmc::Queue`, with its padded sender count tracking the
+/// clones. Unlike std's shared flavor there is no select support, so the
+/// channel count doesn't need a DISCONNECTED sentinel: disconnection is a
+/// separate flag, checked after the receiver has published its token.
+
+pub use self::Failure::*;
+
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::fmt;
+use std::ptr;
+use std::time::Instant;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+
+use backoff::Backoff;
+use blocking::{self, SignalToken};
+use cache_padded::CachePadded;
+use layout::{self, Field, Layout, Side};
+use mpmc;
+
+#[cfg(test)]
+const MAX_STEALS: isize = 5;
+#[cfg(not(test))]
+const MAX_STEALS: isize = 1 << 20;
+
+pub struct SharedPacket<T> {
+    queue: mpmc::Queue<T, mpmc::CacheAligned>,
+    cnt: CachePadded<AtomicIsize>, // How many items are on this channel, negative if the receiver is parked
+    steals: UnsafeCell<isize>, // How many times has a port received without blocking?
+    to_wake: CachePadded<AtomicUsize>, // SignalToken for the blocked thread to wake up
+    disconnected: CachePadded<AtomicBool>, // set once the last sender is gone
+    port_dropped: CachePadded<AtomicBool>, // flag if the channel has been destroyed.
+    #[cfg(feature = "async")]
+    polled: UnsafeCell<bool>, // whether a Pending poll_recv left a reservation
+}
+
+unsafe impl<T: Send> Send for SharedPacket<T> {}
+unsafe impl<T: Send> Sync for SharedPacket<T> {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure {
+    Empty,
+    Disconnected,
+    // only returned by a `recv` with a deadline
+    Timeout,
+}
+
+impl<T> SharedPacket<T> {
+    /// Creates a packet with one sender and one receiver.
+    pub fn new() -> Self {
+        let queue = mpmc::Queue::aligned();
+        queue.add_sender();
+        SharedPacket {
+            queue: queue,
+            cnt: CachePadded::new(AtomicIsize::new(0)),
+            steals: UnsafeCell::new(0),
+            to_wake: CachePadded::new(AtomicUsize::new(0)),
+            disconnected: CachePadded::new(AtomicBool::new(false)),
+            port_dropped: CachePadded::new(AtomicBool::new(false)),
+            #[cfg(feature = "async")]
+            polled: UnsafeCell::new(false),
+        }
+    }
+
+    pub fn send(&self, t: T) -> Result<(), T> {
+        // See stream2 for why a send which races with the port being dropped
+        // is still considered sent, its message is dropped with the queue.
+        if self.port_dropped.load(Ordering::SeqCst) { return Err(t) }
+
+        self.queue.push(t);
+        // Senders which pushed a message the receiver has already stolen, but
+        // have not yet counted it, can leave the count below -1. Only the
+        // send which brings it back up from -1 has a new message for a
+        // parked receiver, so only it wakes it.
+        if self.cnt.fetch_add(1, Ordering::SeqCst) == -1 {
+            if let Some(token) = self.try_take_to_wake() {
+                token.signal();
+            }
+        }
+        Ok(())
+    }
+
+    // Consumes ownership of the 'to_wake' field.
+    fn try_take_to_wake(&self) -> Option<SignalToken> {
+        let ptr = self.to_wake.swap(0, Ordering::SeqCst);
+        if ptr == 0 {
+            None
+        } else {
+            Some(unsafe { SignalToken::cast_from_usize(ptr) })
+        }
+    }
+
+    // Decrements the count on the channel for a sleeper, returning the sleeper
+    // back if it shouldn't sleep. Note that this is the location where we take
+    // steals into account.
+    fn decrement(&self, token: SignalToken) -> Result<(), SignalToken> {
+        assert_eq!(self.to_wake.load(Ordering::SeqCst), 0);
+        let ptr = unsafe { token.cast_to_usize() };
+        self.to_wake.store(ptr, Ordering::SeqCst);
+
+        let steals = unsafe { ptr::replace(self.steals.get(), 0) };
+
+        let n = self.cnt.fetch_sub(1 + steals, Ordering::SeqCst);
+        // If we factor in our steals and notice that the channel has no data,
+        // we successfully sleep, unless the last sender is already gone.
+        if n - steals <= 0 && !self.disconnected.load(Ordering::SeqCst) {
+            return Ok(())
+        }
+
+        // Take our token back. If it's already gone a sender has it, and
+        // will signal us shortly.
+        match self.try_take_to_wake() {
+            Some(token) => Err(token),
+            None => Ok(()),
+        }
+    }
+
+    pub fn recv(&self, deadline: Option<Instant>) -> Result<T, Failure> {
+        loop {
+            // Optimistic preflight check (scheduling is expensive).
+            match self.try_recv() {
+                Err(Empty) => {}
+                data => return data,
+            }
+
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline { return Err(Timeout) }
+            }
+
+            let (wait_token, signal_token) = blocking::tokens();
+            if self.decrement(signal_token).is_ok() {
+                if let Some(deadline) = deadline {
+                    if !wait_token.wait_max_until(deadline) {
+                        // As in stream2, if we can take our token back no
+                        // sender has seen our reservation, so hand it back and
+                        // make one last check for data which raced with the
+                        // timeout.
+                        if self.try_take_to_wake().is_some() {
+                            self.cnt.fetch_add(1, Ordering::SeqCst);
+                            return match self.try_recv() {
+                                Err(Empty) => Err(Timeout),
+                                data => data,
+                            }
+                        }
+                    }
+                } else {
+                    wait_token.wait();
+                }
+            }
+
+            match self.recv_reserved() {
+                Err(Empty) => {}
+                data => return data,
+            }
+        }
+    }
+
+    // Receives after a decrement which a sender has answered, or which we
+    // didn't sleep on.
+    fn recv_reserved(&self) -> Result<T, Failure> {
+        match self.try_recv() {
+            // The message we were woken for was reserved by our decrement, so
+            // it shouldn't count as a steal.
+            data @ Ok(..) => unsafe {
+                *self.steals.get() -= 1;
+                data
+            },
+
+            // A sender which brought the count up to 0 for an earlier, timed
+            // out, reservation may only get to `to_wake` once we have parked
+            // again, and so wake us with nothing to receive. Hand this
+            // reservation back and start over.
+            Err(Empty) => {
+                self.cnt.fetch_add(1, Ordering::SeqCst);
+                Err(Empty)
+            }
+
+            data => data,
+        }
+    }
+
+    /// Receives without blocking, or registers the task to be woken when
+    /// there's something to receive. Like `recv` this may only be called by
+    /// the receiver, and a receiver uses one or the other, never both.
+    #[cfg(feature = "async")]
+    pub fn poll_recv(&self, cx: &mut Context) -> Poll<Result<T, Failure>> {
+        // A Pending poll leaves a reservation, as a parked `recv` does. If we
+        // can take our token back no sender has seen it, and we hand it back
+        // as on a timeout, otherwise a sender has counted a message for it.
+        if unsafe { ptr::replace(self.polled.get(), false) } {
+            if self.try_take_to_wake().is_some() {
+                self.cnt.fetch_add(1, Ordering::SeqCst);
+            } else {
+                match self.recv_reserved() {
+                    Err(Empty) => {}
+                    data => return Poll::Ready(data),
+                }
+            }
+        }
+        loop {
+            match self.try_recv() {
+                Err(Empty) => {}
+                data => return Poll::Ready(data),
+            }
+            if self.decrement(blocking::task_token(cx.waker().clone())).is_ok() {
+                unsafe { *self.polled.get() = true }
+                return Poll::Pending
+            }
+            match self.recv_reserved() {
+                Err(Empty) => {}
+                data => return Poll::Ready(data),
+            }
+        }
+    }
+
+    /// Hands back the reservation of a Pending `poll_recv`, for when the
+    /// receiver is dropped without polling again.
+    #[cfg(feature = "async")]
+    pub fn cancel_poll(&self) {
+        if unsafe { ptr::replace(self.polled.get(), false) } {
+            if self.try_take_to_wake().is_some() {
+                self.cnt.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, Failure> {
+        let ret = match self.queue.pop() {
+            mpmc::Data(t) => Some(t),
+            mpmc::Empty => None,
+
+            // The queue reports Inconsistent when a sender has started a push
+            // but not yet linked it in. Its message is as good as there, and
+            // this window is only a few instructions wide, so wait it out
+            // rather than report Empty and maybe park.
+            mpmc::Inconsistent => {
+                let data;
+                let mut backoff = Backoff::new();
+                loop {
+                    backoff.snooze();
+                    match self.queue.pop() {
+                        mpmc::Data(t) => { data = t; break }
+                        mpmc::Empty => panic!("inconceivable"),
+                        mpmc::Inconsistent => {}
+                    }
+                }
+                Some(data)
+            }
+        };
+        match ret {
+            // See the discussion in stream2::Packet::try_recv for why we
+            // bound steals.
+            Some(data) => unsafe {
+                if *self.steals.get() > MAX_STEALS {
+                    let n = self.cnt.swap(0, Ordering::SeqCst);
+                    let m = cmp::min(n, *self.steals.get());
+                    *self.steals.get() -= m;
+                    self.cnt.fetch_add(n - m, Ordering::SeqCst);
+                    assert!(*self.steals.get() >= 0);
+                }
+                *self.steals.get() += 1;
+                Ok(data)
+            },
+
+            None => {
+                if !self.disconnected.load(Ordering::SeqCst) {
+                    return Err(Empty)
+                }
+                // Every sender pushed before it went away, but the pushes
+                // may have landed between our pop and seeing the disconnect.
+                match self.queue.pop() {
+                    mpmc::Data(t) => Ok(t),
+                    mpmc::Empty => Err(Disconnected),
+                    // All the pushes finished before the last sender left.
+                    mpmc::Inconsistent => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// The approximate number of messages sent but not yet received, see
+    /// `mpmc::Queue::len`.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Prepares this shared packet for a channel clone, essentially just bumping
+    // a refcount.
+    pub fn clone_chan(&self) {
+        self.queue.add_sender();
+    }
+
+    // Decrement the reference count on a channel. This is called whenever a
+    // Sender is dropped, and the last one disconnects the channel.
+    pub fn drop_chan(&self) {
+        if !self.queue.remove_sender() { return }
+
+        self.disconnected.store(true, Ordering::SeqCst);
+        if let Some(token) = self.try_take_to_wake() {
+            token.signal();
+        }
+    }
+
+    // See the long discussion inside of stream2 for why this is necessary.
+    // There is no count of in-flight senders here, so a send which got past
+    // its port_dropped check may push after we drain; that message is dropped
+    // with the queue instead.
+    pub fn drop_port(&self) {
+        self.port_dropped.store(true, Ordering::SeqCst);
+        let mut backoff = Backoff::new();
+        loop {
+            match self.queue.pop() {
+                mpmc::Data(..) => backoff.reset(),
+                mpmc::Empty => break,
+                mpmc::Inconsistent => backoff.snooze(),
+            }
+        }
+    }
+}
+
+// So that stream2's Failure, which can carry a packet, stays Debug.
+impl<T> fmt::Debug for SharedPacket<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedPacket {{ .. }}")
+    }
+}
+
+impl<T> Layout for SharedPacket<T> {
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = Vec::new();
+        layout::nest(&mut fields, "queue", self, &self.queue);
+        fields.extend(vec![
+            Field::new("cnt", Side::Both, self, ptr::addr_of!(self.cnt)),
+            Field::new("steals", Side::Consumer, self, ptr::addr_of!(self.steals)),
+            Field::new("to_wake", Side::Both, self, ptr::addr_of!(self.to_wake)),
+            Field::new("disconnected", Side::Cold, self, ptr::addr_of!(self.disconnected)),
+            Field::new("port_dropped", Side::Producer, self, ptr::addr_of!(self.port_dropped)),
+        ]);
+        fields
+    }
+}
+
+impl<T> Drop for SharedPacket<T> {
+    fn drop(&mut self) {
+        // Note that this load is not only an assert for correctness about
+        // disconnection, but also a proper fence before the read of
+        // `to_wake`, so this assert cannot be removed without also removing
+        // the `to_wake` assert.
+        assert!(self.port_dropped.load(Ordering::SeqCst));
+        assert_eq!(self.to_wake.load(Ordering::SeqCst), 0);
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{SharedPacket, Empty, Disconnected, Timeout};
+    use verify::DropCounter;
+
+    #[test]
+    fn smoke() {
+        let p = SharedPacket::new();
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        assert_eq!(p.recv(None), Ok(1));
+        assert_eq!(p.try_recv(), Ok(2));
+        assert_eq!(p.try_recv(), Err(Empty));
+        p.drop_chan();
+        assert_eq!(p.recv(None), Err(Disconnected));
+        p.drop_port();
+    }
+
+    #[test]
+    fn disconnects_on_last_sender() {
+        let p = SharedPacket::new();
+        p.clone_chan();
+        p.clone_chan();
+        p.send(1).unwrap();
+        p.drop_chan();
+        p.drop_chan();
+        assert_eq!(p.recv(None), Ok(1));
+        assert_eq!(p.try_recv(), Err(Empty));
+        p.drop_chan();
+        assert_eq!(p.try_recv(), Err(Disconnected));
+        p.drop_port();
+    }
+
+    #[test]
+    fn send_after_port_drop() {
+        let p = SharedPacket::new();
+        p.drop_port();
+        assert_eq!(p.send(1), Err(1));
+        p.drop_chan();
+    }
+
+    #[test]
+    fn port_drop_drains() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let p = SharedPacket::new();
+        for _ in 0..10 {
+            p.send(DropCounter(drops.clone())).ok().unwrap();
+        }
+        p.drop_port();
+        assert_eq!(drops.load(Ordering::SeqCst), 10);
+        p.drop_chan();
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let p = SharedPacket::<i32>::new();
+        let start = Instant::now();
+        assert_eq!(p.recv(Some(start + Duration::from_millis(50))), Err(Timeout));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(p.to_wake.load(Ordering::SeqCst), 0);
+        p.send(1).unwrap();
+        assert_eq!(p.recv(Some(Instant::now() + Duration::from_millis(50))), Ok(1));
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn last_sender_wakes_receiver() {
+        let p = Arc::new(SharedPacket::<i32>::new());
+        p.clone_chan();
+        let senders: Vec<_> = (0..2).map(|_| {
+            let p = p.clone();
+            thread::spawn(move|| {
+                thread::sleep(Duration::from_millis(20));
+                p.drop_chan();
+            })
+        }).collect();
+        assert_eq!(p.recv(None), Err(Disconnected));
+        for s in senders { s.join().unwrap() }
+        p.drop_port();
+    }
+
+    #[test]
+    fn stress() {
+        const SENDERS: usize = 4;
+        const COUNT: usize = 10_000;
+        let p = Arc::new(SharedPacket::new());
+        for _ in 1..SENDERS { p.clone_chan() }
+        let senders: Vec<_> = (0..SENDERS).map(|s| {
+            let p = p.clone();
+            thread::spawn(move|| {
+                for i in 0..COUNT {
+                    p.send((s, i)).unwrap();
+                    if i % 64 == 0 { thread::yield_now() }
+                }
+                p.drop_chan();
+            })
+        }).collect();
+
+        // each sender's messages arrive in order
+        let mut next = [0; SENDERS];
+        loop {
+            match p.recv(None) {
+                Ok((s, i)) => { assert_eq!(i, next[s]); next[s] += 1 }
+                Err(Disconnected) => break,
+                Err(..) => unreachable!(),
+            }
+        }
+        assert_eq!(next, [COUNT; SENDERS]);
+        for s in senders { s.join().unwrap() }
+        p.drop_port();
+    }
+
+    #[test]
+    fn short_timeouts_racing_sends() {
+        const COUNT: usize = 2_000;
+        let p = Arc::new(SharedPacket::new());
+        p.clone_chan();
+        let senders: Vec<_> = (0..2).map(|_| {
+            let p = p.clone();
+            thread::spawn(move|| {
+                for i in 0..COUNT {
+                    p.send(i).unwrap();
+                    if i % 4 == 0 { thread::yield_now() }
+                }
+            })
+        }).collect();
+        let mut received = 0;
+        while received < 2 * COUNT {
+            match p.recv(Some(Instant::now() + Duration::new(0, 1_000))) {
+                Ok(..) => received += 1,
+                Err(Timeout) => {}
+                Err(..) => panic!(),
+            }
+        }
+        for s in senders { s.join().unwrap() }
+        assert_eq!(p.to_wake.load(Ordering::SeqCst), 0);
+        p.drop_chan();
+        p.drop_chan();
+        p.drop_port();
+    }
+}
@@ -0,0 +1,631 @@
+// Copyright 2013-2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// Shared channels, as std has them
+///
+/// This is the flavor of channels which are not necessarily optimized for any
+/// particular use case, but are the most general in how they are used. Shared
+/// channels are cloneable allowing for multiple senders.
+///
+/// This is a copy of std's shared.rs from 1.22, kept as close to it as
+/// possible so that the shared slowdown can be bisected here rather than in
+/// rustc: the count still carries the DISCONNECTED sentinel, with the FUDGE
+/// range for racing senders and `sender_drain` arbitrating who drains the
+/// queue after the port is dropped. The queue is this crate's `mpmc::Queue`.
+///
+/// Like the oneshot flavor this version has no selection support. The
+/// select lock, `start_selection`, `can_recv`, and the `inherit_blocker` and
+/// `postinit_lock` used when upgrading to it are gone, and
+/// `abort_selection` is only used to take back the token of a receiver which
+/// timed out. Nothing upgrades to this packet, so it starts with one sender.
+///
+/// `new` keeps std's layout, with `cnt`, `steals`, `to_wake`, `channels` and
+/// `port_dropped` next to each other though both sides write them. `aligned`
+/// gives each of those fields, and `sender_drain`, its own line, with the
+/// queue left as std has it, so that the difference between the two is the
+/// false sharing between the packet's own fields.
+
+pub use self::Failure::*;
+use self::StartResult::*;
+
+use std::cell::UnsafeCell;
+use std::cmp;
+use std::isize;
+use std::process;
+use std::ptr;
+use std::thread;
+use std::time::Instant;
+
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+
+use blocking::{self, SignalToken};
+use cache_padded::{CacheAligned, NoAlign, Padding};
+use layout::{self, Field, Layout, Side};
+use mpmc;
+
+const DISCONNECTED: isize = isize::MIN;
+const FUDGE: isize = 1024;
+const MAX_REFCOUNT: usize = (isize::MAX) as usize;
+#[cfg(test)]
+const MAX_STEALS: isize = 5;
+#[cfg(not(test))]
+const MAX_STEALS: isize = 1 << 20;
+
+pub struct Packet<T, A: Padding = NoAlign> {
+    queue: mpmc::Queue<T, mpmc::NoAlign>,
+    cnt: A::Padded<AtomicIsize>, // How many items are on this channel
+    steals: A::Padded<UnsafeCell<isize>>, // How many times has a port received without blocking?
+    to_wake: A::Padded<AtomicUsize>, // SignalToken for wake up
+
+    // The number of channels which are currently using this packet.
+    channels: A::Padded<AtomicUsize>,
+
+    // See the discussion in Port::drop and the channel send methods for what
+    // these are used for
+    port_dropped: A::Padded<AtomicBool>,
+    sender_drain: A::Padded<AtomicIsize>,
+}
+
+unsafe impl<T: Send, A: Padding> Send for Packet<T, A> {}
+unsafe impl<T: Send, A: Padding> Sync for Packet<T, A> {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure {
+    Empty,
+    Disconnected,
+}
+
+#[derive(PartialEq, Eq)]
+enum StartResult {
+    Installed,
+    Abort,
+}
+
+impl<T> Packet<T, NoAlign> {
+    /// Creates a packet with one sender and one receiver.
+    pub fn new() -> Self {
+        Packet::padded()
+    }
+}
+
+impl<T> Packet<T, CacheAligned> {
+    /// `new`, with each of the packet's fields on its own line.
+    pub fn aligned() -> Self {
+        Packet::padded()
+    }
+}
+
+impl<T, A: Padding> Packet<T, A> {
+    fn padded() -> Self {
+        Packet {
+            queue: mpmc::Queue::new(),
+            cnt: A::pad(AtomicIsize::new(0)),
+            steals: A::pad(UnsafeCell::new(0)),
+            to_wake: A::pad(AtomicUsize::new(0)),
+            channels: A::pad(AtomicUsize::new(1)),
+            port_dropped: A::pad(AtomicBool::new(false)),
+            sender_drain: A::pad(AtomicIsize::new(0)),
+        }
+    }
+
+    pub fn send(&self, t: T) -> Result<(), T> {
+        // See Port::drop for what's going on
+        if self.port_dropped.load(Ordering::SeqCst) { return Err(t) }
+
+        // Note that the multiple sender case is a little trickier
+        // semantically than the single sender case. The logic for
+        // incrementing is "add and if disconnected store disconnected".
+        // This could end up leading some senders to believe that there
+        // wasn't a disconnect if in fact there was a disconnect. This means
+        // that while one thread is attempting to re-store the disconnected
+        // states, other threads could walk through merrily incrementing
+        // this very-negative disconnected count. To prevent senders from
+        // spuriously attempting to send when the channels is actually
+        // disconnected, the count has a ranged check here.
+        //
+        // This is also done for another reason. Remember that the return
+        // value of this function is:
+        //
+        //  `true` == the data *may* be received, this essentially has no
+        //            meaning
+        //  `false` == the data will *never* be received, this has a lot of
+        //             meaning
+        //
+        // In the SPSC case, we have a check of 'queue.is_empty()' to see
+        // whether the data was actually received, but this same condition
+        // means nothing in a multi-producer context. As a result, this
+        // preflight check serves as the definitive "this will never be
+        // received". Once we get beyond this check, we have permanently
+        // entered the realm of "this may be received"
+        if self.cnt.load(Ordering::SeqCst) < DISCONNECTED + FUDGE {
+            return Err(t)
+        }
+
+        self.queue.push(t);
+        match self.cnt.fetch_add(1, Ordering::SeqCst) {
+            -1 => {
+                self.take_to_wake().signal();
+            }
+
+            // In this case, we have possibly failed to send our data, and
+            // we need to consider re-popping the data in order to fully
+            // destroy it. We must arbitrate among the multiple senders,
+            // however, because the queues that we're using are
+            // single-consumer queues. In order to do this, all exiting
+            // pushers will use an atomic count in order to count those
+            // flowing through. Pushers who see 0 are required to drain as
+            // much as possible, and then can only exit when they are the
+            // only pusher (otherwise they must try again).
+            n if n < DISCONNECTED + FUDGE => {
+                // see the comment in 'try' for a shared channel for why this
+                // window of "not disconnected" is ok.
+                self.cnt.store(DISCONNECTED, Ordering::SeqCst);
+
+                if self.sender_drain.fetch_add(1, Ordering::SeqCst) == 0 {
+                    loop {
+                        // drain the queue, for info on the thread yield see the
+                        // discussion in try_recv
+                        loop {
+                            match self.queue.pop() {
+                                mpmc::Data(..) => {}
+                                mpmc::Empty => break,
+                                mpmc::Inconsistent => thread::yield_now(),
+                            }
+                        }
+                        // maybe we're done, if we're not the last ones
+                        // here, then we need to go try again.
+                        if self.sender_drain.fetch_sub(1, Ordering::SeqCst) == 1 {
+                            break
+                        }
+                    }
+
+                    // At this point, there may still be data on the queue,
+                    // but only if the count hasn't been incremented and
+                    // some other sender hasn't finished pushing data just
+                    // yet. That sender in question will drain its own data.
+                }
+            }
+
+            // Can't make any assumptions about this case like in the SPSC case.
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn recv(&self, deadline: Option<Instant>) -> Result<T, Failure> {
+        // This code is essentially the exact same as that found in the stream
+        // case (see stream.rs)
+        match self.try_recv() {
+            Err(Empty) => {}
+            data => return data,
+        }
+
+        let (wait_token, signal_token) = blocking::tokens();
+        if self.decrement(signal_token) == Installed {
+            if let Some(deadline) = deadline {
+                let timed_out = !wait_token.wait_max_until(deadline);
+                if timed_out {
+                    self.abort_selection(/* was_upgrade = */ false);
+                }
+            } else {
+                wait_token.wait();
+            }
+        }
+
+        match self.try_recv() {
+            data @ Ok(..) => unsafe {
+                *self.steals.get() -= 1;
+                data
+            },
+            data => data,
+        }
+    }
+
+    // Essentially the exact same thing as the stream decrement function.
+    // Returns true if blocking should proceed.
+    fn decrement(&self, token: SignalToken) -> StartResult {
+        unsafe {
+            assert_eq!(self.to_wake.load(Ordering::SeqCst), 0);
+            let ptr = token.cast_to_usize();
+            self.to_wake.store(ptr, Ordering::SeqCst);
+
+            let steals = ptr::replace(self.steals.get(), 0);
+
+            match self.cnt.fetch_sub(1 + steals, Ordering::SeqCst) {
+                DISCONNECTED => { self.cnt.store(DISCONNECTED, Ordering::SeqCst); }
+                // If we factor in our steals and notice that the channel has no
+                // data, we successfully sleep
+                n => {
+                    assert!(n >= 0);
+                    if n - steals <= 0 { return Installed }
+                }
+            }
+
+            self.to_wake.store(0, Ordering::SeqCst);
+            drop(SignalToken::cast_from_usize(ptr));
+            Abort
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, Failure> {
+        let ret = match self.queue.pop() {
+            mpmc::Data(t) => Some(t),
+            mpmc::Empty => None,
+
+            // This is a bit of an interesting case. The channel is reported as
+            // having data available, but our pop() has failed due to the queue
+            // being in an inconsistent state.  This means that there is some
+            // pusher somewhere which has yet to complete, but we are guaranteed
+            // that a pop will eventually succeed. In this case, we spin in a
+            // yield loop because the remote sender should finish their enqueue
+            // operation "very quickly".
+            //
+            // Avoiding this yield loop would require a different queue
+            // abstraction which provides the guarantee that after M pushes have
+            // succeeded, at least M pops will succeed. The current queues
+            // guarantee that if there are N active pushes, you can pop N times
+            // once all N have finished.
+            mpmc::Inconsistent => {
+                let data;
+                loop {
+                    thread::yield_now();
+                    match self.queue.pop() {
+                        mpmc::Data(t) => { data = t; break }
+                        mpmc::Empty => panic!("inconsistent => empty"),
+                        mpmc::Inconsistent => {}
+                    }
+                }
+                Some(data)
+            }
+        };
+        match ret {
+            // See the discussion in the stream implementation for why we
+            // might decrement steals.
+            Some(data) => unsafe {
+                if *self.steals.get() > MAX_STEALS {
+                    match self.cnt.swap(0, Ordering::SeqCst) {
+                        DISCONNECTED => {
+                            self.cnt.store(DISCONNECTED, Ordering::SeqCst);
+                        }
+                        n => {
+                            let m = cmp::min(n, *self.steals.get());
+                            *self.steals.get() -= m;
+                            self.bump(n - m);
+                        }
+                    }
+                    assert!(*self.steals.get() >= 0);
+                }
+                *self.steals.get() += 1;
+                Ok(data)
+            },
+
+            // See the discussion in the stream implementation for why we try
+            // again.
+            None => {
+                match self.cnt.load(Ordering::SeqCst) {
+                    n if n != DISCONNECTED => Err(Empty),
+                    _ => {
+                        match self.queue.pop() {
+                            mpmc::Data(t) => Ok(t),
+                            mpmc::Empty => Err(Disconnected),
+                            // with no senders, an inconsistency is impossible.
+                            mpmc::Inconsistent => unreachable!(),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Prepares this shared packet for a channel clone, essentially just bumping
+    // a refcount.
+    pub fn clone_chan(&self) {
+        let old_count = self.channels.fetch_add(1, Ordering::SeqCst);
+
+        // See comments on Arc::clone() on why we do this (for `mem::forget`).
+        if old_count > MAX_REFCOUNT {
+            process::abort();
+        }
+    }
+
+    // Decrement the reference count on a channel. This is called whenever a
+    // Chan is dropped and may end up waking up a receiver. It's the receiver's
+    // responsibility on the other end to figure out that we've disconnected.
+    pub fn drop_chan(&self) {
+        match self.channels.fetch_sub(1, Ordering::SeqCst) {
+            1 => {}
+            n if n > 1 => return,
+            n => panic!("bad number of channels left {}", n),
+        }
+
+        match self.cnt.swap(DISCONNECTED, Ordering::SeqCst) {
+            -1 => { self.take_to_wake().signal(); }
+            DISCONNECTED => {}
+            n => { assert!(n >= 0); }
+        }
+    }
+
+    // See the long discussion inside of stream.rs for why the queue is drained,
+    // and why it is done in this fashion.
+    pub fn drop_port(&self) {
+        self.port_dropped.store(true, Ordering::SeqCst);
+        let mut steals = unsafe { *self.steals.get() };
+        while {
+            let cnt = match self.cnt.compare_exchange(steals, DISCONNECTED, Ordering::SeqCst,
+                                                      Ordering::SeqCst) {
+                Ok(cnt) | Err(cnt) => cnt,
+            };
+            cnt != DISCONNECTED && cnt != steals
+        } {
+            // See the discussion in 'try_recv' for why we yield
+            // control of this thread.
+            loop {
+                match self.queue.pop() {
+                    mpmc::Data(..) => { steals += 1; }
+                    mpmc::Empty | mpmc::Inconsistent => break,
+                }
+            }
+        }
+    }
+
+    // Consumes ownership of the 'to_wake' field.
+    fn take_to_wake(&self) -> SignalToken {
+        let ptr = self.to_wake.load(Ordering::SeqCst);
+        self.to_wake.store(0, Ordering::SeqCst);
+        assert!(ptr != 0);
+        unsafe { SignalToken::cast_from_usize(ptr) }
+    }
+
+    // increment the count on the channel (used for selection)
+    fn bump(&self, amt: isize) -> isize {
+        match self.cnt.fetch_add(amt, Ordering::SeqCst) {
+            DISCONNECTED => {
+                self.cnt.store(DISCONNECTED, Ordering::SeqCst);
+                DISCONNECTED
+            }
+            n => n
+        }
+    }
+
+    // Removes a previous thread from being blocked in this port. std bounces
+    // on the select lock first, to wait out an in-progress inherit_blocker;
+    // without upgrades into this packet there is nothing to wait for.
+    fn abort_selection(&self, _was_upgrade: bool) -> bool {
+        // Like the stream implementation, we want to make sure that the count
+        // on the channel goes non-negative. We don't know how negative the
+        // stream currently is, so instead of using a steal value of 1, we load
+        // the channel count and figure out what we should do to make it
+        // positive.
+        let steals = {
+            let cnt = self.cnt.load(Ordering::SeqCst);
+            if cnt < 0 && cnt != DISCONNECTED {-cnt} else {0}
+        };
+        let prev = self.bump(steals + 1);
+
+        if prev == DISCONNECTED {
+            assert_eq!(self.to_wake.load(Ordering::SeqCst), 0);
+            true
+        } else {
+            let cur = prev + steals + 1;
+            assert!(cur >= 0);
+            if prev < 0 {
+                drop(self.take_to_wake());
+            } else {
+                while self.to_wake.load(Ordering::SeqCst) != 0 {
+                    thread::yield_now();
+                }
+            }
+            unsafe {
+                // if the number of steals is -1, it was the pre-emptive -1 steal
+                // count from when we inherited a blocker. This is fine because
+                // we're just going to overwrite it with a real value.
+                let old = self.steals.get();
+                assert!(*old == 0 || *old == -1);
+                *old = steals;
+                prev >= 0
+            }
+        }
+    }
+}
+
+impl<T, A: Padding> Layout for Packet<T, A> {
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = Vec::new();
+        layout::nest(&mut fields, "queue", self, &self.queue);
+        fields.extend(vec![
+            Field::new("cnt", Side::Both, self, ptr::addr_of!(self.cnt)),
+            Field::new("steals", Side::Consumer, self, ptr::addr_of!(self.steals)),
+            Field::new("to_wake", Side::Both, self, ptr::addr_of!(self.to_wake)),
+            Field::new("channels", Side::Cold, self, ptr::addr_of!(self.channels)),
+            Field::new("port_dropped", Side::Producer, self, ptr::addr_of!(self.port_dropped)),
+            Field::new("sender_drain", Side::Cold, self, ptr::addr_of!(self.sender_drain)),
+        ]);
+        fields
+    }
+}
+
+impl<T, A: Padding> Drop for Packet<T, A> {
+    fn drop(&mut self) {
+        // Note that this load is not only an assert for correctness about
+        // disconnection, but also a proper fence before the read of
+        // `to_wake`, so this assert cannot be removed with also removing
+        // the `to_wake` assert.
+        assert_eq!(self.cnt.load(Ordering::SeqCst), DISCONNECTED);
+        assert_eq!(self.to_wake.load(Ordering::SeqCst), 0);
+        assert_eq!(self.channels.load(Ordering::SeqCst), 0);
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{Packet, Empty, Disconnected};
+    use cache_padded::Padding;
+
+    #[test]
+    fn smoke() {
+        let p = Packet::new();
+        assert_eq!(p.try_recv(), Err(Empty));
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        assert_eq!(p.recv(None), Ok(1));
+        assert_eq!(p.try_recv(), Ok(2));
+        p.drop_chan();
+        assert_eq!(p.recv(None), Err(Disconnected));
+        p.drop_port();
+    }
+
+    #[test]
+    fn send_after_port_drop() {
+        let p = Packet::new();
+        p.drop_port();
+        assert_eq!(p.send(1), Err(1));
+        p.drop_chan();
+    }
+
+    #[test]
+    fn disconnect_after_last_clone() {
+        let p = Packet::<i32>::new();
+        p.clone_chan();
+        p.drop_chan();
+        assert_eq!(p.try_recv(), Err(Empty));
+        p.drop_chan();
+        assert_eq!(p.try_recv(), Err(Disconnected));
+        p.drop_port();
+    }
+
+    #[test]
+    fn recv_wakes_on_chan_drop() {
+        let p = Arc::new(Packet::<i32>::new());
+        let p2 = p.clone();
+        let t = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(20));
+            p2.drop_chan();
+        });
+        assert_eq!(p.recv(None), Err(Disconnected));
+        t.join().unwrap();
+        p.drop_port();
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let p = Packet::<i32>::new();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(p.recv(Some(deadline)), Err(Empty));
+        assert!(Instant::now() >= deadline);
+        p.send(1).unwrap();
+        assert_eq!(p.recv(Some(Instant::now())), Ok(1));
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    // Enough messages to pass MAX_STEALS many times over, from several
+    // senders, with the receiver blocking whenever it runs dry.
+    #[test]
+    fn many_senders() {
+        many_senders_with(Packet::new());
+        many_senders_with(Packet::aligned());
+    }
+
+    fn many_senders_with<A: Padding + 'static>(p: Packet<usize, A>) {
+        const SENDERS: usize = 4;
+        const COUNT: usize = 10_000;
+        let p = Arc::new(p);
+        for _ in 1..SENDERS { p.clone_chan() }
+        let threads: Vec<_> = (0..SENDERS).map(|_| {
+            let p = p.clone();
+            thread::spawn(move|| {
+                for i in 0..COUNT {
+                    p.send(i).unwrap();
+                    if i % 64 == 0 { thread::yield_now() }
+                }
+                p.drop_chan();
+            })
+        }).collect();
+        let mut received = 0;
+        while let Ok(_) = p.recv(None) {
+            received += 1;
+        }
+        assert_eq!(received, SENDERS * COUNT);
+        for t in threads {
+            t.join().unwrap();
+        }
+        p.drop_port();
+    }
+
+    #[test]
+    fn short_timeouts_racing_sends() {
+        const COUNT: usize = 2_000;
+        let p = Arc::new(Packet::new());
+        let p2 = p.clone();
+        let t = thread::spawn(move|| {
+            for i in 0..COUNT {
+                p2.send(i).unwrap();
+                if i % 8 == 0 { thread::yield_now() }
+            }
+            p2.drop_chan();
+        });
+        let mut received = 0;
+        loop {
+            match p.recv(Some(Instant::now() + Duration::from_micros(10))) {
+                Ok(_) => received += 1,
+                Err(Empty) => {}
+                Err(Disconnected) => break,
+            }
+        }
+        assert_eq!(received, COUNT);
+        t.join().unwrap();
+        p.drop_port();
+    }
+
+    #[test]
+    fn port_drop_with_racing_senders() {
+        let p = Arc::new(Packet::new());
+        p.clone_chan();
+        let threads: Vec<_> = (0..2).map(|_| {
+            let p = p.clone();
+            thread::spawn(move|| {
+                while p.send(1).is_ok() {
+                    thread::yield_now();
+                }
+                p.drop_chan();
+            })
+        }).collect();
+        thread::yield_now();
+        p.drop_port();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn aligned_layout() {
+        use layout::Report;
+        let p = Packet::<u64>::new();
+        assert!(!Report::of(&p).contended().is_empty());
+        p.drop_chan();
+        p.drop_port();
+        let p = Packet::<u64, _>::aligned();
+        let aligned = Report::of(&p);
+        let own_line = |name: &str| {
+            let offset = aligned.fields.iter().find(|f| f.name == name).unwrap().offset;
+            aligned.fields.iter().all(|f| f.name == name || f.offset / 64 != offset / 64)
+        };
+        for name in &["cnt", "steals", "to_wake", "channels", "port_dropped", "sender_drain"] {
+            assert!(own_line(name), "{} shares a line\n{}", name, aligned);
+        }
+        p.drop_chan();
+        p.drop_port();
+    }
+}
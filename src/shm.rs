@@ -0,0 +1,383 @@
+//! A bounded spsc ring in a shared file mapping, for Linux, so that the
+//! producer and consumer can be separate processes.
+//!
+//! The file starts with a header holding a magic number, a layout version,
+//! the size of the elements and the capacity, and then the consumer's and
+//! producer's indices, each on its own cache line. The slots follow it. The
+//! indices count every push and pop, and are masked into the slots. Each
+//! process keeps its own copy of the other side's index, only refreshed when
+//! the copy says the ring is full, or empty.
+//!
+//! The creator fills in the header and stores the magic number last, so an
+//! `open` racing `create` fails rather than reading half a header. `open`
+//! also checks the layout and indices are ones this code could have written,
+//! so that a stale or foreign file is an error rather than a mapping of
+//! garbage.
+//!
+//! There is no protection from a crashed peer beyond that: if one side dies
+//! the other just sees a ring which never fills, or never empties. Nothing
+//! stops a second producer or consumer from opening the file either.
+//!
+//! Only `T: Copy` elements are supported, and both sides must agree on `T`.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const MAGIC: u64 = 0x7370_7363_5f73_686d; // "spsc_shm"
+pub const VERSION: u32 = 1;
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+// std links libc anyway, so there's no need for the crate to reach these.
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64)
+        -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+#[repr(C, align(64))]
+struct Index {
+    value: AtomicU64,
+}
+
+#[repr(C)]
+struct Header {
+    magic: AtomicU64,
+    version: u32,
+    elem_size: u32,
+    capacity: u64,
+    head: Index, // pops, written by the consumer
+    tail: Index, // pushes, written by the producer
+}
+
+pub struct Ring<T: Copy> {
+    map: *mut c_void,
+    map_len: usize,
+    mask: u64,
+    head_copy: UnsafeCell<u64>, // the producer's copy of head
+    tail_copy: UnsafeCell<u64>, // the consumer's copy of tail
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for Ring<T> { }
+unsafe impl<T: Copy + Send> Sync for Ring<T> { }
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+// The slots start at the first multiple of T's alignment after the header.
+fn slots_offset<T>() -> usize {
+    let align = mem::align_of::<T>();
+    (mem::size_of::<Header>() + align - 1) / align * align
+}
+
+fn map_len<T>(capacity: u64) -> Option<usize> {
+    (capacity as usize).checked_mul(mem::size_of::<T>())
+        .and_then(|slots| slots.checked_add(slots_offset::<T>()))
+}
+
+impl<T: Copy> Ring<T> {
+    /// Creates the file at `path`, or truncates it, and maps a ring of at
+    /// least `capacity` elements, rounded up to a power of two, in it.
+    ///
+    /// This is unsafe as nothing enforces a single producer and consumer
+    /// across the processes mapping the file, nor that they agree on `T`
+    /// beyond its size.
+    pub unsafe fn create<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<Self> {
+        let capacity = capacity.max(1).next_power_of_two() as u64;
+        let len = map_len::<T>(capacity).ok_or_else(|| invalid("capacity too large"))?;
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        let ring = Ring::map(&file, len, capacity)?;
+        let header = ring.map as *mut Header;
+        (*header).version = VERSION;
+        (*header).elem_size = mem::size_of::<T>() as u32;
+        (*header).capacity = capacity;
+        (*header).head.value.store(0, Ordering::Relaxed);
+        (*header).tail.value.store(0, Ordering::Relaxed);
+        (*header).magic.store(MAGIC, Ordering::Release);
+        Ok(ring)
+    }
+
+    /// Maps the ring `create` made at `path`, checking that its header and
+    /// indices are valid for a ring of `T`s.
+    ///
+    /// This is unsafe for the same reasons as `create`.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len();
+        if len < mem::size_of::<Header>() as u64 {
+            return Err(invalid("too short for a header"))
+        }
+        // Map just the header until it's checked.
+        let header = Ring::<T>::map(&file, mem::size_of::<Header>(), 1)?;
+        let capacity = header.validate(len)?;
+        drop(header);
+        let mut ring = Ring::map(&file, len as usize, capacity)?;
+        // The file could have changed in between.
+        ring.validate(len)?;
+        ring.head_copy = UnsafeCell::new(ring.header().head.value.load(Ordering::Acquire));
+        ring.tail_copy = UnsafeCell::new(ring.header().tail.value.load(Ordering::Acquire));
+        Ok(ring)
+    }
+
+    unsafe fn map(file: &File, len: usize, capacity: u64) -> io::Result<Self> {
+        let map = mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_SHARED,
+            file.as_raw_fd(), 0);
+        if map == MAP_FAILED {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(Ring {
+            map: map,
+            map_len: len,
+            mask: capacity - 1,
+            head_copy: UnsafeCell::new(0),
+            tail_copy: UnsafeCell::new(0),
+            _marker: PhantomData,
+        })
+    }
+
+    // Returns the capacity, if the header is that of a ring of Ts filling a
+    // file of `len` bytes.
+    fn validate(&self, len: u64) -> io::Result<u64> {
+        let header = self.header();
+        if header.magic.load(Ordering::Acquire) != MAGIC {
+            return Err(invalid("bad magic number, not a ring or not yet created"))
+        }
+        if header.version != VERSION {
+            return Err(invalid("unknown layout version"))
+        }
+        if header.elem_size as usize != mem::size_of::<T>() {
+            return Err(invalid("element size doesn't match"))
+        }
+        let capacity = header.capacity;
+        if capacity == 0 || !capacity.is_power_of_two() {
+            return Err(invalid("capacity isn't a power of two"))
+        }
+        if map_len::<T>(capacity).map(|l| l as u64) != Some(len) {
+            return Err(invalid("file length doesn't match the capacity"))
+        }
+        let head = header.head.value.load(Ordering::Acquire);
+        let tail = header.tail.value.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) > capacity {
+            return Err(invalid("indices are further apart than the capacity"))
+        }
+        Ok(capacity)
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.map as *const Header) }
+    }
+
+    fn slot(&self, index: u64) -> *mut T {
+        unsafe {
+            ((self.map as *mut u8).add(slots_offset::<T>()) as *mut T)
+                .add((index & self.mask) as usize)
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.mask as usize + 1
+    }
+
+    /// Pushes `t` unless the ring is full, in which case it's handed back.
+    /// Note that to use this function safely, it must be externally
+    /// guaranteed that there is only one pusher.
+    pub fn try_push(&self, t: T) -> Result<(), T> {
+        unsafe {
+            let header = self.header();
+            let tail = header.tail.value.load(Ordering::Relaxed);
+            let head_copy = self.head_copy.get();
+            if tail.wrapping_sub(*head_copy) > self.mask {
+                *head_copy = header.head.value.load(Ordering::Acquire);
+                if tail.wrapping_sub(*head_copy) > self.mask {
+                    return Err(t)
+                }
+            }
+            ptr::write_volatile(self.slot(tail), t);
+            header.tail.value.store(tail.wrapping_add(1), Ordering::Release);
+            Ok(())
+        }
+    }
+
+    /// Attempts to pop a value from the ring. Remember that to use this type
+    /// safely you must ensure that there is only one popper at a time.
+    pub fn pop(&self) -> Option<T> {
+        unsafe {
+            let header = self.header();
+            let head = header.head.value.load(Ordering::Relaxed);
+            let tail_copy = self.tail_copy.get();
+            if head == *tail_copy {
+                *tail_copy = header.tail.value.load(Ordering::Acquire);
+                if head == *tail_copy {
+                    return None
+                }
+            }
+            let t = ptr::read_volatile(self.slot(head));
+            header.head.value.store(head.wrapping_add(1), Ordering::Release);
+            Some(t)
+        }
+    }
+
+    /// The number of values pushed and not yet popped, from either side.
+    pub fn len(&self) -> usize {
+        let header = self.header();
+        let head = header.head.value.load(Ordering::Acquire);
+        let tail = header.tail.value.load(Ordering::Acquire);
+        tail.wrapping_sub(head) as usize
+    }
+}
+
+impl<T: Copy> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe { munmap(self.map, self.map_len); }
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::mem;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+    use super::{Header, Ring};
+
+    // A file under the temp dir, removed once the test is done with it.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            TempPath(env::temp_dir().join(format!("std_spsc_is_slow_shm_{}_{}", process::id(), name)))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn smoke() {
+        let path = TempPath::new("smoke");
+        unsafe {
+            let ring = Ring::create(&path.0, 4).unwrap();
+            assert_eq!(ring.capacity(), 4);
+            assert_eq!(ring.pop(), None);
+            ring.try_push(1u64).unwrap();
+            ring.try_push(2).unwrap();
+            assert_eq!(ring.len(), 2);
+            assert_eq!(ring.pop(), Some(1));
+            assert_eq!(ring.pop(), Some(2));
+            assert_eq!(ring.pop(), None);
+        }
+    }
+
+    #[test]
+    fn full() {
+        let path = TempPath::new("full");
+        unsafe {
+            let ring = Ring::create(&path.0, 3).unwrap();
+            for i in 0..4u32 {
+                ring.try_push(i).unwrap();
+            }
+            assert_eq!(ring.try_push(4), Err(4));
+            assert_eq!(ring.pop(), Some(0));
+            ring.try_push(4).unwrap();
+            for i in 1..5 {
+                assert_eq!(ring.pop(), Some(i));
+            }
+        }
+    }
+
+    // Two mappings of the same file, as two processes would have.
+    #[test]
+    fn two_mappings() {
+        let path = TempPath::new("two_mappings");
+        unsafe {
+            let tx = Ring::create(&path.0, 16).unwrap();
+            tx.try_push(7u64).unwrap();
+            let rx = Arc::new(Ring::<u64>::open(&path.0).unwrap());
+            assert_eq!(rx.capacity(), 16);
+            assert_eq!(rx.pop(), Some(7));
+
+            let consumer = {
+                let rx = rx.clone();
+                thread::spawn(move|| {
+                    for i in 0..100_000 {
+                        loop {
+                            if let Some(j) = rx.pop() {
+                                assert_eq!(i, j);
+                                break
+                            }
+                            thread::yield_now();
+                        }
+                    }
+                })
+            };
+            for i in 0..100_000u64 {
+                while tx.try_push(i).is_err() { thread::yield_now() }
+            }
+            consumer.join().unwrap();
+            assert_eq!(tx.len(), 0);
+        }
+    }
+
+    fn corrupt(path: &PathBuf, offset: u64, bytes: &[u8]) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn open_validates() {
+        let path = TempPath::new("open_validates");
+        unsafe {
+            assert!(Ring::<u64>::open(&path.0).is_err());
+            fs::write(&path.0, b"short").unwrap();
+            assert!(Ring::<u64>::open(&path.0).is_err());
+            fs::write(&path.0, vec![0; 4096]).unwrap();
+            assert!(Ring::<u64>::open(&path.0).is_err());
+
+            drop(Ring::<u64>::create(&path.0, 8).unwrap());
+            assert!(Ring::<u64>::open(&path.0).is_ok());
+            // the wrong element type
+            assert!(Ring::<u32>::open(&path.0).is_err());
+
+            // a truncated file
+            let len = fs::metadata(&path.0).unwrap().len();
+            OpenOptions::new().write(true).open(&path.0).unwrap().set_len(len - 8).unwrap();
+            assert!(Ring::<u64>::open(&path.0).is_err());
+
+            // a capacity which isn't a power of two
+            drop(Ring::<u64>::create(&path.0, 8).unwrap());
+            corrupt(&path.0, 16, &6u64.to_ne_bytes());
+            assert!(Ring::<u64>::open(&path.0).is_err());
+
+            // the tail further from the head than the capacity
+            drop(Ring::<u64>::create(&path.0, 8).unwrap());
+            let tail = mem::size_of::<Header>() as u64 - 64;
+            corrupt(&path.0, tail, &9u64.to_ne_bytes());
+            assert!(Ring::<u64>::open(&path.0).is_err());
+            corrupt(&path.0, tail, &8u64.to_ne_bytes());
+            assert!(Ring::<u64>::open(&path.0).is_ok());
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Running one producer and one consumer over a queue without touching its
+//! unsafe constructors or its single producer, single consumer contract.
+//!
+//! `run_split_with` takes the queue by value, so nothing else can push or pop
+//! it, hands a `Producer` to a scoped thread and a `Consumer` to the calling
+//! one, and joins the producer before returning. Neither half can be cloned
+//! or shared between threads, so each end stays on one thread at a time.
+//! `run_split` does the same for a queue named by a `factory::QueueKind`.
+//!
+//! A producer panic is caught on its thread and resumed on the caller's, with
+//! its own payload, once the consumer is done. The `Consumer` sees the
+//! `Producer` go however it goes, so `recv` returns `None` rather than
+//! waiting forever on a producer which panicked.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossbeam::scope;
+
+use backoff::Backoff;
+use cache_padded::Padding;
+use factory::QueueKind;
+use spsc;
+use spsc2;
+use stream2;
+
+/// The queues `run_split_with` can split.
+pub trait SplitQueue<T> {
+    fn push(&self, t: T);
+    fn pop(&self) -> Option<T>;
+}
+
+impl<T, A: Padding, C: spsc::UseCache> SplitQueue<T> for spsc::Queue<T, A, C> {
+    fn push(&self, t: T) {
+        self.push(t)
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
+}
+
+impl<T, A: Padding> SplitQueue<T> for spsc2::Queue<T, A> {
+    fn push(&self, t: T) {
+        self.push(t)
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
+}
+
+impl<T> SplitQueue<T> for dyn stream2::Queue<T> + Send + Sync {
+    fn push(&self, t: T) {
+        stream2::Queue::push(self, t)
+    }
+
+    fn pop(&self) -> Option<T> {
+        stream2::Queue::pop(self)
+    }
+}
+
+/// The pushing half, given to the producer closure.
+pub struct Producer<'a, T, Q: ?Sized + 'a> {
+    queue: &'a Q,
+    // set on drop, unwinding included
+    done: &'a AtomicBool,
+    // !Sync, so the producer can't hand `&Producer` to another thread
+    _pd: PhantomData<(T, Cell<()>)>,
+}
+
+unsafe impl<'a, T: Send, Q: ?Sized + Sync> Send for Producer<'a, T, Q> { }
+
+impl<'a, T, Q: ?Sized + SplitQueue<T>> Producer<'a, T, Q> {
+    pub fn push(&self, t: T) {
+        self.queue.push(t)
+    }
+}
+
+impl<'a, T, Q: ?Sized> Drop for Producer<'a, T, Q> {
+    fn drop(&mut self) {
+        // Release, so that the consumer's Acquire of `done` sees every push
+        self.done.store(true, Ordering::Release)
+    }
+}
+
+/// The popping half, given to the consumer closure.
+pub struct Consumer<'a, T, Q: ?Sized + 'a> {
+    queue: &'a Q,
+    done: &'a AtomicBool,
+    _pd: PhantomData<(T, Cell<()>)>,
+}
+
+impl<'a, T, Q: ?Sized + SplitQueue<T>> Consumer<'a, T, Q> {
+    /// Pops a value if there is one, without waiting.
+    pub fn pop(&self) -> Option<T> {
+        self.queue.pop()
+    }
+
+    /// Waits for a value, with a `Backoff`, or for the producer to finish,
+    /// returning `None` once it has and its values are all popped.
+    pub fn recv(&self) -> Option<T> {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(t) = self.queue.pop() { return Some(t) }
+            if self.done.load(Ordering::Acquire) {
+                // it may have pushed again between the pop and the load
+                return self.queue.pop()
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Whether the producer has finished, or panicked.
+    pub fn producer_done(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+}
+
+/// Runs `producer` on a scoped thread and `consumer` on this one, over the
+/// two halves of `queue`, returning what `consumer` does once the producer
+/// has been joined.
+///
+/// # Panics
+///
+/// Resumes the producer's panic, if it panicked, after `consumer` returns.
+pub fn run_split_with<T, Q, P, C, R>(queue: Q, producer: P, consumer: C) -> R
+where T: Send, Q: SplitQueue<T> + Sync, P: FnOnce(Producer<T, Q>) + Send, C: FnOnce(Consumer<T, Q>) -> R {
+    run_split_ref(&queue, producer, consumer)
+}
+
+/// `run_split_with` over the queue `kind` names, see `factory`. The queue is
+/// boxed, so each push and pop is dynamically dispatched.
+pub fn run_split<T, P, C, R>(kind: &QueueKind, producer: P, consumer: C) -> R
+where T: Send + 'static,
+      P: FnOnce(Producer<T, dyn stream2::Queue<T> + Send + Sync + 'static>) + Send,
+      C: FnOnce(Consumer<T, dyn stream2::Queue<T> + Send + Sync + 'static>) -> R {
+    run_split_ref(&*kind.build(), producer, consumer)
+}
+
+// Both of the above, over a queue which outlives the scope and which nothing
+// else can reach.
+fn run_split_ref<T, Q, P, C, R>(queue: &Q, producer: P, consumer: C) -> R
+where T: Send, Q: ?Sized + SplitQueue<T> + Sync, P: FnOnce(Producer<T, Q>) + Send, C: FnOnce(Consumer<T, Q>) -> R {
+    let done = AtomicBool::new(false);
+    let tx = Producer { queue: queue, done: &done, _pd: PhantomData };
+    let rx = Consumer { queue: queue, done: &done, _pd: PhantomData };
+    let (r, produced) = scope(|scope| {
+        // crossbeam's join would unwrap the panic into a new one, catching it
+        // here keeps the payload
+        let handle = scope.spawn(move|| panic::catch_unwind(AssertUnwindSafe(move|| producer(tx))));
+        let r = consumer(rx);
+        (r, handle.join())
+    });
+    match produced {
+        Ok(()) => r,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::{run_split, run_split_with};
+    use factory::QueueKind;
+    use spsc;
+    use verify::stress_count;
+
+    const COUNT: usize = stress_count(100_000);
+
+    #[test]
+    fn in_order() {
+        let q = unsafe { spsc::CNQueue::aligned(2) };
+        let received = run_split_with(q, |tx| {
+            for i in 0..COUNT {
+                tx.push(i)
+            }
+        }, |rx| {
+            let mut next = 0;
+            while let Some(i) = rx.recv() {
+                assert_eq!(i, next);
+                next += 1;
+            }
+            next
+        });
+        assert_eq!(received, COUNT);
+    }
+
+    #[test]
+    fn every_kind() {
+        for kind in QueueKind::all(&[0, 1, 16]) {
+            let sum = run_split(&kind, |tx| {
+                for i in 0..1_000u64 {
+                    tx.push(Box::new(i))
+                }
+            }, |rx| {
+                let mut sum = 0;
+                while let Some(i) = rx.recv() {
+                    sum += *i;
+                }
+                sum
+            });
+            assert_eq!(sum, 999 * 1_000 / 2, "{}", kind);
+        }
+    }
+
+    // The consumer isn't left waiting on a producer which panicked, and the
+    // panic comes out of run_split_with with its own payload.
+    #[test]
+    fn producer_panics() {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_split_with(unsafe { spsc::CNQueue::aligned(2) }, |tx| {
+                for i in 0..10 {
+                    if i == 5 { panic!("producer panicked at {}", i) }
+                    tx.push(i)
+                }
+            }, |rx| {
+                let mut received = Vec::new();
+                while let Some(i) = rx.recv() {
+                    received.push(i)
+                }
+                assert_eq!(received, vec![0, 1, 2, 3, 4]);
+            })
+        }));
+        let panic = result.unwrap_err();
+        assert_eq!(panic.downcast_ref::<String>().map(|s| &**s), Some("producer panicked at 5"));
+    }
+}
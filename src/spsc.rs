@@ -3,11 +3,21 @@
 //!   - cache aligning the producer and consumer
 //!   - unbounding the node cache
 //!   - removing the node cache entirely
+//!   - sharing spare nodes between queues through a `NodePool`
+//!   - taking nodes from some other allocator, through `NodeAlloc`
 
+use std::alloc;
 use std::cell::UnsafeCell;
-use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::Arc;
+use sched::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 use std::ptr;
 
+use cache_padded::{CachePadded, Padding};
+use chain::{ChainReport, Pointers};
+use layout::{Field, Layout, Side};
+
 struct Node<T> {
     // FIXME: this could be an uninitialized T if we're careful enough, and
     //      that would reduce memory usage (and be a bit faster).
@@ -16,73 +26,164 @@ struct Node<T> {
     next: AtomicPtr<Node<T>>,   // next node in the queue
 }
 
-pub struct NoAlign;
-
-#[repr(align(64))]
-pub struct CacheAligned;
+pub use cache_padded::{NoAlign, CacheAligned};
 
-pub struct Queue<T, Align, CacheType> {
+pub struct Queue<T, Align: Padding, CacheType: UseCache> {
     // consumer fields
-    consumer: Consumer<T, Align>,
+    consumer: Align::Padded<Consumer<T>>,
 
     // producer fields
-    producer: Producer<T, Align>,
+    producer: Align::Padded<Producer<T>>,
 
     // Cache maintenance fields. Additions and subtractions are stored
     // separately in order to allow them to use nonatomic addition/subtraction.
-    cache: Cache<Align, CacheType>,
+    cache: Align::Padded<Cache>,
+
+    // where the cache overflows to, and nodes come from before malloc
+    pool: CacheType::Pool<T>,
+
+    _cache_type: PhantomData<CacheType>,
 }
 
-struct Consumer<T, Align> {
+struct Consumer<T> {
     tail: UnsafeCell<*mut Node<T>>, // where to pop from
     tail_prev: AtomicPtr<Node<T>>, // where to pop from
-    _align: [Align; 0],
 }
 
-struct Producer<T, Align> {
+struct Producer<T> {
     head: UnsafeCell<*mut Node<T>>,      // where to push to
     first: UnsafeCell<*mut Node<T>>,     // where to get new nodes from
     tail_copy: UnsafeCell<*mut Node<T>>, // between first/tail
-    _align: [Align; 0],
 }
 
-struct Cache<Align, CacheType> {
+struct Cache {
     cache_bound: usize,
     cache_additions: AtomicUsize,
     cache_subtractions: AtomicUsize,
-    _align: [(Align, CacheType); 0],
 }
 
-unsafe impl<T: Send, A, C> Send for Queue<T, A, C> { }
-unsafe impl<T: Send, A, C> Sync for Queue<T, A, C> { }
+unsafe impl<T: Send, A: Padding, C: UseCache> Send for Queue<T, A, C> { }
+unsafe impl<T: Send, A: Padding, C: UseCache> Sync for Queue<T, A, C> { }
 
 pub struct NormalNodeCache;
 pub struct NoNodeCache;
+/// The normal node cache, backed by a `NodePool` shared with other queues.
+pub struct PooledNodeCache;
+/// The normal node cache, over nodes from `A` rather than malloc.
+pub struct AllocNodeCache<A>(PhantomData<A>);
+/// No node cache, every node comes from `A` and goes back to it.
+pub struct AllocNoNodeCache<A>(PhantomData<A>);
 
 pub trait UseCache {
     const USE_CACHE: bool;
+    /// What the queue keeps to reach its allocator, `()` for malloc.
+    type Pool<T>: NodeAlloc;
 }
 
 impl UseCache for NormalNodeCache {
     const USE_CACHE: bool = true;
+    type Pool<T> = ();
 }
 
 impl UseCache for NoNodeCache {
     const USE_CACHE: bool = false;
+    type Pool<T> = ();
+}
+
+impl UseCache for PooledNodeCache {
+    const USE_CACHE: bool = true;
+    type Pool<T> = Arc<NodePool<T>>;
+}
+
+impl<A: NodeAlloc> UseCache for AllocNodeCache<A> {
+    const USE_CACHE: bool = true;
+    type Pool<T> = A;
+}
+
+impl<A: NodeAlloc> UseCache for AllocNoNodeCache<A> {
+    const USE_CACHE: bool = false;
+    type Pool<T> = A;
+}
+
+/// Where a queue's nodes come from when its cache has none to give, and go
+/// when the cache is full or the queue is dropped. The queue falls back on
+/// malloc for whatever the allocator won't give or take.
+///
+/// This is unsafe to implement as the producer allocates while the consumer
+/// deallocates, so an implementation must be safe to call from both at once,
+/// and from any thread, which the queue being `Send` and `Sync` whatever its
+/// allocator doesn't check.
+pub unsafe trait NodeAlloc {
+    /// Memory for a node of `layout`, or null for the queue to malloc one.
+    unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8;
+
+    /// Takes back a node of `layout`, its value already dropped. Returns false
+    /// if it isn't one of this allocator's, for the queue to free.
+    unsafe fn dealloc(&self, node: *mut u8, layout: alloc::Layout) -> bool;
+}
+
+/// Malloc.
+unsafe impl NodeAlloc for () {
+    #[inline(always)]
+    unsafe fn alloc(&self, _: alloc::Layout) -> *mut u8 { ptr::null_mut() }
+    #[inline(always)]
+    unsafe fn dealloc(&self, _: *mut u8, _: alloc::Layout) -> bool { false }
+}
+
+unsafe impl<A: NodeAlloc> NodeAlloc for Arc<A> {
+    #[inline]
+    unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 { (**self).alloc(layout) }
+    #[inline]
+    unsafe fn dealloc(&self, node: *mut u8, layout: alloc::Layout) -> bool {
+        (**self).dealloc(node, layout)
+    }
+}
+
+/// The layout of the nodes a `Queue<T, ..>` gets from its `NodeAlloc`.
+pub fn node_layout<T>() -> alloc::Layout {
+    alloc::Layout::new::<Node<T>>()
+}
+
+/// Spare nodes shared by any number of queues, a Treiber stack.
+///
+/// A queue takes from the pool before it mallocs, and a consumer whose cache
+/// is full puts the node it's done with here rather than freeing it, as does
+/// a queue being dropped, so short lived queues can keep reusing the same
+/// nodes. Past `cap` nodes the pool frees what it's given.
+///
+/// Puts are a plain CAS push. Takes are serialized by a flag, which a take
+/// only tries: with a single taker the top node can't be taken, reused and
+/// put back, or freed, between reading its next and the CAS, which is what
+/// makes a Treiber pop unsafe. A take which finds another in progress comes
+/// back empty, and its queue mallocs instead.
+pub struct NodePool<T> {
+    // top of the stack, on its own line, away from the Arc's counts
+    head: CachePadded<AtomicPtr<Node<T>>>,
+    taking: AtomicBool,
+    len: AtomicUsize, // nodes in the stack, or about to be
+    cap: usize,
 }
 
+unsafe impl<T: Send> Send for NodePool<T> { }
+unsafe impl<T: Send> Sync for NodePool<T> { }
+
 pub type CNQueue<T> = Queue<T, CacheAligned, NormalNodeCache>;
 #[allow(non_camel_case_types)]
 pub type C_Queue<T> = Queue<T, CacheAligned, NoNodeCache>;
 pub type _NQueue<T> = Queue<T, NoAlign, NormalNodeCache>;
 pub type __Queue<T> = Queue<T, NoAlign, NoNodeCache>;
+pub type CPQueue<T> = Queue<T, CacheAligned, PooledNodeCache>;
+// nodes from an allocator `A`, with and without the cache
+pub type CAQueue<T, A> = Queue<T, CacheAligned, AllocNodeCache<A>>;
+#[allow(non_camel_case_types)]
+pub type CA_Queue<T, A> = Queue<T, CacheAligned, AllocNoNodeCache<A>>;
 
 impl<T> Node<T> {
     fn new() -> *mut Node<T> {
-        Box::into_raw(box Node {
+        Box::into_raw(Box::new(Node {
             value: None,
             next: AtomicPtr::new(ptr::null_mut::<Node<T>>()),
-        })
+        }))
     }
 }
 
@@ -109,24 +210,25 @@ impl<T> Queue<T, NoAlign, NormalNodeCache> {
         let n2 = Node::new();
         (*n1).next.store(n2, Ordering::Relaxed);
         Queue {
-            consumer: Consumer {
+            consumer: NoAlign::pad(Consumer {
                 tail: UnsafeCell::new(n2),
                 tail_prev: AtomicPtr::new(n1),
-                _align: [],
-            },
-            producer: Producer {
+            }),
+            producer: NoAlign::pad(Producer {
                 head: UnsafeCell::new(n2),
                 first: UnsafeCell::new(n1),
                 tail_copy: UnsafeCell::new(n1),
-                _align: [],
-            },
+            }),
 
-            cache: Cache {
+            cache: NoAlign::pad(Cache {
                 cache_bound: bound,
                 cache_additions: AtomicUsize::new(0),
                 cache_subtractions: AtomicUsize::new(0),
-                _align: [],
-            },
+            }),
+
+            pool: (),
+
+            _cache_type: PhantomData,
         }
     }
 }
@@ -137,24 +239,25 @@ impl<T> Queue<T, NoAlign, NoNodeCache> {
         let n2 = Node::new();
         (*n1).next.store(n2, Ordering::Relaxed);
         Queue {
-            consumer: Consumer {
+            consumer: NoAlign::pad(Consumer {
                 tail: UnsafeCell::new(n2),
                 tail_prev: AtomicPtr::new(n1),
-                _align: [],
-            },
-            producer: Producer {
+            }),
+            producer: NoAlign::pad(Producer {
                 head: UnsafeCell::new(n2),
                 first: UnsafeCell::new(n1),
                 tail_copy: UnsafeCell::new(n1),
-                _align: [],
-            },
+            }),
 
-            cache: Cache {
+            cache: NoAlign::pad(Cache {
                 cache_bound: 0,
                 cache_additions: AtomicUsize::new(0),
                 cache_subtractions: AtomicUsize::new(0),
-                _align: [],
-            },
+            }),
+
+            pool: (),
+
+            _cache_type: PhantomData,
         }
     }
 }
@@ -165,24 +268,25 @@ impl<T> Queue<T, CacheAligned, NormalNodeCache> {
         let n2 = Node::new();
         (*n1).next.store(n2, Ordering::Relaxed);
         Queue {
-            consumer: Consumer {
+            consumer: CacheAligned::pad(Consumer {
                 tail: UnsafeCell::new(n2),
                 tail_prev: AtomicPtr::new(n1),
-                _align: [],
-            },
-            producer: Producer {
+            }),
+            producer: CacheAligned::pad(Producer {
                 head: UnsafeCell::new(n2),
                 first: UnsafeCell::new(n1),
                 tail_copy: UnsafeCell::new(n1),
-                _align: [],
-            },
+            }),
 
-            cache: Cache {
+            cache: CacheAligned::pad(Cache {
                 cache_bound: bound,
                 cache_additions: AtomicUsize::new(0),
                 cache_subtractions: AtomicUsize::new(0),
-                _align: [],
-            },
+            }),
+
+            pool: (),
+
+            _cache_type: PhantomData,
         }
     }
 }
@@ -193,53 +297,153 @@ impl<T> Queue<T, CacheAligned, NoNodeCache> {
         let n2 = Node::new();
         (*n1).next.store(n2, Ordering::Relaxed);
         Queue {
-            consumer: Consumer {
+            consumer: CacheAligned::pad(Consumer {
                 tail: UnsafeCell::new(n2),
                 tail_prev: AtomicPtr::new(n1),
-                _align: [],
-            },
-            producer: Producer {
+            }),
+            producer: CacheAligned::pad(Producer {
                 head: UnsafeCell::new(n2),
                 first: UnsafeCell::new(n1),
                 tail_copy: UnsafeCell::new(n1),
-                _align: [],
-            },
+            }),
 
-            cache: Cache {
+            cache: CacheAligned::pad(Cache {
                 cache_bound: 0,
                 cache_additions: AtomicUsize::new(0),
                 cache_subtractions: AtomicUsize::new(0),
-                _align: [],
-            },
+            }),
+
+            pool: (),
+
+            _cache_type: PhantomData,
+        }
+    }
+}
+
+impl<T, Align: Padding> Queue<T, Align, PooledNodeCache> {
+    /// Creates a new queue whose node cache is backed by `pool`: nodes come
+    /// from the pool before they're malloced, and go back to it when they
+    /// would be freed, when the cache is over `bound` and when the queue is
+    /// dropped. `bound` is as for `new`.
+    ///
+    /// This is unsafe for the same reasons as `new`.
+    pub unsafe fn with_pool(bound: usize, pool: Arc<NodePool<T>>) -> Self {
+        Queue::with_nodes_from(bound, pool)
+    }
+}
+
+impl<T, Align: Padding, A: NodeAlloc> Queue<T, Align, AllocNodeCache<A>> {
+    /// Creates a new queue whose nodes come from `alloc`, falling back on
+    /// malloc when it has none, and go back to it when the node cache is
+    /// over `bound` and when the queue is dropped. `bound` is as for `new`.
+    ///
+    /// This is unsafe for the same reasons as `new`.
+    pub unsafe fn with_alloc(bound: usize, alloc: A) -> Self {
+        Queue::with_nodes_from(bound, alloc)
+    }
+}
+
+impl<T, Align: Padding, A: NodeAlloc> Queue<T, Align, AllocNoNodeCache<A>> {
+    /// Creates a new queue without a node cache, which takes every node from
+    /// `alloc`, or malloc when it has none, and gives each back once popped.
+    ///
+    /// This is unsafe for the same reasons as `new`.
+    pub unsafe fn no_cache_with_alloc(alloc: A) -> Self {
+        Queue::with_nodes_from(0, alloc)
+    }
+}
+
+impl<T, Align: Padding, CacheType: UseCache> Queue<T, Align, CacheType> {
+    unsafe fn with_nodes_from(bound: usize, pool: CacheType::Pool<T>) -> Self {
+        let n1 = Self::node_from(&pool);
+        let n2 = Self::node_from(&pool);
+        (*n1).next.store(n2, Ordering::Relaxed);
+        Queue {
+            consumer: Align::pad(Consumer {
+                tail: UnsafeCell::new(n2),
+                tail_prev: AtomicPtr::new(n1),
+            }),
+            producer: Align::pad(Producer {
+                head: UnsafeCell::new(n2),
+                first: UnsafeCell::new(n1),
+                tail_copy: UnsafeCell::new(n1),
+            }),
+
+            cache: Align::pad(Cache {
+                cache_bound: bound,
+                cache_additions: AtomicUsize::new(0),
+                cache_subtractions: AtomicUsize::new(0),
+            }),
+
+            pool: pool,
+
+            _cache_type: PhantomData,
+        }
+    }
+
+    // A node from the allocator if it has one, otherwise a new one.
+    #[inline]
+    unsafe fn node_from(pool: &CacheType::Pool<T>) -> *mut Node<T> {
+        let node = pool.alloc(node_layout::<T>()) as *mut Node<T>;
+        if node.is_null() {
+            return Node::new()
         }
+        ptr::write(node, Node { value: None, next: AtomicPtr::new(ptr::null_mut()) });
+        node
     }
+
 }
 
-impl<T, Align, CacheType> Queue<T, Align, CacheType>
+impl<T, Align: Padding, CacheType> Queue<T, Align, CacheType>
 where CacheType: UseCache {
 
 
     /// Pushes a new value onto this queue. Note that to use this function
     /// safely, it must be externally guaranteed that there is only one pusher.
     pub fn push(&self, t: T) {
+        self.push_with(|_| t)
+    }
+
+    // `push`, with the value made once the node it goes in is known, so the
+    // weak memory tests can tag each value with its node.
+    #[inline(always)]
+    fn push_with<F: FnOnce(*mut Node<T>) -> T>(&self, make: F) {
         unsafe {
             // Acquire a node (which either uses a cached one or allocates a new
             // one), and then append this to the 'head' node.
             let n = self.alloc();
             assert!((*n).value.is_none());
-            (*n).value = Some(t);
+            (*n).value = Some(make(n));
+            // Relaxed: no one reads a node's `next` before the Release below
+            // publishes the node, which carries this store with it.
             (*n).next.store(ptr::null_mut(), Ordering::Relaxed);
+            // Release: pairs with the Acquire of `next` in `pop` and `peek`,
+            // publishing the value and the null `next` written above.
             (**self.producer.head.get()).next.store(n, Ordering::Release);
             *self.producer.head.get() = n;
         }
     }
 
     unsafe fn alloc(&self) -> *mut Node<T> {
-        if !CacheType::USE_CACHE { return Node::new() }
+        if !CacheType::USE_CACHE { return self.new_node() }
         // First try to see if we can consume the 'first' node for our uses.
         // We try to avoid as many atomic instructions as possible here, so
         // the addition to cache_subtractions is not atomic (plus we're the
         // only one subtracting from the cache).
+        //
+        // Every node from `first` up to `tail_copy` is one the consumer has
+        // moved past. The `next` of such a node was last written either by us,
+        // linking it in, or by the consumer, unlinking a node it freed while
+        // this one was its `tail_prev`. The consumer stores a later
+        // `tail_prev` with Release after that write, and we have loaded that
+        // `tail_prev`, or a later one, with the Acquire below, so the Relaxed
+        // loads of `next` here see the unlinking, as does our reuse of the
+        // node see the consumer's take of its value.
+        //
+        // The cache counters only bound the cache size, so they're Relaxed. A
+        // stale `cache_subtractions` makes the consumer think the cache is
+        // fuller than it is, and free a node it could have kept, never the
+        // other way.
         if *self.producer.first.get() != *self.producer.tail_copy.get() {
             if self.cache.cache_bound > 0 {
                 let b = self.cache.cache_subtractions.load(Ordering::Relaxed);
@@ -250,7 +454,8 @@ where CacheType: UseCache {
             return ret;
         }
         // If the above fails, then update our copy of the tail and try
-        // again.
+        // again. Acquire: pairs with the consumer's Release stores of
+        // `tail_prev`, see above.
         *self.producer.tail_copy.get() = self.consumer.tail_prev.load(Ordering::Acquire);
         if *self.producer.first.get() != *self.producer.tail_copy.get() {
             if self.cache.cache_bound > 0 {
@@ -263,7 +468,21 @@ where CacheType: UseCache {
         }
         // If all of that fails, then we have to allocate a new node
         // (there's nothing in the node cache).
-        Node::new()
+        self.new_node()
+    }
+
+    #[inline]
+    unsafe fn new_node(&self) -> *mut Node<T> {
+        Self::node_from(&self.pool)
+    }
+
+    // Gives an empty node back to the allocator, or frees it if it won't take
+    // it.
+    #[inline]
+    unsafe fn free_node(&self, node: *mut Node<T>) {
+        if !self.pool.dealloc(node as *mut u8, node_layout::<T>()) {
+            drop(Box::from_raw(node))
+        }
     }
 
     /// Attempts to pop a value from this queue. Remember that to use this type
@@ -275,19 +494,29 @@ where CacheType: UseCache {
             // tail's next field and see if we can use it. If we do a pop, then
             // the current tail node is a candidate for going into the cache.
             let tail = *self.consumer.tail.get();
+            // Acquire: pairs with the Release in `push` which linked `next`
+            // in, so its value is the one pushed.
             let next = (*tail).next.load(Ordering::Acquire);
             if next.is_null() { return None }
             assert!((*next).value.is_some());
             let ret = (*next).value.take();
 
             *self.consumer.tail.get() = next;
+            // Only the consumer stores `tail_prev`, so its own loads of it are
+            // Relaxed. Without a cache the producer never reads `tail_prev`,
+            // or a node it has linked past, so the unlinking store is Relaxed
+            // too, and freeing `tail` is ordered after the producer's last
+            // touch of it, the Release store to its `next`, by the Acquire
+            // above.
             if !CacheType::USE_CACHE {
                 (*self.consumer.tail_prev.load(Ordering::Relaxed))
                     .next.store(next, Ordering::Relaxed);
-                let _: Box<Node<T>> = Box::from_raw(tail);
+                self.free_node(tail);
                 return ret
             }
 
+            // Release: hands `tail`, its value taken, to the producer to
+            // reuse, see `alloc`.
             if self.cache.cache_bound == 0 {
                 self.consumer.tail_prev.store(tail, Ordering::Release);
             } else {
@@ -300,11 +529,13 @@ where CacheType: UseCache {
                     self.consumer.tail_prev.store(tail, Ordering::Release);
                     self.cache.cache_additions.store(additions + 1, Ordering::Relaxed);
                 } else {
+                    // The producer won't read this `next` until we Release a
+                    // later `tail_prev`, which orders this store before it.
                     (*self.consumer.tail_prev.load(Ordering::Relaxed))
                           .next.store(next, Ordering::Relaxed);
                     // We have successfully erased all references to 'tail', so
                     // now we can safely drop it.
-                    let _: Box<Node<T>> = Box::from_raw(tail);
+                    self.free_node(tail);
                 }
             }
             ret
@@ -327,27 +558,169 @@ where CacheType: UseCache {
             if next.is_null() { None } else { (*next).value.as_mut() }
         }
     }
+
+    /// Walks the nodes from `first` to `head`, see `chain`.
+    ///
+    /// This is unsafe as it reads the producer's fields as well as the
+    /// consumer's: it must be called from the consumer with the producer
+    /// stopped, and the pushes it made visible to this thread, e.g. by a join.
+    pub unsafe fn debug_chain(&self) -> ChainReport {
+        let pointers = Pointers {
+            first: *self.producer.first.get(),
+            tail_copy: *self.producer.tail_copy.get(),
+            tail_prev: self.consumer.tail_prev.load(Ordering::Relaxed),
+            tail: *self.consumer.tail.get(),
+            head: *self.producer.head.get(),
+        };
+        ChainReport::walk(&pointers, |n| (*n).next.load(Ordering::Acquire), |n| (*n).value.is_some())
+    }
 }
 
-impl<T, Align, CacheType> Drop for Queue<T, Align, CacheType> {
+/// The values left in the queue are dropped in the order they were pushed,
+/// oldest first, and only then are the nodes freed.
+impl<T, Align: Padding, CacheType: UseCache> Drop for Queue<T, Align, CacheType> {
     fn drop(&mut self) {
         unsafe {
+            // Only the nodes after `tail` hold values, in push order.
+            let mut cur = (**self.consumer.tail.get()).next.load(Ordering::Relaxed);
+            while !cur.is_null() {
+                (*cur).value = None;
+                cur = (*cur).next.load(Ordering::Relaxed);
+            }
             let mut cur = *self.producer.first.get();
             while !cur.is_null() {
                 let next = (*cur).next.load(Ordering::Relaxed);
-                let _n: Box<Node<T>> = Box::from_raw(cur);
+                debug_assert!((*cur).value.is_none());
+                self.free_node(cur);
                 cur = next;
             }
         }
     }
 }
 
-#[cfg(all(test, not(target_os = "emscripten")))]
+impl<T> NodePool<T> {
+    /// Creates an empty pool which keeps up to `cap` spare nodes.
+    pub fn new(cap: usize) -> Self {
+        NodePool {
+            head: CachePadded::new(AtomicPtr::new(ptr::null_mut())),
+            taking: AtomicBool::new(false),
+            len: AtomicUsize::new(0),
+            cap: cap,
+        }
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// The spare nodes in the pool, racing any queues using it.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn take(&self) -> Option<*mut Node<T>> {
+        if self.taking.swap(true, Ordering::Acquire) {
+            return None
+        }
+        // Acquire, here and on the CAS: pairs with the Release of the put
+        // which pushed `top`, for its `next`. Puts are RMWs on `head`, so
+        // acquiring the latest also acquires those under it.
+        let mut top = self.head.load(Ordering::Acquire);
+        let node = loop {
+            if top.is_null() {
+                break None
+            }
+            // No other take can unlink top while we hold the flag, so it's
+            // still a spare node whatever the puts are doing.
+            let next = unsafe { (*top).next.load(Ordering::Relaxed) };
+            match self.head.compare_exchange_weak(top, next, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => break Some(top),
+                Err(cur) => top = cur,
+            }
+        };
+        // Release, paired with the swap's Acquire: the next take sees the
+        // `head` this one left.
+        self.taking.store(false, Ordering::Release);
+        if node.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        node
+    }
+
+    unsafe fn put(&self, node: *mut Node<T>) {
+        debug_assert!((*node).value.is_none());
+        if self.len.fetch_add(1, Ordering::Relaxed) >= self.cap {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            drop(Box::from_raw(node));
+            return
+        }
+        let mut top = self.head.load(Ordering::Relaxed);
+        loop {
+            (*node).next.store(top, Ordering::Relaxed);
+            match self.head.compare_exchange_weak(top, node, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(cur) => top = cur,
+            }
+        }
+    }
+}
+
+/// Only for the nodes of `Queue<T, ..>`s.
+unsafe impl<T> NodeAlloc for NodePool<T> {
+    #[inline]
+    unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 {
+        debug_assert_eq!(layout, node_layout::<T>());
+        self.take().map_or(ptr::null_mut(), |node| node as *mut u8)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, node: *mut u8, layout: alloc::Layout) -> bool {
+        debug_assert_eq!(layout, node_layout::<T>());
+        self.put(node as *mut Node<T>);
+        true
+    }
+}
+
+impl<T> Drop for NodePool<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let mut cur = self.head.load(Ordering::Relaxed);
+            while !cur.is_null() {
+                let next = (*cur).next.load(Ordering::Relaxed);
+                drop(Box::from_raw(cur));
+                cur = next;
+            }
+        }
+    }
+}
+
+impl<T, Align: Padding, CacheType: UseCache> Layout for Queue<T, Align, CacheType> {
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = vec![
+            Field::new("consumer.tail", Side::Consumer, self, ptr::addr_of!(self.consumer.tail)),
+            Field::new("consumer.tail_prev", Side::Consumer, self, ptr::addr_of!(self.consumer.tail_prev)),
+            Field::new("producer.head", Side::Producer, self, ptr::addr_of!(self.producer.head)),
+            Field::new("producer.first", Side::Producer, self, ptr::addr_of!(self.producer.first)),
+            Field::new("producer.tail_copy", Side::Producer, self, ptr::addr_of!(self.producer.tail_copy)),
+            Field::new("cache.cache_bound", Side::Cold, self, ptr::addr_of!(self.cache.cache_bound)),
+            Field::new("cache.cache_additions", Side::Consumer, self, ptr::addr_of!(self.cache.cache_additions)),
+            Field::new("cache.cache_subtractions", Side::Producer, self, ptr::addr_of!(self.cache.cache_subtractions)),
+        ];
+        if mem::size_of::<CacheType::Pool<T>>() > 0 {
+            fields.push(Field::new("pool", Side::Cold, self, ptr::addr_of!(self.pool)));
+        }
+        fields
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
 mod tests {
-    use std::sync::Arc;
-    use super::Queue;
-    use std::thread;
-    use std::sync::mpsc::channel;
+    use super::{Padding, Queue, UseCache};
+    use verify::{self, Seq};
 
     #[test]
     fn smoke() {
@@ -393,8 +766,8 @@ mod tests {
     fn drop_full() {
         unsafe {
             let q: Queue<Box<_>, _, _> = Queue::new(0);
-            q.push(box 1);
-            q.push(box 2);
+            q.push(Box::new(1));
+            q.push(Box::new(2));
         }
     }
 
@@ -415,6 +788,136 @@ mod tests {
         }
     }
 
+    #[test]
+    fn aligned_layout() {
+        use std::mem;
+        use std::ptr::addr_of;
+        use cache_padded::{line, CACHE_LINE};
+        use layout::offset;
+        use super::{CNQueue, __Queue};
+        type Q = CNQueue<u64>;
+        assert_eq!(mem::align_of::<Q>(), CACHE_LINE);
+        let q: Q = unsafe { CNQueue::aligned(0) };
+        let lines = [
+            line(offset(&q, addr_of!(q.consumer))),
+            line(offset(&q, addr_of!(q.producer))),
+            line(offset(&q, addr_of!(q.cache))),
+        ];
+        assert!(lines[0] != lines[1] && lines[1] != lines[2] && lines[0] != lines[2], "{:?}", lines);
+        assert_eq!(mem::size_of::<Q>(), 3 * CACHE_LINE);
+        // unaligned, all three fit on one line
+        assert!(mem::size_of::<__Queue<u64>>() <= CACHE_LINE);
+    }
+
+    #[test]
+    fn pool_takes_back_nodes() {
+        use std::sync::Arc;
+        use super::{CPQueue, NodePool};
+        let pool = Arc::new(NodePool::new(8));
+        unsafe {
+            let q = CPQueue::with_pool(1, pool.clone());
+            for i in 0..4 {
+                q.push(vec![i]);
+            }
+            for i in 0..3 {
+                assert_eq!(q.pop(), Some(vec![i]));
+            }
+            // one node in the cache, the other popped ones overflowed
+            assert_eq!(pool.len(), 2);
+        }
+        // the queue's six nodes, one still holding a value
+        assert_eq!(pool.len(), 6);
+        unsafe {
+            let q = CPQueue::with_pool(0, pool.clone());
+            assert_eq!(pool.len(), 4);
+            for i in 0..10 {
+                q.push(vec![i]);
+            }
+            assert!(pool.is_empty());
+            for i in 0..10 {
+                assert_eq!(q.pop(), Some(vec![i]));
+            }
+        }
+        // no more than the cap are kept
+        assert_eq!(pool.len(), 8);
+    }
+
+    // push 1, 2, 3, pop 1, 2, then push 4 into the first node, reused
+    #[test]
+    fn debug_chain() {
+        use chain::NodeState::*;
+        unsafe {
+            let q = Queue::new(0);
+            let first = q.debug_chain().nodes[0].ptr;
+            q.push(1);
+            q.push(2);
+            q.push(3);
+            assert_eq!(q.pop(), Some(1));
+            assert_eq!(q.pop(), Some(2));
+            let report = q.debug_chain();
+            assert_eq!(report.states(), vec![Free, Free, Free, Sentinel, Live]);
+            let marks: Vec<_> = report.nodes.iter().map(|n| n.marks.clone()).collect();
+            assert_eq!(marks, vec![vec!["first", "tail_copy"], vec![], vec!["tail_prev"], vec!["tail"], vec!["head"]]);
+
+            q.push(4);
+            let report = q.debug_chain();
+            assert_eq!(report.states(), vec![Cached, Free, Sentinel, Live, Live]);
+            let marks: Vec<_> = report.nodes.iter().map(|n| n.marks.clone()).collect();
+            assert_eq!(marks, vec![vec!["first"], vec!["tail_copy", "tail_prev"], vec!["tail"], vec![], vec!["head"]]);
+            let values: Vec<_> = report.nodes.iter().map(|n| n.has_value).collect();
+            assert_eq!(values, vec![false, false, false, true, true]);
+            assert_eq!(report.nodes.iter().map(|n| n.index).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+            assert_eq!(report.nodes[4].ptr, first);
+            assert_eq!(report.cycle_to, None);
+            assert!(report.missing.is_empty());
+        }
+    }
+
+    // Without a cache the popped nodes are freed, and the first node stays
+    // put as `tail_prev`.
+    #[test]
+    fn debug_chain_no_cache() {
+        use chain::NodeState::*;
+        unsafe {
+            let q = Queue::no_cache();
+            q.push(1);
+            q.push(2);
+            assert_eq!(q.pop(), Some(1));
+            let report = q.debug_chain();
+            assert_eq!(report.states(), vec![Free, Sentinel, Live]);
+            let marks: Vec<_> = report.nodes.iter().map(|n| n.marks.clone()).collect();
+            assert_eq!(marks, vec![vec!["first", "tail_copy", "tail_prev"], vec!["tail"], vec!["head"]]);
+            assert_eq!(report.nodes.iter().map(|n| n.has_value).collect::<Vec<_>>(), vec![false, false, true]);
+        }
+    }
+
+    fn drops_in_order<A: Padding, C: UseCache>(q: Queue<Seq, A, C>) {
+        verify::drops_in_order(q, Queue::push, Queue::pop)
+    }
+
+    #[test]
+    fn drops_fifo() {
+        unsafe {
+            drops_in_order(Queue::new(2));
+            drops_in_order(Queue::new(0));
+            drops_in_order(Queue::no_cache());
+            drops_in_order(Queue::aligned(2));
+            drops_in_order(Queue::aligned_no_cache());
+        }
+    }
+}
+
+// These spawn threads, which emscripten and wasm32 don't have
+#[cfg(all(test, feature = "queue_experiments", not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use std::sync::Arc;
+    use super::{CacheAligned, CPQueue, NodePool, Queue, UseCache};
+    use std::thread;
+    use split::run_split_with;
+    use verify::{stress_count, weak_memory_count};
+
+    const COUNT: usize = stress_count(100000);
+
     #[test]
     fn stress() {
         unsafe {
@@ -423,26 +926,95 @@ mod tests {
         }
 
         unsafe fn stress_bound(bound: usize) {
-            let q = Arc::new(Queue::new(bound));
-
-            let (tx, rx) = channel();
-            let q2 = q.clone();
-            let _t = thread::spawn(move|| {
-                for _ in 0..100000 {
-                    loop {
-                        match q2.pop() {
-                            Some(1) => break,
-                            Some(_) => panic!(),
-                            None => {}
-                        }
+            run_split_with(Queue::new(bound), |tx| {
+                for _ in 0..COUNT {
+                    tx.push(1);
+                }
+            }, |rx| {
+                for _ in 0..COUNT {
+                    match rx.recv() {
+                        Some(1) => {}
+                        other => panic!("{:?}", other),
                     }
                 }
-                tx.send(()).unwrap();
+                assert_eq!(rx.recv(), None);
             });
-            for _ in 0..100000 {
-                q.push(1);
+        }
+    }
+
+    // Each value is tagged with its sequence number and the node it was pushed
+    // into, and the consumer checks both against the node it popped it from,
+    // its new `tail`. A `next` read stale, still pointing at a node the
+    // consumer freed or one the producer has since reused, or a value read
+    // from before the node was reused, is a mismatch rather than a pass. The
+    // small bounds keep the producer reusing nodes through `alloc` and the
+    // consumer freeing them, the paths a weakly ordered machine can reorder.
+    fn weak_memory<C: UseCache + 'static>(q: Queue<(usize, usize), CacheAligned, C>) {
+        let count = weak_memory_count(200_000);
+        let q = Arc::new(q);
+        let q2 = q.clone();
+        let consumer = thread::spawn(move|| {
+            for seq in 0..count {
+                let (got, node) = loop {
+                    match q2.pop() {
+                        Some(v) => break v,
+                        None => thread::yield_now(),
+                    }
+                };
+                let tail = unsafe { *q2.consumer.tail.get() } as usize;
+                assert_eq!(got, seq, "value {} popped out of order", seq);
+                assert_eq!(node, tail, "value {} popped from the wrong node", seq);
             }
-            rx.recv().unwrap();
+            assert_eq!(q2.pop(), None);
+        });
+        for seq in 0..count {
+            q.push_with(|n| (seq, n as usize));
+        }
+        consumer.join().unwrap();
+    }
+
+    #[test]
+    fn weak_memory_cached() {
+        for &bound in &[0, 1, 2, 16] {
+            weak_memory(unsafe { Queue::aligned(bound) });
+        }
+    }
+
+    #[test]
+    fn weak_memory_no_cache() {
+        weak_memory(unsafe { Queue::aligned_no_cache() });
+    }
+
+    #[test]
+    fn weak_memory_pooled() {
+        let pool = Arc::new(NodePool::new(4));
+        for &bound in &[1, 2] {
+            weak_memory(unsafe { CPQueue::with_pool(bound, pool.clone()) });
+        }
+    }
+
+    // Queues on several threads taking from and giving back to one pool.
+    #[test]
+    fn shared_pool() {
+        let pool = Arc::new(NodePool::new(64));
+        let threads: Vec<_> = (0..4).map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move|| {
+                for round in 0..500 {
+                    let q = unsafe { CPQueue::with_pool(4, pool.clone()) };
+                    for i in 0..(round % 32) {
+                        q.push((t, i));
+                    }
+                    for i in 0..(round % 32) {
+                        assert_eq!(q.pop(), Some((t, i)));
+                    }
+                    assert_eq!(q.pop(), None);
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
         }
+        assert!(pool.len() <= 64);
     }
 }
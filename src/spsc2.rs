@@ -8,6 +8,10 @@ use std::cell::UnsafeCell;
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::ptr;
 
+use cache_padded::Padding;
+use chain::{ChainReport, Pointers};
+use layout::{Field, Layout, Side};
+
 struct Node<T> {
     // FIXME: this could be an uninitialized T if we're careful enough, and
     //      that would reduce memory usage (and be a bit faster).
@@ -17,36 +21,31 @@ struct Node<T> {
     next: AtomicPtr<Node<T>>,   // next node in the queue
 }
 
-pub struct NoAlign;
-
-#[repr(align(64))]
-pub struct CacheAligned;
+pub use cache_padded::{NoAlign, CacheAligned};
 
-pub struct Queue<T, Align> {
+pub struct Queue<T, Align: Padding> {
     // consumer fields
-    consumer: Consumer<T, Align>,
+    consumer: Align::Padded<Consumer<T>>,
 
     // producer fields
-    producer: Producer<T, Align>,
+    producer: Align::Padded<Producer<T>>,
 }
 
-struct Consumer<T, Align> {
+struct Consumer<T> {
     tail: UnsafeCell<*mut Node<T>>, // where to pop from
     tail_prev: AtomicPtr<Node<T>>, // where to pop from
     cache_bound: usize, // maximum cache size
     cached_nodes: AtomicUsize, // number of nodes marked as cachable
-    _align: [Align; 0],
 }
 
-struct Producer<T, Align> {
+struct Producer<T> {
     head: UnsafeCell<*mut Node<T>>,      // where to push to
     first: UnsafeCell<*mut Node<T>>,     // where to get new nodes from
     tail_copy: UnsafeCell<*mut Node<T>>, // between first/tail
-    _align: [Align; 0],
 }
 
-unsafe impl<T: Send, A> Send for Queue<T, A> { }
-unsafe impl<T: Send, A> Sync for Queue<T, A> { }
+unsafe impl<T: Send, A: Padding> Send for Queue<T, A> { }
+unsafe impl<T: Send, A: Padding> Sync for Queue<T, A> { }
 
 pub type _Queue<T> = Queue<T, NoAlign>;
 pub type AQueue<T> = Queue<T, CacheAligned>;
@@ -56,11 +55,11 @@ pub struct NoNodeCache;
 
 impl<T> Node<T> {
     fn new() -> *mut Node<T> {
-        Box::into_raw(box Node {
+        Box::into_raw(Box::new(Node {
             value: None,
             cached: false,
             next: AtomicPtr::new(ptr::null_mut::<Node<T>>()),
-        })
+        }))
     }
 }
 
@@ -87,19 +86,17 @@ impl<T> Queue<T, NoAlign> {
         let n2 = Node::new();
         (*n1).next.store(n2, Ordering::Relaxed);
         Queue {
-            consumer: Consumer {
+            consumer: NoAlign::pad(Consumer {
                 tail: UnsafeCell::new(n2),
                 tail_prev: AtomicPtr::new(n1),
                 cache_bound: bound,
                 cached_nodes: AtomicUsize::new(0),
-                _align: [],
-            },
-            producer: Producer {
+            }),
+            producer: NoAlign::pad(Producer {
                 head: UnsafeCell::new(n2),
                 first: UnsafeCell::new(n1),
                 tail_copy: UnsafeCell::new(n1),
-                _align: [],
-            },
+            }),
         }
     }
 }
@@ -110,24 +107,22 @@ impl<T> Queue<T, CacheAligned> {
         let n2 = Node::new();
         (*n1).next.store(n2, Ordering::Relaxed);
         Queue {
-            consumer: Consumer {
+            consumer: CacheAligned::pad(Consumer {
                 tail: UnsafeCell::new(n2),
                 tail_prev: AtomicPtr::new(n1),
                 cache_bound: bound,
                 cached_nodes: AtomicUsize::new(0),
-                _align: [],
-            },
-            producer: Producer {
+            }),
+            producer: CacheAligned::pad(Producer {
                 head: UnsafeCell::new(n2),
                 first: UnsafeCell::new(n1),
                 tail_copy: UnsafeCell::new(n1),
-                _align: [],
-            },
+            }),
         }
     }
 }
 
-impl<T, Align> Queue<T, Align> {
+impl<T, Align: Padding> Queue<T, Align> {
 
 
     /// Pushes a new value onto this queue. Note that to use this function
@@ -223,11 +218,34 @@ impl<T, Align> Queue<T, Align> {
             if next.is_null() { None } else { (*next).value.as_mut() }
         }
     }
+
+    /// Walks the nodes from `first` to `head`, see `chain`.
+    ///
+    /// This is unsafe as it reads the producer's fields as well as the
+    /// consumer's: it must be called from the consumer with the producer
+    /// stopped, and the pushes it made visible to this thread, e.g. by a join.
+    pub unsafe fn debug_chain(&self) -> ChainReport {
+        let pointers = Pointers {
+            first: *self.producer.first.get(),
+            tail_copy: *self.producer.tail_copy.get(),
+            tail_prev: self.consumer.tail_prev.load(Ordering::Relaxed),
+            tail: *self.consumer.tail.get(),
+            head: *self.producer.head.get(),
+        };
+        ChainReport::walk(&pointers, |n| (*n).next.load(Ordering::Acquire), |n| (*n).value.is_some())
+    }
 }
 
-impl<T, Align> Drop for Queue<T, Align> {
+/// The values left in the queue are dropped oldest first, as in spsc,
+/// before any node is freed.
+impl<T, Align: Padding> Drop for Queue<T, Align> {
     fn drop(&mut self) {
         unsafe {
+            let mut cur = (**self.consumer.tail.get()).next.load(Ordering::Relaxed);
+            while !cur.is_null() {
+                (*cur).value = None;
+                cur = (*cur).next.load(Ordering::Relaxed);
+            }
             let mut cur = *self.producer.first.get();
             while !cur.is_null() {
                 let next = (*cur).next.load(Ordering::Relaxed);
@@ -238,12 +256,24 @@ impl<T, Align> Drop for Queue<T, Align> {
     }
 }
 
-#[cfg(all(test, not(target_os = "emscripten")))]
+impl<T, Align: Padding> Layout for Queue<T, Align> {
+    fn fields(&self) -> Vec<Field> {
+        vec![
+            Field::new("consumer.tail", Side::Consumer, self, ptr::addr_of!(self.consumer.tail)),
+            Field::new("consumer.tail_prev", Side::Consumer, self, ptr::addr_of!(self.consumer.tail_prev)),
+            Field::new("consumer.cache_bound", Side::Cold, self, ptr::addr_of!(self.consumer.cache_bound)),
+            Field::new("consumer.cached_nodes", Side::Consumer, self, ptr::addr_of!(self.consumer.cached_nodes)),
+            Field::new("producer.head", Side::Producer, self, ptr::addr_of!(self.producer.head)),
+            Field::new("producer.first", Side::Producer, self, ptr::addr_of!(self.producer.first)),
+            Field::new("producer.tail_copy", Side::Producer, self, ptr::addr_of!(self.producer.tail_copy)),
+        ]
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
 mod tests {
-    use std::sync::Arc;
-    use super::Queue;
-    use std::thread;
-    use std::sync::mpsc::channel;
+    use super::{Padding, Queue};
+    use verify::{self, Seq};
 
     #[test]
     fn smoke() {
@@ -289,8 +319,8 @@ mod tests {
     fn drop_full() {
         unsafe {
             let q: Queue<Box<_>, _> = Queue::new(0);
-            q.push(box 1);
-            q.push(box 2);
+            q.push(Box::new(1));
+            q.push(Box::new(2));
         }
     }
 
@@ -311,6 +341,70 @@ mod tests {
         }
     }
 
+    #[test]
+    fn aligned_layout() {
+        use std::mem;
+        use std::ptr::addr_of;
+        use cache_padded::{line, CACHE_LINE};
+        use layout::offset;
+        use super::{AQueue, _Queue};
+        type Q = AQueue<u64>;
+        assert_eq!(mem::align_of::<Q>(), CACHE_LINE);
+        let q: Q = unsafe { AQueue::aligned(0) };
+        assert!(line(offset(&q, addr_of!(q.consumer))) != line(offset(&q, addr_of!(q.producer))));
+        assert_eq!(mem::size_of::<Q>(), 2 * CACHE_LINE);
+        assert!(mem::size_of::<_Queue<u64>>() <= CACHE_LINE);
+    }
+
+    // push 1, 2, 3, pop 1, 2, then push 4 into the first node, reused
+    #[test]
+    fn debug_chain() {
+        use chain::NodeState::*;
+        unsafe {
+            let q = Queue::new(0);
+            let first = q.debug_chain().nodes[0].ptr;
+            q.push(1);
+            q.push(2);
+            q.push(3);
+            assert_eq!(q.pop(), Some(1));
+            assert_eq!(q.pop(), Some(2));
+            q.push(4);
+            let report = q.debug_chain();
+            assert_eq!(report.states(), vec![Cached, Free, Sentinel, Live, Live]);
+            let marks: Vec<_> = report.nodes.iter().map(|n| n.marks.clone()).collect();
+            assert_eq!(marks, vec![vec!["first"], vec!["tail_copy", "tail_prev"], vec!["tail"], vec![], vec!["head"]]);
+            let values: Vec<_> = report.nodes.iter().map(|n| n.has_value).collect();
+            assert_eq!(values, vec![false, false, false, true, true]);
+            assert_eq!(report.nodes[4].ptr, first);
+            assert!(report.missing.is_empty());
+        }
+    }
+
+    fn drops_in_order<A: Padding>(q: Queue<Seq, A>) {
+        verify::drops_in_order(q, Queue::push, Queue::pop)
+    }
+
+    #[test]
+    fn drops_fifo() {
+        unsafe {
+            drops_in_order(Queue::new(2));
+            drops_in_order(Queue::new(0));
+            drops_in_order(Queue::aligned(2));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments", not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use std::sync::Arc;
+    use super::Queue;
+    use std::thread;
+    use std::sync::mpsc::channel;
+    use split::run_split_with;
+    use verify::stress_count;
+
+    const COUNT: usize = stress_count(100000);
+
     #[test]
     fn stress() {
         unsafe {
@@ -319,26 +413,19 @@ mod tests {
         }
 
         unsafe fn stress_bound(bound: usize) {
-            let q = Arc::new(Queue::new(bound));
-
-            let (tx, rx) = channel();
-            let q2 = q.clone();
-            let _t = thread::spawn(move|| {
-                for _ in 0..100000 {
-                    loop {
-                        match q2.pop() {
-                            Some(1) => break,
-                            Some(_) => panic!(),
-                            None => {}
-                        }
+            run_split_with(Queue::new(bound), |tx| {
+                for _ in 0..COUNT {
+                    tx.push(1);
+                }
+            }, |rx| {
+                for _ in 0..COUNT {
+                    match rx.recv() {
+                        Some(1) => {}
+                        other => panic!("{:?}", other),
                     }
                 }
-                tx.send(()).unwrap();
+                assert_eq!(rx.recv(), None);
             });
-            for _ in 0..100000 {
-                q.push(1);
-            }
-            rx.recv().unwrap();
         }
     }
 
@@ -355,7 +442,7 @@ mod tests {
             let (tx, rx) = channel();
             let q2 = q.clone();
             let _t = thread::spawn(move|| {
-                for i in 0..100000 {
+                for i in 0..COUNT {
                     loop {
                         match q2.pop() {
                             Some(j) => assert_eq!(i, j),
@@ -365,7 +452,7 @@ mod tests {
                 }
                 tx.send(()).unwrap();
             });
-            for i in 0..100000 {
+            for i in 0..COUNT {
                 q.push(i);
             }
             rx.recv().unwrap();
@@ -0,0 +1,353 @@
+//! A thin async layer straight over the queues, for running inside your own
+//! executor, without the channel protocol of stream2's `AsyncReceiver`.
+//!
+//! `split` hands out the two halves of a queue. The consumer's `recv` is a
+//! future which pops, or registers the task's waker in an `AtomicWaker` for
+//! the next push to wake. Over spsc, which is unbounded, the producer's
+//! `send` just pushes. Over bounded_mpmc it's a future too, which waits for
+//! room the same way, woken by the next pop.
+//!
+//! There's no disconnection. A `recv` whose producer is gone waits forever,
+//! so pair this with whatever shutdown the executor already has.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use bounded_mpmc;
+use cache_padded::{CachePadded, Padding};
+use spsc;
+
+/// A slot for one task's waker, which the other side takes and wakes.
+///
+/// The waker is boxed and the slot is a pointer to it, so registering and
+/// waking are both swaps, and whichever side swaps a waker out owns it.
+/// Only the registering side ever swaps one in.
+///
+/// A wake and a registration race on the slot, and since both are swaps,
+/// one of them sees the other: either the wake finds the waker and wakes
+/// it, or the registration's swap comes after the wake's and acquires it,
+/// and with it everything done before the wake. So register, then check
+/// once more for whatever the wake is about, and return Pending only if it
+/// still isn't there.
+pub struct AtomicWaker {
+    waker: AtomicPtr<Waker>,
+}
+
+unsafe impl Send for AtomicWaker { }
+unsafe impl Sync for AtomicWaker { }
+
+impl AtomicWaker {
+    pub fn new() -> Self {
+        AtomicWaker { waker: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    /// Makes `waker` the one the next `wake` wakes, replacing any already
+    /// registered. Only one thread may register at a time.
+    pub fn register(&self, waker: &Waker) {
+        // Take back whatever is there, so a task which is polled again keeps
+        // its box rather than allocating another one.
+        let old = self.waker.swap(ptr::null_mut(), Ordering::Acquire);
+        let new = if old.is_null() {
+            box waker.clone()
+        } else {
+            let mut old = unsafe { Box::from_raw(old) };
+            if !old.will_wake(waker) {
+                *old = waker.clone();
+            }
+            old
+        };
+        let prev = self.waker.swap(Box::into_raw(new), Ordering::AcqRel);
+        // a wake only swaps in null
+        debug_assert!(prev.is_null());
+    }
+
+    /// Wakes the registered waker, if there is one, and empties the slot.
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake()
+        }
+    }
+
+    /// Empties the slot without waking. Returns the waker if it was still
+    /// there, `None` if there was none or a `wake` already took it.
+    pub fn take(&self) -> Option<Waker> {
+        let waker = self.waker.swap(ptr::null_mut(), Ordering::AcqRel);
+        if waker.is_null() {
+            None
+        } else {
+            Some(*unsafe { Box::from_raw(waker) })
+        }
+    }
+
+    pub fn is_registered(&self) -> bool {
+        !self.waker.load(Ordering::Relaxed).is_null()
+    }
+}
+
+impl Drop for AtomicWaker {
+    fn drop(&mut self) {
+        drop(self.take());
+    }
+}
+
+/// The queues `split` can wrap.
+pub trait RawQueue<T> {
+    /// Whether a push can fail for lack of room, and so wait for a pop.
+    const BOUNDED: bool;
+    fn try_push(&self, t: T) -> Result<(), T>;
+    fn pop(&self) -> Option<T>;
+}
+
+impl<T, A: Padding, C: spsc::UseCache> RawQueue<T> for spsc::Queue<T, A, C> {
+    const BOUNDED: bool = false;
+
+    fn try_push(&self, t: T) -> Result<(), T> {
+        self.push(t);
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
+}
+
+impl<T, A: Padding> RawQueue<T> for bounded_mpmc::Queue<T, A> {
+    const BOUNDED: bool = true;
+
+    fn try_push(&self, t: T) -> Result<(), T> {
+        self.push(t)
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
+}
+
+struct Shared<Q> {
+    queue: Q,
+    // the consumer's task, woken by a push
+    recv_waker: CachePadded<AtomicWaker>,
+    // the producer's task waiting for room, woken by a pop
+    send_waker: CachePadded<AtomicWaker>,
+}
+
+/// Splits `queue` into its producing and consuming halves.
+///
+/// Each half may be sent to another thread but not shared, since the queue
+/// is single producer, single consumer. This is the only safe way to get at
+/// an spsc queue's ends, its constructors being unsafe for exactly that.
+pub fn split<T, Q: RawQueue<T>>(queue: Q) -> (AsyncProducer<T, Q>, AsyncConsumer<T, Q>) {
+    let shared = Arc::new(Shared {
+        queue: queue,
+        recv_waker: CachePadded::new(AtomicWaker::new()),
+        send_waker: CachePadded::new(AtomicWaker::new()),
+    });
+    (AsyncProducer { shared: shared.clone(), _marker: PhantomData },
+     AsyncConsumer { shared: shared, _marker: PhantomData })
+}
+
+// Cell is Send but not Sync, which is what the halves should be.
+pub struct AsyncProducer<T, Q> {
+    shared: Arc<Shared<Q>>,
+    _marker: PhantomData<(Cell<()>, fn(T))>,
+}
+
+pub struct AsyncConsumer<T, Q> {
+    shared: Arc<Shared<Q>>,
+    _marker: PhantomData<(Cell<()>, fn() -> T)>,
+}
+
+impl<T, Q: RawQueue<T>> AsyncProducer<T, Q> {
+    fn try_push(&self, t: T) -> Result<(), T> {
+        self.shared.queue.try_push(t)?;
+        self.shared.recv_waker.wake();
+        Ok(())
+    }
+}
+
+impl<T, A: Padding, C: spsc::UseCache> AsyncProducer<T, spsc::Queue<T, A, C>> {
+    /// Pushes `t`, waking the consumer if it's waiting. The queue is
+    /// unbounded, so this never waits.
+    pub fn send(&self, t: T) {
+        let _ = self.try_push(t);
+    }
+}
+
+impl<T, A: Padding> AsyncProducer<T, bounded_mpmc::Queue<T, A>> {
+    /// A future which pushes `t` once there's room. Dropping it before it
+    /// completes drops `t` with it.
+    pub fn send<'a>(&'a self, t: T) -> SendFuture<'a, T, bounded_mpmc::Queue<T, A>> {
+        SendFuture { producer: self, value: Some(t), registered: false }
+    }
+
+    /// Pushes `t` if there's room, without waiting, handing it back if not.
+    pub fn try_send(&self, t: T) -> Result<(), T> {
+        self.try_push(t)
+    }
+}
+
+impl<T, Q: RawQueue<T>> AsyncConsumer<T, Q> {
+    /// A future for the next value.
+    ///
+    /// The consumer has one waker slot, so only one of these should be
+    /// pending at a time: a second one's registration replaces the first's.
+    pub fn recv<'a>(&'a self) -> RecvFuture<'a, T, Q> {
+        RecvFuture { consumer: self, registered: false }
+    }
+
+    /// Pops a value if there is one, without waiting.
+    pub fn try_recv(&self) -> Option<T> {
+        let t = self.shared.queue.pop();
+        if Q::BOUNDED && t.is_some() {
+            self.shared.send_waker.wake();
+        }
+        t
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct RecvFuture<'a, T: 'a, Q: 'a> {
+    consumer: &'a AsyncConsumer<T, Q>,
+    registered: bool,
+}
+
+impl<'a, T, Q: RawQueue<T>> Future for RecvFuture<'a, T, Q> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let this = self.get_mut();
+        if let Some(t) = this.consumer.try_recv() {
+            return Poll::Ready(t)
+        }
+        let waker = &this.consumer.shared.recv_waker;
+        waker.register(cx.waker());
+        this.registered = true;
+        match this.consumer.try_recv() {
+            Some(t) => {
+                // The push which raced us may or may not have woken the
+                // task, take the waker back so no later one does.
+                drop(waker.take());
+                this.registered = false;
+                Poll::Ready(t)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// Cancelled while Pending: take our waker back, so the next push doesn't wake
+// a task which is no longer waiting on this.
+impl<'a, T, Q> Drop for RecvFuture<'a, T, Q> {
+    fn drop(&mut self) {
+        if self.registered {
+            drop(self.consumer.shared.recv_waker.take());
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct SendFuture<'a, T: 'a, Q: 'a> {
+    producer: &'a AsyncProducer<T, Q>,
+    value: Option<T>,
+    registered: bool,
+}
+
+// Nothing is pinned, `value` is only ever moved in and out whole.
+impl<'a, T, Q> Unpin for SendFuture<'a, T, Q> { }
+
+impl<'a, T, Q: RawQueue<T>> Future for SendFuture<'a, T, Q> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        let t = this.value.take().expect("SendFuture polled after it completed");
+        let t = match this.producer.try_push(t) {
+            Ok(()) => return Poll::Ready(()),
+            Err(t) => t,
+        };
+        let waker = &this.producer.shared.send_waker;
+        waker.register(cx.waker());
+        this.registered = true;
+        match this.producer.try_push(t) {
+            Ok(()) => {
+                drop(waker.take());
+                this.registered = false;
+                Poll::Ready(())
+            }
+            Err(t) => {
+                this.value = Some(t);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, T, Q> Drop for SendFuture<'a, T, Q> {
+    fn drop(&mut self) {
+        if self.registered {
+            drop(self.producer.shared.send_waker.take());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Wake, Waker};
+    use super::AtomicWaker;
+
+    struct Count(AtomicUsize);
+
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn wakes_once() {
+        let count = Arc::new(Count(AtomicUsize::new(0)));
+        let slot = AtomicWaker::new();
+        slot.wake();
+        slot.register(&Waker::from(count.clone()));
+        assert!(slot.is_registered());
+        slot.wake();
+        slot.wake();
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+        assert!(!slot.is_registered());
+    }
+
+    #[test]
+    fn register_replaces() {
+        let first = Arc::new(Count(AtomicUsize::new(0)));
+        let second = Arc::new(Count(AtomicUsize::new(0)));
+        let slot = AtomicWaker::new();
+        slot.register(&Waker::from(first.clone()));
+        slot.register(&Waker::from(second.clone()));
+        // the replaced waker was dropped, not leaked
+        assert_eq!(Arc::strong_count(&first), 1);
+        slot.wake();
+        assert_eq!(first.0.load(Ordering::SeqCst), 0);
+        assert_eq!(second.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn take_and_drop_release_the_waker() {
+        let count = Arc::new(Count(AtomicUsize::new(0)));
+        let slot = AtomicWaker::new();
+        slot.register(&Waker::from(count.clone()));
+        assert!(slot.take().is_some());
+        assert!(slot.take().is_none());
+        slot.register(&Waker::from(count.clone()));
+        drop(slot);
+        assert_eq!(Arc::strong_count(&count), 1);
+        assert_eq!(count.0.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,254 @@
+//! An spsc queue which leaves freeing its nodes to crossbeam's epoch based
+//! reclamation, rather than keeping a cache of them: the producer allocates a
+//! node for every push, and `pop` unlinks the node it moved past and hands it
+//! to the epoch's garbage.
+//!
+//! With one consumer nothing else can be reading a node it has moved past,
+//! so it could as well free it there and then; going through the epoch is
+//! what's being measured, a pin and the garbage bags on each pop against
+//! spsc's cache counters.
+//!
+//! crossbeam 0.3 frees garbage without running its destructor, so `pop`
+//! takes the value out of a node before retiring it, and only ever empty
+//! nodes are left to the epoch.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::Ordering;
+use std::ptr;
+
+use crossbeam::epoch::{self, Atomic, Owned};
+
+use cache_padded::CachePadded;
+use layout::{Field, Layout, Side};
+
+struct Node<T> {
+    value: UnsafeCell<Option<T>>, // None in the stub, and once popped
+    next: Atomic<Node<T>>,
+}
+
+pub struct Queue<T> {
+    // consumer fields
+    tail: CachePadded<Atomic<Node<T>>>, // the stub, whose next is the next to pop
+
+    // producer fields
+    head: CachePadded<UnsafeCell<*mut Node<T>>>, // where to push to
+}
+
+unsafe impl<T: Send> Send for Queue<T> { }
+unsafe impl<T: Send> Sync for Queue<T> { }
+
+impl<T> Node<T> {
+    fn new(value: Option<T>) -> Owned<Node<T>> {
+        Owned::new(Node {
+            value: UnsafeCell::new(value),
+            next: Atomic::null(),
+        })
+    }
+}
+
+impl<T> Queue<T> {
+    /// Creates a new queue.
+    ///
+    /// This is unsafe as the type system doesn't enforce a single
+    /// consumer-producer relationship. It also allows the consumer to `pop`
+    /// items while there is a `peek` active due to all methods having a
+    /// non-mutable receiver.
+    pub unsafe fn new() -> Self {
+        let stub = Node::new(None);
+        let head = &*stub as *const Node<T> as *mut Node<T>;
+        let tail = Atomic::null();
+        tail.store(Some(stub), Ordering::Relaxed);
+        Queue {
+            tail: CachePadded::new(tail),
+            head: CachePadded::new(UnsafeCell::new(head)),
+        }
+    }
+
+    /// Pushes a new value onto this queue. Note that to use this function
+    /// safely, it must be externally guaranteed that there is only one pusher.
+    pub fn push(&self, t: T) {
+        unsafe {
+            // The consumer only retires nodes it has moved past, and it can't
+            // move past head, so head is still ours without pinning.
+            let node = Node::new(Some(t));
+            let raw = &*node as *const Node<T> as *mut Node<T>;
+            (**self.head.get()).next.store(Some(node), Ordering::Release);
+            *self.head.get() = raw;
+        }
+    }
+
+    /// Attempts to pop a value from this queue. Remember that to use this type
+    /// safely you must ensure that there is only one popper at a time.
+    pub fn pop(&self) -> Option<T> {
+        let guard = epoch::pin();
+        let tail = self.tail.load(Ordering::Relaxed, &guard).unwrap();
+        let next = tail.next.load(Ordering::Acquire, &guard)?;
+        unsafe {
+            let value = (*next.value.get()).take();
+            debug_assert!(value.is_some());
+            self.tail.store_shared(Some(next), Ordering::Relaxed);
+            guard.unlinked(tail);
+            value
+        }
+    }
+
+    /// Attempts to peek at the head of the queue, returning `None` if the queue
+    /// has no data currently
+    ///
+    /// # Warning
+    /// The reference returned is invalid if it is not used before the consumer
+    /// pops the value off the queue.
+    pub fn peek(&self) -> Option<&mut T> {
+        let guard = epoch::pin();
+        let tail = self.tail.load(Ordering::Relaxed, &guard).unwrap();
+        let next = tail.next.load(Ordering::Acquire, &guard)?;
+        // next isn't retired until the pop after the one which takes its value
+        unsafe { (*next.value.get()).as_mut() }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        let guard = epoch::pin();
+        unsafe {
+            // Nothing else can see the nodes still linked, so they, and the
+            // values left in them, are freed here rather than through the
+            // epoch.
+            let mut cur = self.tail.load(Ordering::Relaxed, &guard).map(|n| n.as_raw());
+            while let Some(node) = cur {
+                let node: Box<Node<T>> = Box::from_raw(node);
+                cur = node.next.load(Ordering::Relaxed, &guard).map(|n| n.as_raw());
+            }
+        }
+        // The nodes already popped are in this thread's garbage, where they'd
+        // stay until it pins often enough to collect; hand them to the global
+        // bags so whichever thread next advances the epoch frees them.
+        guard.migrate_garbage();
+    }
+}
+
+impl<T> Layout for Queue<T> {
+    fn fields(&self) -> Vec<Field> {
+        vec![
+            Field::new("tail", Side::Consumer, self, ptr::addr_of!(self.tail)),
+            Field::new("head", Side::Producer, self, ptr::addr_of!(self.head)),
+        ]
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::Queue;
+    use verify::DropCounter;
+
+    #[test]
+    fn smoke() {
+        unsafe {
+            let queue = Queue::new();
+            queue.push(1);
+            queue.push(2);
+            assert_eq!(queue.pop(), Some(1));
+            assert_eq!(queue.pop(), Some(2));
+            assert_eq!(queue.pop(), None);
+            queue.push(3);
+            queue.push(4);
+            assert_eq!(queue.pop(), Some(3));
+            assert_eq!(queue.pop(), Some(4));
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn peek() {
+        unsafe {
+            let queue = Queue::new();
+            queue.push(vec![1]);
+
+            // Ensure the borrowchecker works
+            match queue.peek() {
+                Some(vec) => {
+                    assert_eq!(&*vec, &[1]);
+                },
+                None => unreachable!()
+            }
+
+            match queue.pop() {
+                Some(vec) => {
+                    assert_eq!(&*vec, &[1]);
+                },
+                None => unreachable!()
+            }
+            assert_eq!(queue.peek(), None);
+        }
+    }
+
+    #[test]
+    fn drop_full() {
+        unsafe {
+            let q: Queue<Box<_>> = Queue::new();
+            q.push(Box::new(1));
+            q.push(Box::new(2));
+        }
+    }
+
+    // Every value is dropped exactly once: the popped ones by whoever popped
+    // them, as the garbage holds only empty nodes, the rest with the queue.
+    #[test]
+    fn drop_partially_consumed() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        unsafe {
+            let q = Queue::new();
+            for _ in 0..100 {
+                q.push(DropCounter(drops.clone()));
+            }
+            for _ in 0..40 {
+                drop(q.pop().unwrap());
+            }
+            assert_eq!(drops.load(Ordering::SeqCst), 40);
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 100);
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments", not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::Queue;
+    use std::thread;
+    use std::sync::mpsc::channel;
+    use verify::{stress_count, DropCounter};
+
+    const COUNT: usize = stress_count(100000);
+
+    // Each value is dropped once by the consumer, never again as the epochs
+    // free the nodes it was in.
+    #[test]
+    fn stress() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        unsafe {
+            let q = Arc::new(Queue::new());
+
+            let (tx, rx) = channel();
+            let q2 = q.clone();
+            let _t = thread::spawn(move|| {
+                for i in 0..COUNT {
+                    loop {
+                        match q2.pop() {
+                            Some((j, _)) => { assert_eq!(i, j); break }
+                            None => {}
+                        }
+                    }
+                }
+                tx.send(()).unwrap();
+            });
+            for i in 0..COUNT {
+                q.push((i, DropCounter(drops.clone())));
+            }
+            rx.recv().unwrap();
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), COUNT);
+    }
+}
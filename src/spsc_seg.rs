@@ -0,0 +1,395 @@
+//! An spsc queue of fixed size segments linked together, between spsc's node
+//! per message and a ring: it is unbounded, but allocates once per
+//! `SEG_SIZE` messages and follows a pointer only when it crosses from one
+//! segment to the next.
+//!
+//! Each segment counts the slots the producer has written, the consumer
+//! keeps its own index into its segment. A segment the consumer is done with
+//! goes into a one segment cache for the producer to reuse, the node cache
+//! at segment granularity, and is freed if that is full.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::ptr;
+
+use cache_padded::Padding;
+use layout::{Field, Layout, Side};
+
+pub const SEG_SIZE: usize = 64;
+
+struct Segment<T> {
+    slots: UnsafeCell<[MaybeUninit<T>; SEG_SIZE]>,
+    written: AtomicUsize,         // slots the producer has filled
+    next: AtomicPtr<Segment<T>>,  // set once this one is full
+}
+
+pub use cache_padded::{NoAlign, CacheAligned};
+
+pub struct Queue<T, Align: Padding> {
+    // consumer fields
+    consumer: Align::Padded<Consumer<T>>,
+
+    // producer fields
+    producer: Align::Padded<Producer<T>>,
+
+    // a segment the consumer finished, for the producer's next one
+    cache: AtomicPtr<Segment<T>>,
+}
+
+struct Consumer<T> {
+    tail: UnsafeCell<*mut Segment<T>>, // where to pop from
+    read: UnsafeCell<usize>,           // slots of tail already popped
+}
+
+struct Producer<T> {
+    head: UnsafeCell<*mut Segment<T>>, // where to push to
+    write: UnsafeCell<usize>,          // our copy of head's written
+}
+
+unsafe impl<T: Send, A: Padding> Send for Queue<T, A> { }
+unsafe impl<T: Send, A: Padding> Sync for Queue<T, A> { }
+
+pub type _Queue<T> = Queue<T, NoAlign>;
+pub type AQueue<T> = Queue<T, CacheAligned>;
+
+impl<T> Segment<T> {
+    fn new() -> *mut Segment<T> {
+        Box::into_raw(Box::new(Segment {
+            // an array of uninitialized slots needs no initializing
+            slots: UnsafeCell::new(unsafe { MaybeUninit::uninit().assume_init() }),
+            written: AtomicUsize::new(0),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+
+    unsafe fn slot(&self, i: usize) -> *mut T {
+        (self.slots.get() as *mut MaybeUninit<T>).add(i) as *mut T
+    }
+}
+
+impl<T> Queue<T, NoAlign> {
+    /// Creates a new queue.
+    ///
+    /// This is unsafe as the type system doesn't enforce a single
+    /// consumer-producer relationship. It also allows the consumer to `pop`
+    /// items while there is a `peek` active due to all methods having a
+    /// non-mutable receiver.
+    pub unsafe fn new() -> Self {
+        Queue::build()
+    }
+}
+
+impl<T> Queue<T, CacheAligned> {
+    pub unsafe fn aligned() -> Self {
+        Queue::build()
+    }
+}
+
+impl<T, Align: Padding> Queue<T, Align> {
+    fn build() -> Self {
+        let seg = Segment::new();
+        Queue {
+            consumer: Align::pad(Consumer {
+                tail: UnsafeCell::new(seg),
+                read: UnsafeCell::new(0),
+            }),
+            producer: Align::pad(Producer {
+                head: UnsafeCell::new(seg),
+                write: UnsafeCell::new(0),
+            }),
+            cache: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Pushes a new value onto this queue. Note that to use this function
+    /// safely, it must be externally guaranteed that there is only one pusher.
+    pub fn push(&self, t: T) {
+        unsafe {
+            let mut head = *self.producer.head.get();
+            let write = self.producer.write.get();
+            if *write == SEG_SIZE {
+                // Link a fresh segment after the full one. After this store
+                // the consumer may retire `head` at any point, so it must be
+                // the last we touch it.
+                let seg = self.alloc();
+                (*head).next.store(seg, Ordering::Release);
+                head = seg;
+                *self.producer.head.get() = seg;
+                *write = 0;
+            }
+            ptr::write((*head).slot(*write), t);
+            *write += 1;
+            (*head).written.store(*write, Ordering::Release);
+        }
+    }
+
+    unsafe fn alloc(&self) -> *mut Segment<T> {
+        let seg = self.cache.swap(ptr::null_mut(), Ordering::Acquire);
+        if seg.is_null() {
+            return Segment::new()
+        }
+        // Everything in it was popped, so there's nothing to drop. These are
+        // published along with the segment by the store linking it.
+        (*seg).written.store(0, Ordering::Relaxed);
+        (*seg).next.store(ptr::null_mut(), Ordering::Relaxed);
+        seg
+    }
+
+    // Moves the consumer onto the next segment if it has finished its
+    // current one and the producer has moved on. Returns the segment to read
+    // from and the slot to read, if one has been written.
+    unsafe fn front(&self) -> Option<(*mut Segment<T>, usize)> {
+        let mut tail = *self.consumer.tail.get();
+        let read = self.consumer.read.get();
+        if *read == SEG_SIZE {
+            let next = (*tail).next.load(Ordering::Acquire);
+            if next.is_null() { return None }
+            self.retire(tail);
+            tail = next;
+            *self.consumer.tail.get() = next;
+            *read = 0;
+        }
+        if *read == (*tail).written.load(Ordering::Acquire) {
+            None
+        } else {
+            Some((tail, *read))
+        }
+    }
+
+    unsafe fn retire(&self, seg: *mut Segment<T>) {
+        let cached = self.cache.compare_exchange(
+            ptr::null_mut(), seg, Ordering::Release, Ordering::Relaxed);
+        if cached.is_err() {
+            let _: Box<Segment<T>> = Box::from_raw(seg);
+        }
+    }
+
+    /// Attempts to pop a value from this queue. Remember that to use this type
+    /// safely you must ensure that there is only one popper at a time.
+    pub fn pop(&self) -> Option<T> {
+        unsafe {
+            let (tail, i) = self.front()?;
+            *self.consumer.read.get() = i + 1;
+            Some(ptr::read((*tail).slot(i)))
+        }
+    }
+
+    /// Attempts to peek at the head of the queue, returning `None` if the queue
+    /// has no data currently
+    ///
+    /// # Warning
+    /// The reference returned is invalid if it is not used before the consumer
+    /// pops the value off the queue.
+    pub fn peek(&self) -> Option<&mut T> {
+        unsafe {
+            let (tail, i) = self.front()?;
+            Some(&mut *(*tail).slot(i))
+        }
+    }
+}
+
+impl<T, Align: Padding> Drop for Queue<T, Align> {
+    fn drop(&mut self) {
+        unsafe {
+            // Drop whatever wasn't popped, from the consumer's slot on.
+            let mut cur = *self.consumer.tail.get();
+            let mut read = *self.consumer.read.get();
+            while !cur.is_null() {
+                let written = (*cur).written.load(Ordering::Relaxed);
+                for i in read..written {
+                    ptr::drop_in_place((*cur).slot(i));
+                }
+                let next = (*cur).next.load(Ordering::Relaxed);
+                let _: Box<Segment<T>> = Box::from_raw(cur);
+                cur = next;
+                read = 0;
+            }
+            let cached = self.cache.load(Ordering::Relaxed);
+            if !cached.is_null() {
+                let _: Box<Segment<T>> = Box::from_raw(cached);
+            }
+        }
+    }
+}
+
+impl<T, Align: Padding> Layout for Queue<T, Align> {
+    fn fields(&self) -> Vec<Field> {
+        vec![
+            Field::new("consumer.tail", Side::Consumer, self, ptr::addr_of!(self.consumer.tail)),
+            Field::new("consumer.read", Side::Consumer, self, ptr::addr_of!(self.consumer.read)),
+            Field::new("producer.head", Side::Producer, self, ptr::addr_of!(self.producer.head)),
+            Field::new("producer.write", Side::Producer, self, ptr::addr_of!(self.producer.write)),
+            // swapped by each side once a segment
+            Field::new("cache", Side::Cold, self, ptr::addr_of!(self.cache)),
+        ]
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments"))]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::{Queue, SEG_SIZE};
+    use verify::DropCounter;
+
+    #[test]
+    fn smoke() {
+        unsafe {
+            let queue = Queue::new();
+            queue.push(1);
+            queue.push(2);
+            assert_eq!(queue.pop(), Some(1));
+            assert_eq!(queue.pop(), Some(2));
+            assert_eq!(queue.pop(), None);
+            queue.push(3);
+            queue.push(4);
+            assert_eq!(queue.pop(), Some(3));
+            assert_eq!(queue.pop(), Some(4));
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn peek() {
+        unsafe {
+            let queue = Queue::aligned();
+            queue.push(vec![1]);
+
+            // Ensure the borrowchecker works
+            match queue.peek() {
+                Some(vec) => {
+                    assert_eq!(&*vec, &[1]);
+                },
+                None => unreachable!()
+            }
+
+            match queue.pop() {
+                Some(vec) => {
+                    assert_eq!(&*vec, &[1]);
+                },
+                None => unreachable!()
+            }
+        }
+    }
+
+    // Peeking at a segment boundary moves the consumer on, which the pop
+    // after it must not do again.
+    #[test]
+    fn peek_across_segments() {
+        unsafe {
+            let queue = Queue::new();
+            for i in 0..(SEG_SIZE + 1) {
+                queue.push(i);
+            }
+            for i in 0..SEG_SIZE {
+                assert_eq!(queue.pop(), Some(i));
+            }
+            assert_eq!(queue.peek().map(|v| *v), Some(SEG_SIZE));
+            assert_eq!(queue.pop(), Some(SEG_SIZE));
+            assert_eq!(queue.peek(), None);
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    // Many segments' worth, pushed and popped in lockstep and in bulk, so
+    // that segments are both recycled and freed.
+    #[test]
+    fn segments() {
+        unsafe {
+            let queue = Queue::new();
+            for i in 0..(SEG_SIZE * 10) {
+                queue.push(i);
+                assert_eq!(queue.pop(), Some(i));
+            }
+            for i in 0..(SEG_SIZE * 10) {
+                queue.push(i);
+            }
+            for i in 0..(SEG_SIZE * 10) {
+                assert_eq!(queue.pop(), Some(i));
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn drop_full() {
+        unsafe {
+            let q: Queue<Box<_>, _> = Queue::new();
+            q.push(Box::new(1));
+            q.push(Box::new(2));
+        }
+    }
+
+    // Only the values which weren't popped are dropped with the queue, from
+    // the middle of the consumer's segment on, through the producer's.
+    #[test]
+    fn drop_partially_consumed() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        unsafe {
+            let q = Queue::new();
+            for _ in 0..(SEG_SIZE * 2 + 10) {
+                q.push(DropCounter(drops.clone()));
+            }
+            for _ in 0..(SEG_SIZE / 2) {
+                drop(q.pop().unwrap());
+            }
+            assert_eq!(drops.load(Ordering::SeqCst), SEG_SIZE / 2);
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), SEG_SIZE * 2 + 10);
+    }
+
+    // The same with the consumer right at the end of its segment, and the
+    // one before it in the cache.
+    #[test]
+    fn drop_at_segment_end() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        unsafe {
+            let q = Queue::aligned();
+            for _ in 0..(SEG_SIZE * 3) {
+                q.push(DropCounter(drops.clone()));
+            }
+            for _ in 0..(SEG_SIZE * 2) {
+                drop(q.pop().unwrap());
+            }
+            assert!(!q.cache.load(Ordering::SeqCst).is_null());
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), SEG_SIZE * 3);
+    }
+}
+
+#[cfg(all(test, feature = "queue_experiments", not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod stress_tests {
+    use std::sync::Arc;
+    use super::Queue;
+    use std::thread;
+    use std::sync::mpsc::channel;
+    use verify::stress_count;
+
+    const COUNT: usize = stress_count(100000);
+
+    #[test]
+    fn stress() {
+        unsafe {
+            let q = Arc::new(Queue::new());
+
+            let (tx, rx) = channel();
+            let q2 = q.clone();
+            let _t = thread::spawn(move|| {
+                for i in 0..COUNT {
+                    loop {
+                        match q2.pop() {
+                            Some(j) => { assert_eq!(i, j); break }
+                            None => {}
+                        }
+                    }
+                }
+                tx.send(()).unwrap();
+            });
+            for i in 0..COUNT {
+                q.push(i);
+            }
+            rx.recv().unwrap();
+        }
+    }
+}
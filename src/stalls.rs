@@ -0,0 +1,200 @@
+//! Counting a polling consumer's stalls: the long runs of empty polls while
+//! the producer is descheduled, which an average ns/send smears over every
+//! message.
+//!
+//! The consumer calls `empty` for each poll which found nothing and `data`
+//! for each which didn't. A run of `threshold` empty polls in a row is a
+//! stall, timed from the poll which crossed the threshold up to the next
+//! `data`, so that a short wait costs only a counter and a compare. The
+//! tracker keeps the stall count, the total time stalled, the longest stall
+//! and a reservoir sample of the rest.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The default threshold: well past the point where `Backoff` has given up
+/// spinning and is yielding on every poll.
+pub const DEFAULT_THRESHOLD: u32 = 100;
+
+const RESERVOIR: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stall {
+    /// The empty polls in a row, including the first `threshold`.
+    pub polls: u32,
+    /// From the poll which crossed the threshold to the next `data`.
+    pub duration: Duration,
+}
+
+pub struct StallTracker {
+    streak: u32,
+    threshold: u32,
+    crossed: Option<Instant>,
+    stalls: u64,
+    stalled: Duration,
+    longest: Stall,
+    reservoir: Vec<Stall>,
+    rand: u32,
+}
+
+impl StallTracker {
+    pub fn new() -> Self {
+        StallTracker::with_threshold(DEFAULT_THRESHOLD)
+    }
+
+    /// # Panics
+    ///
+    /// If `threshold` is 0.
+    pub fn with_threshold(threshold: u32) -> Self {
+        assert!(threshold > 0, "a stall is at least one empty poll");
+        StallTracker {
+            streak: 0,
+            threshold,
+            crossed: None,
+            stalls: 0,
+            stalled: Duration::new(0, 0),
+            longest: Stall::default(),
+            reservoir: Vec::with_capacity(RESERVOIR),
+            rand: 0x2545_f491,
+        }
+    }
+
+    /// A poll found nothing.
+    #[inline]
+    pub fn empty(&mut self) {
+        self.streak += 1;
+        if self.streak == self.threshold {
+            self.crossed = Some(Instant::now())
+        }
+    }
+
+    /// A poll found a value, ending any streak of empty polls.
+    #[inline]
+    pub fn data(&mut self) {
+        if self.streak >= self.threshold {
+            self.record()
+        }
+        self.streak = 0;
+    }
+
+    #[cold]
+    fn record(&mut self) {
+        let duration = self.crossed.take().map_or(Duration::new(0, 0), |t| t.elapsed());
+        let stall = Stall { polls: self.streak, duration };
+        self.stalls += 1;
+        self.stalled += duration;
+        if duration > self.longest.duration {
+            self.longest = stall
+        }
+        // Algorithm R: the nth stall replaces a random one with chance
+        // RESERVOIR / n
+        if self.reservoir.len() < RESERVOIR {
+            self.reservoir.push(stall)
+        } else {
+            let i = (self.next_rand() as u64 % self.stalls) as usize;
+            if i < RESERVOIR {
+                self.reservoir[i] = stall
+            }
+        }
+    }
+
+    // xorshift, as in verify
+    fn next_rand(&mut self) -> u32 {
+        self.rand ^= self.rand << 13;
+        self.rand ^= self.rand >> 17;
+        self.rand ^= self.rand << 5;
+        self.rand
+    }
+
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+
+    /// The time spent in all stalls.
+    pub fn stalled(&self) -> Duration {
+        self.stalled
+    }
+
+    pub fn longest(&self) -> Stall {
+        self.longest
+    }
+
+    /// Up to 32 of the stalls, picked uniformly.
+    pub fn samples(&self) -> &[Stall] {
+        &self.reservoir
+    }
+}
+
+impl Default for StallTracker {
+    fn default() -> Self {
+        StallTracker::new()
+    }
+}
+
+fn micros(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e6 + d.subsec_nanos() as f64 / 1e3
+}
+
+// One line, to print with a benchmark row.
+impl fmt::Display for StallTracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} stalls, {:.1} us stalled, longest {:.1} us ({} polls)", self.stalls,
+            micros(self.stalled), micros(self.longest.duration), self.longest.polls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{StallTracker, RESERVOIR};
+
+    fn polls(stalls: &mut StallTracker, empty: u32) {
+        for _ in 0..empty {
+            stalls.empty()
+        }
+        stalls.data()
+    }
+
+    #[test]
+    fn below_threshold() {
+        let mut stalls = StallTracker::with_threshold(10);
+        for _ in 0..100 {
+            polls(&mut stalls, 9)
+        }
+        assert_eq!(stalls.stalls(), 0);
+        assert_eq!(stalls.stalled(), Duration::new(0, 0));
+        assert!(stalls.samples().is_empty());
+    }
+
+    #[test]
+    fn longest() {
+        let mut stalls = StallTracker::with_threshold(10);
+        polls(&mut stalls, 10);
+        for _ in 0..10 {
+            stalls.empty()
+        }
+        thread::sleep(Duration::from_millis(5));
+        polls(&mut stalls, 4);
+        polls(&mut stalls, 3);
+        assert_eq!(stalls.stalls(), 2);
+        assert_eq!(stalls.longest().polls, 14);
+        assert!(stalls.longest().duration >= Duration::from_millis(5) / 2, "{}", stalls);
+        assert!(stalls.stalled() >= stalls.longest().duration);
+        assert!(stalls.to_string().starts_with("2 stalls, "), "{}", stalls);
+    }
+
+    #[test]
+    fn reservoir() {
+        let mut stalls = StallTracker::with_threshold(1);
+        for i in 0..1_000 {
+            polls(&mut stalls, if i < RESERVOIR { 1 } else { 2 })
+        }
+        assert_eq!(stalls.stalls(), 1_000);
+        assert_eq!(stalls.samples().len(), RESERVOIR);
+        // sampled from the whole run, not just the first few
+        let later = stalls.samples().iter().filter(|s| s.polls == 2).count();
+        assert!(later > RESERVOIR / 2, "{:?}", stalls.samples());
+    }
+}
@@ -35,6 +35,7 @@ use std::sync::mpsc::Receiver;
 
 
 use blocking::{self, SignalToken};
+use layout::{self, Field, Layout, Side};
 use spsc;
 use spsc2;
 
@@ -594,13 +595,64 @@ where Q: Queue<Message<T>> {
     }
 }
 
-impl<Q, T> Packet<Q, T> {
+impl<Q: Layout, T> Layout for Packet<Q, T> {
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = Vec::new();
+        layout::nest(&mut fields, "queue", self, &self.queue);
+        fields.extend(vec![
+            Field::new("cnt", Side::Both, self, ptr::addr_of!(self.cnt)),
+            Field::new("steals", Side::Consumer, self, ptr::addr_of!(self.steals)),
+            Field::new("to_wake", Side::Both, self, ptr::addr_of!(self.to_wake)),
+            Field::new("port_dropped", Side::Producer, self, ptr::addr_of!(self.port_dropped)),
+        ]);
+        fields
+    }
+}
+
+impl<Q, T> Drop for Packet<Q, T> {
     fn drop(&mut self) {
-        // Note that this load is not only an assert for correctness about
-        // disconnection, but also a proper fence before the read of
-        // `to_wake`, so this assert cannot be removed with also removing
-        // the `to_wake` assert.
-        assert_eq!(self.cnt.load(Ordering::SeqCst), DISCONNECTED);
-        assert_eq!(self.to_wake.load(Ordering::SeqCst), 0);
+        // std asserts here that the channel was disconnected and that no one
+        // is waiting, but the benchmarks drive this copy directly and never
+        // disconnect it. Instead take back any token left in `to_wake`, which
+        // would otherwise leak. There's no point in signalling it first: a
+        // thread can only be parked on it while borrowing the packet, in
+        // which case we couldn't be dropping it.
+        let ptr = self.to_wake.swap(0, Ordering::SeqCst);
+        if ptr != 0 {
+            drop(unsafe { SignalToken::cast_from_usize(ptr) });
+        }
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::thread;
+
+    use super::Packet;
+    use blocking;
+    use spsc;
+
+    #[test]
+    fn drop_reclaims_stored_token() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let (wait_token, signal_token) = blocking::tokens();
+        // the channel is empty, so the receiver would go to sleep
+        assert!(p.decrement(signal_token).is_ok());
+        assert_eq!(wait_token.signal_tokens(), 1);
+        thread::spawn(move|| drop(p)).join().unwrap();
+        assert_eq!(wait_token.signal_tokens(), 0);
+    }
+
+    #[test]
+    fn drop_without_token() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let (wait_token, signal_token) = blocking::tokens();
+        p.send(1).unwrap();
+        // there's data, so the token is handed straight back
+        let signal_token = p.decrement(signal_token).err().unwrap();
+        drop(p);
+        assert_eq!(wait_token.signal_tokens(), 1);
+        drop(signal_token);
+        assert_eq!(wait_token.signal_tokens(), 0);
     }
 }
\ No newline at end of file
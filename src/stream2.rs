@@ -22,26 +22,257 @@ pub use self::UpgradeResult::*;
 pub use self::SelectionResult::*;
 use self::Message::*;
 
-use std::isize;
+use std::cell::{Cell, UnsafeCell};
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::marker::PhantomData;
-use std::time::Instant;
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::Arc;
+#[cfg(feature = "trace")]
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use std::async_iter::AsyncIterator;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+#[cfg(feature = "eventfd")]
+use std::task::Waker;
+#[cfg(feature = "eventfd")]
+use std::os::unix::io::{AsRawFd, RawFd};
 
-use std::sync::atomic::{AtomicUsize, Ordering, AtomicBool};
-use std::sync::mpsc::Receiver;
+use sched::{self, atomic::{self, AtomicUsize, Ordering, AtomicBool}};
+#[cfg(feature = "stats")]
+use std::sync::atomic::AtomicU64;
 
-
-use blocking::{self, SignalToken};
+use backoff::Backoff;
+use cache_padded::CachePadded;
+use blocking::{self, AdaptiveSpin, DefaultBlocking, SignalToken, Wakeup};
+#[cfg(feature = "eventfd")]
+use blocking::eventfd::EventFd;
+use layout::{self, Field, Layout, Side};
+use shared::{self, SharedPacket};
 use spsc;
 use spsc2;
+use spsc_seg;
+use spsc_epoch;
+
+/// The ordering of the sender's second look at `port_dropped`, after it has
+/// pushed. Acquire is enough, see `Packet::do_send`; the `seqcst_channel`
+/// feature puts back SeqCst for comparison.
+#[cfg(not(feature = "seqcst_channel"))]
+pub const PORT_DROPPED_RECHECK: Ordering = Ordering::Acquire;
+#[cfg(feature = "seqcst_channel")]
+pub const PORT_DROPPED_RECHECK: Ordering = Ordering::SeqCst;
+
+/// Counts of blocking events, see `Packet::stats`.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Default)]
+pub struct PacketStats {
+    /// Sends which found a parked receiver and took its token.
+    pub woke_parked: u64,
+    /// Times `recv` parked.
+    pub parks: u64,
+    /// Times `recv` was woken and found nothing to receive.
+    pub spurious_wakeups: u64,
+    /// Parks which were signalled while `recv` was still spinning on its
+    /// token, see `Packet::set_park_spin`.
+    pub spin_hits: u64,
+    /// Signals sent to a parked receiver, by sends, upgrades and disconnects.
+    pub signals: u64,
+    /// Upgrade requests queued by the sender.
+    pub upgrades_sent: u64,
+    /// Upgrade requests taken off the queue by the receiver.
+    pub upgrades_received: u64,
+    /// Messages still queued when the receiver went away, thrown away by
+    /// `drop_port`.
+    pub drained: u64,
+    /// Sends which handed their message back as the receiver was gone.
+    pub disconnected_sends: u64,
+}
+
+// Bumped by whichever side the event happens on, atomic so that they can be
+// read from anywhere.
+#[cfg(feature = "stats")]
+#[derive(Default)]
+struct Stats {
+    woke_parked: AtomicU64,
+    parks: AtomicU64,
+    spurious_wakeups: AtomicU64,
+    spin_hits: AtomicU64,
+    signals: AtomicU64,
+    upgrades_sent: AtomicU64,
+    upgrades_received: AtomicU64,
+    drained: AtomicU64,
+    disconnected_sends: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+impl Stats {
+    fn bump(counter: &AtomicU64) {
+        Stats::add(counter, 1)
+    }
+
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> PacketStats {
+        PacketStats {
+            woke_parked: self.woke_parked.load(Ordering::Relaxed),
+            parks: self.parks.load(Ordering::Relaxed),
+            spurious_wakeups: self.spurious_wakeups.load(Ordering::Relaxed),
+            spin_hits: self.spin_hits.load(Ordering::Relaxed),
+            signals: self.signals.load(Ordering::Relaxed),
+            upgrades_sent: self.upgrades_sent.load(Ordering::Relaxed),
+            upgrades_received: self.upgrades_received.load(Ordering::Relaxed),
+            drained: self.drained.load(Ordering::Relaxed),
+            disconnected_sends: self.disconnected_sends.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A blocking protocol event, see `Packet::trace`.
+#[cfg(feature = "trace")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The receiver stored its token and rang the doorbell.
+    TokenStored,
+    /// A sender, or the sender's drop, took the receiver's token to wake it.
+    TokenTaken,
+    /// `recv` went to sleep.
+    Parked,
+    /// `recv` woke up, whether there's anything to receive or not.
+    Woke,
+    /// `recv` gave up waiting at its deadline.
+    TimedOut,
+    /// `recv` was woken and found nothing to receive.
+    SpuriousWake,
+    /// The receiver went away.
+    PortDropped,
+    /// `drop_port` started draining the queue.
+    DrainStarted,
+    /// `drop_port` drained this many messages.
+    DrainFinished(usize),
+    /// The sender queued an upgrade request.
+    UpgradeSent,
+    /// The receiver took an upgrade request off the queue.
+    UpgradeObserved,
+}
+
+#[cfg(feature = "trace")]
+impl Event {
+    // Packed into a usize for the ring, with 0 left for an empty slot.
+    fn encode(self) -> usize {
+        match self {
+            Event::TokenStored => 1,
+            Event::TokenTaken => 2,
+            Event::Parked => 3,
+            Event::Woke => 4,
+            Event::TimedOut => 5,
+            Event::SpuriousWake => 6,
+            Event::PortDropped => 7,
+            Event::DrainStarted => 8,
+            Event::DrainFinished(n) => 9 | n << 4,
+            Event::UpgradeSent => 10,
+            Event::UpgradeObserved => 11,
+        }
+    }
+
+    fn decode(e: usize) -> Option<Event> {
+        Some(match e & 0xf {
+            0 => return None,
+            1 => Event::TokenStored,
+            2 => Event::TokenTaken,
+            3 => Event::Parked,
+            4 => Event::Woke,
+            5 => Event::TimedOut,
+            6 => Event::SpuriousWake,
+            7 => Event::PortDropped,
+            8 => Event::DrainStarted,
+            9 => Event::DrainFinished(e >> 4),
+            10 => Event::UpgradeSent,
+            11 => Event::UpgradeObserved,
+            _ => unreachable!(),
+        })
+    }
+}
+
+// How many of the most recent events a packet keeps.
+#[cfg(feature = "trace")]
+const TRACE_LEN: usize = 64;
+
+// A ring of the last TRACE_LEN events, written by both sides. Each side's
+// events are in the order it recorded them, and the two are interleaved in
+// the order they claimed their slots.
+#[cfg(feature = "trace")]
+struct Trace {
+    events: Vec<AtomicUsize>,
+    next: AtomicUsize,
+}
+
+#[cfg(feature = "trace")]
+impl Trace {
+    fn new() -> Self {
+        Trace {
+            events: (0..TRACE_LEN).map(|_| AtomicUsize::new(0)).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn record(&self, event: Event) {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        self.events[i % TRACE_LEN].store(event.encode(), Ordering::Release);
+    }
+
+    fn snapshot(&self) -> Vec<Event> {
+        let next = self.next.load(Ordering::Acquire);
+        let start = next.saturating_sub(TRACE_LEN);
+        (start..next)
+            .filter_map(|i| Event::decode(self.events[i % TRACE_LEN].load(Ordering::Acquire)))
+            .collect()
+    }
+}
+
+// Records an event in the packet's trace. Without the `trace` feature this
+// expands to nothing, the event isn't even evaluated.
+#[cfg(feature = "trace")]
+macro_rules! trace {
+    ($packet:expr, $event:expr) => { $packet.trace.record($event) }
+}
+
+#[cfg(not(feature = "trace"))]
+macro_rules! trace {
+    ($packet:expr, $event:expr) => { () }
+}
+
+/// The default steal budget, see `Packet::with_steal_budget`. Every receive
+/// is published straight away.
+pub const DEFAULT_STEAL_BUDGET: usize = 1;
+
+// How many times a spinning `recv` yields before it parks.
+const SPIN_YIELDS: u32 = 10;
+
+/// How long `recv` waits on its token before it sleeps, once it has stored
+/// it, see `Packet::set_park_spin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParkSpin {
+    /// Spin this many times, then yield a few times. 0 sleeps straight away.
+    Fixed(u32),
+    /// Spin for a budget which grows while the sender keeps arriving before
+    /// it runs out, and shrinks while it doesn't, see `AdaptiveSpin`.
+    Adaptive,
+}
 
-const DISCONNECTED: isize = isize::MIN;
-#[cfg(test)]
-const MAX_STEALS: isize = 5;
-#[cfg(not(test))]
-const MAX_STEALS: isize = 1 << 20;
+// `ParkSpin::Adaptive` in `Packet::park_spin`, any other value is Fixed.
+const PARK_SPIN_ADAPTIVE: usize = !0;
 
+// `new` is left out of trait objects so that factory can box the queues.
 pub trait Queue<T> {
-    fn new(bound: usize) -> Self;
+    fn new(bound: usize) -> Self where Self: Sized;
     fn push(&self, t: T);
     fn pop(&self) -> Option<T>;
     fn peek(&self) -> Option<&mut T>;
@@ -149,233 +380,933 @@ impl<T> Queue<T> for spsc2::Queue<T, spsc2::CacheAligned> {
     }
 }
 
-unsafe impl<Q, T> Send for Packet<Q, T> where Q: Send + Sync, T: Send + Sync {}
-unsafe impl<Q, T> Sync for Packet<Q, T> where Q: Send + Sync, T: Send + Sync {}
+// The segments are recycled through a cache of one, whatever the bound.
+impl<T> Queue<T> for spsc_seg::Queue<T, spsc_seg::NoAlign> {
+    fn new(_bound: usize) -> Self {
+        unsafe { spsc_seg::Queue::new() }
+    }
+
+    fn push(&self, t: T) {
+        self.push(t)
+    }
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
+
+    fn peek(&self) -> Option<&mut T> {
+        self.peek()
+    }
+}
 
-#[repr(align(64))]
-struct AlignToCache;
+impl<T> Queue<T> for spsc_seg::Queue<T, spsc_seg::CacheAligned> {
+    fn new(_bound: usize) -> Self {
+        unsafe { spsc_seg::Queue::aligned() }
+    }
 
-struct CacheAligned<T>(T, [AlignToCache; 0]);
+    fn push(&self, t: T) {
+        self.push(t)
+    }
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
 
-impl<T> CacheAligned<T> {
-     fn new(t: T) -> Self {
-         CacheAligned(t, [])
-     }
+    fn peek(&self) -> Option<&mut T> {
+        self.peek()
+    }
 }
 
-impl<T> ::std::ops::Deref for CacheAligned<T> {
-     type Target = T;
-     fn deref(&self) -> &Self::Target {
-         &self.0
-     }
+// Every push allocates, there's no cache for the bound to limit.
+impl<T> Queue<T> for spsc_epoch::Queue<T> {
+    fn new(_bound: usize) -> Self {
+        unsafe { spsc_epoch::Queue::new() }
+    }
+
+    fn push(&self, t: T) {
+        self.push(t)
+    }
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
+
+    fn peek(&self) -> Option<&mut T> {
+        self.peek()
+    }
 }
 
-pub struct Packet<Q, T> {
+unsafe impl<Q, T, W> Send for Packet<Q, T, W>
+where Q: Send + Sync, T: Send, W: Wakeup, W::Signal: Send {}
+unsafe impl<Q, T, W> Sync for Packet<Q, T, W>
+where Q: Send + Sync, T: Send, W: Wakeup, W::Signal: Send {}
+
+pub struct Packet<Q, T, W: Wakeup = DefaultBlocking> {
     queue: Q, // internal queue for all message
-    port_dropped: CacheAligned<AtomicBool>, // flag if the channel has been destroyed.
-    to_wake: CacheAligned<AtomicUsize>, // SignalToken for the blocked thread to wake up
-    _pd: PhantomData<T>,
+    port_dropped: CachePadded<AtomicBool>, // flag if the channel has been destroyed.
+    sender_done: CachePadded<AtomicBool>, // set by drop_chan, the sender will never send again
+    sending: CachePadded<AtomicBool>, // set while the sender is between its port_dropped check and the end of its push
+    to_wake: CachePadded<AtomicUsize>, // SignalToken for the blocked thread to wake up
+    receiver_parked: CachePadded<AtomicBool>, // the doorbell, set while the receiver is parked or about to park
+    sent: CachePadded<AtomicUsize>, // Data messages pushed, only written by the sender
+    received: CachePadded<AtomicUsize>, // Data messages popped, only written by the receiver
+    steals: UnsafeCell<usize>, // Data messages popped but not yet added to received
+    steal_budget: usize, // how many steals the receiver keeps before publishing them
+    spin: AtomicUsize, // how many times recv polls before parking, see set_spin
+    park_spin: AtomicUsize, // how long recv spins on its token before sleeping, see set_park_spin
+    adaptive_spin: AdaptiveSpin, // the receiver's budget for ParkSpin::Adaptive
+    upgrade: UnsafeCell<Option<Arc<SharedPacket<T>>>>, // a GoUp taken off the queue by peek, for the next recv
+    upgraded: AtomicBool, // set by the sender once it has queued a GoUp, see into_queue
+    #[cfg(feature = "stats")]
+    stats: Stats,
+    #[cfg(feature = "trace")]
+    trace: Trace,
+    _pd: PhantomData<(T, W)>,
 }
 
 #[derive(Debug)]
 pub enum Failure<T> {
     Empty,
     Disconnected,
-    Upgraded(Receiver<T>),
+    Upgraded(Arc<SharedPacket<T>>),
+    // only returned by a `recv` with a deadline
+    Timeout,
+}
+
+// The errors returned through `Sender` and `Receiver`, which match those of
+// `std::sync::mpsc`. `Failure` is the packets' own error, and never makes it
+// out of the wrappers.
+
+/// An error returned from `Sender::send`, handing back the value which could
+/// not be sent as the receiver is gone.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct SendError<T>(pub T);
+
+/// An error returned from `Receiver::recv`, the sender is gone and the
+/// channel is empty.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RecvError;
+
+/// An error returned from `Receiver::try_recv`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TryRecvError {
+    /// The channel is empty, but the sender may still send.
+    Empty,
+    /// The sender is gone and the channel is empty.
+    Disconnected,
+}
+
+/// An error returned from `Receiver::recv_timeout` and `recv_deadline`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum RecvTimeoutError {
+    /// Nothing was sent before the time ran out.
+    Timeout,
+    /// The sender is gone and the channel is empty.
+    Disconnected,
+}
+
+/// An error returned from `try_send`, handing back the value which could not
+/// be sent.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum TrySendError<T> {
+    /// The channel is full. Only returned by bounded channels.
+    Full(T),
+    /// The receiver is gone.
+    Disconnected(T),
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("sending on a closed channel")
+    }
+}
+
+impl<T: Send> error::Error for SendError<T> {
+    fn description(&self) -> &str {
+        "sending on a closed channel"
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrySendError::Full(..) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(..) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrySendError::Full(..) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(..) => f.write_str("sending on a closed channel"),
+        }
+    }
+}
+
+impl<T: Send> error::Error for TrySendError<T> {
+    fn description(&self) -> &str {
+        match *self {
+            TrySendError::Full(..) => "sending on a full channel",
+            TrySendError::Disconnected(..) => "sending on a closed channel",
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for TrySendError<T> {
+    fn from(err: SendError<T>) -> TrySendError<T> {
+        match err {
+            SendError(t) => TrySendError::Disconnected(t),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("receiving on a closed channel")
+    }
+}
+
+impl error::Error for RecvError {
+    fn description(&self) -> &str {
+        "receiving on a closed channel"
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl error::Error for TryRecvError {
+    fn description(&self) -> &str {
+        match *self {
+            TryRecvError::Empty => "receiving on an empty channel",
+            TryRecvError::Disconnected => "receiving on a closed channel",
+        }
+    }
 }
 
-pub enum UpgradeResult {
+impl From<RecvError> for TryRecvError {
+    fn from(err: RecvError) -> TryRecvError {
+        match err {
+            RecvError => TryRecvError::Disconnected,
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvTimeoutError::Timeout => f.write_str("timed out waiting on channel"),
+            RecvTimeoutError::Disconnected => f.write_str("channel is empty and sending half is closed"),
+        }
+    }
+}
+
+impl error::Error for RecvTimeoutError {
+    fn description(&self) -> &str {
+        match *self {
+            RecvTimeoutError::Timeout => "timed out waiting on channel",
+            RecvTimeoutError::Disconnected => "channel is empty and sending half is closed",
+        }
+    }
+}
+
+impl From<RecvError> for RecvTimeoutError {
+    fn from(err: RecvError) -> RecvTimeoutError {
+        match err {
+            RecvError => RecvTimeoutError::Disconnected,
+        }
+    }
+}
+
+pub enum UpgradeResult<S = SignalToken> {
     UpSuccess,
     UpDisconnected,
-    UpWoke(SignalToken),
+    UpWoke(S),
 }
 
 pub enum SelectionResult<T> {
     SelSuccess,
     SelCanceled,
-    SelUpgraded(SignalToken, Receiver<T>),
+    SelUpgraded(SignalToken, Arc<SharedPacket<T>>),
+}
+
+/// What `Packet::peek` found at the head of the channel.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PeekResult<'a, T: 'a> {
+    Data(&'a T),
+    // The sender upgraded, the next `recv` or `try_recv` returns `Upgraded`.
+    Upgraded,
+    Empty,
+    Disconnected,
 }
 
 // Any message could contain an "upgrade request" to a new shared port, so the
 // internal queue it's a queue of T, but rather Message<T>
 pub enum Message<T> {
     Data(T),
-    GoUp(Receiver<T>),
+    GoUp(Arc<SharedPacket<T>>),
 }
 
-impl<Q, T> Packet<Q, T>
-where Q: Queue<Message<T>> {
+impl<Q, T, W> Packet<Q, T, W>
+where Q: Queue<Message<T>>, W: Wakeup {
     pub fn new() -> Self {
+        Packet::with_steal_budget(DEFAULT_STEAL_BUDGET)
+    }
+
+    /// Creates a packet whose receiver counts up to `budget` receives
+    /// privately, as steals, before adding them to the count the sender's
+    /// `len` reads, so that a busy receiver only writes that line once per
+    /// `budget` messages. This is std's steals, which bound how far its
+    /// receiver gets ahead of the shared count, turned around: here the
+    /// receiver is never behind, as parking doesn't depend on the count, and
+    /// the budget only bounds how stale `len` is. Steals are also published
+    /// whenever the receiver parks.
+    pub fn with_steal_budget(budget: usize) -> Self {
+        assert!(budget > 0, "the steal budget must be at least 1");
         Packet {
             queue: Q::new(128),
 
-            to_wake: CacheAligned::new(AtomicUsize::new(0)),
+            to_wake: CachePadded::new(AtomicUsize::new(0)),
+            receiver_parked: CachePadded::new(AtomicBool::new(false)),
+            sent: CachePadded::new(AtomicUsize::new(0)),
+            received: CachePadded::new(AtomicUsize::new(0)),
+            steals: UnsafeCell::new(0),
+            steal_budget: budget,
+            spin: AtomicUsize::new(0),
+            park_spin: AtomicUsize::new(0),
+            adaptive_spin: AdaptiveSpin::new(),
+            upgrade: UnsafeCell::new(None),
+            upgraded: AtomicBool::new(false),
+            #[cfg(feature = "stats")]
+            stats: Stats::default(),
+            #[cfg(feature = "trace")]
+            trace: Trace::new(),
 
-            port_dropped: CacheAligned::new(AtomicBool::new(false)),
+            port_dropped: CachePadded::new(AtomicBool::new(false)),
+            sender_done: CachePadded::new(AtomicBool::new(false)),
+            sending: CachePadded::new(AtomicBool::new(false)),
             _pd: Default::default(),
         }
     }
 
     pub fn send(&self, t: T) -> Result<(), T> {
+        match self.try_send(t) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(t)) | Err(TrySendError::Full(t)) => Err(t),
+        }
+    }
+
+    // The channel is unbounded so this never reports `Full`, but unlike `send`
+    // the caller can tell why the value came back.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
         // If the other port has deterministically gone away, then definitely
         // must return the data back up the stack. Otherwise, the data is
         // considered as being sent.
-        if self.port_dropped.load(Ordering::SeqCst) { return Err(t) }
+        if !self.begin_send() {
+            #[cfg(feature = "stats")]
+            Stats::bump(&self.stats.disconnected_sends);
+            return Err(TrySendError::Disconnected(t))
+        }
 
-        match self.do_send(Data(t)) {
-            UpSuccess | UpDisconnected => {},
-            UpWoke(token) => { token.signal(); }
+        // Counted before the push, so that it can never be received before
+        // it is counted, see `len`.
+        bump(&self.sent, 1);
+        let res = self.do_send(Data(t));
+        self.end_send();
+        match res {
+            Ok(None) => {},
+            Ok(Some(token)) => {
+                #[cfg(feature = "stats")]
+                Stats::bump(&self.stats.signals);
+                W::signal(&token);
+            }
+            // we lost the race with drop_port
+            Err(Data(t)) => {
+                bump(&self.sent, !0);
+                #[cfg(feature = "stats")]
+                Stats::bump(&self.stats.disconnected_sends);
+                return Err(TrySendError::Disconnected(t))
+            }
+            Err(GoUp(..)) => unreachable!(),
         }
         Ok(())
     }
 
-    pub fn upgrade(&self, up: Receiver<T>) -> UpgradeResult {
+    pub fn upgrade(&self, up: Arc<SharedPacket<T>>) -> UpgradeResult<W::Signal> {
         // If the port has gone away, then there's no need to proceed any
         // further.
-        if self.port_dropped.load(Ordering::SeqCst) { return UpDisconnected }
+        if !self.begin_send() { return UpDisconnected }
 
-        self.do_send(GoUp(up))
+        let res = self.do_send(GoUp(up));
+        self.end_send();
+        #[cfg(feature = "stats")]
+        {
+            if res.is_ok() { Stats::bump(&self.stats.upgrades_sent) }
+        }
+        if res.is_ok() {
+            self.upgraded.store(true, Ordering::SeqCst);
+            trace!(self, Event::UpgradeSent)
+        }
+        match res {
+            Ok(None) => UpSuccess,
+            Ok(Some(token)) => {
+                // the caller signals it
+                #[cfg(feature = "stats")]
+                Stats::bump(&self.stats.signals);
+                UpWoke(token)
+            }
+            Err(..) => UpDisconnected,
+        }
     }
 
-    fn do_send(&self, t: Message<T>) -> UpgradeResult {
-        self.queue.push(t);
-        //TODO DISCONNECTED?
+    // The sender's half of the two phase port drop. The sender announces that
+    // it is sending _before_ checking port_dropped, while the port sets
+    // port_dropped _before_ checking whether the sender is sending. Both are
+    // SeqCst, so either the sender sees the port is gone and backs out, or
+    // the port sees the send and waits for it to finish before draining.
+    //
+    // This is a store followed by a load of a different location on each side,
+    // which is the one pattern Acquire and Release can't order, so these have
+    // to stay SeqCst (or be paired with fences, which cost the same).
+    fn begin_send(&self) -> bool {
+        self.sending.store(true, Ordering::SeqCst);
         if self.port_dropped.load(Ordering::SeqCst) {
-            // Be sure to preserve the disconnected state, and the return value
-            // in this case is going to be whether our data was received or not.
-            // This manifests itself on whether we have an empty queue or not.
-            //
-            // Primarily, are required to drain the queue here because the port
-            // will never remove this data. We can only have at most one item to
-            // drain (the port drains the rest).
-            let first = self.queue.pop();
-            let second = self.queue.pop();
-            assert!(second.is_none());
+            self.sending.store(false, Ordering::Release);
+            return false
+        }
+        true
+    }
 
-            return match first {
-                Some(..) => UpSuccess,  // we failed to send the data
-                None => UpDisconnected, // we successfully sent data
+    fn end_send(&self) {
+        self.sending.store(false, Ordering::Release);
+    }
+
+    // Pushes a message, returning the token of a receiver which needs waking.
+    // If the port was dropped before it could see the message, the message is
+    // handed back instead.
+    fn do_send(&self, t: Message<T>) -> Result<Option<W::Signal>, Message<T>> {
+        self.queue.push(t);
+        // The fence is for the doorbell below, but it also orders the push
+        // before this re-check.
+        atomic::fence(Ordering::SeqCst);
+        // Unlike the check in `begin_send`, this one needs no ordering with
+        // the port's `sending` load: the port waits for us before draining
+        // whichever way this goes. If we miss the flag, the port drains our
+        // message after we're done. If we see it, the port has stopped
+        // receiving, and Acquire makes its last pops visible to ours.
+        if self.port_dropped.load(PORT_DROPPED_RECHECK) {
+            // The port is gone, and it can't be draining the queue as it waits
+            // for us to finish sending first, so for the moment we are the
+            // only one popping. Our message was pushed last, so if the queue
+            // is not empty the last message in it is ours, and the port never
+            // saw it. Anything before it was sent successfully by an earlier
+            // call and would have been dropped by the port anyway. If the
+            // queue is empty the port received our message before it went
+            // away, and the send succeeded.
+            let mut last = None;
+            while let Some(msg) = self.queue.pop() {
+                last = Some(msg);
+            }
+            return match last {
+                Some(msg) => Err(msg),
+                None => Ok(None),
             }
         }
 
-        match self.try_take_to_wake() {
-            Some(token) => UpWoke(token),
-            None => UpSuccess,
+        // The doorbell. Most of the time the receiver is busy and there's no
+        // one to wake, so rather than touch a line the receiver writes we only
+        // read its flag, and only go for its token when it is set. The fence
+        // above pairs with the one in `park`: either we see the flag, or the
+        // receiver sees our message when it re-checks the queue.
+        if self.receiver_parked.load(Ordering::Relaxed) {
+            let token = self.try_take_to_wake();
+            #[cfg(feature = "stats")]
+            {
+                if token.is_some() { Stats::bump(&self.stats.woke_parked) }
+            }
+            if token.is_some() { trace!(self, Event::TokenTaken) }
+            Ok(token)
+        } else {
+            Ok(None)
         }
     }
 
     // Consumes ownership of the 'to_wake' field.
-    fn take_to_wake(&self) -> SignalToken {
+    fn take_to_wake(&self) -> W::Signal {
         let ptr = self.to_wake.load(Ordering::SeqCst);
         self.to_wake.store(0, Ordering::SeqCst);
         assert!(ptr != 0);
-        unsafe { SignalToken::cast_from_usize(ptr) }
+        unsafe { W::cast_from_usize(ptr) }
     }
 
     // Consumes ownership of the 'to_wake' field.
-    fn try_take_to_wake(&self) -> Option<SignalToken> {
+    fn try_take_to_wake(&self) -> Option<W::Signal> {
         let ptr = self.to_wake.swap(0, Ordering::SeqCst);
         if ptr == 0 {
             None
         } else {
-            Some(unsafe { SignalToken::cast_from_usize(ptr) })
+            Some(unsafe { W::cast_from_usize(ptr) })
         }
     }
 
-    // Decrements the count on the channel for a sleeper, returning the sleeper
-    // back if it shouldn't sleep. Note that this is the location where we take
-    // steals into account.
-    fn decrement(&self, token: SignalToken) -> Result<Option<T>, SignalToken> {
+    // Rings the doorbell for a sleeper, returning the sleeper back if it
+    // shouldn't sleep.
+    //
+    // We store our token and set the flag _before_ re-checking the queue,
+    // while a sender pushes _before_ checking the flag, and both fence in
+    // between, so either we see the message or the sender sees the flag. A
+    // sender which sees the flag may be a late one whose message we already
+    // received, so a wakeup doesn't promise data; see `recv`.
+    fn park(&self, token: W::Signal) -> Result<(), W::Signal> {
         assert_eq!(self.to_wake.load(Ordering::SeqCst), 0);
-        let ptr = unsafe { token.cast_to_usize() };
+        // Let `len` see everything we've received while we sleep.
+        self.publish_steals();
+        let ptr = unsafe { W::cast_to_usize(token) };
         self.to_wake.store(ptr, Ordering::SeqCst);
+        self.receiver_parked.store(true, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        trace!(self, Event::TokenStored);
 
-        match self.try_recv() {
-            Err(Empty) | Err(Disconnected) => {}
-            Err(Upgraded(..)) => unimplemented!(),
-            Ok(data) => {
-                self.to_wake.store(0, Ordering::SeqCst);
-                return Ok(Some(data))
-            }
+        // Sleep, unless there's data or there will never be any more.
+        if self.queue.peek().is_none() && !self.sender_done.load(Ordering::SeqCst) {
+            return Ok(())
         }
 
-        if self.port_dropped.load(Ordering::SeqCst) {
-            self.to_wake.store(0, Ordering::SeqCst);
-            return Err(unsafe { SignalToken::cast_from_usize(ptr) })
+        // Take our token back. If it's already gone a sender has it, and will
+        // signal us shortly.
+        self.receiver_parked.store(false, Ordering::Relaxed);
+        match self.try_take_to_wake() {
+            Some(token) => Err(token),
+            None => Ok(()),
         }
-
-        return Ok(None)
     }
 
     pub fn recv(&self, deadline: Option<Instant>) -> Result<T, Failure<T>> {
-        // Optimistic preflight check (scheduling is expensive).
-        match self.try_recv() {
-            Err(Empty) => {}
-            data => return data,
-        }
-        'recv: loop {
+        self.recv_by(deadline, || self.try_recv())
+    }
+
+    /// Blocks as `recv` does, but rather than moving the message out runs
+    /// `f` on it where it sits in the queue, then pops it and returns what
+    /// `f` returned. With a large `T` this saves copying it out of the node
+    /// only to read part of it. Upgrades and disconnects are returned as by
+    /// `recv`, without calling `f`.
+    ///
+    /// The borrow handed to `f` can't outlive the call, so it can't dangle
+    /// once the node is freed. If `f` panics the message is left in the
+    /// queue, and the next receive gets it again.
+    pub fn recv_with<R, F>(&self, deadline: Option<Instant>, f: F) -> Result<R, Failure<T>>
+    where F: FnOnce(&mut T) -> R {
+        let mut f = Some(f);
+        self.recv_by(deadline, || self.try_recv_with(&mut f))
+    }
+
+    // The blocking protocol shared by `recv` and `recv_with`, with `try_recv`
+    // standing in for the non-blocking receive.
+    fn recv_by<R, F>(&self, deadline: Option<Instant>, mut try_recv: F) -> Result<R, Failure<T>>
+    where F: FnMut() -> Result<R, Failure<T>> {
+        let mut woken = false;
+        loop {
+            // Optimistic preflight check (scheduling is expensive).
+            match try_recv() {
+                Err(Empty) => {
+                    #[cfg(feature = "stats")]
+                    {
+                        if woken { Stats::bump(&self.stats.spurious_wakeups) }
+                    }
+                    if woken { trace!(self, Event::SpuriousWake) }
+                }
+                data => return data,
+            }
+
+            // If asked to, keep checking for a while in case the data is
+            // about to arrive.
+            match self.spin_recv(&mut try_recv) {
+                Err(Empty) => {}
+                data => return data,
+            }
+
+            // Don't bother parking if we're already out of time.
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline { return Err(Timeout) }
+            }
+
             // Welp, our channel has no data. Deschedule the current thread and
             // initiate the blocking protocol.
-            let (wait_token, signal_token) = blocking::tokens();
-            match self.decrement(signal_token) {
-                Ok(Some(data)) => return Ok(data),
-                Ok(None) => if let Some(deadline) = deadline {
-                        wait_token.wait_max_until(deadline);
-                    } else {
-                        wait_token.wait();
-                    },
-                Err(..) => {}
+            let (wait_token, signal_token) = W::tokens();
+            if self.park(signal_token).is_ok() {
+                #[cfg(feature = "stats")]
+                Stats::bump(&self.stats.parks);
+                trace!(self, Event::Parked);
+                if self.spin_on(&wait_token) {
+                    #[cfg(feature = "stats")]
+                    Stats::bump(&self.stats.spin_hits);
+                } else if let Some(deadline) = deadline {
+                    if !W::wait_max_until(wait_token, deadline) {
+                        trace!(self, Event::TimedOut);
+                        // We timed out, but a sender may be about to wake us.
+                        // If we can take our token back no one else will, so
+                        // make one last check for data which raced with the
+                        // timeout. Otherwise a sender has it, and we go
+                        // around to see what it sent.
+                        if self.try_take_to_wake().is_some() {
+                            self.receiver_parked.store(false, Ordering::Relaxed);
+                            return match try_recv() {
+                                Err(Empty) => Err(Timeout),
+                                data => data,
+                            }
+                        }
+                    }
+                } else {
+                    W::wait(wait_token);
+                }
+                self.receiver_parked.store(false, Ordering::Relaxed);
+                trace!(self, Event::Woke);
+                woken = true;
             }
 
-            match self.try_recv() {
-                // We get get spurious wakeups under the correct interleaving
-                // so if we recv an Empty here go back to sleep
-                Err(Empty) => continue 'recv,
-                // Messages which actually popped from the queue shouldn't count as
-                // a steal, so offset the decrement here (we already have our
-                // "steal" factored into the channel count above).
-                data @ Ok(..) | data @ Err(Upgraded(..)) | data @ Err(Disconnected) => return data,
+            // We were woken, or didn't park, because there's data or the
+            // sender is gone. Or a late sender rang the doorbell for a message
+            // we had already received, in which case the queue is empty and we
+            // go back to sleep.
+        }
+    }
+
+    /// Takes back the waker of a Pending `poll_recv`, if no sender has it.
+    #[cfg(feature = "async")]
+    pub fn cancel_poll(&self) {
+        drop(self.try_take_to_wake());
+        self.receiver_parked.store(false, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> PacketStats {
+        self.stats.snapshot()
+    }
+
+    /// The most recent blocking protocol events, oldest first. The trace is
+    /// also printed if the packet is dropped during a panic.
+    #[cfg(feature = "trace")]
+    pub fn trace(&self) -> Vec<Event> {
+        self.trace.snapshot()
+    }
+
+    /// Sets how many spin loop hints `recv` spends polling for data, backing
+    /// off between polls, before it parks. A non-zero count is followed by a
+    /// few `yield_now`s, to let a descheduled sender run. The default of 0
+    /// parks straight away.
+    pub fn set_spin(&self, spin: usize) {
+        self.spin.store(spin, Ordering::Relaxed);
+    }
+
+    /// Sets how long `recv` waits on its token, once it has stored it, before
+    /// it puts the thread to sleep. Unlike `set_spin` this only spins once
+    /// the sender has been told to wake us, and a hit saves the sender its
+    /// unpark as well as us our sleep. The default is `ParkSpin::Fixed(0)`.
+    pub fn set_park_spin(&self, spin: ParkSpin) {
+        let spin = match spin {
+            ParkSpin::Fixed(n) => n as usize,
+            ParkSpin::Adaptive => PARK_SPIN_ADAPTIVE,
+        };
+        self.park_spin.store(spin, Ordering::Relaxed);
+    }
+
+    // Spins on a stored token as `set_park_spin` asks, returning whether it
+    // was signalled in that time.
+    fn spin_on(&self, token: &W::Wait) -> bool {
+        match self.park_spin.load(Ordering::Relaxed) {
+            0 => false,
+            PARK_SPIN_ADAPTIVE => {
+                let hit = blocking::spin_wait::<W>(token, self.adaptive_spin.budget());
+                self.adaptive_spin.record(hit);
+                hit
+            }
+            n => blocking::spin_wait::<W>(token, n as u32),
+        }
+    }
+
+    /// Blocks as `recv` does until there's at least one message, then takes
+    /// whatever else is already queued along with it, up to `max` in all,
+    /// appending them to `out`. Returns how many were taken. After a wakeup
+    /// there is usually a backlog, and this takes it without going back
+    /// through the checks `recv` makes before parking for each message.
+    ///
+    /// A GoUp ends the batch, and is returned by the next call.
+    pub fn recv_many(&self, out: &mut Vec<T>, max: usize, deadline: Option<Instant>)
+        -> Result<usize, Failure<T>> {
+        if max == 0 { return Ok(0) }
+        out.push(self.recv(deadline)?);
+        let mut n = 1;
+        while n < max {
+            match self.queue.peek() {
+                Some(&mut Data(..)) => {}
+                Some(&mut GoUp(..)) | None => break,
+            }
+            match self.queue.pop() {
+                Some(Data(t)) => out.push(t),
+                _ => unreachable!(),
+            }
+            n += 1;
+        }
+        // The first was counted by recv.
+        self.steal(n - 1);
+        Ok(n)
+    }
+
+    fn spin_recv<R, F>(&self, try_recv: &mut F) -> Result<R, Failure<T>>
+    where F: FnMut() -> Result<R, Failure<T>> {
+        let spin = self.spin.load(Ordering::Relaxed);
+        if spin == 0 { return Err(Empty) }
+        let spin = cmp::min(spin, u32::max_value() as usize) as u32;
+        let mut backoff = Backoff::with_limits(spin, SPIN_YIELDS);
+        while !backoff.is_completed() {
+            backoff.snooze();
+            match try_recv() {
+                Err(Empty) => {}
+                data => return data,
             }
         }
+        Err(Empty)
     }
 
     pub fn try_recv(&self) -> Result<T, Failure<T>> {
+        if let Some(up) = unsafe { (*self.upgrade.get()).take() } {
+            return Err(Upgraded(up))
+        }
         match self.queue.pop() {
-            Some(data) => {
-                match data {
-                    Data(t) => Ok(t),
-                    GoUp(up) => Err(Upgraded(up)),
-                }
-            },
+            Some(Data(t)) => { self.steal(1); Ok(t) }
+            Some(GoUp(up)) => {
+                #[cfg(feature = "stats")]
+                Stats::bump(&self.stats.upgrades_received);
+                trace!(self, Event::UpgradeObserved);
+                Err(Upgraded(up))
+            }
 
             None => {
-                if !self.port_dropped.load(Ordering::SeqCst) {
+                if !self.sender_done.load(Ordering::SeqCst) {
                     return Err(Empty)
                 }
+                // More data could have been sent between our pop and seeing
+                // the disconnect, so be sure there's none.
                 match self.queue.pop() {
-                    Some(Data(t)) => Ok(t),
-                    Some(GoUp(up)) => Err(Upgraded(up)),
+                    Some(Data(t)) => { self.steal(1); Ok(t) }
+                    Some(GoUp(up)) => {
+                        #[cfg(feature = "stats")]
+                        Stats::bump(&self.stats.upgrades_received);
+                        trace!(self, Event::UpgradeObserved);
+                        Err(Upgraded(up))
+                    }
                     None => Err(Disconnected),
                 }
             }
         }
     }
 
+    // `try_recv` for `recv_with`. `f` is only taken, and called, once there
+    // is data, so a caller can keep retrying with the same `f` while this
+    // returns Empty.
+    fn try_recv_with<R, F>(&self, f: &mut Option<F>) -> Result<R, Failure<T>>
+    where F: FnOnce(&mut T) -> R {
+        if let Some(up) = unsafe { (*self.upgrade.get()).take() } {
+            return Err(Upgraded(up))
+        }
+        if let Some(ret) = self.recv_head_with(f) {
+            return ret
+        }
+        if !self.sender_done.load(Ordering::SeqCst) {
+            return Err(Empty)
+        }
+        // As in try_recv, data could have been sent between our peek and
+        // seeing the disconnect.
+        match self.recv_head_with(f) {
+            Some(ret) => ret,
+            None => Err(Disconnected),
+        }
+    }
+
+    fn recv_head_with<R, F>(&self, f: &mut Option<F>) -> Option<Result<R, Failure<T>>>
+    where F: FnOnce(&mut T) -> R {
+        let ret = match self.queue.peek() {
+            Some(&mut Data(ref mut t)) => (f.take().unwrap())(t),
+            Some(&mut GoUp(..)) => match self.queue.pop() {
+                Some(GoUp(up)) => {
+                    #[cfg(feature = "stats")]
+                    Stats::bump(&self.stats.upgrades_received);
+                    trace!(self, Event::UpgradeObserved);
+                    return Some(Err(Upgraded(up)))
+                }
+                _ => unreachable!(),
+            },
+            None => return None,
+        };
+        drop(self.queue.pop());
+        self.steal(1);
+        Some(Ok(ret))
+    }
+
+    /// Whether `try_recv` would return something other than `Empty`: data,
+    /// an upgrade, or the disconnect. Consumes nothing, so it can be used to
+    /// poll. Like `try_recv` it may only be called by the receiver.
+    pub fn can_recv(&self) -> bool {
+        match self.peek() {
+            PeekResult::Empty => false,
+            _ => true,
+        }
+    }
+
+    /// Looks at what `try_recv` would return without receiving it. The data
+    /// is only borrowed until the next receive, and like `try_recv` this may
+    /// only be called by the receiver.
+    ///
+    /// A GoUp can't be left at the head, as data sent after it goes to the
+    /// shared packet, so it's taken off the queue and kept for the next
+    /// receive, which switches packets as it would have anyway.
+    pub fn peek<'a>(&'a self) -> PeekResult<'a, T> {
+        if unsafe { (*self.upgrade.get()).is_some() } {
+            return PeekResult::Upgraded
+        }
+        if self.queue.peek().is_none() {
+            if !self.sender_done.load(Ordering::SeqCst) {
+                return PeekResult::Empty
+            }
+            // As in try_recv, data could have been sent between our peek
+            // and seeing the disconnect.
+            if self.queue.peek().is_none() {
+                return PeekResult::Disconnected
+            }
+        }
+        let up = match self.queue.peek() {
+            Some(&mut Data(ref t)) => return PeekResult::Data(t),
+            Some(&mut GoUp(..)) => match self.queue.pop() {
+                Some(GoUp(up)) => up,
+                _ => unreachable!(),
+            },
+            None => unreachable!(),
+        };
+        #[cfg(feature = "stats")]
+        Stats::bump(&self.stats.upgrades_received);
+        trace!(self, Event::UpgradeObserved);
+        unsafe { *self.upgrade.get() = Some(up) }
+        PeekResult::Upgraded
+    }
+
+    /// Moves everything queued when this is called into `out`, without
+    /// blocking, and returns how many messages that was. Like `try_recv`
+    /// this may only be called by the receiver.
+    ///
+    /// A GoUp ends the drain, as everything after it goes to the shared
+    /// packet. It's kept for the next receive, as `peek` keeps one.
+    pub fn drain_into(&self, out: &mut Vec<T>) -> usize {
+        // Nothing comes after an upgrade we've already taken off the queue.
+        if unsafe { (*self.upgrade.get()).is_some() } {
+            return 0
+        }
+        // Bound the drain by what's queued now, else a fast sender could
+        // keep us here forever.
+        self.publish_steals();
+        let (n, up) = self.drain_with(self.len(), |t| out.push(t));
+        if let Some(up) = up {
+            #[cfg(feature = "stats")]
+            Stats::bump(&self.stats.upgrades_received);
+            trace!(self, Event::UpgradeObserved);
+            unsafe { *self.upgrade.get() = Some(up) }
+        }
+        self.steal(n);
+        n
+    }
+
+    // Pops up to `max` messages, handing the data to `sink`, and returns how
+    // many there were along with the GoUp, if there was one. A GoUp is
+    // always the last message in the queue, and once there is one any later
+    // send goes to the shared packet, so it ends the drain. An upgrade peek
+    // already took off the queue is returned the same way.
+    //
+    // Both `drain_into` and `drop_port` drain through this, so it's the one
+    // place which handles a GoUp being mixed in with the data.
+    fn drain_with<F>(&self, max: usize, mut sink: F) -> (usize, Option<Arc<SharedPacket<T>>>)
+    where F: FnMut(T) {
+        if let Some(up) = unsafe { (*self.upgrade.get()).take() } {
+            return (0, Some(up))
+        }
+        let mut n = 0;
+        while n < max {
+            match self.queue.pop() {
+                Some(Data(t)) => { sink(t); n += 1 }
+                Some(GoUp(up)) => return (n, Some(up)),
+                None => break,
+            }
+        }
+        (n, None)
+    }
+
+    // Counts `n` more messages as received, publishing them once the steal
+    // budget is used up.
+    fn steal(&self, n: usize) {
+        let steals = unsafe { &mut *self.steals.get() };
+        *steals += n;
+        if *steals >= self.steal_budget {
+            self.publish_steals();
+        }
+    }
+
+    fn publish_steals(&self) {
+        let steals = unsafe { &mut *self.steals.get() };
+        if *steals > 0 {
+            bump(&self.received, *steals);
+            *steals = 0;
+        }
+    }
+
+    /// The number of messages sent but not yet received. Only exact once
+    /// neither side is running, otherwise it may be stale by the time it is
+    /// returned. Upgrade requests are not messages, and are not counted.
+    ///
+    /// With a steal budget above 1 it also counts up to `budget - 1`
+    /// messages which the receiver has taken but not yet published, unless
+    /// the receiver is parked.
+    pub fn len(&self) -> usize {
+        // Read received first: a message is counted as sent before it is
+        // pushed, so anything counted here is counted in the sent we read
+        // after it, and the difference can't underflow.
+        let received = self.received.load(Ordering::Acquire);
+        let sent = self.sent.load(Ordering::Acquire);
+        sent - received
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     // drops the a sender
     pub fn drop_chan(&self) {
         // Dropping a channel is pretty simple, we just flag it as disconnected
-        // and then wakeup a blocker if there is one.
-        self.port_dropped.store(true, Ordering::SeqCst);
+        // and then wakeup a blocker if there is one. This is the sender's own
+        // flag rather than port_dropped, so that once the receiver sees it
+        // the queue is final: every message was pushed before it was set.
+        self.sender_done.store(true, Ordering::SeqCst);
         if let Some(to_wake) = self.try_take_to_wake() {
-            to_wake.signal();
+            trace!(self, Event::TokenTaken);
+            #[cfg(feature = "stats")]
+            Stats::bump(&self.stats.signals);
+            W::signal(&to_wake);
         }
     }
 
     // drops the one receiver
-    // FIXME: The simplest way to implement this without a count is likely 2-phase commit:
-    //        1. mark the receiver as dropped, after this no new sends can start
-    //        2. wait for sender to not be sendning
-    //        3. flush any remaining
+    // This is a 2-phase commit:
+    //   1. mark the receiver as dropped, after this no new sends can start
+    //   2. wait for sender to not be sending
+    //   3. flush any remaining
     pub fn drop_port(&self) {
         // Dropping a port seems like a fairly trivial thing. In theory all we
         // need to do is flag that we're disconnected and then everything else
@@ -397,35 +1328,2358 @@ where Q: Queue<Message<T>> {
         // there are a bounded number of active sends that we'll have to deal
         // with.
         self.port_dropped.store(true, Ordering::SeqCst);
+        trace!(self, Event::PortDropped);
 
-        // Now that we're guaranteed to deal with a bounded number of senders,
-        // we need to drain the queue. This draining process happens atomically
-        // with respect to the "count" of the channel. If the count is nonzero
-        // (with steals taken into account), then there must be data on the
-        // channel. In this case we drain everything and then try again. We will
-        // continue to fail while active senders send data while we're dropping
-        // data, but eventually we're guaranteed to break out of this loop
-        // (because there is a bounded number of senders).
-
-        //TODO we need a second signal to indicate that the sender will no longer send
-        //     this can be easily done with an additional read-mostly flag
-        while let Some(_) = self.queue.pop() { }
+        // A send which got past its port_dropped check before we set it may
+        // still be pushing, wait for it to finish so that its message is
+        // in the queue for us to drain. This is the only send we can ever
+        // wait on, any later one will see port_dropped, so however fast the
+        // sender is the drain below has a bound: what was queued when we set
+        // the flag, plus that one message. We can't start draining while we
+        // wait, as a send which sees the flag pops its own message back, and
+        // the queue only has room for one consumer at a time.
+        // If the sender is already gone it set `sender_done` after its last
+        // send, so there's nothing to wait for.
+        if !self.sender_done.load(Ordering::SeqCst) {
+            while self.sending.load(Ordering::SeqCst) {
+                sched::yield_now();
+            }
+        }
+
+        // Now that we're guaranteed no send is in flight, and none will
+        // start, we can drain the queue.
+        trace!(self, Event::DrainStarted);
+        let (_drained, up) = self.drain_with(usize::MAX, drop);
+        trace!(self, Event::DrainFinished(_drained));
+        #[cfg(feature = "stats")]
+        Stats::add(&self.stats.drained, _drained as u64);
+        // No one will ever receive from the shared packet we were upgraded
+        // to, so its senders must see the port as gone.
+        if let Some(up) = up {
+            up.drop_port();
+        }
 
         // At this point in time, we have gated all future senders from sending,
-        // and we have flagged the channel as being disconnected. The senders
-        // still have some responsibility, however, because some sends may not
-        // complete until after we flag the disconnection. There are more
-        // details in the sending methods that see DISCONNECTED
+        // and we have flagged the channel as being disconnected. A send which
+        // saw the flag after pushing has already taken its message back, see
+        // `do_send`.
+    }
+
+    /// Takes the queue back, with whatever the receiver hadn't yet received
+    /// still in it, rather than draining it as `drop_port` would. The sender
+    /// must be gone, as it has nothing left to send through, and the channel
+    /// must not have been upgraded, as a queued `GoUp` would hand over a
+    /// shared packet no one disconnects; otherwise the packet comes back.
+    pub fn into_queue(self) -> Result<Q, Self> {
+        if !self.sender_done.load(Ordering::SeqCst) || self.upgraded.load(Ordering::SeqCst) {
+            return Err(self)
+        }
+        // What `Drop` does, short of dropping the queue.
+        drop(self.try_take_to_wake());
+        let mut packet = ManuallyDrop::new(self);
+        unsafe {
+            let queue = ptr::read(&packet.queue);
+            // the rest of the fields which may own something
+            ptr::drop_in_place(&mut packet.upgrade);
+            #[cfg(feature = "trace")]
+            ptr::drop_in_place(&mut packet.trace);
+            Ok(queue)
+        }
+    }
+}
+
+// Polling needs a task's waker in `to_wake`, which only `blocking`'s tokens
+// can hold.
+#[cfg(feature = "async")]
+impl<Q, T> Packet<Q, T, DefaultBlocking>
+where Q: Queue<Message<T>> {
+    /// Receives without blocking, or registers the task to be woken when
+    /// there's something to receive. Like `recv` this may only be called by
+    /// the receiver, and a receiver uses one or the other, never both.
+    ///
+    /// The task's waker goes in `to_wake`, and rings the same doorbell, as a
+    /// blocked receiver's token would.
+    pub fn poll_recv(&self, cx: &mut Context) -> Poll<Result<T, Failure<T>>> {
+        // Take back the waker of an earlier Pending poll. If a sender has
+        // already taken it, it woke the task, and it's that wakeup which has
+        // us polling now (or it's about to, and we get polled once extra).
+        self.cancel_poll();
+        loop {
+            match self.try_recv() {
+                Err(Empty) => {}
+                data => return Poll::Ready(data),
+            }
+            if self.park(::blocking::task_token(cx.waker().clone())).is_ok() {
+                return Poll::Pending
+            }
+        }
     }
 }
 
-impl<Q, T> Packet<Q, T> {
+// Adds `delta` to a counter which only one thread writes, so there's no need
+// for a read-modify-write. Wraps, so `!0` subtracts one.
+fn bump(counter: &AtomicUsize, delta: usize) {
+    let n = counter.load(Ordering::Relaxed);
+    counter.store(n.wrapping_add(delta), Ordering::Release);
+}
+
+impl<Q: Layout, T, W: Wakeup> Layout for Packet<Q, T, W> {
+    fn fields(&self) -> Vec<Field> {
+        let mut fields = Vec::new();
+        layout::nest(&mut fields, "queue", self, &self.queue);
+        fields.extend(vec![
+            Field::new("port_dropped", Side::Producer, self, ptr::addr_of!(self.port_dropped)),
+            Field::new("sender_done", Side::Consumer, self, ptr::addr_of!(self.sender_done)),
+            Field::new("sending", Side::Producer, self, ptr::addr_of!(self.sending)),
+            Field::new("to_wake", Side::Both, self, ptr::addr_of!(self.to_wake)),
+            Field::new("receiver_parked", Side::Both, self, ptr::addr_of!(self.receiver_parked)),
+            Field::new("sent", Side::Producer, self, ptr::addr_of!(self.sent)),
+            Field::new("received", Side::Consumer, self, ptr::addr_of!(self.received)),
+            Field::new("steals", Side::Consumer, self, ptr::addr_of!(self.steals)),
+            Field::new("steal_budget", Side::Cold, self, ptr::addr_of!(self.steal_budget)),
+            Field::new("spin", Side::Cold, self, ptr::addr_of!(self.spin)),
+            Field::new("park_spin", Side::Cold, self, ptr::addr_of!(self.park_spin)),
+            Field::new("adaptive_spin", Side::Consumer, self, ptr::addr_of!(self.adaptive_spin)),
+            Field::new("upgrade", Side::Consumer, self, ptr::addr_of!(self.upgrade)),
+            Field::new("upgraded", Side::Cold, self, ptr::addr_of!(self.upgraded)),
+        ]);
+        fields
+    }
+}
+
+impl<Q, T, W: Wakeup> Drop for Packet<Q, T, W> {
     fn drop(&mut self) {
-        // Note that this load is not only an assert for correctness about
-        // disconnection, but also a proper fence before the read of
-        // `to_wake`, so this assert cannot be removed with also removing
-        // the `to_wake` assert.
-        // assert_eq!(self.cnt.load(Ordering::SeqCst), DISCONNECTED);
-        assert_eq!(self.to_wake.load(Ordering::SeqCst), 0);
+        // Both ends should have hung up by now, so the sender can't be
+        // mid-send either.
+        debug_assert!(self.port_dropped.load(Ordering::SeqCst)
+            && self.sender_done.load(Ordering::SeqCst)
+            && !self.sending.load(Ordering::SeqCst));
+
+        // A receiver which gave up on waiting may have left its token behind,
+        // we're the last one who can free it. Any messages still queued are
+        // dropped by the queue itself.
+        let ptr = self.to_wake.swap(0, Ordering::SeqCst);
+        if ptr != 0 {
+            drop(unsafe { W::cast_from_usize(ptr) });
+        }
+
+        #[cfg(feature = "trace")]
+        {
+            if thread::panicking() {
+                eprintln!("stream2 packet trace, oldest first: {:?}", self.trace.snapshot());
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// channel wrappers
+////////////////////////////////////////////////////////////////////////////////
+
+/// The sending half of a channel built on a stream `Packet`. Cloning it
+/// upgrades the channel to a `shared::SharedPacket` which every clone sends
+/// through. It is not `Sync`.
+pub struct Sender<T, Q = spsc::CNQueue<Message<T>>>
+where Q: Queue<Message<T>> {
+    inner: UnsafeCell<Flavor<T, Q>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// The receiving half of a channel built on a stream `Packet`. It follows the
+/// sender when it is upgraded.
+pub struct Receiver<T, Q = spsc::CNQueue<Message<T>>>
+where Q: Queue<Message<T>> {
+    inner: UnsafeCell<Flavor<T, Q>>,
+    // The fd of `readiness_fd`, once it has been asked for.
+    #[cfg(feature = "eventfd")]
+    readiness: UnsafeCell<Option<Arc<EventFd>>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+// What a `Sender` sends through, or a `Receiver` receives from.
+enum Flavor<T, Q> {
+    Stream(Arc<Packet<Q, T>>),
+    Shared(Arc<SharedPacket<T>>),
+}
+
+impl<T, Q> Flavor<T, Q>
+where Q: Queue<Message<T>> {
+    fn len(&self) -> usize {
+        match *self {
+            Flavor::Stream(ref p) => p.len(),
+            Flavor::Shared(ref p) => p.len(),
+        }
+    }
+}
+
+fn from_shared<T>(failure: shared::Failure) -> Failure<T> {
+    match failure {
+        shared::Empty => Empty,
+        shared::Disconnected => Disconnected,
+        shared::Timeout => Timeout,
+    }
+}
+
+/// Creates a channel backed by the default queue, `spsc::CNQueue`.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    channel_with_queue()
+}
+
+/// Creates a channel backed by the queue `Q`.
+pub fn channel_with_queue<T, Q>() -> (Sender<T, Q>, Receiver<T, Q>)
+where Q: Queue<Message<T>> {
+    let packet = Arc::new(Packet::new());
+    let sender = Sender::new(Flavor::Stream(packet.clone()));
+    let receiver = Receiver::new(Flavor::Stream(packet));
+    (sender, receiver)
+}
+
+impl<T, Q> Sender<T, Q>
+where Q: Queue<Message<T>> {
+    fn new(inner: Flavor<T, Q>) -> Self {
+        Sender { inner: UnsafeCell::new(inner), _not_sync: PhantomData }
+    }
+
+    fn inner(&self) -> &Flavor<T, Q> {
+        unsafe { &*self.inner.get() }
+    }
+
+    // Only called from `clone`, and as `Sender` is not `Sync` no one else can
+    // be looking at `inner` at the same time.
+    unsafe fn inner_mut(&self) -> &mut Flavor<T, Q> {
+        &mut *self.inner.get()
+    }
+
+    /// Sends a value, handing it back if the receiver is gone.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        match *self.inner() {
+            Flavor::Stream(ref p) => p.send(t),
+            Flavor::Shared(ref p) => p.send(t),
+        }.map_err(SendError)
+    }
+
+    /// Sends a value without blocking, reporting why it was handed back if
+    /// it could not be sent.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        match *self.inner() {
+            Flavor::Stream(ref p) => p.try_send(t),
+            Flavor::Shared(ref p) => p.send(t).map_err(TrySendError::Disconnected),
+        }
+    }
+
+    /// The number of messages sent but not yet received. The receiver may be
+    /// taking them as we look, so this is only an estimate.
+    pub fn len(&self) -> usize {
+        self.inner().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, Q> Clone for Sender<T, Q>
+where Q: Queue<Message<T>> {
+    fn clone(&self) -> Self {
+        let up = match *self.inner() {
+            Flavor::Stream(ref p) => {
+                // Hand the receiver the shared packet. The GoUp is queued
+                // behind everything we already sent, so the receiver only
+                // switches once it has received all of it.
+                let up = Arc::new(SharedPacket::new());
+                match p.upgrade(up.clone()) {
+                    UpSuccess => {},
+                    UpDisconnected => up.drop_port(),
+                    UpWoke(token) => { token.signal(); }
+                }
+                up
+            }
+            Flavor::Shared(ref p) => {
+                p.clone_chan();
+                return Sender::new(Flavor::Shared(p.clone()))
+            }
+        };
+        // The packet starts with one sender, which we keep; this is the other.
+        up.clone_chan();
+        let clone = Sender::new(Flavor::Shared(up.clone()));
+        let old = ::std::mem::replace(unsafe { self.inner_mut() }, Flavor::Shared(up));
+        // We are done with the stream packet, it is disconnected once the
+        // receiver has seen the GoUp.
+        if let Flavor::Stream(ref p) = old {
+            p.drop_chan();
+        }
+        clone
+    }
+}
+
+impl<T, Q> Drop for Sender<T, Q>
+where Q: Queue<Message<T>> {
+    fn drop(&mut self) {
+        match *self.inner() {
+            Flavor::Stream(ref p) => p.drop_chan(),
+            Flavor::Shared(ref p) => p.drop_chan(),
+        }
+    }
+}
+
+impl<T, Q> fmt::Debug for Sender<T, Q>
+where Q: Queue<Message<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Sender { .. }")
+    }
+}
+
+impl<T, Q> Receiver<T, Q>
+where Q: Queue<Message<T>> {
+    fn new(inner: Flavor<T, Q>) -> Self {
+        Receiver {
+            inner: UnsafeCell::new(inner),
+            #[cfg(feature = "eventfd")]
+            readiness: UnsafeCell::new(None),
+            _not_sync: PhantomData,
+        }
+    }
+
+    fn inner(&self) -> &Flavor<T, Q> {
+        unsafe { &*self.inner.get() }
+    }
+
+    // Receiver is not `Sync`, so this is only ever called by the one thread
+    // receiving, and never while a reference from `inner` is live.
+    unsafe fn inner_mut(&self) -> &mut Flavor<T, Q> {
+        &mut *self.inner.get()
+    }
+
+    /// Takes the channel's queue back once the sender is gone, with the
+    /// messages not yet received still in it, as `Message::Data`s. Unlike
+    /// dropping the receiver this doesn't drain them, so the queue can be
+    /// reused or the backlog handed on as it is. The receiver comes back if
+    /// the sender is still around, or hasn't quite finished dropping, or if
+    /// it was ever cloned, which upgrades the channel off this queue.
+    pub fn into_inner(self) -> Result<Q, Self> {
+        // Not dropped, as that would drop the port, draining the queue.
+        let rx = ManuallyDrop::new(self);
+        // puts back the flavor read out of a receiver we can't take apart
+        let restore = |rx: ManuallyDrop<Self>, inner: Flavor<T, Q>| -> Self {
+            unsafe { ptr::write(rx.inner.get(), inner) };
+            ManuallyDrop::into_inner(rx)
+        };
+        let p = match unsafe { ptr::read(rx.inner.get()) } {
+            Flavor::Stream(p) => p,
+            shared => return Err(restore(rx, shared)),
+        };
+        match Arc::try_unwrap(p) {
+            Ok(p) => match p.into_queue() {
+                Ok(queue) => {
+                    // A waker readiness mode left parked went with the
+                    // packet's token.
+                    #[cfg(feature = "eventfd")]
+                    unsafe { ptr::drop_in_place(rx.readiness.get()) };
+                    Ok(queue)
+                }
+                Err(p) => Err(restore(rx, Flavor::Stream(Arc::new(p)))),
+            },
+            Err(p) => Err(restore(rx, Flavor::Stream(p))),
+        }
+    }
+
+    // Switch to the port the sender upgraded to, dropping our end of the old
+    // packet.
+    fn upgrade(&self, up: Arc<SharedPacket<T>>) {
+        let old = ::std::mem::replace(unsafe { self.inner_mut() }, Flavor::Shared(up));
+        if let Flavor::Stream(ref p) = old {
+            p.drop_port();
+        }
+    }
+
+    /// Blocks until a value is available or the sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        match self.recv_inner(None) {
+            Ok(t) => Ok(t),
+            Err(Disconnected) => Err(RecvError),
+            // without a deadline we only return once there's data or the
+            // sender is gone
+            Err(Empty) | Err(Timeout) | Err(Upgraded(..)) => unreachable!(),
+        }
+    }
+
+    /// Blocks until a value is available, then takes it along with any others
+    /// already waiting, up to `max` in all, appending them to `out`. Returns
+    /// how many were taken, which is 0 only if `max` is.
+    pub fn recv_many(&self, out: &mut Vec<T>, max: usize) -> Result<usize, RecvError> {
+        loop {
+            let up = match *self.inner() {
+                Flavor::Stream(ref p) => match p.recv_many(out, max, None) {
+                    Ok(n) => return Ok(n),
+                    Err(Upgraded(up)) => up,
+                    Err(Disconnected) => return Err(RecvError),
+                    Err(Empty) | Err(Timeout) => unreachable!(),
+                },
+                Flavor::Shared(ref p) => {
+                    if max == 0 { return Ok(0) }
+                    match p.recv(None) {
+                        Ok(t) => out.push(t),
+                        Err(shared::Disconnected) => return Err(RecvError),
+                        Err(..) => unreachable!(),
+                    }
+                    let mut n = 1;
+                    while n < max {
+                        match p.try_recv() {
+                            Ok(t) => out.push(t),
+                            Err(..) => break,
+                        }
+                        n += 1;
+                    }
+                    return Ok(n)
+                }
+            };
+            self.upgrade(up);
+        }
+    }
+
+    /// Blocks until a value is available, then returns the result of running
+    /// `f` on it in place, see `Packet::recv_with`. The shared packet has no
+    /// way to lend out its head, so after an upgrade the value is received
+    /// as by `recv` and `f` runs on that.
+    pub fn recv_with<R, F>(&self, f: F) -> Result<R, RecvError>
+    where F: FnOnce(&mut T) -> R {
+        let mut f = Some(f);
+        loop {
+            let up = match *self.inner() {
+                Flavor::Stream(ref p) => {
+                    match p.recv_with(None, |t| (f.take().unwrap())(t)) {
+                        Ok(r) => return Ok(r),
+                        Err(Upgraded(up)) => up,
+                        Err(Disconnected) => return Err(RecvError),
+                        Err(Empty) | Err(Timeout) => unreachable!(),
+                    }
+                }
+                Flavor::Shared(ref p) => return match p.recv(None) {
+                    Ok(mut t) => Ok((f.take().unwrap())(&mut t)),
+                    Err(shared::Disconnected) => Err(RecvError),
+                    Err(..) => unreachable!(),
+                },
+            };
+            self.upgrade(up);
+        }
+    }
+
+    /// Moves every value available without blocking into `out`, following
+    /// an upgrade to the shared packet, and returns how many there were.
+    pub fn drain(&self, out: &mut Vec<T>) -> usize {
+        let mut n = 0;
+        loop {
+            let up = match *self.inner() {
+                Flavor::Stream(ref p) => {
+                    n += p.drain_into(out);
+                    // The drain stops at a GoUp, leaving it for the next
+                    // receive.
+                    match p.try_recv() {
+                        Err(Upgraded(up)) => up,
+                        Ok(t) => { out.push(t); return n + 1 }
+                        Err(..) => return n,
+                    }
+                }
+                Flavor::Shared(ref p) => {
+                    // As in drain_into, only take what's there now.
+                    for _ in 0..p.len() {
+                        match p.try_recv() {
+                            Ok(t) => out.push(t),
+                            Err(..) => break,
+                        }
+                        n += 1;
+                    }
+                    return n
+                }
+            };
+            self.upgrade(up);
+        }
+    }
+
+    /// Returns a value if one is available without blocking.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        #[cfg(feature = "eventfd")]
+        {
+            if let Some(fd) = unsafe { (*self.readiness.get()).clone() } {
+                return self.try_recv_ready(fd)
+            }
+        }
+        match self.try_recv_inner() {
+            Ok(t) => Ok(t),
+            Err(Empty) => Err(TryRecvError::Empty),
+            Err(Disconnected) => Err(TryRecvError::Disconnected),
+            Err(Timeout) | Err(Upgraded(..)) => unreachable!(),
+        }
+    }
+
+    /// Blocks until a value is available, the sender is gone, or `timeout`
+    /// has passed.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_deadline(Instant::now() + timeout)
+    }
+
+    /// Blocks until a value is available, the sender is gone, or `deadline`
+    /// is reached.
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        match self.recv_inner(Some(deadline)) {
+            Ok(t) => Ok(t),
+            Err(Timeout) => Err(RecvTimeoutError::Timeout),
+            Err(Disconnected) => Err(RecvTimeoutError::Disconnected),
+            Err(Empty) | Err(Upgraded(..)) => unreachable!(),
+        }
     }
-}
\ No newline at end of file
+
+    // The packets' receive paths, chasing upgrades until they get an answer.
+    // So the errors these return are never `Upgraded`.
+
+    fn try_recv_inner(&self) -> Result<T, Failure<T>> {
+        loop {
+            let up = match *self.inner() {
+                Flavor::Stream(ref p) => match p.try_recv() {
+                    Err(Upgraded(up)) => up,
+                    data => return data,
+                },
+                Flavor::Shared(ref p) => return p.try_recv().map_err(from_shared),
+            };
+            self.upgrade(up);
+        }
+    }
+
+    fn recv_inner(&self, deadline: Option<Instant>) -> Result<T, Failure<T>> {
+        loop {
+            let up = match *self.inner() {
+                Flavor::Stream(ref p) => match p.recv(deadline) {
+                    Err(Upgraded(up)) => up,
+                    data => return data,
+                },
+                Flavor::Shared(ref p) => return p.recv(deadline).map_err(from_shared),
+            };
+            self.upgrade(up);
+        }
+    }
+
+    /// The number of messages sent but not yet received, exact if the sender
+    /// is not sending. While an upgrade is pending the messages already sent
+    /// through the shared packet are not counted until we switch to it.
+    pub fn len(&self) -> usize {
+        self.inner().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator which blocks waiting for values, and ends once the
+    /// sender is gone.
+    pub fn iter<'a>(&'a self) -> Iter<'a, T, Q> {
+        Iter { rx: self }
+    }
+
+    /// Returns an iterator over the values which are available without
+    /// blocking.
+    pub fn try_iter<'a>(&'a self) -> TryIter<'a, T, Q> {
+        TryIter { rx: self }
+    }
+}
+
+// The iterators go through `Receiver::recv` and `try_recv` rather than the
+// packet, so that they follow the receiver across upgrades.
+
+pub struct Iter<'a, T: 'a, Q: 'a>
+where Q: Queue<Message<T>> {
+    rx: &'a Receiver<T, Q>,
+}
+
+pub struct TryIter<'a, T: 'a, Q: 'a>
+where Q: Queue<Message<T>> {
+    rx: &'a Receiver<T, Q>,
+}
+
+pub struct IntoIter<T, Q>
+where Q: Queue<Message<T>> {
+    rx: Receiver<T, Q>,
+}
+
+impl<'a, T, Q> Iterator for Iter<'a, T, Q>
+where Q: Queue<Message<T>> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a, T, Q> Iterator for TryIter<'a, T, Q>
+where Q: Queue<Message<T>> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl<T, Q> Iterator for IntoIter<T, Q>
+where Q: Queue<Message<T>> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a, T, Q> IntoIterator for &'a Receiver<T, Q>
+where Q: Queue<Message<T>> {
+    type Item = T;
+    type IntoIter = Iter<'a, T, Q>;
+
+    fn into_iter(self) -> Iter<'a, T, Q> {
+        self.iter()
+    }
+}
+
+impl<T, Q> IntoIterator for Receiver<T, Q>
+where Q: Queue<Message<T>> {
+    type Item = T;
+    type IntoIter = IntoIter<T, Q>;
+
+    fn into_iter(self) -> IntoIter<T, Q> {
+        IntoIter { rx: self }
+    }
+}
+
+/// The receiving half of a channel, for async code. Rather than blocking the
+/// thread it registers the task polling it, which a send then wakes. It
+/// follows upgrades as `Receiver` does.
+#[cfg(feature = "async")]
+pub struct AsyncReceiver<T, Q = spsc::CNQueue<Message<T>>>
+where Q: Queue<Message<T>> {
+    rx: Receiver<T, Q>,
+}
+
+/// Creates a channel whose receiver is polled rather than blocked on.
+#[cfg(feature = "async")]
+pub fn async_channel<T>() -> (Sender<T>, AsyncReceiver<T>) {
+    let (tx, rx) = channel();
+    (tx, rx.into_async())
+}
+
+#[cfg(feature = "async")]
+impl<T, Q> Receiver<T, Q>
+where Q: Queue<Message<T>> {
+    /// Turns this into a receiver for async code. A packet has either a
+    /// blocking or an async receiver, so this gives up the blocking one.
+    pub fn into_async(self) -> AsyncReceiver<T, Q> {
+        AsyncReceiver { rx: self }
+    }
+
+    fn poll_recv_inner(&self, cx: &mut Context) -> Poll<Result<T, Failure<T>>> {
+        loop {
+            let up = match *self.inner() {
+                Flavor::Stream(ref p) => match p.poll_recv(cx) {
+                    Poll::Ready(Err(Upgraded(up))) => up,
+                    poll => return poll,
+                },
+                Flavor::Shared(ref p) => {
+                    return p.poll_recv(cx).map(|res| res.map_err(from_shared))
+                }
+            };
+            self.upgrade(up);
+        }
+    }
+}
+
+#[cfg(feature = "eventfd")]
+impl<T, Q> Receiver<T, Q>
+where Q: Queue<Message<T>> {
+    /// An fd which becomes readable when there may be something to receive,
+    /// for registering with an event loop. It's made on the first call, and
+    /// the same one is returned after that.
+    ///
+    /// Readiness is level-ish: once the fd is readable, call `try_recv`
+    /// until it returns `Empty`. That clears the fd, and the `Empty` leaves
+    /// a waker parked which makes it readable again when the next message
+    /// arrives, or the sender goes away. The fd starts out readable, so
+    /// the first drain also parks the first waker. A readiness may turn out
+    /// to have nothing behind it.
+    ///
+    /// Once this has been called the receiver should only receive through
+    /// `try_recv`, since the blocking receives would find the waker parked
+    /// in their place.
+    pub fn readiness_fd(&self) -> RawFd {
+        let readiness = unsafe { &mut *self.readiness.get() };
+        if readiness.is_none() {
+            let fd = EventFd::new().expect("couldn't create an eventfd");
+            fd.notify();
+            *readiness = Some(Arc::new(fd));
+        }
+        readiness.as_ref().unwrap().as_raw_fd()
+    }
+
+    // try_recv in readiness mode, which polls as an async receiver would,
+    // with a waker which notifies the fd.
+    fn try_recv_ready(&self, fd: Arc<EventFd>) -> Result<T, TryRecvError> {
+        // Clear the fd before we look. A message which arrives after this
+        // either makes it into this receive, or wakes the waker we park.
+        fd.reset();
+        let waker = Waker::from(fd);
+        let mut cx = Context::from_waker(&waker);
+        match self.poll_recv_inner(&mut cx) {
+            Poll::Ready(Ok(t)) => Ok(t),
+            Poll::Ready(Err(Disconnected)) => Err(TryRecvError::Disconnected),
+            Poll::Ready(Err(..)) => unreachable!(),
+            Poll::Pending => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, Q> AsyncReceiver<T, Q>
+where Q: Queue<Message<T>> {
+    /// Returns the next value, or `None` once the sender is gone. If there's
+    /// neither the task is woken when one of them happens.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        match self.rx.poll_recv_inner(cx) {
+            Poll::Ready(Ok(t)) => Poll::Ready(Some(t)),
+            Poll::Ready(Err(Disconnected)) => Poll::Ready(None),
+            Poll::Ready(Err(..)) => unreachable!(),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.rx.try_recv()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rx.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rx.is_empty()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, Q> AsyncIterator for AsyncReceiver<T, Q>
+where Q: Queue<Message<T>> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+// Our waker can't be left in the packet, `rx` then drops the port.
+#[cfg(feature = "async")]
+impl<T, Q> Drop for AsyncReceiver<T, Q>
+where Q: Queue<Message<T>> {
+    fn drop(&mut self) {
+        match *self.rx.inner() {
+            Flavor::Stream(ref p) => p.cancel_poll(),
+            Flavor::Shared(ref p) => p.cancel_poll(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, Q> fmt::Debug for AsyncReceiver<T, Q>
+where Q: Queue<Message<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AsyncReceiver { .. }")
+    }
+}
+
+impl<T, Q> fmt::Debug for Receiver<T, Q>
+where Q: Queue<Message<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Receiver { .. }")
+    }
+}
+
+impl<'a, T, Q> fmt::Debug for Iter<'a, T, Q>
+where Q: Queue<Message<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Iter").field("rx", &self.rx).finish()
+    }
+}
+
+impl<'a, T, Q> fmt::Debug for TryIter<'a, T, Q>
+where Q: Queue<Message<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TryIter").field("rx", &self.rx).finish()
+    }
+}
+
+impl<T, Q> fmt::Debug for IntoIter<T, Q>
+where Q: Queue<Message<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IntoIter").field("rx", &self.rx).finish()
+    }
+}
+
+impl<T, Q> Drop for Receiver<T, Q>
+where Q: Queue<Message<T>> {
+    fn drop(&mut self) {
+        // Take back the waker readiness mode leaves parked, as an async
+        // receiver does.
+        #[cfg(feature = "eventfd")]
+        {
+            if unsafe { (*self.readiness.get()).is_some() } {
+                match *self.inner() {
+                    Flavor::Stream(ref p) => p.cancel_poll(),
+                    Flavor::Shared(ref p) => p.cancel_poll(),
+                }
+            }
+        }
+        match *self.inner() {
+            Flavor::Stream(ref p) => p.drop_port(),
+            Flavor::Shared(ref p) => p.drop_port(),
+        }
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::mem;
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{channel, channel_with_queue, Packet, Message, Data, Disconnected, Empty, Timeout};
+    use super::{Flavor, Queue, Receiver, Sender, SendError, TrySendError};
+    use super::{RecvError, TryRecvError, RecvTimeoutError};
+    use super::{ParkSpin, PeekResult, Upgraded, UpSuccess};
+    use blocking::{MockCounts, MockWakeup, Wakeup};
+    use shared::SharedPacket;
+    use spsc;
+    use spsc2;
+    use verify::{stress_count, DropCounter};
+
+    #[test]
+    fn smoke() {
+        let (tx, rx) = channel();
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv().unwrap(), 1);
+        tx.send(2).unwrap();
+        tx.send(3).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), 2);
+        assert_eq!(rx.recv().unwrap(), 3);
+        match rx.try_recv() { Err(TryRecvError::Empty) => {}, _ => panic!() }
+    }
+
+    #[test]
+    fn smoke_other_queue() {
+        let (tx, rx) = channel_with_queue::<_, spsc2::AQueue<Message<_>>>();
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn recv_blocks_until_send() {
+        let (tx, rx) = channel();
+        let t = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(50));
+            tx.send(1).unwrap();
+            tx
+        });
+        assert_eq!(rx.recv().unwrap(), 1);
+        drop(t.join().unwrap());
+    }
+
+    #[test]
+    fn send_after_receiver_drop() {
+        let (tx, rx) = channel();
+        drop(rx);
+        assert_eq!(tx.send(1), Err(SendError(1)));
+    }
+
+    #[test]
+    fn recv_after_sender_drop() {
+        let (tx, rx) = channel::<i32>();
+        tx.send(1).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv().unwrap(), 1);
+        match rx.recv() { Err(RecvError) => {}, _ => panic!() }
+        match rx.try_recv() { Err(TryRecvError::Disconnected) => {}, _ => panic!() }
+    }
+
+    #[test]
+    fn recv_wakes_on_sender_drop() {
+        let (tx, rx) = channel::<i32>();
+        let t = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(50));
+            drop(tx);
+        });
+        match rx.recv() { Err(RecvError) => {}, _ => panic!() }
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn stress() {
+        let (tx, rx) = channel();
+        let t = thread::spawn(move|| {
+            for i in 0..10000 {
+                tx.send(i).unwrap();
+            }
+        });
+        for i in 0..10000 {
+            assert_eq!(rx.recv().unwrap(), i);
+        }
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn iter_ends_on_sender_drop() {
+        let (tx, rx) = channel();
+        let t = thread::spawn(move|| {
+            for i in 0..100 {
+                tx.send(i).unwrap();
+            }
+        });
+        assert_eq!(rx.iter().collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+        t.join().unwrap();
+
+        let (tx, rx) = channel();
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        drop(tx);
+        let mut sum = 0;
+        for i in rx {
+            sum += i;
+        }
+        assert_eq!(sum, 3);
+    }
+
+    #[test]
+    fn try_iter() {
+        let (tx, rx) = channel();
+        assert_eq!(rx.try_iter().next(), None);
+        for i in 0..5 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        assert_eq!(rx.try_iter().next(), None);
+        tx.send(5).unwrap();
+        assert_eq!((&rx).into_iter().next(), Some(5));
+        drop(tx);
+        assert_eq!(rx.try_iter().next(), None);
+    }
+
+    #[test]
+    fn errors_match_std() {
+        // as in std, the value handed back doesn't need to be Debug
+        struct NotDebug;
+        let (tx, rx) = channel();
+        match rx.try_recv() { Err(TryRecvError::Empty) => {}, _ => panic!() }
+        drop(rx);
+        let err = tx.send(NotDebug).unwrap_err();
+        assert_eq!(format!("{:?}", err), "SendError(..)");
+        assert_eq!(err.to_string(), "sending on a closed channel");
+
+        let (tx, rx) = channel::<i32>();
+        drop(tx);
+        assert_eq!(rx.recv(), Err(RecvError));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(rx.recv_timeout(Duration::from_millis(1)),
+            Err(RecvTimeoutError::Disconnected));
+        assert_eq!(RecvError.to_string(), "receiving on a closed channel");
+    }
+
+    #[test]
+    fn len() {
+        let (tx, rx) = channel();
+        assert!(rx.is_empty() && tx.is_empty());
+        for i in 0..3 { tx.send(i).unwrap() }
+        assert_eq!(rx.len(), 3);
+        assert_eq!(tx.len(), 3);
+        rx.recv().unwrap();
+        assert_eq!(rx.len(), 2);
+
+        // the upgrade request is not a message
+        let tx2 = tx.clone();
+        assert_eq!(rx.len(), 2);
+        rx.recv().unwrap();
+        rx.recv().unwrap();
+        assert!(rx.is_empty());
+
+        // after the upgrade the shared packet does the counting
+        tx2.send(3).unwrap();
+        tx.send(4).unwrap();
+        assert_eq!(rx.recv().unwrap(), 3);
+        assert!(rx.is_upgraded());
+        assert_eq!(rx.len(), 1);
+        assert_eq!(tx.len(), 1);
+    }
+
+    #[test]
+    fn len_handed_back_not_counted() {
+        let (tx, rx) = channel();
+        drop(rx);
+        assert!(tx.send(1).is_err());
+        assert!(tx.is_empty());
+    }
+
+    #[test]
+    fn len_concurrent() {
+        const COUNT: usize = stress_count(100_000);
+        let (tx, rx) = channel();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let sender = {
+            let sent = sent.clone();
+            thread::spawn(move|| {
+                for i in 0..COUNT {
+                    tx.send(i).unwrap();
+                    sent.store(i + 1, Ordering::SeqCst);
+                    if i % 64 == 0 { thread::yield_now() }
+                }
+            })
+        };
+        let mut received = 0;
+        while received < COUNT {
+            let len = rx.len();
+            // a send may have pushed but not yet published its count
+            assert!(len <= sent.load(Ordering::SeqCst) + 1 - received,
+                "len {} sent {} received {}", len, sent.load(Ordering::SeqCst), received);
+            if rx.try_recv().is_ok() { received += 1 } else { thread::yield_now() }
+        }
+        sender.join().unwrap();
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let (tx, rx) = channel::<i32>();
+        let start = Instant::now();
+        match rx.recv_timeout(Duration::from_millis(50)) { Err(RecvTimeoutError::Timeout) => {}, _ => panic!() }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+        // the timed out receiver must not leave its token behind
+        assert_eq!(rx.packet().to_wake.load(Ordering::SeqCst), 0);
+
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_millis(50)).unwrap(), 1);
+    }
+
+    #[test]
+    fn recv_timeout_send_before_expiry() {
+        let (tx, rx) = channel();
+        let t = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(40));
+            tx.send(1).unwrap();
+            tx
+        });
+        assert_eq!(rx.recv_timeout(Duration::from_millis(500)).unwrap(), 1);
+        drop(t.join().unwrap());
+    }
+
+    #[test]
+    fn recv_deadline_in_past() {
+        let (tx, rx) = channel::<i32>();
+        let deadline = Instant::now();
+        thread::sleep(Duration::from_millis(1));
+        let start = Instant::now();
+        match rx.recv_deadline(deadline) { Err(RecvTimeoutError::Timeout) => {}, _ => panic!() }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(rx.packet().to_wake.load(Ordering::SeqCst), 0);
+
+        // data is still returned if there is some
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv_deadline(deadline).unwrap(), 1);
+    }
+
+    #[test]
+    fn recv_many() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let mut out = vec![];
+        for i in 0..10 {
+            p.send(i).unwrap();
+        }
+        assert_eq!(p.recv_many(&mut out, 4, None).ok(), Some(4));
+        assert_eq!(out, vec![0, 1, 2, 3]);
+        assert_eq!(p.len(), 6);
+        assert_eq!(p.recv_many(&mut out, 100, None).ok(), Some(6));
+        assert_eq!(out, (0..10).collect::<Vec<_>>());
+        assert_eq!(p.len(), 0);
+        assert_eq!(p.recv_many(&mut out, 0, None).ok(), Some(0));
+        match p.recv_many(&mut out, 4, Some(Instant::now())) {
+            Err(Timeout) => {}
+            _ => panic!(),
+        }
+        p.drop_chan();
+        match p.recv_many(&mut out, 4, None) {
+            Err(Disconnected) => {}
+            _ => panic!(),
+        }
+        p.drop_port();
+    }
+
+    #[test]
+    fn recv_many_stops_at_upgrade() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let up = Arc::new(SharedPacket::new());
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        match p.upgrade(up.clone()) {
+            UpSuccess => {}
+            _ => panic!(),
+        }
+        let mut out = vec![];
+        assert_eq!(p.recv_many(&mut out, 10, None).ok(), Some(2));
+        assert_eq!(out, vec![1, 2]);
+        match p.recv_many(&mut out, 10, None) {
+            Err(Upgraded(..)) => {}
+            _ => panic!(),
+        }
+        p.drop_chan();
+        p.drop_port();
+        up.drop_chan();
+        up.drop_port();
+    }
+
+    #[test]
+    fn receiver_recv_many_follows_upgrade() {
+        let (tx, rx) = channel();
+        tx.send(1).unwrap();
+        let tx2 = tx.clone();
+        tx2.send(2).unwrap();
+        tx.send(3).unwrap();
+        let mut out = vec![];
+        assert_eq!(rx.recv_many(&mut out, 10), Ok(1));
+        assert_eq!(rx.recv_many(&mut out, 10), Ok(2));
+        assert_eq!(out, vec![1, 2, 3]);
+        let t = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(10));
+            tx2.send(4).unwrap();
+        });
+        assert_eq!(rx.recv_many(&mut out, 10), Ok(1));
+        t.join().unwrap();
+        drop(tx);
+        assert_eq!(rx.recv_many(&mut out, 10), Err(RecvError));
+        assert_eq!(out, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn recv_with() {
+        let p = Packet::<spsc::CNQueue<_>, Vec<i32>>::new();
+        p.send(vec![1, 2, 3]).unwrap();
+        p.send(vec![4]).unwrap();
+        assert_eq!(p.recv_with(None, |v| v.len()).ok(), Some(3));
+        assert_eq!(p.len(), 1);
+        // the closure gets the value itself, and may take it
+        assert_eq!(p.recv_with(None, |v| mem::replace(v, vec![])).ok(), Some(vec![4]));
+        assert_eq!(p.len(), 0);
+        match p.recv_with(Some(Instant::now()), |_| panic!()) {
+            Err(Timeout) => {}
+            _ => panic!(),
+        }
+        p.send(vec![5]).unwrap();
+        p.drop_chan();
+        assert_eq!(p.recv_with(None, |v| v[0]).ok(), Some(5));
+        match p.recv_with(None, |_| panic!()) {
+            Err(Disconnected) => {}
+            _ => panic!(),
+        }
+        p.drop_port();
+    }
+
+    #[test]
+    fn recv_with_upgrade() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let up = Arc::new(SharedPacket::new());
+        p.send(1).unwrap();
+        match p.upgrade(up.clone()) {
+            UpSuccess => {}
+            _ => panic!(),
+        }
+        assert_eq!(p.recv_with(None, |t| *t).ok(), Some(1));
+        match p.recv_with(None, |_| panic!()) {
+            Err(Upgraded(..)) => {}
+            _ => panic!(),
+        }
+        p.drop_chan();
+        p.drop_port();
+        up.drop_chan();
+        up.drop_port();
+    }
+
+    #[test]
+    fn recv_with_blocks() {
+        let p = Arc::new(Packet::<spsc::CNQueue<_>, i32>::new());
+        let p2 = p.clone();
+        let t = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(10));
+            p2.send(1).unwrap();
+        });
+        assert_eq!(p.recv_with(None, |t| *t + 1).ok(), Some(2));
+        t.join().unwrap();
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn receiver_recv_with_follows_upgrade() {
+        let (tx, rx) = channel();
+        tx.send(1).unwrap();
+        let tx2 = tx.clone();
+        tx2.send(2).unwrap();
+        assert_eq!(rx.recv_with(|t| *t * 10), Ok(10));
+        assert_eq!(rx.recv_with(|t| *t * 10), Ok(20));
+        drop(tx);
+        drop(tx2);
+        assert_eq!(rx.recv_with(|t| *t), Err(RecvError));
+    }
+
+    #[test]
+    fn peek() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        assert_eq!(p.peek(), PeekResult::Empty);
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        assert_eq!(p.peek(), PeekResult::Data(&1));
+        // nothing was received
+        assert_eq!(p.peek(), PeekResult::Data(&1));
+        assert_eq!(p.len(), 2);
+        assert_eq!(p.try_recv().ok(), Some(1));
+        assert_eq!(p.peek(), PeekResult::Data(&2));
+        assert_eq!(p.try_recv().ok(), Some(2));
+        assert_eq!(p.peek(), PeekResult::Empty);
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn peek_upgrade_at_head() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let up = Arc::new(SharedPacket::new());
+        match p.upgrade(up.clone()) {
+            UpSuccess => {}
+            _ => panic!(),
+        }
+        p.drop_chan();
+        up.send(1).unwrap();
+        assert_eq!(p.peek(), PeekResult::Upgraded);
+        // the GoUp is kept, not lost, even though the queue is now empty and
+        // the sender is gone
+        assert_eq!(p.peek(), PeekResult::Upgraded);
+        assert!(p.can_recv());
+        match p.try_recv() {
+            Err(Upgraded(ref next)) => assert!(Arc::ptr_eq(next, &up)),
+            _ => panic!(),
+        }
+        assert_eq!(up.try_recv(), Ok(1));
+        assert_eq!(p.peek(), PeekResult::Disconnected);
+        p.drop_port();
+        up.drop_chan();
+        up.drop_port();
+    }
+
+    #[test]
+    fn peek_data_before_upgrade() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let up = Arc::new(SharedPacket::new());
+        p.send(1).unwrap();
+        match p.upgrade(up.clone()) {
+            UpSuccess => {}
+            _ => panic!(),
+        }
+        assert_eq!(p.peek(), PeekResult::Data(&1));
+        assert_eq!(p.recv(None).ok(), Some(1));
+        assert_eq!(p.peek(), PeekResult::Upgraded);
+        match p.recv(None) {
+            Err(Upgraded(..)) => {}
+            _ => panic!(),
+        }
+        p.drop_chan();
+        p.drop_port();
+        up.drop_chan();
+        up.drop_port();
+    }
+
+    #[test]
+    fn peek_disconnected() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        p.send(1).unwrap();
+        p.drop_chan();
+        // data sent before the disconnect comes first
+        assert_eq!(p.peek(), PeekResult::Data(&1));
+        assert_eq!(p.try_recv().ok(), Some(1));
+        assert_eq!(p.peek(), PeekResult::Disconnected);
+        match p.try_recv() {
+            Err(Disconnected) => {}
+            _ => panic!(),
+        }
+        p.drop_port();
+    }
+
+    #[test]
+    fn drop_port_after_peeked_upgrade() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let up = Arc::new(SharedPacket::new());
+        match p.upgrade(up.clone()) {
+            UpSuccess => {}
+            _ => panic!(),
+        }
+        assert_eq!(p.peek(), PeekResult::Upgraded);
+        p.drop_port();
+        // no one will receive from the shared packet either
+        assert_eq!(up.send(1), Err(1));
+        p.drop_chan();
+        up.drop_chan();
+    }
+
+    #[test]
+    fn drop_port_waits_for_in_flight_send() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let packet = Arc::new(Packet::<spsc::CNQueue<_>, _>::new());
+        let barrier = Arc::new(Barrier::new(2));
+
+        // the sender gets past its port_dropped check, then stalls before
+        // pushing, just as `send` would if it were pre-empted there
+        assert!(packet.begin_send());
+        let port = {
+            let (packet, barrier) = (packet.clone(), barrier.clone());
+            thread::spawn(move|| {
+                barrier.wait();
+                packet.drop_port();
+            })
+        };
+        barrier.wait();
+        thread::sleep(Duration::from_millis(20));
+        packet.queue.push(Data(DropCounter(drops.clone())));
+        packet.end_send();
+        port.join().unwrap();
+
+        // the port drained the stranded message
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        // and any later send backs out
+        match packet.send(DropCounter(drops.clone())) {
+            Err(d) => drop(d),
+            Ok(()) => panic!(),
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+
+        assert_eq!(packet.to_wake.load(Ordering::SeqCst), 0);
+        packet.drop_chan();
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_park_wake() {
+        use super::Event::*;
+
+        let p = Arc::new(Packet::<spsc::CNQueue<_>, i32>::new());
+        let sender = {
+            let p = p.clone();
+            thread::spawn(move|| {
+                while !p.trace().contains(&Parked) {
+                    thread::yield_now();
+                }
+                p.send(1).unwrap();
+                p.drop_chan();
+            })
+        };
+        assert_eq!(p.recv(None).ok(), Some(1));
+        sender.join().unwrap();
+        p.drop_port();
+        // the sender took the token, so its drop has no one to wake
+        assert_eq!(p.trace(), vec![TokenStored, Parked, TokenTaken, Woke,
+            PortDropped, DrainStarted, DrainFinished(0)]);
+    }
+
+    type MockPacket = Packet<spsc::CNQueue<Message<i32>>, i32, MockWakeup>;
+
+    #[test]
+    fn mock_send_wakes_parked() {
+        let p = MockPacket::new();
+        let (wait, signal) = MockWakeup::tokens();
+        assert!(p.park(signal).is_ok());
+        p.send(1).unwrap();
+        assert!(wait.load(Ordering::SeqCst));
+        assert_eq!(MockWakeup::counts().signals, 1);
+        // the sender has the token, so a second send rings no one
+        p.send(2).unwrap();
+        assert_eq!(MockWakeup::counts().signals, 1);
+        assert_eq!(p.try_recv().ok(), Some(1));
+        assert_eq!(p.try_recv().ok(), Some(2));
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn mock_park_sees_data() {
+        let p = MockPacket::new();
+        p.send(1).unwrap();
+        let (wait, signal) = MockWakeup::tokens();
+        // the receiver gets its token back rather than sleeping on data
+        assert!(p.park(signal).is_err());
+        assert!(!wait.load(Ordering::SeqCst));
+        assert_eq!(p.to_wake.load(Ordering::SeqCst), 0);
+        assert_eq!(p.recv(None).ok(), Some(1));
+        assert_eq!(MockWakeup::counts(), MockCounts::default());
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn mock_busy_receiver_not_signalled() {
+        let p = MockPacket::new();
+        for i in 0..10 {
+            p.send(i).unwrap();
+            assert_eq!(p.recv(None).ok(), Some(i));
+        }
+        assert_eq!(MockWakeup::counts(), MockCounts::default());
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn mock_recv_timeout() {
+        let p = MockPacket::new();
+        match p.recv(Some(Instant::now() + Duration::from_secs(3600))) {
+            Err(Timeout) => {}
+            _ => panic!(),
+        }
+        assert_eq!(MockWakeup::counts(), MockCounts { signals: 0, waits: 1, timeouts: 1 });
+        // the receiver took its token back
+        assert_eq!(p.to_wake.load(Ordering::SeqCst), 0);
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn mock_drop_chan_wakes_parked() {
+        let p = MockPacket::new();
+        let (wait, signal) = MockWakeup::tokens();
+        assert!(p.park(signal).is_ok());
+        p.drop_chan();
+        assert!(wait.load(Ordering::SeqCst));
+        assert_eq!(MockWakeup::counts().signals, 1);
+        match p.recv(None) {
+            Err(Disconnected) => {}
+            _ => panic!(),
+        }
+        p.drop_port();
+    }
+
+    #[test]
+    fn mock_drop_reclaims_token() {
+        let (wait, signal) = MockWakeup::tokens();
+        {
+            let p = MockPacket::new();
+            assert!(p.park(signal).is_ok());
+            assert_eq!(Arc::strong_count(&wait), 2);
+            p.drop_chan();
+            p.drop_port();
+        }
+        assert_eq!(Arc::strong_count(&wait), 1);
+    }
+
+    #[test]
+    fn mock_into_queue_reclaims_token() {
+        let (wait, signal) = MockWakeup::tokens();
+        let p = MockPacket::new();
+        p.send(1).unwrap();
+        assert_eq!(p.recv(None).ok(), Some(1));
+        assert!(p.park(signal).is_ok());
+        // as drop_chan leaves it before it takes the token, so that the
+        // token is left for into_queue to free
+        p.sender_done.store(true, Ordering::SeqCst);
+        let queue = p.into_queue().ok().unwrap();
+        assert_eq!(Arc::strong_count(&wait), 1);
+        assert!(!wait.load(Ordering::SeqCst));
+        assert!(queue.pop().is_none());
+        assert_eq!(MockWakeup::counts(), MockCounts::default());
+    }
+
+    #[test]
+    fn drain_into() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let mut out = vec![];
+        assert_eq!(p.drain_into(&mut out), 0);
+        assert!(out.is_empty());
+        for i in 0..10 {
+            p.send(i).unwrap();
+        }
+        assert_eq!(p.drain_into(&mut out), 10);
+        assert_eq!(out, (0..10).collect::<Vec<_>>());
+        assert_eq!(p.len(), 0);
+        assert_eq!(p.drain_into(&mut out), 0);
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn drain_then_recv() {
+        let p = Arc::new(Packet::<spsc::CNQueue<_>, i32>::new());
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        let mut out = vec![];
+        assert_eq!(p.drain_into(&mut out), 2);
+        let p2 = p.clone();
+        let t = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(10));
+            p2.send(3).unwrap();
+            p2.drop_chan();
+        });
+        assert_eq!(p.recv(None).ok(), Some(3));
+        t.join().unwrap();
+        match p.recv(None) {
+            Err(Disconnected) => {}
+            _ => panic!(),
+        }
+        p.drop_port();
+    }
+
+    #[test]
+    fn drain_stops_at_upgrade() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let up = Arc::new(SharedPacket::new());
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        match p.upgrade(up.clone()) {
+            UpSuccess => {}
+            _ => panic!(),
+        }
+        let mut out = vec![];
+        assert_eq!(p.drain_into(&mut out), 2);
+        assert_eq!(out, vec![1, 2]);
+        // the upgrade is kept for the next receive
+        assert_eq!(p.drain_into(&mut out), 0);
+        match p.try_recv() {
+            Err(Upgraded(..)) => {}
+            _ => panic!(),
+        }
+        p.drop_chan();
+        p.drop_port();
+        up.drop_chan();
+        up.drop_port();
+    }
+
+    #[test]
+    fn drop_port_after_partial_drain() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let p = Packet::<spsc::CNQueue<_>, _>::new();
+        for _ in 0..3 {
+            p.send(DropCounter(drops.clone())).unwrap();
+        }
+        let mut out = vec![];
+        assert_eq!(p.drain_into(&mut out), 3);
+        for _ in 0..4 {
+            p.send(DropCounter(drops.clone())).unwrap();
+        }
+        drop(out);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+        p.drop_port();
+        assert_eq!(drops.load(Ordering::SeqCst), 7);
+        p.drop_chan();
+    }
+
+    #[test]
+    fn into_queue() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let p = Packet::<spsc::CNQueue<_>, _>::new();
+        for _ in 0..3 {
+            p.send(DropCounter(drops.clone())).unwrap();
+        }
+        // not while the sender may still send
+        let p = p.into_queue().err().unwrap();
+        p.drop_chan();
+        let queue = p.into_queue().ok().unwrap();
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        for _ in 0..3 {
+            match queue.pop() {
+                Some(Data(..)) => {}
+                _ => panic!(),
+            }
+        }
+        assert!(queue.pop().is_none());
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn receiver_into_inner() {
+        const N: usize = 100;
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = channel();
+        for _ in 0..N {
+            tx.send(DropCounter(drops.clone())).unwrap();
+        }
+        let rx = rx.into_inner().err().unwrap();
+        drop(tx);
+        let queue = rx.into_inner().unwrap();
+        // the backlog survived, and each value is dropped exactly once,
+        // here, rather than by the receiver
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        let mut popped = vec![];
+        while let Some(msg) = queue.pop() {
+            match msg {
+                Data(t) => popped.push(t),
+                _ => panic!(),
+            }
+        }
+        assert_eq!(popped.len(), N);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        drop(popped);
+        assert_eq!(drops.load(Ordering::SeqCst), N);
+        // and the queue can be reused
+        queue.push(Data(DropCounter(drops.clone())));
+        assert!(queue.pop().is_some());
+        drop(queue);
+        assert_eq!(drops.load(Ordering::SeqCst), N + 1);
+    }
+
+    #[test]
+    fn receiver_into_inner_upgraded() {
+        let (tx, rx) = channel();
+        tx.send(1).unwrap();
+        let tx2 = tx.clone();
+        drop(tx);
+        // the GoUp is still queued behind 1
+        let rx = rx.into_inner().err().unwrap();
+        assert_eq!(rx.recv(), Ok(1));
+        tx2.send(2).unwrap();
+        assert_eq!(rx.recv(), Ok(2));
+        // and now the receiver is on the shared packet
+        drop(tx2);
+        let rx = rx.into_inner().err().unwrap();
+        assert_eq!(rx.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn receiver_drain_follows_upgrade() {
+        let (tx, rx) = channel();
+        let mut out = vec![];
+        assert_eq!(rx.drain(&mut out), 0);
+        tx.send(1).unwrap();
+        let tx2 = tx.clone();
+        tx2.send(2).unwrap();
+        tx.send(3).unwrap();
+        assert_eq!(rx.drain(&mut out), 3);
+        assert_eq!(out, vec![1, 2, 3]);
+        drop(tx);
+        drop(tx2);
+        assert_eq!(rx.drain(&mut out), 0);
+        assert_eq!(rx.recv(), Err(RecvError));
+    }
+
+    // Records the drop of the value with id `.0` in `.1`.
+    struct Tracked(usize, Arc<Vec<AtomicUsize>>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.1[self.0].fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Races `send` against the receiver dropping, and checks that every value
+    // either came back from `send` or reached the channel.
+    fn race_port_drop<F>(send: F)
+    where F: Fn(&Sender<Tracked>, Tracked) -> Result<(), Tracked> + Send + Copy + 'static {
+        const SENDS: usize = 1000;
+        for round in 0..100 {
+            let drops = Arc::new((0..SENDS).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+            let (tx, rx) = channel();
+            let sender = {
+                let drops = drops.clone();
+                thread::spawn(move|| {
+                    let mut returned = vec![];
+                    for id in 0..SENDS {
+                        match send(&tx, Tracked(id, drops.clone())) {
+                            Ok(()) => {},
+                            Err(t) => {
+                                // we get back exactly what we sent
+                                assert_eq!(t.0, id);
+                                returned.push(id);
+                            }
+                        }
+                    }
+                    returned
+                })
+            };
+
+            // vary how far the sender gets before the port goes away
+            let mut received = vec![];
+            for _ in 0..(round * 7 % 50) {
+                match rx.recv() {
+                    Ok(t) => received.push(t.0),
+                    Err(..) => break,
+                }
+            }
+            drop(rx);
+            let returned = sender.join().unwrap();
+
+            // once the port is gone it stays gone
+            for w in returned.windows(2) {
+                assert_eq!(w[0] + 1, w[1]);
+            }
+            assert!(returned.iter().all(|id| !received.contains(id)));
+            // and every value was dropped exactly once, whether it was
+            // received, handed back, or still queued when the port went
+            assert!(drops.iter().all(|d| d.load(Ordering::SeqCst) == 1));
+        }
+    }
+
+    // A sender in a tight loop can't keep the drain going: once the port is
+    // dropped every later send backs out without pushing.
+    #[test]
+    fn drop_port_bounded_against_tight_sender() {
+        const SENDS: usize = 10_000_000;
+        let drops = Arc::new((0..SENDS).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+        let packet = Arc::new(Packet::<spsc::CNQueue<_>, _>::new());
+        let sender = {
+            let (packet, drops) = (packet.clone(), drops.clone());
+            thread::spawn(move|| {
+                let mut returned = 0;
+                for id in 0..SENDS {
+                    if let Err(t) = packet.send(Tracked(id, drops.clone())) {
+                        drop(t);
+                        returned += 1;
+                    }
+                }
+                returned
+            })
+        };
+        for _ in 0..10_000 {
+            match packet.recv(None) {
+                Ok(t) => drop(t),
+                Err(..) => panic!(),
+            }
+        }
+        let start = Instant::now();
+        packet.drop_port();
+        let elapsed = start.elapsed();
+        let returned = sender.join().unwrap();
+        packet.drop_chan();
+
+        assert!(elapsed < Duration::from_secs(5), "drop_port took {:?}", elapsed);
+        // The port went mid-stream.
+        assert!(returned > 0);
+        assert!(drops.iter().all(|d| d.load(Ordering::SeqCst) == 1));
+    }
+
+    #[test]
+    fn send_races_port_drop() {
+        race_port_drop(|tx, t| tx.send(t).map_err(|e| e.0))
+    }
+
+    #[test]
+    fn try_send_races_port_drop() {
+        race_port_drop(|tx, t| match tx.try_send(t) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(t)) => Err(t),
+            Err(TrySendError::Full(..)) => panic!("unbounded channels are never full"),
+        })
+    }
+
+    #[test]
+    fn try_send() {
+        let (tx, rx) = channel();
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(rx.recv().unwrap(), 1);
+        drop(rx);
+        assert_eq!(tx.try_send(2), Err(TrySendError::Disconnected(2)));
+
+        // an upgraded sender reports the same
+        let (tx, rx) = channel();
+        let tx2 = tx.clone();
+        assert_eq!(tx2.try_send(1), Ok(()));
+        assert_eq!(rx.recv().unwrap(), 1);
+        drop(rx);
+        assert_eq!(tx.try_send(2), Err(TrySendError::Disconnected(2)));
+    }
+
+    impl<T, Q> Receiver<T, Q>
+    where Q: Queue<Message<T>> {
+        fn packet(&self) -> &Packet<Q, T> {
+            match *self.inner() {
+                Flavor::Stream(ref p) => p,
+                Flavor::Shared(..) => panic!("receiver was upgraded"),
+            }
+        }
+
+        fn is_upgraded(&self) -> bool {
+            match *self.inner() {
+                Flavor::Stream(..) => false,
+                Flavor::Shared(..) => true,
+            }
+        }
+    }
+
+    #[test]
+    fn clone_and_send_from_both() {
+        let (tx, rx) = channel();
+        tx.send(0).unwrap();
+        let tx2 = tx.clone();
+        tx.send(1).unwrap();
+        tx2.send(2).unwrap();
+        assert!(!rx.is_upgraded());
+        // messages sent before the clone come first
+        assert_eq!(rx.recv().unwrap(), 0);
+        let mut rest = vec![rx.recv().unwrap(), rx.recv().unwrap()];
+        rest.sort();
+        assert_eq!(rest, [1, 2]);
+        assert!(rx.is_upgraded());
+
+        let tx3 = tx2.clone();
+        drop(tx);
+        drop(tx2);
+        tx3.send(3).unwrap();
+        drop(tx3);
+        assert_eq!(rx.recv().unwrap(), 3);
+        match rx.recv() { Err(RecvError) => {}, _ => panic!() }
+        match rx.try_recv() { Err(TryRecvError::Disconnected) => {}, _ => panic!() }
+    }
+
+    #[test]
+    fn clone_stress() {
+        const SENDERS: u64 = 4;
+        const COUNT: u64 = 10000;
+        let (tx, rx) = channel();
+        let senders: Vec<_> = (0..SENDERS).map(|s| {
+            let tx = tx.clone();
+            thread::spawn(move|| {
+                for i in 0..COUNT {
+                    tx.send(s * COUNT + i).unwrap();
+                }
+            })
+        }).collect();
+        drop(tx);
+        let mut seen: Vec<_> = rx.iter().collect();
+        for s in senders {
+            s.join().unwrap();
+        }
+        seen.sort();
+        assert_eq!(seen, (0..SENDERS * COUNT).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn clone_while_receiver_parked() {
+        let (tx, rx) = channel();
+        let receiver = thread::spawn(move|| {
+            let first = rx.recv().unwrap();
+            let second = rx.recv().unwrap();
+            match rx.recv() { Err(RecvError) => {}, _ => panic!() }
+            (first, second)
+        });
+        thread::sleep(Duration::from_millis(20));
+        let tx2 = tx.clone();
+        tx2.send(1).unwrap();
+        drop(tx2);
+        tx.send(2).unwrap();
+        drop(tx);
+        assert_eq!(receiver.join().unwrap(), (1, 2));
+    }
+
+    #[test]
+    fn clone_while_receiver_waits_with_timeout() {
+        let (tx, rx) = channel();
+        let receiver = thread::spawn(move|| {
+            rx.recv_timeout(Duration::from_secs(10)).unwrap()
+        });
+        thread::sleep(Duration::from_millis(20));
+        let tx2 = tx.clone();
+        tx2.send(1).unwrap();
+        assert_eq!(receiver.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn clone_after_receiver_drop() {
+        let (tx, rx) = channel();
+        drop(rx);
+        let tx2 = tx.clone();
+        assert_eq!(tx.send(1), Err(SendError(1)));
+        assert_eq!(tx2.send(2), Err(SendError(2)));
+    }
+
+    #[test]
+    fn receiver_drop_before_upgrade() {
+        let (tx, rx) = channel();
+        tx.send(0).unwrap();
+        // the GoUp is still queued when the receiver goes away, so it never
+        // switches, but the shared packet must still see the port as gone
+        let tx2 = tx.clone();
+        drop(rx);
+        assert_eq!(tx.send(1), Err(SendError(1)));
+        assert_eq!(tx2.send(2), Err(SendError(2)));
+    }
+
+    // A tiny xorshift, so that the sleeps differ from run to run of the loop
+    // without pulling in a dependency.
+    fn next_rand(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    #[test]
+    fn no_lost_wakeup() {
+        const ROUNDS: u32 = 20;
+        const COUNT: u32 = 200;
+        for round in 0..ROUNDS {
+            let (tx, rx) = channel();
+            let (done_tx, done_rx) = ::std::sync::mpsc::channel();
+            let sender = thread::spawn(move|| {
+                let mut rand = round * 7919 + 1;
+                for i in 0..COUNT {
+                    if next_rand(&mut rand) % 2 == 0 {
+                        thread::sleep(Duration::new(0, next_rand(&mut rand) % 50_000));
+                    }
+                    tx.send(i).unwrap();
+                }
+            });
+            let receiver = thread::spawn(move|| {
+                let mut rand = round * 104729 + 1;
+                for i in 0..COUNT {
+                    if next_rand(&mut rand) % 2 == 0 {
+                        thread::sleep(Duration::new(0, next_rand(&mut rand) % 50_000));
+                    }
+                    assert_eq!(rx.recv().unwrap(), i);
+                }
+                done_tx.send(()).unwrap();
+            });
+            // a lost wakeup leaves the receiver parked with data queued,
+            // which shows up as a hang, so don't wait for it forever
+            done_rx.recv_timeout(Duration::from_secs(10))
+                .expect("receiver missed a wakeup");
+            sender.join().unwrap();
+            receiver.join().unwrap();
+        }
+    }
+
+    // Waits for the receiver of `packet` to ring the doorbell.
+    fn wait_for_park<Q, T>(packet: &Packet<Q, T>) {
+        while !packet.receiver_parked.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn disconnected_after_final_message() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        p.drop_chan();
+        // the sender is gone, but what it sent comes first
+        assert_eq!(p.peek(), PeekResult::Data(&1));
+        assert_eq!(p.try_recv().ok(), Some(1));
+        assert_eq!(p.recv(None).ok(), Some(2));
+        assert_eq!(p.peek(), PeekResult::Disconnected);
+        match p.try_recv() { Err(Disconnected) => {}, _ => panic!() }
+        p.drop_port();
+    }
+
+    #[test]
+    fn port_dropped_is_not_disconnected() {
+        // The receiver's own flag says nothing about the sender, which may
+        // still send.
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        p.port_dropped.store(true, Ordering::SeqCst);
+        match p.try_recv() { Err(Empty) => {}, _ => panic!() }
+        assert_eq!(p.peek(), PeekResult::Empty);
+        p.drop_chan();
+        match p.try_recv() { Err(Disconnected) => {}, _ => panic!() }
+        p.drop_port();
+    }
+
+    #[test]
+    fn disconnected_only_after_everything_sent() {
+        const COUNT: usize = 10_000;
+        let p = Arc::new(Packet::<spsc::CNQueue<_>, _>::new());
+        let sender = {
+            let p = p.clone();
+            thread::spawn(move|| {
+                for i in 0..COUNT {
+                    p.send(i).unwrap();
+                    if i % 64 == 0 { thread::yield_now() }
+                }
+                p.drop_chan();
+            })
+        };
+        let mut received = 0;
+        loop {
+            match p.try_recv() {
+                Ok(i) => { assert_eq!(i, received); received += 1 }
+                Err(Empty) => thread::yield_now(),
+                Err(Disconnected) => break,
+                Err(..) => panic!(),
+            }
+        }
+        assert_eq!(received, COUNT);
+        sender.join().unwrap();
+        p.drop_port();
+    }
+
+    #[test]
+    fn steal_budget() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::with_steal_budget(4);
+        for i in 0..10 {
+            p.send(i).unwrap();
+        }
+        for i in 0..3 {
+            assert_eq!(p.try_recv().ok(), Some(i));
+        }
+        assert_eq!(p.len(), 10);
+        assert_eq!(p.try_recv().ok(), Some(3));
+        assert_eq!(p.len(), 6);
+        let mut out = vec![];
+        assert_eq!(p.recv_many(&mut out, 3, None).ok(), Some(3));
+        assert_eq!(p.len(), 6);
+        assert_eq!(p.try_recv().ok(), Some(7));
+        assert_eq!(p.len(), 2);
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn steals_published_on_park() {
+        let p = Arc::new(Packet::<spsc::CNQueue<_>, i32>::with_steal_budget(1 << 20));
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        assert_eq!(p.try_recv().ok(), Some(1));
+        assert_eq!(p.try_recv().ok(), Some(2));
+        assert_eq!(p.len(), 2);
+        let receiver = {
+            let p = p.clone();
+            thread::spawn(move|| p.recv(None).ok().unwrap())
+        };
+        wait_for_park(&p);
+        assert_eq!(p.len(), 0);
+        p.send(3).unwrap();
+        assert_eq!(receiver.join().unwrap(), 3);
+        p.drop_chan();
+        p.drop_port();
+    }
+
+    #[test]
+    fn send_skips_doorbell_when_not_parked() {
+        let packet = Packet::<spsc::CNQueue<_>, _>::new();
+        let (_wait, signal) = ::blocking::tokens();
+        // a token is stored but the flag isn't set, so a send leaves it alone
+        packet.to_wake.store(unsafe { signal.cast_to_usize() }, Ordering::SeqCst);
+        packet.send(1).unwrap();
+        assert!(packet.to_wake.load(Ordering::SeqCst) != 0);
+        assert_eq!(packet.try_recv().unwrap(), 1);
+        drop(packet.try_take_to_wake());
+        packet.drop_chan();
+        packet.drop_port();
+    }
+
+    #[test]
+    fn spin_then_park() {
+        for &spin in &[0, 100] {
+            let packet = Arc::new(Packet::<spsc::CNQueue<_>, _>::new());
+            packet.set_spin(spin);
+            let receiver = {
+                let packet = packet.clone();
+                thread::spawn(move|| packet.recv(None).ok().unwrap())
+            };
+            // with nothing to receive, it gives up spinning and parks
+            wait_for_park(&packet);
+            packet.send(spin).unwrap();
+            assert_eq!(receiver.join().unwrap(), spin);
+            packet.drop_chan();
+            packet.drop_port();
+        }
+    }
+
+    #[test]
+    fn park_spin() {
+        for &spin in &[ParkSpin::Fixed(0), ParkSpin::Fixed(100), ParkSpin::Adaptive] {
+            let packet = Arc::new(Packet::<spsc::CNQueue<_>, _>::new());
+            packet.set_park_spin(spin);
+            // a deadline still passes while nothing is sent
+            let start = Instant::now();
+            let deadline = start + Duration::from_millis(10);
+            match packet.recv(Some(deadline)) { Err(Timeout) => {}, _ => panic!() }
+            assert!(Instant::now() >= deadline);
+
+            let sender = {
+                let packet = packet.clone();
+                thread::spawn(move|| {
+                    for i in 0..1000 {
+                        if i % 100 == 0 { thread::sleep(Duration::from_millis(1)) }
+                        packet.send(i).unwrap();
+                    }
+                    packet.drop_chan();
+                })
+            };
+            for i in 0..1000 {
+                assert_eq!(packet.recv(None).ok().unwrap(), i);
+            }
+            match packet.recv(None) { Err(Disconnected) => {}, _ => panic!() }
+            sender.join().unwrap();
+            packet.drop_port();
+        }
+    }
+
+    #[test]
+    fn spin_receives() {
+        let packet = Arc::new(Packet::<spsc::CNQueue<_>, _>::new());
+        packet.set_spin(10_000);
+        let sender = {
+            let packet = packet.clone();
+            thread::spawn(move|| {
+                for i in 0..1000 { packet.send(i).unwrap() }
+                packet.drop_chan();
+            })
+        };
+        for i in 0..1000 {
+            assert_eq!(packet.recv(None).ok().unwrap(), i);
+        }
+        match packet.recv(None) { Err(Disconnected) => {}, _ => panic!() }
+        sender.join().unwrap();
+        packet.drop_port();
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let packet = Arc::new(Packet::<spsc::CNQueue<_>, _>::new());
+        // a late doorbell, then a real one
+        packet.queue.push(Data(0));
+        assert_eq!(packet.try_recv().unwrap(), 0);
+        let receiver = {
+            let packet = packet.clone();
+            thread::spawn(move|| packet.recv(None).ok().unwrap())
+        };
+        wait_for_park(&packet);
+        packet.try_take_to_wake().unwrap().signal();
+        thread::sleep(Duration::from_millis(20));
+        wait_for_park(&packet);
+        thread::sleep(Duration::from_millis(20));
+        packet.send(1).unwrap();
+        assert_eq!(receiver.join().unwrap(), 1);
+        packet.drop_chan();
+
+        let stats = packet.stats();
+        assert_eq!(stats.parks, 2);
+        assert_eq!(stats.spurious_wakeups, 1);
+        assert_eq!(stats.woke_parked, 1);
+        assert_eq!(stats.signals, 1);
+        packet.drop_port();
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn upgrade_stats() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        let up = Arc::new(SharedPacket::new());
+        match p.upgrade(up.clone()) {
+            UpSuccess => {}
+            _ => panic!(),
+        }
+        assert_eq!(p.stats().upgrades_sent, 1);
+        assert_eq!(p.stats().upgrades_received, 0);
+        // peek takes the GoUp off the queue, the receive which follows it
+        // doesn't count it again
+        assert_eq!(p.peek(), PeekResult::Upgraded);
+        assert_eq!(p.stats().upgrades_received, 1);
+        match p.try_recv() {
+            Err(Upgraded(..)) => {}
+            _ => panic!(),
+        }
+        assert_eq!(p.stats().upgrades_received, 1);
+        p.drop_chan();
+        p.drop_port();
+        up.drop_chan();
+        up.drop_port();
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn drain_stats() {
+        let p = Packet::<spsc::CNQueue<_>, i32>::new();
+        for i in 0..5 {
+            p.send(i).unwrap();
+        }
+        assert_eq!(p.try_recv().ok(), Some(0));
+        let up = Arc::new(SharedPacket::new());
+        match p.upgrade(up.clone()) {
+            UpSuccess => {}
+            _ => panic!(),
+        }
+        p.drop_port();
+        // the upgrade isn't a message, and isn't counted
+        assert_eq!(p.stats().drained, 4);
+        assert_eq!(p.stats().disconnected_sends, 0);
+        assert_eq!(p.send(5), Err(5));
+        assert_eq!(p.try_send(6), Err(TrySendError::Disconnected(6)));
+        assert_eq!(p.stats().disconnected_sends, 2);
+        p.drop_chan();
+        up.drop_chan();
+    }
+
+    #[test]
+    fn late_doorbell_parks_again() {
+        let packet = Arc::new(Packet::<spsc::CNQueue<_>, _>::new());
+        // the message a late sender rang the doorbell for has already been
+        // received by the time the receiver parks
+        packet.queue.push(Data(1));
+        assert_eq!(packet.try_recv().unwrap(), 1);
+        let receiver = {
+            let packet = packet.clone();
+            thread::spawn(move|| packet.recv(None).ok().unwrap())
+        };
+        wait_for_park(&packet);
+        // the late sender takes the token, waking the receiver to an empty
+        // queue, and it must park again rather than give up
+        packet.try_take_to_wake().unwrap().signal();
+        thread::sleep(Duration::from_millis(20));
+        wait_for_park(&packet);
+        packet.send(2).unwrap();
+        assert_eq!(receiver.join().unwrap(), 2);
+        packet.drop_chan();
+        packet.drop_port();
+    }
+
+    #[test]
+    fn late_doorbell_after_timeout() {
+        let packet = Arc::new(Packet::<spsc::CNQueue<Message<i32>>, _>::new());
+        let receiver = {
+            let packet = packet.clone();
+            thread::spawn(move|| {
+                match packet.recv(Some(Instant::now() + Duration::from_millis(50))) {
+                    Err(Timeout) => {}
+                    _ => panic!(),
+                }
+            })
+        };
+        wait_for_park(&packet);
+        // a sender holds the token through the deadline, so the receiver
+        // can't take it back and has to look again before timing out
+        let token = packet.try_take_to_wake().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        token.signal();
+        receiver.join().unwrap();
+        assert_eq!(packet.to_wake.load(Ordering::SeqCst), 0);
+        packet.drop_chan();
+        packet.drop_port();
+    }
+
+    #[test]
+    fn short_timeouts_racing_sends() {
+        const COUNT: u32 = 2_000;
+        let (tx, rx) = channel();
+        let sender = thread::spawn(move|| {
+            for i in 0..COUNT {
+                tx.send(i).unwrap();
+                if i % 4 == 0 { thread::yield_now() }
+            }
+        });
+        let mut next = 0;
+        while next < COUNT {
+            match rx.recv_timeout(Duration::new(0, 1_000)) {
+                Ok(i) => { assert_eq!(i, next); next += 1 }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(..) => panic!(),
+            }
+        }
+        sender.join().unwrap();
+        assert_eq!(rx.packet().to_wake.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn drop_unused() {
+        let (tx, rx) = channel::<DropCounter>();
+        drop(tx);
+        drop(rx);
+        let (tx, rx) = channel::<DropCounter>();
+        drop(rx);
+        drop(tx);
+    }
+
+    #[test]
+    fn drop_sender_first() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = channel();
+        for _ in 0..3 {
+            tx.send(DropCounter(drops.clone())).unwrap();
+        }
+        drop(rx.recv().unwrap());
+        drop(tx);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        drop(rx);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn drop_receiver_first() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = channel();
+        for _ in 0..3 {
+            tx.send(DropCounter(drops.clone())).unwrap();
+        }
+        // the receiver drains what was queued
+        drop(rx);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+        drop(tx.send(DropCounter(drops.clone())).unwrap_err());
+        assert_eq!(drops.load(Ordering::SeqCst), 4);
+        drop(tx);
+        assert_eq!(drops.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn drop_with_messages_queued() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let packet = Packet::<spsc::CNQueue<_>, _>::new();
+        for _ in 0..3 {
+            packet.send(DropCounter(drops.clone())).unwrap();
+        }
+        // the port hangs up without draining, so nothing drains the queue but
+        // the packet itself
+        packet.drop_chan();
+        packet.port_dropped.store(true, Ordering::SeqCst);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        drop(packet);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn drop_with_token_stored() {
+        let packet = Packet::<spsc::CNQueue<Message<u8>>, u8>::new();
+        let (_wait, signal) = ::blocking::tokens();
+        packet.to_wake.store(unsafe { signal.cast_to_usize() }, Ordering::SeqCst);
+        packet.drop_chan();
+        // drop_chan took and signalled the token
+        assert_eq!(packet.to_wake.load(Ordering::SeqCst), 0);
+        packet.drop_port();
+
+        let packet = Packet::<spsc::CNQueue<Message<u8>>, u8>::new();
+        let (_wait, signal) = ::blocking::tokens();
+        packet.to_wake.store(unsafe { signal.cast_to_usize() }, Ordering::SeqCst);
+        // both ends hang up without taking it
+        packet.port_dropped.store(true, Ordering::SeqCst);
+        packet.sender_done.store(true, Ordering::SeqCst);
+        // the packet frees the token itself
+        drop(packet);
+    }
+
+    #[test]
+    fn flags_on_their_own_lines() {
+        use std::ptr::addr_of;
+        use cache_padded::line;
+        use layout::offset;
+        let p = Packet::<spsc::CNQueue<Message<u64>>, u64>::new();
+        let padded = [
+            ("port_dropped", line(offset(&p, addr_of!(p.port_dropped)))),
+            ("sender_done", line(offset(&p, addr_of!(p.sender_done)))),
+            ("sending", line(offset(&p, addr_of!(p.sending)))),
+            ("to_wake", line(offset(&p, addr_of!(p.to_wake)))),
+            ("receiver_parked", line(offset(&p, addr_of!(p.receiver_parked)))),
+            ("sent", line(offset(&p, addr_of!(p.sent)))),
+            ("received", line(offset(&p, addr_of!(p.received)))),
+        ];
+        p.drop_chan();
+        p.drop_port();
+        // in particular the receiver's to_wake and the sender's port_dropped
+        assert!(padded[0].1 != padded[3].1);
+        for (i, &(a, la)) in padded.iter().enumerate() {
+            for &(b, lb) in &padded[i + 1..] {
+                assert!(la != lb, "{} and {} share line {}", a, b, la);
+            }
+        }
+    }
+}
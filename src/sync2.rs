@@ -0,0 +1,316 @@
+/// Bounded stream channels
+///
+/// A bounded flavor of stream2, for comparison with `sync_channel`. As with
+/// stream2 there is exactly one sender and one receiver, but at most
+/// `capacity` messages may be outstanding, after which `send` blocks until
+/// the receiver catches up.
+///
+/// Occupancy is tracked with a pair of counters, one written only by the
+/// sender (messages pushed) and one written only by the receiver (messages
+/// popped), so the hot paths never contend on a read-modify-write. Each side
+/// has its own token slot to park in. A side which is about to park stores
+/// its token and then re-reads the other side's counter, while the other side
+/// updates its counter and then looks for a token; as all four are SeqCst at
+/// least one of them sees the other, so no wakeup can be lost.
+///
+/// Rendezvous channels (capacity 0) are not supported.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::marker::PhantomData;
+
+use blocking::{self, SignalToken};
+use cache_padded::CachePadded;
+use stream2::{Queue, TrySendError};
+
+pub struct SyncPacket<Q, T> {
+    queue: Q,
+    capacity: usize,
+    pushed: CachePadded<AtomicUsize>, // only written by the sender
+    popped: CachePadded<AtomicUsize>, // only written by the receiver
+    disconnected: CachePadded<AtomicBool>, // set when either end is dropped
+    sender_to_wake: CachePadded<AtomicUsize>, // SignalToken for a sender blocked on a full channel
+    receiver_to_wake: CachePadded<AtomicUsize>, // SignalToken for a receiver blocked on an empty channel
+    _pd: PhantomData<T>,
+}
+
+unsafe impl<Q, T> Send for SyncPacket<Q, T> where Q: Send + Sync, T: Send {}
+unsafe impl<Q, T> Sync for SyncPacket<Q, T> where Q: Send + Sync, T: Send {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure {
+    Empty,
+    Disconnected,
+}
+
+impl<Q, T> SyncPacket<Q, T>
+where Q: Queue<T> {
+    /// Creates a packet which holds at most `capacity` undelivered messages.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity >= 1, "rendezvous channels are not supported");
+        SyncPacket {
+            queue: Q::new(capacity),
+            capacity: capacity,
+            pushed: CachePadded::new(AtomicUsize::new(0)),
+            popped: CachePadded::new(AtomicUsize::new(0)),
+            disconnected: CachePadded::new(AtomicBool::new(false)),
+            sender_to_wake: CachePadded::new(AtomicUsize::new(0)),
+            receiver_to_wake: CachePadded::new(AtomicUsize::new(0)),
+            _pd: PhantomData,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Only valid on the sender, the only thread which writes pushed.
+    fn is_full(&self) -> bool {
+        let pushed = self.pushed.load(Ordering::Relaxed);
+        pushed.wrapping_sub(self.popped.load(Ordering::SeqCst)) >= self.capacity
+    }
+
+    // Only valid on the receiver, the only thread which writes popped.
+    fn is_empty(&self) -> bool {
+        self.pushed.load(Ordering::SeqCst) == self.popped.load(Ordering::Relaxed)
+    }
+
+    /// Sends a value, blocking while the channel is full. The value is handed
+    /// back if the receiver is gone.
+    pub fn send(&self, t: T) -> Result<(), T> {
+        loop {
+            if self.disconnected.load(Ordering::SeqCst) { return Err(t) }
+            if !self.is_full() { break }
+
+            let (wait_token, signal_token) = blocking::tokens();
+            let ptr = unsafe { signal_token.cast_to_usize() };
+            assert_eq!(self.sender_to_wake.load(Ordering::SeqCst), 0);
+            self.sender_to_wake.store(ptr, Ordering::SeqCst);
+            if self.is_full() && !self.disconnected.load(Ordering::SeqCst) {
+                wait_token.wait();
+            } else if let Some(token) = take(&self.sender_to_wake) {
+                // we got our token back, no one else will signal it
+                drop(token);
+            } else {
+                // the receiver took our token and is about to signal it
+                wait_token.wait();
+            }
+        }
+        self.do_send(t);
+        Ok(())
+    }
+
+    /// Sends a value if there is room for it, without blocking.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        if self.disconnected.load(Ordering::SeqCst) {
+            return Err(TrySendError::Disconnected(t))
+        }
+        if self.is_full() { return Err(TrySendError::Full(t)) }
+        self.do_send(t);
+        Ok(())
+    }
+
+    fn do_send(&self, t: T) {
+        self.queue.push(t);
+        let pushed = self.pushed.load(Ordering::Relaxed);
+        self.pushed.store(pushed.wrapping_add(1), Ordering::SeqCst);
+        if self.receiver_to_wake.load(Ordering::SeqCst) != 0 {
+            if let Some(token) = take(&self.receiver_to_wake) {
+                token.signal();
+            }
+        }
+    }
+
+    /// Blocks until a value is available or the sender is gone.
+    pub fn recv(&self) -> Result<T, Failure> {
+        loop {
+            match self.try_recv() {
+                Err(Failure::Empty) => {},
+                data => return data,
+            }
+
+            let (wait_token, signal_token) = blocking::tokens();
+            let ptr = unsafe { signal_token.cast_to_usize() };
+            assert_eq!(self.receiver_to_wake.load(Ordering::SeqCst), 0);
+            self.receiver_to_wake.store(ptr, Ordering::SeqCst);
+            if self.is_empty() && !self.disconnected.load(Ordering::SeqCst) {
+                wait_token.wait();
+            } else if let Some(token) = take(&self.receiver_to_wake) {
+                drop(token);
+            } else {
+                wait_token.wait();
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, Failure> {
+        if self.is_empty() {
+            if !self.disconnected.load(Ordering::SeqCst) {
+                return Err(Failure::Empty)
+            }
+            // The sender may have sent more before it went away.
+            if self.is_empty() { return Err(Failure::Disconnected) }
+        }
+
+        let t = self.queue.pop().expect("pushed is only published after the push");
+        let popped = self.popped.load(Ordering::Relaxed);
+        self.popped.store(popped.wrapping_add(1), Ordering::SeqCst);
+        if self.sender_to_wake.load(Ordering::SeqCst) != 0 {
+            if let Some(token) = take(&self.sender_to_wake) {
+                token.signal();
+            }
+        }
+        Ok(t)
+    }
+
+    // drops the sender
+    pub fn drop_chan(&self) {
+        self.disconnected.store(true, Ordering::SeqCst);
+        if let Some(token) = take(&self.receiver_to_wake) {
+            token.signal();
+        }
+    }
+
+    // drops the receiver
+    // Unlike stream2 this does not drain the queue, as the sender may be
+    // pushing concurrently. Whatever is left is dropped with the queue.
+    pub fn drop_port(&self) {
+        self.disconnected.store(true, Ordering::SeqCst);
+        if let Some(token) = take(&self.sender_to_wake) {
+            token.signal();
+        }
+    }
+}
+
+// Consumes ownership of a token slot.
+fn take(slot: &AtomicUsize) -> Option<SignalToken> {
+    let ptr = slot.swap(0, Ordering::SeqCst);
+    if ptr == 0 {
+        None
+    } else {
+        Some(unsafe { SignalToken::cast_from_usize(ptr) })
+    }
+}
+
+impl<Q, T> Drop for SyncPacket<Q, T> {
+    fn drop(&mut self) {
+        assert_eq!(self.sender_to_wake.load(Ordering::SeqCst), 0);
+        assert_eq!(self.receiver_to_wake.load(Ordering::SeqCst), 0);
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "emscripten", target_arch = "wasm32"))))]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{SyncPacket, Failure, TrySendError};
+    use spsc;
+    use verify::stress_count;
+
+    type Packet<T> = SyncPacket<spsc::CNQueue<T>, T>;
+
+    #[test]
+    fn smoke() {
+        let p = Packet::new(2);
+        assert_eq!(p.capacity(), 2);
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        assert_eq!(p.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(p.recv(), Ok(1));
+        p.try_send(3).unwrap();
+        assert_eq!(p.recv(), Ok(2));
+        assert_eq!(p.try_recv(), Ok(3));
+        assert_eq!(p.try_recv(), Err(Failure::Empty));
+        p.drop_chan();
+        assert_eq!(p.recv(), Err(Failure::Disconnected));
+    }
+
+    #[test]
+    #[should_panic]
+    fn no_rendezvous() {
+        let _: Packet<u8> = SyncPacket::new(0);
+    }
+
+    #[test]
+    fn drained_after_sender_drop() {
+        let p = Packet::new(4);
+        p.send(1).unwrap();
+        p.send(2).unwrap();
+        p.drop_chan();
+        assert_eq!(p.recv(), Ok(1));
+        assert_eq!(p.try_recv(), Ok(2));
+        assert_eq!(p.try_recv(), Err(Failure::Disconnected));
+    }
+
+    #[test]
+    fn send_after_receiver_drop() {
+        let p = Packet::new(1);
+        p.drop_port();
+        assert_eq!(p.send(1), Err(1));
+        assert_eq!(p.try_send(2), Err(TrySendError::Disconnected(2)));
+    }
+
+    #[test]
+    fn send_blocks_until_recv() {
+        let p = Arc::new(Packet::new(1));
+        p.send(1).unwrap();
+        let p2 = p.clone();
+        let t = thread::spawn(move|| {
+            // the channel is full, so this waits for the recv below
+            p2.send(2).unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(p.try_recv(), Ok(1));
+        t.join().unwrap();
+        assert_eq!(p.try_recv(), Ok(2));
+    }
+
+    #[test]
+    fn blocked_send_wakes_on_receiver_drop() {
+        let p = Arc::new(Packet::new(1));
+        p.send(1).unwrap();
+        let p2 = p.clone();
+        let t = thread::spawn(move|| p2.send(2));
+        thread::sleep(Duration::from_millis(20));
+        p.drop_port();
+        assert_eq!(t.join().unwrap(), Err(2));
+    }
+
+    #[test]
+    fn never_over_capacity() {
+        const CAPACITY: usize = 4;
+        const COUNT: usize = stress_count(100_000);
+        let p = Arc::new(Packet::new(CAPACITY));
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let sender = {
+            let (p, sent) = (p.clone(), sent.clone());
+            thread::spawn(move|| {
+                for i in 0..COUNT {
+                    p.send(i).unwrap();
+                    sent.fetch_add(1, Ordering::SeqCst);
+                }
+                p.drop_chan();
+            })
+        };
+
+        let mut received = 0;
+        loop {
+            match p.recv() {
+                Ok(i) => {
+                    assert_eq!(i, received);
+                    received += 1;
+                    // everything sent but not yet received is in the channel
+                    assert!(sent.load(Ordering::SeqCst) <= received + CAPACITY);
+                    if received % 64 == 0 { thread::yield_now() }
+                }
+                Err(Failure::Disconnected) => break,
+                Err(Failure::Empty) => unreachable!(),
+            }
+        }
+        sender.join().unwrap();
+        assert_eq!(received, COUNT);
+    }
+}
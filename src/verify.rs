@@ -0,0 +1,540 @@
+//! Checks that a packet, blocking layer and all, delivers every message
+//! exactly once and in order.
+//!
+//! Each sender sends an increasing sequence of checksummed messages, stalling
+//! for a random few microseconds before each one. The receiver picks at
+//! random between a blocking `recv`, a `recv` with a deadline only a few
+//! microseconds away, and a short run of `try_recv`s, so that sends race it
+//! parking, timing out and taking its token back, as well as polling. A
+//! protocol bug shows up as a corrupt, repeated or out of order message, or
+//! as a hang, which a watchdog turns into a panic.
+//!
+//! The queues' multi-producer tests and benches check with the pieces below:
+//! each producer pushes `Stamped` values, its id and a sequence number packed
+//! into a `u64`. A single consumer runs them through a `StreamChecker`, which
+//! panics at the first message out of its producer's order, naming the
+//! producer and the sequence number it expected. Several consumers can't see
+//! a total order, so each sums what it got into a `ChecksumAccumulator`, and
+//! the merged sums are checked against a full run's once they're done.
+//!
+//! The tests of what becomes of values still queued when a queue or packet
+//! goes away count their drops with a `DropCounter`, or check their order
+//! with `drops_in_order`.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use shared::{self, SharedPacket};
+use stream2::{self, Message, Packet};
+
+const MAX_STALL_NS: u32 = 50_000;
+const MAX_DEADLINE_NS: u32 = 20_000;
+const MAX_TRY_RECVS: u32 = 100;
+
+/// The iterations for a stress loop of `n`: `n` itself, or under
+/// ThreadSanitizer a hundredth of it, but no fewer than 1000. TSan makes each
+/// atomic op tens of times slower, and it reports a race the first time the
+/// accesses happen unordered, so it doesn't need the long runs which wait
+/// for a race to actually lose a message.
+pub const fn stress_count(n: usize) -> usize {
+    if !cfg!(sanitize = "thread") || n <= 1_000 {
+        n
+    } else if n / 100 < 1_000 {
+        1_000
+    } else {
+        n / 100
+    }
+}
+
+/// The iterations for a stress loop of `n` which hunts for missing orderings:
+/// ten times `stress_count(n)` on weakly ordered targets, where the hardware
+/// can actually reorder what a missing Acquire or Release lets it, and
+/// `stress_count(n)` elsewhere. On x86 every load is an acquire and every
+/// store a release, so the longer runs would find nothing more.
+pub const fn weak_memory_count(n: usize) -> usize {
+    if cfg!(any(target_arch = "aarch64", target_arch = "arm",
+                target_arch = "powerpc64", target_arch = "riscv64")) {
+        stress_count(n) * 10
+    } else {
+        stress_count(n)
+    }
+}
+
+const SEQ_BITS: u32 = 48;
+
+/// A producer's id in the top 16 bits and its sequence number in the other
+/// 48, so that it fits wherever a `u64` payload does.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Stamped(u64);
+
+impl Stamped {
+    pub const MAX_SEQ: u64 = (1 << SEQ_BITS) - 1;
+
+    /// # Panics
+    ///
+    /// If `seq` is over `MAX_SEQ`.
+    pub fn new(producer: u16, seq: u64) -> Self {
+        assert!(seq <= Stamped::MAX_SEQ, "sequence number {} doesn't fit in a stamp", seq);
+        Stamped((producer as u64) << SEQ_BITS | seq)
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Stamped(bits)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn producer(self) -> u16 {
+        (self.0 >> SEQ_BITS) as u16
+    }
+
+    pub fn seq(self) -> u64 {
+        self.0 & Stamped::MAX_SEQ
+    }
+}
+
+impl fmt::Debug for Stamped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stamped({}, {})", self.producer(), self.seq())
+    }
+}
+
+/// Checks one consumer's stream from `producers` producers, each sending
+/// `0, 1, 2...` in order, keeping only the next number due from each.
+#[derive(Debug, Clone)]
+pub struct StreamChecker {
+    next: Vec<u64>,
+    total: u64,
+}
+
+impl StreamChecker {
+    pub fn new(producers: usize) -> Self {
+        StreamChecker { next: vec![0; producers], total: 0 }
+    }
+
+    /// # Panics
+    ///
+    /// Unless `s` is the next message due from its producer, saying whether
+    /// it came from a producer that doesn't exist, was already received, or
+    /// skipped some which were lost.
+    pub fn check(&mut self, s: Stamped) {
+        let (producer, seq) = (s.producer() as usize, s.seq());
+        assert!(producer < self.next.len(), "{:?} from producer {}, but there are only {}",
+            s, producer, self.next.len());
+        let expected = self.next[producer];
+        if seq < expected {
+            panic!("expected {} from producer {}, got {} again", expected, producer, seq)
+        }
+        if seq > expected {
+            panic!("expected {} from producer {}, got {}, losing {}",
+                expected, producer, seq, seq - expected)
+        }
+        self.next[producer] = seq + 1;
+        self.total += 1;
+    }
+
+    /// The next sequence number due from `producer`.
+    pub fn next(&self, producer: u16) -> u64 {
+        self.next[producer as usize]
+    }
+
+    /// The messages checked so far, from every producer.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// # Panics
+    ///
+    /// Unless every producer's `count` messages were checked.
+    pub fn finish(&self, count: u64) {
+        for (producer, &n) in self.next.iter().enumerate() {
+            assert_eq!(n, count, "got {} of {} messages from producer {}", n, count, producer);
+        }
+    }
+}
+
+/// Order-insensitive per producer totals, for several consumers each taking
+/// some of the messages: a count, and a sum of each message's hash, so a
+/// corrupt message, or a duplicate standing in for a lost one, changes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumAccumulator {
+    // (count, sum) for each producer
+    producers: Vec<(u64, u64)>,
+}
+
+impl ChecksumAccumulator {
+    pub fn new(producers: usize) -> Self {
+        ChecksumAccumulator { producers: vec![(0, 0); producers] }
+    }
+
+    /// The totals from receiving `0..count` from each of `producers`.
+    pub fn expected(producers: usize, count: u64) -> Self {
+        let mut sums = ChecksumAccumulator::new(producers);
+        for producer in 0..producers {
+            for seq in 0..count {
+                sums.add(Stamped::new(producer as u16, seq));
+            }
+        }
+        sums
+    }
+
+    /// # Panics
+    ///
+    /// If `s` is from a producer past the ones this was created for.
+    pub fn add(&mut self, s: Stamped) {
+        let producer = s.producer() as usize;
+        assert!(producer < self.producers.len(), "{:?} from producer {}, but there are only {}",
+            s, producer, self.producers.len());
+        let (ref mut count, ref mut sum) = self.producers[producer];
+        *count += 1;
+        *sum = sum.wrapping_add(checksum(producer, s.seq() as usize));
+    }
+
+    /// Adds in another consumer's totals.
+    pub fn merge(&mut self, other: &ChecksumAccumulator) {
+        assert_eq!(self.producers.len(), other.producers.len());
+        for (mine, theirs) in self.producers.iter_mut().zip(&other.producers) {
+            mine.0 += theirs.0;
+            mine.1 = mine.1.wrapping_add(theirs.1);
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Unless every producer's `count` messages were added, exactly once
+    /// each, naming the first producer whose totals are off.
+    pub fn finish(&self, count: u64) {
+        let expected = ChecksumAccumulator::expected(self.producers.len(), count);
+        for (producer, (got, want)) in self.producers.iter().zip(&expected.producers).enumerate() {
+            assert_eq!(got.0, want.0, "got {} of {} messages from producer {}",
+                got.0, want.0, producer);
+            assert_eq!(got.1, want.1, "the {} messages from producer {} don't sum to 0..{}, \
+                some were corrupt or duplicated in place of others", got.0, producer, count);
+        }
+    }
+}
+
+/// Counts how many times it has been dropped.
+#[derive(Debug)]
+pub struct DropCounter(pub Arc<AtomicUsize>);
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Records its sequence number when dropped, for `drops_in_order`.
+pub struct Seq(usize, Rc<RefCell<Vec<usize>>>);
+
+impl Drop for Seq {
+    fn drop(&mut self) {
+        self.1.borrow_mut().push(self.0)
+    }
+}
+
+/// Checks that dropping `q` drops the values left in it in the order they
+/// were pushed. Pops some first, so that the leftovers are spread over
+/// reused nodes as well as new ones.
+pub fn drops_in_order<Q, Push, Pop>(q: Q, push: Push, pop: Pop)
+where Push: Fn(&Q, Seq), Pop: Fn(&Q) -> Option<Seq> {
+    let dropped = Rc::default();
+    for i in 0..8 {
+        push(&q, Seq(i, Rc::clone(&dropped)));
+    }
+    for i in 0..5 {
+        assert_eq!(pop(&q).map(|s| s.0), Some(i));
+    }
+    for i in 8..12 {
+        push(&q, Seq(i, Rc::clone(&dropped)));
+    }
+    dropped.borrow_mut().clear();
+    drop(q);
+    assert_eq!(*dropped.borrow(), (5..12).collect::<Vec<_>>());
+}
+
+/// A message: which sender sent it, its place in that sender's sequence, and
+/// a checksum of the two.
+#[derive(Debug)]
+pub struct Checked {
+    sender: usize,
+    seq: usize,
+    sum: u64,
+}
+
+impl Checked {
+    fn new(sender: usize, seq: usize) -> Self {
+        Checked { sender: sender, seq: seq, sum: checksum(sender, seq) }
+    }
+}
+
+fn checksum(sender: usize, seq: usize) -> u64 {
+    ((sender as u64) << 48 ^ seq as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+pub enum Recv {
+    Data(Checked),
+    /// Nothing before the deadline, or nothing to take without blocking.
+    Empty,
+    Disconnected,
+}
+
+/// The packet operations the check needs, so it can run over each packet.
+pub trait Chan: Send + Sync + 'static {
+    fn send(&self, t: Checked);
+    fn recv(&self, deadline: Option<Instant>) -> Recv;
+    fn try_recv(&self) -> Recv;
+    fn clone_chan(&self);
+    fn drop_chan(&self);
+    fn drop_port(&self);
+}
+
+impl<Q> Chan for Packet<Q, Checked>
+where Q: stream2::Queue<Message<Checked>> + Send + Sync + 'static {
+    fn send(&self, t: Checked) { Packet::send(self, t).unwrap() }
+
+    fn recv(&self, deadline: Option<Instant>) -> Recv {
+        match Packet::recv(self, deadline) {
+            Ok(t) => Recv::Data(t),
+            Err(stream2::Timeout) => Recv::Empty,
+            Err(stream2::Disconnected) => Recv::Disconnected,
+            Err(stream2::Empty) | Err(stream2::Upgraded(..)) => unreachable!(),
+        }
+    }
+
+    fn try_recv(&self) -> Recv {
+        match Packet::try_recv(self) {
+            Ok(t) => Recv::Data(t),
+            Err(stream2::Empty) => Recv::Empty,
+            Err(stream2::Disconnected) => Recv::Disconnected,
+            Err(stream2::Timeout) | Err(stream2::Upgraded(..)) => unreachable!(),
+        }
+    }
+
+    fn clone_chan(&self) { panic!("a stream packet has a single sender") }
+    fn drop_chan(&self) { Packet::drop_chan(self) }
+    fn drop_port(&self) { Packet::drop_port(self) }
+}
+
+impl Chan for SharedPacket<Checked> {
+    fn send(&self, t: Checked) { SharedPacket::send(self, t).unwrap() }
+
+    fn recv(&self, deadline: Option<Instant>) -> Recv {
+        match SharedPacket::recv(self, deadline) {
+            Ok(t) => Recv::Data(t),
+            Err(shared::Timeout) => Recv::Empty,
+            Err(shared::Disconnected) => Recv::Disconnected,
+            Err(shared::Empty) => unreachable!(),
+        }
+    }
+
+    fn try_recv(&self) -> Recv {
+        match SharedPacket::try_recv(self) {
+            Ok(t) => Recv::Data(t),
+            Err(shared::Empty) => Recv::Empty,
+            Err(shared::Disconnected) => Recv::Disconnected,
+            Err(shared::Timeout) => unreachable!(),
+        }
+    }
+
+    fn clone_chan(&self) { SharedPacket::clone_chan(self) }
+    fn drop_chan(&self) { SharedPacket::drop_chan(self) }
+    fn drop_port(&self) { SharedPacket::drop_port(self) }
+}
+
+// A tiny xorshift, so that the stalls differ without pulling in a dependency.
+fn next_rand(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+// Up to `tries` `try_recv`s, yielding in between so that on a single core
+// the senders get to run.
+fn try_recvs<C: Chan>(chan: &C, tries: u32) -> Recv {
+    for _ in 0..tries {
+        match chan.try_recv() {
+            Recv::Empty => thread::yield_now(),
+            r => return r,
+        }
+    }
+    Recv::Empty
+}
+
+/// Sends `count` messages from each of `senders` threads and checks that
+/// the receiver gets each exactly once, in each sender's order, then sees
+/// the disconnect. Panics if it doesn't, or if no message arrives for
+/// `watchdog`. Returns how long it took.
+pub fn verify<C: Chan>(chan: C, senders: usize, count: usize, watchdog: Duration) -> Duration {
+    let chan = Arc::new(chan);
+    for _ in 1..senders {
+        chan.clone_chan();
+    }
+    let received = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+
+    let sender_threads: Vec<_> = (0..senders).map(|s| {
+        let chan = chan.clone();
+        thread::spawn(move|| {
+            let mut rand = 0x2545_f491 ^ (s as u32 + 1);
+            for seq in 0..count {
+                thread::sleep(Duration::new(0, next_rand(&mut rand) % MAX_STALL_NS));
+                chan.send(Checked::new(s, seq));
+            }
+            chan.drop_chan();
+        })
+    }).collect();
+
+    let receiver = {
+        let (chan, received, done) = (chan.clone(), received.clone(), done.clone());
+        thread::spawn(move|| {
+            let mut rand = 0x9e37_79b9;
+            let mut checker = StreamChecker::new(senders);
+            loop {
+                let r = match next_rand(&mut rand) % 3 {
+                    0 => chan.recv(None),
+                    1 => {
+                        let wait = Duration::new(0, next_rand(&mut rand) % MAX_DEADLINE_NS);
+                        chan.recv(Some(Instant::now() + wait))
+                    }
+                    _ => try_recvs(&*chan, next_rand(&mut rand) % MAX_TRY_RECVS),
+                };
+                let m = match r {
+                    Recv::Data(m) => m,
+                    Recv::Empty => continue,
+                    Recv::Disconnected => break,
+                };
+                assert!(m.sender < senders, "message from unknown sender {:?}", m);
+                assert_eq!(m.sum, checksum(m.sender, m.seq), "corrupt message {:?}", m);
+                checker.check(Stamped::new(m.sender as u16, m.seq as u64));
+                received.store(checker.total() as usize, Ordering::SeqCst);
+            }
+            checker.finish(count as u64);
+            match chan.try_recv() {
+                Recv::Disconnected => {}
+                _ => panic!("received after the disconnect"),
+            }
+            chan.drop_port();
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+
+    // The watchdog. Any thread panicking also stops progress, so this
+    // catches those as well, and they're reported by the joins below.
+    let total = senders * count;
+    let mut last = 0;
+    let mut last_progress = Instant::now();
+    while !done.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(10));
+        let now = received.load(Ordering::SeqCst);
+        if now != last {
+            last = now;
+            last_progress = Instant::now();
+        } else if last_progress.elapsed() > watchdog {
+            panic!("no progress for {:?} after {} of {} messages, \
+                the receiver missed a wakeup", watchdog, now, total);
+        }
+    }
+    let elapsed = start.elapsed();
+    for s in sender_threads {
+        s.join().unwrap();
+    }
+    receiver.join().unwrap();
+    assert_eq!(received.load(Ordering::SeqCst), total);
+    elapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChecksumAccumulator, Stamped, StreamChecker};
+
+    #[test]
+    fn stamped() {
+        let s = Stamped::new(7, Stamped::MAX_SEQ);
+        assert_eq!((s.producer(), s.seq()), (7, Stamped::MAX_SEQ));
+        assert_eq!(Stamped::from_bits(s.bits()), s);
+        assert_eq!(format!("{:?}", Stamped::new(3, 12)), "Stamped(3, 12)");
+    }
+
+    #[test]
+    fn stream_in_order() {
+        let mut checker = StreamChecker::new(2);
+        for seq in 0..10 {
+            checker.check(Stamped::new(1, seq));
+            checker.check(Stamped::new(0, seq));
+        }
+        assert_eq!((checker.next(0), checker.total()), (10, 20));
+        checker.finish(10);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected 2 from producer 1, got 1 again")]
+    fn stream_duplicate() {
+        let mut checker = StreamChecker::new(2);
+        for &seq in &[0, 1, 1] {
+            checker.check(Stamped::new(1, seq));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "expected 1 from producer 0, got 3, losing 2")]
+    fn stream_dropped() {
+        let mut checker = StreamChecker::new(1);
+        for &seq in &[0, 3] {
+            checker.check(Stamped::new(0, seq));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "from producer 4, but there are only 2")]
+    fn stream_unknown_producer() {
+        StreamChecker::new(2).check(Stamped::new(4, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "got 9 of 10 messages from producer 1")]
+    fn stream_short() {
+        let mut checker = StreamChecker::new(2);
+        for seq in 0..10 {
+            checker.check(Stamped::new(0, seq));
+            if seq < 9 { checker.check(Stamped::new(1, seq)) }
+        }
+        checker.finish(10);
+    }
+
+    // Split between two consumers in no particular order.
+    fn halves(skip: u64, extra: u64) -> ChecksumAccumulator {
+        let (mut a, mut b) = (ChecksumAccumulator::new(2), ChecksumAccumulator::new(2));
+        for seq in (0..100).rev().filter(|&seq| seq != skip) {
+            let sums = if seq % 3 == 0 { &mut a } else { &mut b };
+            sums.add(Stamped::new(0, seq));
+            sums.add(Stamped::new(1, seq));
+        }
+        b.add(Stamped::new(0, extra));
+        a.merge(&b);
+        a
+    }
+
+    #[test]
+    fn checksum_any_order() {
+        let mut sums = halves(100, 100);
+        sums.add(Stamped::new(1, 100));
+        assert_eq!(sums, ChecksumAccumulator::expected(2, 101));
+        sums.finish(101);
+    }
+
+    #[test]
+    #[should_panic(expected = "the 100 messages from producer 0 don't sum to 0..100")]
+    fn checksum_duplicate_for_lost() {
+        halves(40, 41).finish(100);
+    }
+}
@@ -0,0 +1,168 @@
+//! A single slot holding only the latest value, for consumers which skip
+//! straight to the newest message and would otherwise drain a queue to get
+//! it.
+//!
+//! The value lives in its own allocation and the slot is a pointer to it, so
+//! publishing is a swap which hands the producer whatever it replaced, and
+//! taking is a swap with null. Neither ever waits on the other, and a value
+//! can't be seen half written since it's finished before it is swapped in.
+//! Emptied allocations are kept in a spare slot of one, for the next
+//! publish, so a producer and consumer taking turns don't allocate.
+
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+pub struct Slot<T> {
+    // the latest value, if it hasn't been taken
+    current: AtomicPtr<MaybeUninit<T>>,
+    // an emptied allocation for the next publish
+    spare: AtomicPtr<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Slot<T> { }
+unsafe impl<T: Send> Sync for Slot<T> { }
+
+impl<T> Slot<T> {
+    /// Creates an empty slot.
+    ///
+    /// It is meant for one producer and one consumer, but as every operation
+    /// is a swap any number are safe, if not very meaningful.
+    pub fn new() -> Self {
+        Slot {
+            current: AtomicPtr::new(ptr::null_mut()),
+            spare: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Makes `t` the value, dropping any value which wasn't taken.
+    pub fn publish(&self, t: T) {
+        let mut buf = self.spare.swap(ptr::null_mut(), Ordering::Acquire);
+        if buf.is_null() {
+            buf = Box::into_raw(box MaybeUninit::uninit());
+        }
+        unsafe {
+            (*buf).as_mut_ptr().write(t);
+            let old = self.current.swap(buf, Ordering::AcqRel);
+            if !old.is_null() {
+                ptr::drop_in_place((*old).as_mut_ptr());
+                self.recycle(old);
+            }
+        }
+    }
+
+    /// Removes the value, if there is one, so that the next `take` only
+    /// returns something once there's been another `publish`.
+    pub fn take(&self) -> Option<T> {
+        let buf = self.current.swap(ptr::null_mut(), Ordering::AcqRel);
+        if buf.is_null() {
+            return None
+        }
+        unsafe {
+            let t = (*buf).as_ptr().read();
+            self.recycle(buf);
+            Some(t)
+        }
+    }
+
+    /// Whether there's a value to take.
+    pub fn is_empty(&self) -> bool {
+        self.current.load(Ordering::Acquire).is_null()
+    }
+
+    // Keeps an emptied allocation for the next publish, unless there's one
+    // there already.
+    unsafe fn recycle(&self, buf: *mut MaybeUninit<T>) {
+        let kept = self.spare.compare_exchange(
+            ptr::null_mut(), buf, Ordering::Release, Ordering::Relaxed);
+        if kept.is_err() {
+            let _: Box<MaybeUninit<T>> = Box::from_raw(buf);
+        }
+    }
+}
+
+impl<T: Clone> Slot<T> {
+    /// Returns a copy of the value, leaving it to be taken again.
+    ///
+    /// The value is taken out to clone it, then put back unless a newer one
+    /// was published meanwhile, in which case it's dropped and the newer one
+    /// stays. A `take` on another thread in between would find the slot
+    /// empty.
+    pub fn get_cloned(&self) -> Option<T> {
+        let buf = self.current.swap(ptr::null_mut(), Ordering::AcqRel);
+        if buf.is_null() {
+            return None
+        }
+        unsafe {
+            let t = (*(*buf).as_ptr()).clone();
+            let put_back = self.current.compare_exchange(
+                ptr::null_mut(), buf, Ordering::AcqRel, Ordering::Relaxed);
+            if put_back.is_err() {
+                ptr::drop_in_place((*buf).as_mut_ptr());
+                self.recycle(buf);
+            }
+            Some(t)
+        }
+    }
+}
+
+impl<T> Drop for Slot<T> {
+    fn drop(&mut self) {
+        drop(self.take());
+        let spare = *self.spare.get_mut();
+        if !spare.is_null() {
+            let _: Box<MaybeUninit<T>> = unsafe { Box::from_raw(spare) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use super::Slot;
+
+    #[test]
+    fn smoke() {
+        let slot = Slot::new();
+        assert!(slot.is_empty());
+        assert_eq!(slot.take(), None);
+        slot.publish(1);
+        assert!(!slot.is_empty());
+        assert_eq!(slot.take(), Some(1));
+        assert_eq!(slot.take(), None);
+        slot.publish(2);
+        slot.publish(3);
+        assert_eq!(slot.take(), Some(3));
+        assert_eq!(slot.take(), None);
+    }
+
+    #[test]
+    fn get_cloned() {
+        let slot = Slot::new();
+        assert_eq!(slot.get_cloned(), None);
+        slot.publish(vec![1]);
+        assert_eq!(slot.get_cloned(), Some(vec![1]));
+        assert_eq!(slot.get_cloned(), Some(vec![1]));
+        assert_eq!(slot.take(), Some(vec![1]));
+        assert_eq!(slot.get_cloned(), None);
+    }
+
+    // Overwritten values are dropped by the publish which replaces them, and
+    // the last by the slot.
+    #[test]
+    fn drops() {
+        let value = Rc::new(());
+        {
+            let slot = Slot::new();
+            slot.publish(value.clone());
+            slot.publish(value.clone());
+            assert_eq!(Rc::strong_count(&value), 2);
+            drop(slot.take());
+            assert_eq!(Rc::strong_count(&value), 1);
+            slot.publish(value.clone());
+            slot.publish(value.clone());
+            assert_eq!(Rc::strong_count(&value), 2);
+        }
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,167 @@
+//! Data integrity tests for `byte_ring`, streaming a pseudo-random sequence
+//! of bytes through a small ring in uneven chunks, so that nearly every
+//! write and read wraps around, and comparing checksums at each end.
+
+#![cfg(feature = "queue_experiments")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::byte_ring::{channel, ByteReader, ByteWriter};
+
+use std::io::{Read, Write};
+use std::thread;
+
+const BYTES: usize = 4 << 20;
+const CAPACITY: usize = 61;
+
+// xorshift, so the stream isn't periodic in anything like the ring's size
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // 1 to 2 * CAPACITY
+    fn chunk(&mut self) -> usize {
+        (self.next() % (2 * CAPACITY as u64)) as usize + 1
+    }
+}
+
+// Fletcher-64, which unlike a plain sum notices reordered bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Checksum(u64, u64);
+
+impl Checksum {
+    fn new() -> Self { Checksum(0, 0) }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 + b as u64) % 0xffff_ffff;
+            self.1 = (self.1 + self.0) % 0xffff_ffff;
+        }
+    }
+}
+
+fn produce<F>(seed: u64, mut write: F) -> Checksum
+where F: FnMut(&[u8]) {
+    let mut data = Rng(seed);
+    let mut chunks = Rng(seed ^ 0x5eed);
+    let mut sum = Checksum::new();
+    let mut buf = Vec::new();
+    let mut sent = 0;
+    while sent < BYTES {
+        let len = ::std::cmp::min(chunks.chunk(), BYTES - sent);
+        buf.clear();
+        buf.extend((0..len).map(|_| data.next() as u8));
+        sum.update(&buf);
+        write(&buf);
+        sent += len;
+    }
+    sum
+}
+
+fn io_writer(seed: u64, mut tx: ByteWriter) -> Checksum {
+    produce(seed, |chunk| tx.write_all(chunk).unwrap())
+}
+
+fn io_reader(seed: u64, mut rx: ByteReader) -> (Checksum, usize) {
+    let mut chunks = Rng(seed ^ 0xcafe);
+    let mut sum = Checksum::new();
+    let mut received = 0;
+    let mut buf = vec![0; 2 * CAPACITY];
+    loop {
+        let len = chunks.chunk();
+        match rx.read(&mut buf[..len]).unwrap() {
+            0 => return (sum, received),
+            n => {
+                sum.update(&buf[..n]);
+                received += n;
+            }
+        }
+    }
+}
+
+// Writes in place through the slices, as much of each chunk as fits at a
+// time.
+fn slice_writer(seed: u64, mut tx: ByteWriter) -> Checksum {
+    produce(seed, |mut chunk| {
+        while !chunk.is_empty() {
+            let n = {
+                let (first, second) = tx.write_slices();
+                let a = ::std::cmp::min(first.len(), chunk.len());
+                first[..a].copy_from_slice(&chunk[..a]);
+                let b = ::std::cmp::min(second.len(), chunk.len() - a);
+                second[..b].copy_from_slice(&chunk[a..a + b]);
+                a + b
+            };
+            tx.commit(n);
+            chunk = &chunk[n..];
+            if n == 0 { thread::yield_now() }
+        }
+    })
+}
+
+// Checksums in place through the slices, consuming an uneven amount of what
+// is available each time.
+fn slice_reader(seed: u64, mut rx: ByteReader, total: usize) -> (Checksum, usize) {
+    let mut chunks = Rng(seed ^ 0xcafe);
+    let mut sum = Checksum::new();
+    let mut received = 0;
+    while received < total {
+        let n = {
+            let (first, second) = rx.read_slices();
+            let n = ::std::cmp::min(chunks.chunk(), first.len() + second.len());
+            let a = ::std::cmp::min(first.len(), n);
+            sum.update(&first[..a]);
+            sum.update(&second[..n - a]);
+            n
+        };
+        rx.consume(n);
+        received += n;
+        if n == 0 { thread::yield_now() }
+    }
+    (sum, received)
+}
+
+#[test]
+fn io_stream() {
+    for seed in 1..4 {
+        let (tx, rx) = channel(CAPACITY);
+        let writer = thread::spawn(move|| io_writer(seed, tx));
+        let (got, received) = io_reader(seed, rx);
+        assert_eq!(received, BYTES);
+        assert_eq!(got, writer.join().unwrap());
+    }
+}
+
+#[test]
+fn slice_stream() {
+    for seed in 1..4 {
+        let (tx, rx) = channel(CAPACITY);
+        let writer = thread::spawn(move|| slice_writer(seed, tx));
+        let (got, received) = slice_reader(seed, rx, BYTES);
+        assert_eq!(received, BYTES);
+        assert_eq!(got, writer.join().unwrap());
+    }
+}
+
+// Each side may use either interface.
+#[test]
+fn mixed_stream() {
+    let (tx, rx) = channel(CAPACITY);
+    let writer = thread::spawn(move|| slice_writer(7, tx));
+    let (got, received) = io_reader(7, rx);
+    assert_eq!(received, BYTES);
+    assert_eq!(got, writer.join().unwrap());
+
+    let (tx, rx) = channel(CAPACITY);
+    let writer = thread::spawn(move|| io_writer(8, tx));
+    let (got, received) = slice_reader(8, rx, BYTES);
+    assert_eq!(received, BYTES);
+    assert_eq!(got, writer.join().unwrap());
+}
@@ -0,0 +1,161 @@
+//! Drives the C interface as a C caller would, through the exported symbols
+//! declared as in `include/spsc.h`, with the queues as opaque pointers.
+
+#![cfg(feature = "queue_experiments")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::verify::stress_count;
+
+use std::os::raw::c_int;
+use std::ptr;
+use std::thread;
+
+enum SpscU64 {}
+enum SpscBytes {}
+
+const SPSC_POPPED: c_int = 1;
+const SPSC_EMPTY: c_int = 0;
+const SPSC_TOO_SMALL: c_int = -1;
+const SPSC_ERROR: c_int = -2;
+
+extern "C" {
+    fn spsc_u64_new(bound: usize, aligned: bool) -> *mut SpscU64;
+    fn spsc_u64_push(q: *mut SpscU64, v: u64) -> bool;
+    fn spsc_u64_pop(q: *mut SpscU64, out: *mut u64) -> bool;
+    fn spsc_u64_free(q: *mut SpscU64);
+
+    fn spsc_bytes_new(bound: usize, aligned: bool) -> *mut SpscBytes;
+    fn spsc_bytes_push(q: *mut SpscBytes, data: *const u8, len: usize) -> bool;
+    fn spsc_bytes_pop(q: *mut SpscBytes, buf: *mut u8, cap: usize, len: *mut usize) -> c_int;
+    fn spsc_bytes_free(q: *mut SpscBytes);
+}
+
+// The constants in the header must match the library's.
+#[test]
+fn constants_match() {
+    use std_spsc_is_slow::ffi;
+    assert_eq!(ffi::SPSC_POPPED, SPSC_POPPED);
+    assert_eq!(ffi::SPSC_EMPTY, SPSC_EMPTY);
+    assert_eq!(ffi::SPSC_TOO_SMALL, SPSC_TOO_SMALL);
+    assert_eq!(ffi::SPSC_ERROR, SPSC_ERROR);
+}
+
+#[test]
+fn u64_smoke() {
+    unsafe {
+        for &aligned in &[false, true] {
+            let q = spsc_u64_new(128, aligned);
+            assert!(!q.is_null());
+            let mut out = 0;
+            assert!(!spsc_u64_pop(q, &mut out));
+            assert!(spsc_u64_push(q, 1));
+            assert!(spsc_u64_push(q, 2));
+            assert!(spsc_u64_pop(q, &mut out));
+            assert_eq!(out, 1);
+            assert!(spsc_u64_pop(q, &mut out));
+            assert_eq!(out, 2);
+            assert!(!spsc_u64_pop(q, &mut out));
+            // freed with something left in it
+            assert!(spsc_u64_push(q, 3));
+            spsc_u64_free(q);
+        }
+    }
+}
+
+#[test]
+fn u64_nulls() {
+    unsafe {
+        let mut out = 0;
+        assert!(!spsc_u64_push(ptr::null_mut(), 1));
+        assert!(!spsc_u64_pop(ptr::null_mut(), &mut out));
+        spsc_u64_free(ptr::null_mut());
+
+        let q = spsc_u64_new(0, true);
+        assert!(spsc_u64_push(q, 1));
+        assert!(!spsc_u64_pop(q, ptr::null_mut()));
+        // the value is still there
+        assert!(spsc_u64_pop(q, &mut out));
+        assert_eq!(out, 1);
+        spsc_u64_free(q);
+    }
+}
+
+// So the pointer can go to the producer thread.
+#[derive(Copy, Clone)]
+struct Queue(*mut SpscU64);
+unsafe impl Send for Queue {}
+
+#[test]
+fn u64_threads() {
+    const COUNT: u64 = stress_count(100_000) as u64;
+    let q = Queue(unsafe { spsc_u64_new(128, true) });
+    let producer = thread::spawn(move|| {
+        let q = q;
+        for i in 0..COUNT {
+            assert!(unsafe { spsc_u64_push(q.0, i) });
+        }
+    });
+    let mut out = 0;
+    for i in 0..COUNT {
+        while !unsafe { spsc_u64_pop(q.0, &mut out) } { thread::yield_now() }
+        assert_eq!(out, i);
+    }
+    producer.join().unwrap();
+    unsafe { spsc_u64_free(q.0) };
+}
+
+#[test]
+fn bytes_smoke() {
+    unsafe {
+        for &aligned in &[false, true] {
+            let q = spsc_bytes_new(128, aligned);
+            assert!(!q.is_null());
+            let mut buf = [0u8; 8];
+            let mut len = 99;
+            assert_eq!(spsc_bytes_pop(q, buf.as_mut_ptr(), buf.len(), &mut len), SPSC_EMPTY);
+            assert_eq!(len, 99);
+
+            assert!(spsc_bytes_push(q, b"hello".as_ptr(), 5));
+            assert!(spsc_bytes_push(q, ptr::null(), 0));
+            assert!(spsc_bytes_push(q, b"a longer one".as_ptr(), 12));
+
+            assert_eq!(spsc_bytes_pop(q, buf.as_mut_ptr(), buf.len(), &mut len), SPSC_POPPED);
+            assert_eq!(&buf[..len], b"hello");
+            assert_eq!(spsc_bytes_pop(q, ptr::null_mut(), 0, &mut len), SPSC_POPPED);
+            assert_eq!(len, 0);
+
+            // too long for the buffer, it stays queued
+            assert_eq!(spsc_bytes_pop(q, buf.as_mut_ptr(), buf.len(), &mut len), SPSC_TOO_SMALL);
+            assert_eq!(len, 12);
+            let mut big = vec![0u8; len];
+            assert_eq!(spsc_bytes_pop(q, big.as_mut_ptr(), big.len(), &mut len), SPSC_POPPED);
+            assert_eq!(&big[..len], b"a longer one");
+            assert_eq!(spsc_bytes_pop(q, buf.as_mut_ptr(), buf.len(), &mut len), SPSC_EMPTY);
+
+            assert!(spsc_bytes_push(q, b"left".as_ptr(), 4));
+            spsc_bytes_free(q);
+        }
+    }
+}
+
+#[test]
+fn bytes_nulls() {
+    unsafe {
+        let mut buf = [0u8; 8];
+        let mut len = 0;
+        assert!(!spsc_bytes_push(ptr::null_mut(), b"x".as_ptr(), 1));
+        assert_eq!(spsc_bytes_pop(ptr::null_mut(), buf.as_mut_ptr(), 8, &mut len), SPSC_ERROR);
+        spsc_bytes_free(ptr::null_mut());
+
+        let q = spsc_bytes_new(0, false);
+        assert!(!spsc_bytes_push(q, ptr::null(), 1));
+        assert!(spsc_bytes_push(q, b"x".as_ptr(), 1));
+        assert_eq!(spsc_bytes_pop(q, buf.as_mut_ptr(), 8, ptr::null_mut()), SPSC_ERROR);
+        assert_eq!(spsc_bytes_pop(q, ptr::null_mut(), 8, &mut len), SPSC_ERROR);
+        assert_eq!(spsc_bytes_pop(q, buf.as_mut_ptr(), 8, &mut len), SPSC_POPPED);
+        assert_eq!(&buf[..len], b"x");
+        spsc_bytes_free(q);
+    }
+}
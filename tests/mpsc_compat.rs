@@ -0,0 +1,94 @@
+//! Checks that `mpsc_compat` is a drop-in for `std::sync::mpsc`: the same
+//! code is compiled and run against each of them.
+
+#![cfg(feature = "queue_experiments")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::mpsc_compat;
+
+// Only the `use` line differs between the two instances, as it would in an
+// application switching over.
+macro_rules! suite {
+    ($name:ident, $($path:ident)::+) => {
+        mod $name {
+            use std::thread;
+            use std::time::Duration;
+
+            use $($path)::+::{channel, Sender, Receiver, Iter, TryIter, IntoIter};
+            use $($path)::+::{SendError, RecvError, TryRecvError, RecvTimeoutError};
+
+            fn produce(tx: Sender<u32>, from: u32) {
+                for i in from..from + 100 {
+                    tx.send(i).unwrap();
+                }
+            }
+
+            fn sum(rx: Receiver<u32>) -> u32 {
+                let into_iter: IntoIter<u32> = rx.into_iter();
+                into_iter.sum()
+            }
+
+            #[test]
+            fn send_recv() {
+                let (tx, rx) = channel();
+                tx.send(1).unwrap();
+                assert_eq!(rx.recv(), Ok(1));
+                assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+                assert_eq!(rx.recv_timeout(Duration::from_millis(1)),
+                    Err(RecvTimeoutError::Timeout));
+                drop(tx);
+                assert_eq!(rx.recv(), Err(RecvError));
+                assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+            }
+
+            #[test]
+            fn send_disconnected() {
+                let (tx, rx) = channel::<u32>();
+                drop(rx);
+                assert_eq!(tx.send(1), Err(SendError(1)));
+            }
+
+            #[test]
+            fn many_senders() {
+                let (tx, rx) = channel();
+                let threads: Vec<_> = (0..4).map(|i| {
+                    let tx = tx.clone();
+                    thread::spawn(move|| produce(tx, i * 100))
+                }).collect();
+                drop(tx);
+                assert_eq!(sum(rx), (0..400).sum());
+                for t in threads {
+                    t.join().unwrap();
+                }
+            }
+
+            #[test]
+            fn iters() {
+                let (tx, rx) = channel();
+                for i in 0..3 {
+                    tx.send(i).unwrap();
+                }
+                {
+                    let try_iter: TryIter<u32> = rx.try_iter();
+                    assert_eq!(try_iter.collect::<Vec<_>>(), vec![0, 1, 2]);
+                }
+                tx.send(3).unwrap();
+                drop(tx);
+                let iter: Iter<u32> = rx.iter();
+                assert_eq!(iter.collect::<Vec<_>>(), vec![3]);
+            }
+
+            #[test]
+            fn debug() {
+                let (tx, rx) = channel::<u32>();
+                assert_eq!(format!("{:?}", tx), "Sender { .. }");
+                assert_eq!(format!("{:?}", rx), "Receiver { .. }");
+            }
+        }
+    }
+}
+
+suite!(std_mpsc, std::sync::mpsc);
+suite!(compat, mpsc_compat);
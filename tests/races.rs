@@ -0,0 +1,140 @@
+//! The races these queues are built around, one small test each, for
+//! ThreadSanitizer to check the orderings of:
+//!
+//!     RUSTFLAGS="-Zsanitizer=thread" cargo +nightly test -Zbuild-std \
+//!         --target x86_64-unknown-linux-gnu --features queue_experiments --test races
+//!
+//! Every value is boxed, so that reading it touches memory the other thread
+//! wrote without atomics, and TSan reports the read unless an Acquire on this
+//! side pairs with a Release on that side between the two. Each test names
+//! the pair it depends on; weakening either to Relaxed is what it catches.
+//! Without TSan they're short stress tests, and check the values arrive
+//! whole and in order.
+
+#![cfg(feature = "queue_experiments")]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::{mpmc, spsc, stream2};
+use std_spsc_is_slow::verify::stress_count;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+const COUNT: usize = stress_count(20_000);
+
+// spsc recycles the node the consumer last moved past: the producer
+// overwrites its value and stores a null `next` into it, then links it in
+// with a Release store to the previous node's `next`, which pairs with the
+// Acquire load of `next` in `pop`. The reuse is ordered after the consumer's
+// last read of the node by its Release store of `tail_prev`, paired with the
+// Acquire refresh of `tail_copy` in `alloc`.
+//
+// The producer keeps at most a couple of values ahead, so that the nodes
+// are recycled rather than all allocated up front. It waits on a Relaxed
+// count, which orders nothing, so the queue's orderings are all TSan sees.
+#[test]
+fn spsc_recycled_node() {
+    for &bound in &[0, 1, 2] {
+        let q = Arc::new(unsafe { spsc::CNQueue::<Box<usize>>::aligned(bound) });
+        let popped = Arc::new(AtomicUsize::new(0));
+        let consumer = {
+            let (q, popped) = (q.clone(), popped.clone());
+            thread::spawn(move|| {
+                for i in 0..COUNT {
+                    loop {
+                        match q.pop() {
+                            Some(v) => { assert_eq!(*v, i); break }
+                            None => thread::yield_now(),
+                        }
+                    }
+                    popped.store(i + 1, Ordering::Relaxed);
+                }
+            })
+        };
+        for i in 0..COUNT {
+            q.push(Box::new(i));
+            while popped.load(Ordering::Relaxed) + 2 < i {
+                thread::yield_now();
+            }
+        }
+        consumer.join().unwrap();
+    }
+}
+
+// An mpmc producer swaps its node into `head`, then links the previous node
+// to it, and until it does the consumer finds the queue Inconsistent. The
+// next producer to swap writes to that previous node's `next`, so the swap is
+// AcqRel: Release to publish the new node's initialization, Acquire to see
+// the previous one's, and without the Release TSan reports that write racing
+// with the node's initialization. Once the window closes the value comes
+// through the head the consumer acquired in finding it Inconsistent, or
+// through the Release and Acquire of `next` if it never looked.
+#[test]
+fn mpmc_inconsistent_window() {
+    const PRODUCERS: usize = 4;
+    let q = Arc::new(mpmc::Queue::new());
+    let producers: Vec<_> = (0..PRODUCERS).map(|id| {
+        let q = q.clone();
+        thread::spawn(move|| {
+            for seq in 0..COUNT / PRODUCERS {
+                q.push(Box::new((id, seq)));
+            }
+        })
+    }).collect();
+    let mut next = [0; PRODUCERS];
+    let mut received = 0;
+    while received < COUNT / PRODUCERS * PRODUCERS {
+        match q.pop() {
+            mpmc::Data(v) => {
+                let (id, seq) = *v;
+                assert_eq!(seq, next[id], "producer {} out of order", id);
+                next[id] += 1;
+                received += 1;
+            }
+            mpmc::Inconsistent | mpmc::Empty => thread::yield_now(),
+        }
+    }
+    for p in producers {
+        p.join().unwrap();
+    }
+}
+
+type Packet<T> = stream2::Packet<spsc::CNQueue<stream2::Message<T>>, T>;
+
+// A receiver with no spin parks on every empty queue, handing its token over
+// through `to_wake`: it writes the token, then publishes it with a SeqCst
+// store, which the sender's swap in `try_take_to_wake` acquires before it
+// touches the token. The sender waits for each message to be received before
+// sending the next, so every message is sent to a parked, or parking,
+// receiver. The doorbell fences order the push against the flag, TSan can't
+// see those, but a lost wakeup hangs this rather than racing.
+#[test]
+fn packet_to_wake_handshake() {
+    let count = stress_count(2_000);
+    let packet = Arc::new(Packet::<Box<usize>>::new());
+    packet.set_spin(0);
+    let received = Arc::new(AtomicUsize::new(0));
+    let receiver = {
+        let (packet, received) = (packet.clone(), received.clone());
+        thread::spawn(move|| {
+            for i in 0..count {
+                match packet.recv(None) {
+                    Ok(v) => assert_eq!(*v, i),
+                    Err(_) => panic!("recv failed before message {}", i),
+                }
+                received.store(i + 1, Ordering::Release);
+            }
+            packet.drop_port();
+        })
+    };
+    for i in 0..count {
+        packet.send(Box::new(i)).unwrap();
+        while received.load(Ordering::Acquire) <= i {
+            thread::yield_now();
+        }
+    }
+    packet.drop_chan();
+    receiver.join().unwrap();
+}
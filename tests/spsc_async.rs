@@ -0,0 +1,224 @@
+//! Tests for the async halves of the raw queues in `spsc_async`, run by a
+//! small executor like the one in stream2_async.rs.
+
+#![cfg(feature = "async")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::{bounded_mpmc, spsc};
+use std_spsc_is_slow::spsc_async::{split, AsyncConsumer, AsyncProducer};
+use std_spsc_is_slow::verify::stress_count;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+const WATCHDOG: Duration = Duration::from_secs(5);
+
+type Spsc = spsc::CNQueue<u32>;
+type Bounded = bounded_mpmc::Queue<u32, bounded_mpmc::CacheAligned>;
+
+// Counts its wakeups, and unparks the thread which made it.
+struct TestWaker {
+    thread: Thread,
+    woken: AtomicBool,
+    wakes: AtomicUsize,
+}
+
+impl TestWaker {
+    fn new() -> Arc<Self> {
+        Arc::new(TestWaker {
+            thread: thread::current(),
+            woken: AtomicBool::new(false),
+            wakes: AtomicUsize::new(0),
+        })
+    }
+
+    fn wakes(&self) -> usize {
+        self.wakes.load(Ordering::SeqCst)
+    }
+}
+
+impl Wake for TestWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+        self.woken.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+// The executor. Polls `f` until it's ready, parking in between until it's
+// woken. A Pending poll with no wakeup for a while is a lost wakeup.
+fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = Box::pin(f);
+    let test_waker = TestWaker::new();
+    let waker = Waker::from(test_waker.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(r) = f.as_mut().poll(&mut cx) {
+            return r
+        }
+        let start = Instant::now();
+        while !test_waker.woken.swap(false, Ordering::SeqCst) {
+            assert!(start.elapsed() < WATCHDOG, "no wakeup for {:?}, it was lost", WATCHDOG);
+            thread::park_timeout(Duration::from_millis(10));
+        }
+    }
+}
+
+fn poll<F: Future + Unpin>(f: &mut F, waker: &Arc<TestWaker>) -> Poll<F::Output> {
+    let waker = Waker::from(waker.clone());
+    Pin::new(f).poll(&mut Context::from_waker(&waker))
+}
+
+fn unbounded() -> (AsyncProducer<u32, Spsc>, AsyncConsumer<u32, Spsc>) {
+    split(unsafe { spsc::Queue::aligned(128) })
+}
+
+fn bounded(capacity: usize) -> (AsyncProducer<u32, Bounded>, AsyncConsumer<u32, Bounded>) {
+    split(bounded_mpmc::Queue::aligned(capacity))
+}
+
+#[test]
+fn ready() {
+    let (tx, rx) = unbounded();
+    let waker = TestWaker::new();
+    tx.send(1);
+    assert_eq!(poll(&mut rx.recv(), &waker), Poll::Ready(1));
+    assert_eq!(waker.wakes(), 0);
+}
+
+#[test]
+fn wake_on_push() {
+    let (tx, rx) = unbounded();
+    let waker = TestWaker::new();
+    let mut recv = rx.recv();
+    assert_eq!(poll(&mut recv, &waker), Poll::Pending);
+    assert_eq!(waker.wakes(), 0);
+    tx.send(1);
+    assert_eq!(waker.wakes(), 1);
+    // The wake emptied the slot, so only the first push wakes.
+    tx.send(2);
+    assert_eq!(waker.wakes(), 1);
+    assert_eq!(poll(&mut recv, &waker), Poll::Ready(1));
+    assert_eq!(poll(&mut rx.recv(), &waker), Poll::Ready(2));
+}
+
+#[test]
+fn repoll_replaces_waker() {
+    let (tx, rx) = unbounded();
+    let first = TestWaker::new();
+    let second = TestWaker::new();
+    let mut recv = rx.recv();
+    assert_eq!(poll(&mut recv, &first), Poll::Pending);
+    assert_eq!(poll(&mut recv, &second), Poll::Pending);
+    tx.send(1);
+    assert_eq!((first.wakes(), second.wakes()), (0, 1));
+    assert_eq!(poll(&mut recv, &second), Poll::Ready(1));
+}
+
+#[test]
+fn cancel_deregisters() {
+    let (tx, rx) = unbounded();
+    let waker = TestWaker::new();
+    let mut recv = rx.recv();
+    assert_eq!(poll(&mut recv, &waker), Poll::Pending);
+    assert_eq!(Arc::strong_count(&waker), 2);
+    drop(recv);
+    // The slot let go of the waker, and a push doesn't wake it.
+    assert_eq!(Arc::strong_count(&waker), 1);
+    tx.send(1);
+    assert_eq!(waker.wakes(), 0);
+    // Nor was the value lost with the future.
+    assert_eq!(poll(&mut rx.recv(), &waker), Poll::Ready(1));
+}
+
+#[test]
+fn halves_drop_a_registered_waker() {
+    let (tx, rx) = unbounded();
+    let waker = TestWaker::new();
+    let mut recv = rx.recv();
+    assert_eq!(poll(&mut recv, &waker), Poll::Pending);
+    // Leak the future, so only dropping the queue can free the waker.
+    ::std::mem::forget(recv);
+    drop(rx);
+    drop(tx);
+    assert_eq!(Arc::strong_count(&waker), 1);
+}
+
+#[test]
+fn bounded_send_waits_for_room() {
+    let (tx, rx) = bounded(2);
+    let waker = TestWaker::new();
+    assert_eq!(poll(&mut tx.send(1), &waker), Poll::Ready(()));
+    assert_eq!(tx.try_send(2), Ok(()));
+    assert_eq!(tx.try_send(3), Err(3));
+    let mut send = tx.send(3);
+    assert_eq!(poll(&mut send, &waker), Poll::Pending);
+    assert_eq!(rx.try_recv(), Some(1));
+    assert_eq!(waker.wakes(), 1);
+    assert_eq!(poll(&mut send, &waker), Poll::Ready(()));
+    assert_eq!(rx.try_recv(), Some(2));
+    assert_eq!(rx.try_recv(), Some(3));
+    assert_eq!(rx.try_recv(), None);
+}
+
+#[test]
+fn bounded_cancel_deregisters() {
+    let (tx, rx) = bounded(2);
+    let waker = TestWaker::new();
+    tx.try_send(1).unwrap();
+    tx.try_send(2).unwrap();
+    let mut send = tx.send(3);
+    assert_eq!(poll(&mut send, &waker), Poll::Pending);
+    drop(send);
+    assert_eq!(Arc::strong_count(&waker), 1);
+    assert_eq!(rx.try_recv(), Some(1));
+    assert_eq!(waker.wakes(), 0);
+    assert_eq!(rx.try_recv(), Some(2));
+    assert_eq!(rx.try_recv(), None);
+}
+
+// Pushes race the registration of the consumer's waker. Every so often the
+// producer yields, so that the consumer runs dry and registers.
+#[test]
+fn no_lost_wakeups() {
+    const COUNT: u32 = stress_count(20_000) as u32;
+    let (tx, rx) = unbounded();
+    let producer = thread::spawn(move|| {
+        for i in 0..COUNT {
+            tx.send(i);
+            if i % 8 == 0 { thread::yield_now() }
+        }
+    });
+    for i in 0..COUNT {
+        assert_eq!(block_on(rx.recv()), i);
+    }
+    producer.join().unwrap();
+}
+
+// Both sides wait on each other through a queue of two.
+#[test]
+fn no_lost_wakeups_bounded() {
+    const COUNT: u32 = stress_count(20_000) as u32;
+    let (tx, rx) = bounded(2);
+    let producer = thread::spawn(move|| {
+        for i in 0..COUNT {
+            block_on(tx.send(i));
+        }
+    });
+    for i in 0..COUNT {
+        assert_eq!(block_on(rx.recv()), i);
+        if i % 8 == 0 { thread::yield_now() }
+    }
+    producer.join().unwrap();
+}
@@ -0,0 +1,103 @@
+//! Counts the allocations of queues created and dropped over and over, with
+//! and without a shared `NodePool`, and checks every node is freed once.
+
+#![cfg(feature = "queue_experiments")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::spsc;
+use std_spsc_is_slow::cache_padded::Padding;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+use spsc::NodePool;
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+// allocations less frees, of the counting thread
+static LIVE: AtomicIsize = AtomicIsize::new(0);
+
+thread_local! {
+    // Const, so that reaching it doesn't allocate.
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn counting() -> bool {
+    COUNTING.try_with(|c| c.get()).unwrap_or(false)
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if counting() {
+            ALLOCS.fetch_add(1, Ordering::SeqCst);
+            LIVE.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if counting() {
+            LIVE.fetch_sub(1, Ordering::SeqCst);
+        }
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if counting() {
+            ALLOCS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const QUEUES: usize = 1000;
+const MESSAGES: usize = 32;
+
+// Runs `f` counting its allocations, and those still live at its end.
+fn counted<F: FnOnce()>(f: F) -> (usize, isize) {
+    ALLOCS.store(0, Ordering::SeqCst);
+    LIVE.store(0, Ordering::SeqCst);
+    COUNTING.with(|c| c.set(true));
+    f();
+    COUNTING.with(|c| c.set(false));
+    (ALLOCS.load(Ordering::SeqCst), LIVE.load(Ordering::SeqCst))
+}
+
+fn churn<A, C, F>(new: F)
+where A: Padding, C: spsc::UseCache, F: Fn() -> spsc::Queue<usize, A, C> {
+    for _ in 0..QUEUES {
+        let q = new();
+        for i in 0..MESSAGES {
+            q.push(i);
+        }
+        for i in 0..MESSAGES {
+            assert_eq!(q.pop(), Some(i));
+        }
+    }
+}
+
+// Both are run on the one test thread, so the counts don't mix.
+#[test]
+fn churn_reuses_pooled_nodes() {
+    let (unpooled, live) = counted(|| churn(|| unsafe { spsc::CNQueue::aligned(128) }));
+    assert_eq!(live, 0);
+    assert!(unpooled >= QUEUES * MESSAGES, "{} allocations without a pool", unpooled);
+
+    let (pooled, live) = counted(|| {
+        let pool = Arc::new(NodePool::new(1024));
+        churn(|| unsafe { spsc::CPQueue::with_pool(128, pool.clone()) });
+        assert_eq!(pool.len(), MESSAGES + 2);
+        // and dropping the pool frees the nodes it held, once each
+    });
+    assert_eq!(live, 0);
+    // the first queue's nodes and the pool itself
+    assert!(pooled <= MESSAGES + 2 + 1, "{} allocations with a pool, {} without", pooled, unpooled);
+}
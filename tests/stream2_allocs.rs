@@ -0,0 +1,98 @@
+//! Counts the heap allocations a parking receiver makes, with a global
+//! allocator which counts the allocations of whichever threads ask it to.
+
+#![cfg(feature = "queue_experiments")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::{spsc, stream2};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use stream2::Packet;
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Const, so that reaching it doesn't allocate.
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn count() {
+    if COUNTING.try_with(|c| c.get()).unwrap_or(false) {
+        ALLOCS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const WARMUP: usize = 100;
+const PARKS: usize = 10_000;
+
+// The sender pauses before each send, so the receiver parks for each
+// message. Once the thread has its cached tokens that costs no allocations,
+// but allow a few, for when a sender still held the last pair as the
+// receiver parked again.
+#[test]
+fn parks_reuse_tokens() {
+    let packet = Arc::new(Packet::<spsc::CNQueue<_>, usize>::new());
+    let sender = {
+        let packet = packet.clone();
+        thread::spawn(move|| {
+            for i in 0..(WARMUP + PARKS) {
+                thread::sleep(Duration::new(0, 20_000));
+                packet.send(i).unwrap();
+            }
+            packet.drop_chan();
+        })
+    };
+    for i in 0..WARMUP {
+        assert_eq!(packet.recv(None).ok(), Some(i));
+    }
+
+    COUNTING.with(|c| c.set(true));
+    for i in WARMUP..(WARMUP + PARKS) {
+        let got = packet.recv(None).ok();
+        if got != Some(i) {
+            COUNTING.with(|c| c.set(false));
+            panic!("expected {}, got {:?}", i, got);
+        }
+    }
+    COUNTING.with(|c| c.set(false));
+    let allocs = ALLOCS.load(Ordering::SeqCst);
+
+    assert!(packet.recv(None).is_err());
+    sender.join().unwrap();
+    #[cfg(feature = "stats")]
+    {
+        let parks = packet.stats().parks as usize;
+        assert!(parks > PARKS / 2, "only {} parks", parks);
+    }
+    packet.drop_port();
+    assert!(allocs < PARKS / 100, "{} allocations over {} parks", allocs, PARKS);
+}
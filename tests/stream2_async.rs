@@ -0,0 +1,230 @@
+//! Tests for stream2's `AsyncReceiver`, run by a small executor of our own
+//! rather than pulling in a runtime.
+
+#![cfg(feature = "async")]
+#![feature(async_iterator)]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::stream2;
+use std_spsc_is_slow::verify::stress_count;
+
+use std::async_iter::AsyncIterator;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use stream2::{async_channel, AsyncReceiver};
+
+const WATCHDOG: Duration = Duration::from_secs(5);
+
+// Counts its wakeups, and unparks the thread which made it.
+struct TestWaker {
+    thread: Thread,
+    woken: AtomicBool,
+    wakes: AtomicUsize,
+}
+
+impl TestWaker {
+    fn new() -> Arc<Self> {
+        Arc::new(TestWaker {
+            thread: thread::current(),
+            woken: AtomicBool::new(false),
+            wakes: AtomicUsize::new(0),
+        })
+    }
+
+    fn wakes(&self) -> usize {
+        self.wakes.load(Ordering::SeqCst)
+    }
+}
+
+impl Wake for TestWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+        self.woken.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+// The executor. Polls `f` until it's ready, parking in between until it's
+// woken. A Pending poll with no wakeup for a while is a lost wakeup.
+fn block_on<R, F: FnMut(&mut Context) -> Poll<R>>(mut f: F) -> R {
+    let test_waker = TestWaker::new();
+    let waker = Waker::from(test_waker.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(r) = f(&mut cx) {
+            return r
+        }
+        let start = Instant::now();
+        while !test_waker.woken.swap(false, Ordering::SeqCst) {
+            assert!(start.elapsed() < WATCHDOG, "no wakeup for {:?}, it was lost", WATCHDOG);
+            thread::park_timeout(Duration::from_millis(10));
+        }
+    }
+}
+
+fn poll(rx: &mut AsyncReceiver<u32>, waker: &Arc<TestWaker>) -> Poll<Option<u32>> {
+    let waker = Waker::from(waker.clone());
+    rx.poll_recv(&mut Context::from_waker(&waker))
+}
+
+#[test]
+fn ready() {
+    let (tx, mut rx) = async_channel();
+    let waker = TestWaker::new();
+    tx.send(1).unwrap();
+    assert_eq!(poll(&mut rx, &waker), Poll::Ready(Some(1)));
+    assert_eq!(waker.wakes(), 0);
+}
+
+#[test]
+fn wake_on_send() {
+    let (tx, mut rx) = async_channel();
+    let waker = TestWaker::new();
+    assert_eq!(poll(&mut rx, &waker), Poll::Pending);
+    assert_eq!(waker.wakes(), 0);
+    tx.send(1).unwrap();
+    assert_eq!(waker.wakes(), 1);
+    // Only the registered send wakes.
+    tx.send(2).unwrap();
+    assert_eq!(waker.wakes(), 1);
+    assert_eq!(poll(&mut rx, &waker), Poll::Ready(Some(1)));
+    assert_eq!(poll(&mut rx, &waker), Poll::Ready(Some(2)));
+}
+
+#[test]
+fn wake_on_disconnect() {
+    let (tx, mut rx) = async_channel::<u32>();
+    let waker = TestWaker::new();
+    assert_eq!(poll(&mut rx, &waker), Poll::Pending);
+    drop(tx);
+    assert_eq!(waker.wakes(), 1);
+    assert_eq!(poll(&mut rx, &waker), Poll::Ready(None));
+}
+
+#[test]
+fn repoll_replaces_waker() {
+    let (tx, mut rx) = async_channel();
+    let first = TestWaker::new();
+    let second = TestWaker::new();
+    assert_eq!(poll(&mut rx, &first), Poll::Pending);
+    assert_eq!(poll(&mut rx, &second), Poll::Pending);
+    tx.send(1).unwrap();
+    assert_eq!((first.wakes(), second.wakes()), (0, 1));
+    assert_eq!(poll(&mut rx, &second), Poll::Ready(Some(1)));
+}
+
+#[test]
+fn wake_on_upgrade() {
+    let (tx, mut rx) = async_channel();
+    let waker = TestWaker::new();
+    assert_eq!(poll(&mut rx, &waker), Poll::Pending);
+    // The upgrade is sent like a message, and wakes us to follow it.
+    let tx2 = tx.clone();
+    assert_eq!(waker.wakes(), 1);
+    assert_eq!(poll(&mut rx, &waker), Poll::Pending);
+    tx2.send(1).unwrap();
+    assert_eq!(waker.wakes(), 2);
+    assert_eq!(poll(&mut rx, &waker), Poll::Ready(Some(1)));
+    assert_eq!(poll(&mut rx, &waker), Poll::Pending);
+    drop(tx);
+    drop(tx2);
+    assert_eq!(waker.wakes(), 3);
+    assert_eq!(poll(&mut rx, &waker), Poll::Ready(None));
+}
+
+#[test]
+fn drop_while_pending() {
+    let (tx, mut rx) = async_channel();
+    let waker = TestWaker::new();
+    assert_eq!(poll(&mut rx, &waker), Poll::Pending);
+    drop(rx);
+    assert!(tx.send(1).is_err());
+    assert_eq!(waker.wakes(), 0);
+}
+
+#[test]
+fn shared_drop_while_pending() {
+    let (tx, mut rx) = async_channel();
+    let tx2 = tx.clone();
+    let waker = TestWaker::new();
+    assert_eq!(poll(&mut rx, &waker), Poll::Pending);
+    drop(rx);
+    assert!(tx.send(1).is_err());
+    assert!(tx2.send(1).is_err());
+}
+
+#[test]
+fn async_iterator() {
+    let (tx, mut rx) = async_channel();
+    let sender = thread::spawn(move|| {
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+    });
+    let mut got = vec![];
+    while let Some(i) = block_on(|cx| Pin::new(&mut rx).poll_next(cx)) {
+        got.push(i);
+    }
+    assert_eq!(got, (0..10).collect::<Vec<_>>());
+    sender.join().unwrap();
+}
+
+// Sends race the registration of the receiver's waker. Every so often the
+// sender yields, so that the receiver runs dry and registers.
+fn no_lost_wakeups(senders: u32) {
+    const COUNT: u32 = stress_count(20_000) as u32;
+    let (tx, mut rx) = async_channel();
+    let threads: Vec<_> = (0..senders).map(|_| {
+        let tx = tx.clone();
+        thread::spawn(move|| {
+            for i in 0..COUNT {
+                tx.send(i).unwrap();
+                if i % 8 == 0 { thread::yield_now() }
+            }
+        })
+    }).collect();
+    drop(tx);
+    let mut received = 0;
+    while let Some(_) = block_on(|cx| rx.poll_recv(cx)) {
+        received += 1;
+    }
+    assert_eq!(received, senders * COUNT);
+    for t in threads {
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn no_lost_wakeups_stream() {
+    // The one clone upgrades the channel, so keep the original sender as the
+    // only one.
+    const COUNT: u32 = stress_count(20_000) as u32;
+    let (tx, mut rx) = async_channel();
+    let sender = thread::spawn(move|| {
+        for i in 0..COUNT {
+            tx.send(i).unwrap();
+            if i % 8 == 0 { thread::yield_now() }
+        }
+    });
+    for i in 0..COUNT {
+        assert_eq!(block_on(|cx| rx.poll_recv(cx)), Some(i));
+    }
+    assert_eq!(block_on(|cx| rx.poll_recv(cx)), None);
+    sender.join().unwrap();
+}
+
+#[test]
+fn no_lost_wakeups_shared() {
+    no_lost_wakeups(3);
+}
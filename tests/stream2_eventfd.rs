@@ -0,0 +1,124 @@
+//! Tests for `Receiver::readiness_fd`, waiting on the fd with a raw `poll(2)`
+//! as an event loop would, and draining with `try_recv` after each readiness.
+
+#![cfg(all(feature = "eventfd", target_os = "linux"))]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::stream2;
+
+use std::os::raw::{c_int, c_ulong};
+use std::os::unix::io::RawFd;
+use std::thread;
+use std::time::Duration;
+
+use stream2::{channel, Receiver, TryRecvError};
+
+const POLLIN: i16 = 1;
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: i16,
+    revents: i16,
+}
+
+extern "C" {
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+// Whether `fd` becomes readable within `timeout_ms`.
+fn readable(fd: RawFd, timeout_ms: c_int) -> bool {
+    let mut fds = PollFd { fd: fd, events: POLLIN, revents: 0 };
+    let n = unsafe { poll(&mut fds, 1, timeout_ms) };
+    assert!(n >= 0, "poll failed");
+    n == 1 && fds.revents & POLLIN != 0
+}
+
+// Receives until `Empty`, as an event loop does after each readiness.
+// Returns what it got, and whether the sender has gone.
+fn drain(rx: &Receiver<usize>) -> (Vec<usize>, bool) {
+    let mut got = vec![];
+    loop {
+        match rx.try_recv() {
+            Ok(t) => got.push(t),
+            Err(TryRecvError::Empty) => return (got, false),
+            Err(TryRecvError::Disconnected) => return (got, true),
+        }
+    }
+}
+
+#[test]
+fn starts_readable() {
+    let (tx, rx) = channel::<usize>();
+    tx.send(1).unwrap();
+    let fd = rx.readiness_fd();
+    assert_eq!(rx.readiness_fd(), fd);
+    // whatever was sent before the fd was asked for is picked up by the
+    // first drain
+    assert!(readable(fd, 0));
+    assert_eq!(drain(&rx), (vec![1], false));
+    assert!(!readable(fd, 0));
+}
+
+#[test]
+fn readable_on_send() {
+    let (tx, rx) = channel::<usize>();
+    let fd = rx.readiness_fd();
+    assert_eq!(drain(&rx), (vec![], false));
+    assert!(!readable(fd, 10));
+
+    tx.send(1).unwrap();
+    tx.send(2).unwrap();
+    assert!(readable(fd, 0));
+    // several sends between drains are all there for the one drain
+    assert_eq!(drain(&rx), (vec![1, 2], false));
+    assert!(!readable(fd, 0));
+}
+
+#[test]
+fn readable_on_disconnect() {
+    let (tx, rx) = channel::<usize>();
+    let fd = rx.readiness_fd();
+    assert_eq!(drain(&rx), (vec![], false));
+    drop(tx);
+    assert!(readable(fd, 1000));
+    assert_eq!(drain(&rx), (vec![], true));
+}
+
+// An event loop over the fd, against a sender which sends in bursts from
+// another thread, and is cloned halfway through so the channel upgrades.
+#[test]
+fn event_loop() {
+    const COUNT: usize = 10_000;
+    let (tx, rx) = channel();
+    let fd = rx.readiness_fd();
+    let sender = thread::spawn(move|| {
+        let mut tx2 = None;
+        for i in 0..COUNT {
+            if i % 100 == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            if i == COUNT / 2 {
+                tx2 = Some(tx.clone());
+            }
+            match tx2 {
+                Some(ref tx2) => tx2.send(i).unwrap(),
+                None => tx.send(i).unwrap(),
+            }
+        }
+    });
+
+    let mut got = vec![];
+    loop {
+        assert!(readable(fd, 5000), "no readiness after {} messages", got.len());
+        let (mut more, disconnected) = drain(&rx);
+        got.append(&mut more);
+        if disconnected {
+            break
+        }
+    }
+    sender.join().unwrap();
+    assert_eq!(got, (0..COUNT).collect::<Vec<_>>());
+}
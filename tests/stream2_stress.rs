@@ -0,0 +1,211 @@
+//! Lost wakeup stress tests for the blocking protocol of stream2's and
+//! shared's packets.
+//!
+//! The `verify_` tests mix blocking, timed and non-blocking receives, and
+//! check every message is received exactly once and in order, see
+//! `src/verify.rs`.
+//!
+//! The sender sleeps for a random few microseconds between sends, so the
+//! receiver, which only ever uses a blocking `recv`, parks often and the
+//! sends race its parking. A lost wakeup leaves the receiver parked forever
+//! with data queued, so rather than wait on it the test thread watches for
+//! progress and fails the test if there is none for a while.
+
+#![cfg(feature = "queue_experiments")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::{blocking, shared, spsc, spsc2, spsc_seg, spsc_epoch, stream2, verify};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use shared::SharedPacket;
+use stream2::{Message, Packet};
+use verify::{stress_count, verify};
+
+const SHORT_COUNT: usize = 2_000;
+const LONG_COUNT: usize = stress_count(1_000_000);
+const MAX_PAUSE_NS: u32 = 20_000;
+const WATCHDOG: Duration = Duration::from_secs(5);
+
+// The packet operations the test needs, so it can run over each packet.
+trait Chan: Send + Sync + 'static {
+    fn send(&self, t: usize);
+    fn recv(&self) -> Option<usize>;
+    fn drop_chan(&self);
+    fn drop_port(&self);
+}
+
+impl<Q> Chan for Packet<Q, usize>
+where Q: stream2::Queue<Message<usize>> + Send + Sync + 'static {
+    fn send(&self, t: usize) { Packet::send(self, t).unwrap() }
+    fn recv(&self) -> Option<usize> { Packet::recv(self, None).ok() }
+    fn drop_chan(&self) { Packet::drop_chan(self) }
+    fn drop_port(&self) { Packet::drop_port(self) }
+}
+
+impl Chan for SharedPacket<usize> {
+    fn send(&self, t: usize) { SharedPacket::send(self, t).unwrap() }
+    fn recv(&self) -> Option<usize> { SharedPacket::recv(self, None).ok() }
+    fn drop_chan(&self) { SharedPacket::drop_chan(self) }
+    fn drop_port(&self) { SharedPacket::drop_port(self) }
+}
+
+// A tiny xorshift, so that the pauses differ without pulling in a dependency.
+fn next_rand(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+fn stress<C: Chan>(chan: C, count: usize) {
+    let chan = Arc::new(chan);
+    let received = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let sender = {
+        let chan = chan.clone();
+        thread::spawn(move|| {
+            let mut rand = 0x2545_f491;
+            for i in 0..count {
+                thread::sleep(Duration::new(0, next_rand(&mut rand) % MAX_PAUSE_NS));
+                chan.send(i);
+            }
+            chan.drop_chan();
+        })
+    };
+    let receiver = {
+        let (chan, received, done) = (chan.clone(), received.clone(), done.clone());
+        thread::spawn(move|| {
+            for i in 0..count {
+                assert_eq!(chan.recv(), Some(i));
+                received.store(i + 1, Ordering::SeqCst);
+            }
+            assert_eq!(chan.recv(), None);
+            chan.drop_port();
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+
+    // The watchdog. Either thread panicking also stops progress, so this
+    // catches those as well, and they're reported by the joins below.
+    let mut last = 0;
+    let mut last_progress = Instant::now();
+    while !done.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(10));
+        let now = received.load(Ordering::SeqCst);
+        if now != last {
+            last = now;
+            last_progress = Instant::now();
+        } else if last_progress.elapsed() > WATCHDOG {
+            panic!("no progress for {:?} after {} of {} messages, \
+                the receiver missed a wakeup", WATCHDOG, now, count);
+        }
+    }
+    sender.join().unwrap();
+    receiver.join().unwrap();
+    assert_eq!(received.load(Ordering::SeqCst), count);
+}
+
+#[test]
+fn spsc_packet() {
+    stress(Packet::<spsc::CNQueue<_>, _>::new(), SHORT_COUNT);
+}
+
+#[test]
+fn spsc2_packet() {
+    stress(Packet::<spsc2::AQueue<_>, _>::new(), SHORT_COUNT);
+}
+
+#[test]
+fn spsc_seg_packet() {
+    stress(Packet::<spsc_seg::AQueue<_>, _>::new(), SHORT_COUNT);
+}
+
+#[test]
+fn spsc_epoch_packet() {
+    stress(Packet::<spsc_epoch::Queue<_>, _>::new(), SHORT_COUNT);
+}
+
+#[test]
+fn shared_packet() {
+    stress(SharedPacket::new(), SHORT_COUNT);
+}
+
+#[test]
+#[ignore]
+fn spsc_packet_long() {
+    stress(Packet::<spsc::CNQueue<_>, _>::new(), LONG_COUNT);
+}
+
+#[test]
+#[ignore]
+fn spsc2_packet_long() {
+    stress(Packet::<spsc2::AQueue<_>, _>::new(), LONG_COUNT);
+}
+
+#[test]
+#[ignore]
+fn shared_packet_long() {
+    stress(SharedPacket::new(), LONG_COUNT);
+}
+
+// Debug builds check every token's trip through `to_wake`, and panic on a
+// bad one. The protocol must never trip them, and this makes sure they ran.
+#[cfg(debug_assertions)]
+#[test]
+fn token_checks_hold() {
+    let before = blocking::checked_decodes();
+    stress(Packet::<spsc::CNQueue<_>, _>::new(), SHORT_COUNT);
+    verify(Packet::<spsc::CNQueue<_>, _>::new(), 1, SHORT_COUNT, WATCHDOG);
+    verify(SharedPacket::new(), 2, SHORT_COUNT, WATCHDOG);
+    assert!(blocking::checked_decodes() > before);
+}
+
+#[test]
+fn verify_spsc_packet() {
+    verify(Packet::<spsc::CNQueue<_>, _>::new(), 1, SHORT_COUNT, WATCHDOG);
+}
+
+#[test]
+fn verify_spsc2_packet() {
+    verify(Packet::<spsc2::AQueue<_>, _>::new(), 1, SHORT_COUNT, WATCHDOG);
+}
+
+#[test]
+fn verify_spsc_seg_packet() {
+    verify(Packet::<spsc_seg::AQueue<_>, _>::new(), 1, SHORT_COUNT, WATCHDOG);
+}
+
+#[test]
+fn verify_spsc_epoch_packet() {
+    verify(Packet::<spsc_epoch::Queue<_>, _>::new(), 1, SHORT_COUNT, WATCHDOG);
+}
+
+#[test]
+fn verify_shared_packet() {
+    verify(SharedPacket::new(), 2, SHORT_COUNT, WATCHDOG);
+}
+
+#[test]
+#[ignore]
+fn verify_spsc_packet_long() {
+    verify(Packet::<spsc::CNQueue<_>, _>::new(), 1, LONG_COUNT, WATCHDOG);
+}
+
+#[test]
+#[ignore]
+fn verify_spsc2_packet_long() {
+    verify(Packet::<spsc2::AQueue<_>, _>::new(), 1, LONG_COUNT, WATCHDOG);
+}
+
+#[test]
+#[ignore]
+fn verify_shared_packet_long() {
+    verify(SharedPacket::new(), 2, LONG_COUNT, WATCHDOG);
+}
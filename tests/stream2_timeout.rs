@@ -0,0 +1,148 @@
+//! Deadline tests for stream2's `recv_timeout` and `recv_deadline`, on both
+//! the stream and the upgraded shared flavor.
+//!
+//! The timing assertions are generous, as other tests are running alongside
+//! these, but bounded, so that a receiver which keeps extending its wait
+//! still fails.
+
+#![cfg(feature = "queue_experiments")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::stream2;
+#[cfg(feature = "stats")]
+use std_spsc_is_slow::spsc;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use stream2::{channel, Sender, Receiver, RecvTimeoutError};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+const SLACK: Duration = Duration::from_secs(2);
+
+fn stream() -> (Sender<u32>, Receiver<u32>) {
+    channel()
+}
+
+// Cloning the sender upgrades the channel to shared.
+fn shared() -> (Sender<u32>, Receiver<u32>) {
+    let (tx, rx) = channel();
+    drop(tx.clone());
+    (tx, rx)
+}
+
+// The receiver is unparked over and over while it waits, and each time must
+// go back to sleep until its original deadline.
+fn spurious_unparks(chan: fn() -> (Sender<u32>, Receiver<u32>)) {
+    let (tx, rx) = chan();
+    let done = Arc::new(AtomicBool::new(false));
+    let receiver = {
+        let done = done.clone();
+        thread::spawn(move|| {
+            let start = Instant::now();
+            let res = rx.recv_timeout(TIMEOUT);
+            let elapsed = start.elapsed();
+            done.store(true, Ordering::SeqCst);
+            (res, elapsed)
+        })
+    };
+    let mut unparks = 0;
+    while !done.load(Ordering::SeqCst) {
+        receiver.thread().unpark();
+        unparks += 1;
+        thread::sleep(Duration::from_millis(1));
+    }
+    let (res, elapsed) = receiver.join().unwrap();
+    assert_eq!(res, Err(RecvTimeoutError::Timeout));
+    assert!(unparks > 1);
+    assert!(elapsed >= TIMEOUT, "returned early after {:?}", elapsed);
+    assert!(elapsed < TIMEOUT + SLACK, "overslept by {:?}", elapsed - TIMEOUT);
+    drop(tx);
+}
+
+// A message sent shortly before the deadline must be delivered. If the
+// sender is descheduled past the deadline the attempt doesn't tell us
+// anything, so try a few times, and require that at least one counts.
+fn just_before_deadline(chan: fn() -> (Sender<u32>, Receiver<u32>)) {
+    let mut conclusive = 0;
+    for _ in 0..5 {
+        let (tx, rx) = chan();
+        let deadline = Instant::now() + TIMEOUT;
+        let sender = thread::spawn(move|| {
+            thread::sleep(TIMEOUT - Duration::from_millis(1));
+            tx.send(1).unwrap();
+            Instant::now()
+        });
+        let res = rx.recv_deadline(deadline);
+        let sent = sender.join().unwrap();
+        if sent < deadline {
+            assert_eq!(res, Ok(1));
+            conclusive += 1;
+        }
+    }
+    assert!(conclusive > 0, "the sender never made the deadline");
+}
+
+// A deadline which has already arrived returns straight away, with data if
+// there is some.
+fn deadline_now(chan: fn() -> (Sender<u32>, Receiver<u32>)) {
+    let (tx, rx) = chan();
+    let start = Instant::now();
+    assert_eq!(rx.recv_deadline(Instant::now()), Err(RecvTimeoutError::Timeout));
+    assert_eq!(rx.recv_deadline(start), Err(RecvTimeoutError::Timeout));
+    assert_eq!(rx.recv_timeout(Duration::new(0, 0)), Err(RecvTimeoutError::Timeout));
+    assert!(start.elapsed() < SLACK);
+
+    tx.send(1).unwrap();
+    assert_eq!(rx.recv_deadline(start), Ok(1));
+    drop(tx);
+    assert_eq!(rx.recv_deadline(Instant::now()), Err(RecvTimeoutError::Disconnected));
+}
+
+#[test]
+fn stream_spurious_unparks() {
+    spurious_unparks(stream);
+}
+
+#[test]
+fn shared_spurious_unparks() {
+    spurious_unparks(shared);
+}
+
+#[test]
+fn stream_just_before_deadline() {
+    just_before_deadline(stream);
+}
+
+#[test]
+fn shared_just_before_deadline() {
+    just_before_deadline(shared);
+}
+
+#[test]
+fn stream_deadline_now() {
+    deadline_now(stream);
+}
+
+#[test]
+fn shared_deadline_now() {
+    deadline_now(shared);
+}
+
+// With the stats feature we can see that the receiver didn't park at all.
+#[cfg(feature = "stats")]
+#[test]
+fn deadline_now_does_not_park() {
+    let p = stream2::Packet::<spsc::CNQueue<_>, u32>::new();
+    match p.recv(Some(Instant::now())) {
+        Err(stream2::Timeout) => {}
+        res => panic!("{:?}", res),
+    }
+    assert_eq!(p.stats().parks, 0);
+    p.drop_chan();
+    p.drop_port();
+}
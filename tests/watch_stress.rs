@@ -0,0 +1,115 @@
+//! Stress tests for `watch::Slot`, with a producer publishing as fast as it
+//! can against a consumer taking or cloning as fast as it can.
+//!
+//! Each value is a run of copies of its index, so a value seen half
+//! written would show up as a mix of two. The consumer checks that every
+//! value it sees is whole, was published, and is no older than the last it
+//! saw, and every published value must be dropped exactly once.
+
+#![cfg(feature = "queue_experiments")]
+#![allow(dead_code)]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::verify::stress_count;
+use std_spsc_is_slow::watch::Slot;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+const COUNT: usize = stress_count(200_000);
+
+#[derive(Debug)]
+struct Value {
+    copies: [usize; 8],
+    drops: Arc<AtomicUsize>,
+}
+
+impl Value {
+    fn new(i: usize, drops: &Arc<AtomicUsize>) -> Self {
+        Value { copies: [i; 8], drops: drops.clone() }
+    }
+
+    fn index(&self) -> usize {
+        let i = self.copies[0];
+        assert!(self.copies.iter().all(|&c| c == i), "torn value {:?}", self.copies);
+        assert!(i < COUNT, "{} was never published", i);
+        i
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        // as if published again, so that the drop counts still add up
+        self.drops.fetch_sub(1, Ordering::SeqCst);
+        Value { copies: self.copies, drops: self.drops.clone() }
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        self.drops.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// Runs a producer against `consume`, which returns the next value the
+// consumer saw, if any, and returns how many distinct values it saw.
+fn stress<F>(consume: F) -> usize
+where F: Fn(&Slot<Value>) -> Option<Value> {
+    let drops = Arc::new(AtomicUsize::new(0));
+    let slot = Arc::new(Slot::new());
+    let producer = {
+        let slot = slot.clone();
+        let drops = drops.clone();
+        thread::spawn(move|| {
+            for i in 0..COUNT {
+                slot.publish(Value::new(i, &drops));
+            }
+        })
+    };
+
+    let mut last = None;
+    let mut seen = 0;
+    while last != Some(COUNT - 1) {
+        if let Some(value) = consume(&slot) {
+            let i = value.index();
+            match last {
+                Some(last) if i < last => panic!("saw {} after {}", i, last),
+                Some(last) if i == last => {},
+                _ => seen += 1,
+            }
+            last = Some(i);
+        }
+    }
+    producer.join().unwrap();
+    drop(slot);
+    assert_eq!(drops.load(Ordering::SeqCst), COUNT);
+    seen
+}
+
+#[test]
+fn take_in_order() {
+    let seen = stress(|slot| slot.take());
+    assert!(seen >= 1);
+}
+
+#[test]
+fn get_cloned_in_order() {
+    let seen = stress(|slot| slot.get_cloned());
+    assert!(seen >= 1);
+}
+
+// Mixed, so that takes race the put back of get_cloned.
+#[test]
+fn take_and_get_cloned_in_order() {
+    let turn = AtomicUsize::new(0);
+    let seen = stress(|slot| {
+        if turn.fetch_add(1, Ordering::Relaxed) % 2 == 0 {
+            slot.take()
+        } else {
+            slot.get_cloned()
+        }
+    });
+    assert!(seen >= 1);
+}
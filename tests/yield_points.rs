@@ -0,0 +1,152 @@
+//! Interleavings of spsc and stream2's `Packet`, explored by the seeded
+//! scheduler in `sched`: every atomic op in them is a point where it may
+//! switch threads, and a failing schedule is shrunk and printed as the
+//! `SCHED_REPLAY` value which runs it again.
+//!
+//! Each test explores a few hundred schedules from a fixed seed, so a
+//! failure here fails every run, not one in a thousand.
+
+#![cfg(feature = "yield_points")]
+
+extern crate std_spsc_is_slow;
+
+use std_spsc_is_slow::{sched, spsc, stream2};
+use std_spsc_is_slow::sched::{Schedule, Threads};
+use std_spsc_is_slow::sched::atomic::{AtomicUsize, Ordering};
+
+use std::panic;
+use std::sync::Arc;
+
+use stream2::{Failure, Message, Packet};
+
+const SCHEDULES: u64 = 300;
+const COUNT: usize = 6;
+
+// A producer and a consumer over an spsc queue caching `bound` nodes. A bound
+// of 1 or 2 has the producer refresh its tail_copy from the consumer's
+// tail_prev, and the consumer recycle nodes into the cache, every few pushes,
+// so the two race each other on nearly every op.
+fn spsc_threads(bound: usize) -> Threads {
+    let queue = Arc::new(unsafe { spsc::CNQueue::<usize>::aligned(bound) });
+    let consumer = queue.clone();
+    vec![
+        Box::new(move|| {
+            for i in 0..COUNT {
+                queue.push(i);
+            }
+        }),
+        Box::new(move|| {
+            for i in 0..COUNT {
+                loop {
+                    match consumer.pop() {
+                        Some(j) => { assert_eq!(i, j); break }
+                        None => sched::yield_now(),
+                    }
+                }
+            }
+            assert_eq!(consumer.pop(), None);
+        }),
+    ]
+}
+
+#[test]
+fn spsc_bound_1() {
+    sched::explore(1, SCHEDULES, || spsc_threads(1));
+}
+
+#[test]
+fn spsc_bound_2() {
+    sched::explore(2, SCHEDULES, || spsc_threads(2));
+}
+
+type Spsc = spsc::CNQueue<Message<usize>>;
+
+// A blocking receiver against a sender which waits for each message to be
+// received before it sends the next, then hangs up. With no spinning the
+// receiver parks as soon as the queue is empty, so its to_wake store and
+// re-check race each send's push and doorbell check. A send which misses the
+// receiver leaves it parked while the sender waits on it, which the scheduler
+// reports once neither can get anywhere; without the wait, the hang up would
+// wake the receiver and hide it.
+fn packet_threads() -> Threads {
+    let packet = Arc::new(Packet::<Spsc, usize>::new());
+    packet.set_spin(0);
+    let receiver = packet.clone();
+    let received = Arc::new(AtomicUsize::new(0));
+    let acked = received.clone();
+    vec![
+        Box::new(move|| {
+            for i in 0..COUNT {
+                packet.send(i).unwrap();
+                while acked.load(Ordering::SeqCst) <= i {
+                    sched::yield_now();
+                }
+            }
+            packet.drop_chan();
+        }),
+        Box::new(move|| {
+            for i in 0..COUNT {
+                match receiver.recv(None) {
+                    Ok(j) => assert_eq!(i, j),
+                    Err(_) => panic!("recv failed before message {}", i),
+                }
+                received.store(i + 1, Ordering::SeqCst);
+            }
+            match receiver.recv(None) {
+                Err(Failure::Disconnected) => {}
+                _ => panic!("expected the disconnect after the last message"),
+            }
+            receiver.drop_port();
+        }),
+    ]
+}
+
+#[test]
+fn packet_send_recv() {
+    sched::explore(3, SCHEDULES, packet_threads);
+}
+
+// A counter bumped with a load then a store, which loses an update whenever
+// the scheduler switches between the two. The last thread to finish checks.
+fn lost_update_threads() -> Threads {
+    let count = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicUsize::new(0));
+    (0..2).map(|_| {
+        let (count, done) = (count.clone(), done.clone());
+        Box::new(move|| {
+            let n = count.load(Ordering::SeqCst);
+            count.store(n + 1, Ordering::SeqCst);
+            if done.fetch_add(1, Ordering::SeqCst) == 1 {
+                assert_eq!(count.load(Ordering::SeqCst), 2, "lost an update");
+            }
+        }) as Box<dyn FnOnce() + Send>
+    }).collect()
+}
+
+#[test]
+fn finds_and_replays_a_race() {
+    let failure = panic::catch_unwind(|| sched::explore(4, SCHEDULES, lost_update_threads))
+        .expect_err("the lost update wasn't found");
+    let message = failure.downcast_ref::<String>().expect("a formatted panic");
+    assert!(message.contains("lost an update"), "{}", message);
+
+    // the schedule it prints fails again, every time
+    let replay = message.rsplit(&format!("{}=", sched::REPLAY_VAR)).next().unwrap();
+    let schedule = Schedule::parse(replay).expect("a replayable schedule");
+    // one preemption between the load and the store is all it takes
+    assert!(schedule.preemptions <= 1, "not shrunk: {}", schedule);
+    for _ in 0..3 {
+        assert!(sched::run(schedule, &lost_update_threads).is_err());
+    }
+}
+
+#[test]
+fn schedules_are_deterministic() {
+    let traces: Vec<_> = (0..4).map(|seed| {
+        let schedule = Schedule { seed: seed, preemptions: sched::PREEMPTIONS };
+        let trace = sched::run(schedule, &|| spsc_threads(1)).unwrap();
+        assert_eq!(sched::run(schedule, &|| spsc_threads(1)).unwrap(), trace);
+        trace
+    }).collect();
+    assert!(traces.iter().any(|t| *t != traces[0]), "every seed ran the same schedule");
+}